@@ -0,0 +1,10 @@
+use cgi::text_response;
+
+async fn greeting() -> &'static str {
+    "Hello World!"
+}
+
+#[cgi::main]
+async fn main(_request: cgi::Request) -> Result<cgi::Response, String> {
+    Ok(text_response(200, greeting().await))
+}
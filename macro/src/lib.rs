@@ -1,6 +1,57 @@
 use proc_macro::TokenStream;
 use quote::{quote, quote_spanned};
-use syn::{spanned::Spanned, ReturnType, Type};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{Expr, Ident, ReturnType, Token, Type};
+
+// `name` or `name = value`
+struct MainArg {
+    name: Ident,
+    value: Option<Expr>,
+}
+
+impl Parse for MainArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let value = if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        Ok(MainArg { name, value })
+    }
+}
+
+#[derive(Default)]
+struct MainArgs {
+    on_error: Option<Expr>,
+    state: Option<Expr>,
+    // Calls of `cgi::Options` methods
+    options: Vec<proc_macro2::TokenStream>,
+}
+
+impl Parse for MainArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = MainArgs::default();
+        for arg in Punctuated::<MainArg, Token![,]>::parse_terminated(input)? {
+            match (arg.name.to_string().as_str(), arg.value) {
+                ("on_error", Some(value)) => args.on_error = Some(value),
+                ("state", Some(value)) => args.state = Some(value),
+                ("max_body", Some(value)) => args.options.push(quote! { .max_body(#value) }),
+                ("read_to_eof", Some(value)) => args.options.push(quote! { .read_to_eof(#value) }),
+                ("catch_panics", None) => args.options.push(quote! { .catch_panics(true) }),
+                ("crlf", None) => args.options.push(quote! { .line_ending(cgi::LineEnding::CrLf) }),
+                ("nph", None) => args.options.push(quote! { .nph(true) }),
+                ("truncated_body", None) => args.options.push(quote! { .truncated_body(true) }),
+                (name @ ("on_error" | "state" | "max_body" | "read_to_eof"), None) => return Err(syn::Error::new(arg.name.span(), format!("expected `{} = ...`", name))),
+                (name @ ("catch_panics" | "crlf" | "nph" | "truncated_body"), Some(value)) => return Err(syn::Error::new_spanned(value, format!("`{}` doesn't take a value", name))),
+                _ => return Err(syn::Error::new(arg.name.span(), format!("unknown argument `{}`", arg.name))),
+            }
+        }
+        Ok(args)
+    }
+}
 
 fn looks_like_result(return_type: &ReturnType) -> bool {
     if let ReturnType::Type(_, ty) = return_type {
@@ -26,9 +77,64 @@ fn looks_like_result(return_type: &ReturnType) -> bool {
 ///     todo!()
 /// }
 /// ```
+///
+/// `main` can return anything which implements `cgi::IntoResponse`, e.g. a `String`. A
+/// `Result` is special: the error only needs to implement `Debug`, it's printed to `stderr`
+/// and answered with `500 Internal Server Error`.
+///
+/// To answer errors differently, e.g. with an error page or another status, pass a function
+/// from the error type to anything which implements `cgi::IntoResponse`:
+///
+/// ```ignore
+/// #[cgi::main(on_error = error_page)]
+/// fn main(request: cgi::Request) -> Result<cgi::Response, MyError> {
+///     todo!()
+/// }
+///
+/// fn error_page(err: MyError) -> cgi::Response {
+///     todo!()
+/// }
+/// ```
+///
+/// Settings of `cgi::Options` can be given as arguments: `max_body = 1024` (a limit for the
+/// request body), `read_to_eof = 1024` (read a body without `CONTENT_LENGTH` up to this limit),
+/// `catch_panics` (answer panics with a `500`), `crlf` (end header lines with `\r\n`), `nph`
+/// (write an HTTP response, like an NPH script) and `truncated_body` (call `main` with a body
+/// which ended early, see `cgi::TruncatedBody`):
+///
+/// ```ignore
+/// #[cgi::main(max_body = 64 * 1024, catch_panics)]
+/// fn main(request: cgi::Request) -> cgi::Response {
+///     todo!()
+/// }
+/// ```
+///
+/// Expensive setup can be done once by a function named with `state`; `main` then gets a
+/// reference to what it returns before the request (see `cgi::handle_with_state`):
+///
+/// ```ignore
+/// #[cgi::main(state = load_config)]
+/// fn main(config: &Config, request: cgi::Request) -> cgi::Response {
+///     todo!()
+/// }
+///
+/// fn load_config() -> Config {
+///     todo!()
+/// }
+/// ```
+///
+/// With the `tokio` (or `pollster`) feature of `cgi`, `main` can be `async`:
+///
+/// ```ignore
+/// #[cgi::main]
+/// async fn main(request: cgi::Request) -> cgi::Response {
+///     todo!()
+/// }
+/// ```
 //#[cfg(not(test))] // NOTE: exporting main breaks tests, we should file an issue.
 #[proc_macro_attribute]
-pub fn main(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn main(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = syn::parse_macro_input!(attr as MainArgs);
     let input = syn::parse_macro_input!(item as syn::ItemFn);
 
     let ret = &input.sig.output;
@@ -44,37 +150,64 @@ pub fn main(_attr: TokenStream, item: TokenStream) -> TokenStream {
         });
     }
 
-    if input.sig.asyncness.is_some() {
-        return TokenStream::from(quote_spanned! { input.span() =>
-            compile_error!("async not supported"),
-        });
+    let asyncness = &input.sig.asyncness;
+
+    if let Some(on_error) = args.on_error.as_ref().filter(|_| !looks_like_result(ret)) {
+        return syn::Error::new_spanned(on_error, "`on_error` needs main to return a Result").to_compile_error().into();
     }
 
-    let inner = if looks_like_result(ret) {
-        quote! {
-            cgi::handle(|request: cgi::Request| {
-                match inner_main(request) {
-                    Ok(resp) => resp,
-                    Err(err) => {
-                        eprintln!("{:?}", err);
-                        cgi::empty_response(500)
-                    }
+    // With `state`, main gets a reference to the state first
+    let (init_state, call_args) = match &args.state {
+        Some(state) => (quote! { let state = &#state(); }, quote! { state, request }),
+        None => (quote! {}, quote! { request }),
+    };
+
+    let call = if looks_like_result(ret) {
+        let result = if asyncness.is_some() { quote! { inner_main(#call_args).await } } else { quote! { inner_main(#call_args) } };
+        let on_error = match &args.on_error {
+            Some(on_error) => quote! { cgi::IntoResponse::into_response(#on_error(err)) },
+            None => quote! {
+                {
+                    eprintln!("{:?}", err);
+                    cgi::empty_response(500)
                 }
-            })
-        }
-    } else {
+            },
+        };
         quote! {
-            cgi::handle(inner_main)
+            match #result {
+                Ok(resp) => cgi::IntoResponse::into_response(resp),
+                Err(err) => #on_error,
+            }
         }
+    } else if asyncness.is_some() {
+        quote! { inner_main(#call_args).await }
+    } else {
+        quote! { inner_main(#call_args) }
+    };
+
+    // `async fn main` needs the `tokio` feature of cgi
+    let options = &args.options;
+    let inner = match (asyncness.is_some(), options.is_empty()) {
+        (true, true) => quote! {
+            cgi::handle_async(|request: cgi::Request| async move { #call })
+        },
+        (true, false) => quote! {
+            cgi::Options::new() #(#options)* .handle_async(|request: cgi::Request| async move { #call })
+        },
+        (false, true) => quote! {
+            cgi::handle(|request: cgi::Request| #call)
+        },
+        (false, false) => quote! {
+            cgi::Options::new() #(#options)* .handle(|request: cgi::Request| #call)
+        },
     };
 
     let result = quote! {
         #vis fn main() {
             #(#attrs)*
-            fn inner_main(#inputs) #ret {
-                #body
-            }
+            #asyncness fn inner_main(#inputs) #ret #body
 
+            #init_state
             #inner
         }
 
@@ -82,3 +215,57 @@ pub fn main(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
     result.into()
 }
+
+/// Turns a function taking a [`CgiRequestBuilder`] into a `#[test]`.
+///
+/// The function gets a fresh `cgi::testing::CgiRequestBuilder::new()`, with the fake CGI
+/// environment of a `GET` request. It can return `()` or a `Result`, like any test. With the
+/// `tokio` or `pollster` feature of `cgi`, it can be `async`.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[cgi::test]
+/// fn not_found(request: cgi::testing::CgiRequestBuilder) {
+///     let response = request.path_info("/missing").run(handler);
+///     assert_eq!(response.status(), 404);
+/// }
+/// ```
+///
+/// [`CgiRequestBuilder`]: https://docs.rs/cgi2/latest/cgi/testing/struct.CgiRequestBuilder.html
+#[proc_macro_attribute]
+pub fn test(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as syn::ItemFn);
+
+    let ret = &input.sig.output;
+    let inputs = &input.sig.inputs;
+    let name = &input.sig.ident;
+    let body = &input.block;
+    let attrs = &input.attrs;
+
+    if inputs.len() != 1 {
+        return TokenStream::from(quote_spanned! { name.span() =>
+            compile_error!("a #[cgi::test] function takes one argument, the request builder"),
+        });
+    }
+
+    // An `async` test needs the `tokio` or `pollster` feature of cgi
+    let asyncness = &input.sig.asyncness;
+    let call = if asyncness.is_some() {
+        quote! { cgi::testing::block_on(inner_test(cgi::testing::CgiRequestBuilder::new())) }
+    } else {
+        quote! { inner_test(cgi::testing::CgiRequestBuilder::new()) }
+    };
+
+    let result = quote! {
+        #[::core::prelude::v1::test]
+        #(#attrs)*
+        fn #name() #ret {
+            #asyncness fn inner_test(#inputs) #ret #body
+
+            #call
+        }
+    };
+
+    result.into()
+}
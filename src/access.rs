@@ -0,0 +1,389 @@
+//! `.htaccess`-style access control, read from a file so rules can change without
+//! recompiling.
+//!
+//! The supported directives are a subset of Apache's:
+//!
+//! ```text
+//! # Host based access, checked against REMOTE_ADDR
+//! order deny,allow          # or allow,deny
+//! deny from all
+//! allow from 192.0.2.0/24 2001:db8::/32 127.0.0.1
+//!
+//! # Who may access, checked against REMOTE_USER. Any matching `require` line is enough.
+//! authname "Members only"
+//! authgroupfile groups      # lines like `admins: alice bob`, relative to this file
+//! require valid-user
+//! require user alice bob
+//! require group admins
+//! require ip 10.0.0.0/8
+//!
+//! satisfy all               # both host access & require must pass, or `any`
+//! ```
+//!
+//! Authentication itself (checking passwords) is left to the web server, which sets
+//! `REMOTE_USER`. A request which needs a user and doesn't have one gets a `401`, anything
+//! else which doesn't pass a `403`.
+//!
+//! ```rust,no_run
+//! use cgi::access::AccessControl;
+//!
+//! fn main() {
+//!     let access = AccessControl::new("/etc/my-cgi/access.conf");
+//!     cgi::handle(|request: cgi::Request| access.handle(request, |request| {
+//!         cgi::text_response(200, "Secret stuff")
+//!     }))
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::meta::{CgiMeta, RemoteAddr};
+use crate::proxy::{ClientInfo, IpNet};
+use crate::{text_response, Request, Response};
+
+/// A rules file couldn't be read or parsed.
+#[derive(Debug)]
+pub enum AccessError {
+    Io(PathBuf, std::io::Error),
+    /// Line number (from 1) and description
+    Syntax(usize, String),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessError::Io(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            AccessError::Syntax(line, msg) => write!(f, "access rules line {}: {}", line, msg),
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+/// The result of checking a request against the rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    /// Not allowed (a `403`)
+    Deny,
+    /// A user is needed but there isn't one (a `401`)
+    Unauthenticated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Order {
+    DenyAllow,
+    AllowDeny,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    All,
+    Net(IpNet),
+}
+
+impl HostPattern {
+    fn parse(s: &str) -> Option<Self> {
+        if s.eq_ignore_ascii_case("all") {
+            return Some(HostPattern::All);
+        }
+        s.parse().ok().map(HostPattern::Net)
+    }
+
+    fn matches(&self, ip: Option<IpAddr>) -> bool {
+        match (self, ip) {
+            (HostPattern::All, _) => true,
+            (HostPattern::Net(net), Some(ip)) => net.contains(ip),
+            (_, None) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requirement {
+    ValidUser,
+    Users(Vec<String>),
+    Groups(Vec<String>),
+    Ip(Vec<HostPattern>),
+}
+
+/// Parsed access rules. See the [module docs](self) for the syntax.
+#[derive(Debug, Clone)]
+pub struct AccessRules {
+    order: Order,
+    allow: Vec<HostPattern>,
+    deny: Vec<HostPattern>,
+    requirements: Vec<Requirement>,
+    groups: HashMap<String, Vec<String>>,
+    satisfy_any: bool,
+    realm: String,
+}
+
+impl AccessRules {
+    /// Parse rules from a string. `authgroupfile` paths are relative to `base_dir`.
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Self, AccessError> {
+        let mut rules = AccessRules {
+            order: Order::DenyAllow,
+            allow: vec![],
+            deny: vec![],
+            requirements: vec![],
+            groups: HashMap::new(),
+            satisfy_any: false,
+            realm: "Restricted".into(),
+        };
+
+        for (idx, line) in text.lines().enumerate() {
+            let lineno = idx + 1;
+            let syntax = |msg: &str| AccessError::Syntax(lineno, msg.to_owned());
+            let line = strip_comment(line).trim();
+            let mut words = line.split_whitespace();
+            let directive = match words.next() {
+                Some(d) => d.to_ascii_lowercase(),
+                None => continue,
+            };
+            let args: Vec<&str> = words.collect();
+
+            match directive.as_str() {
+                "order" => {
+                    rules.order = match args.join("").to_ascii_lowercase().as_str() {
+                        "deny,allow" => Order::DenyAllow,
+                        "allow,deny" => Order::AllowDeny,
+                        _ => return Err(syntax("order must be deny,allow or allow,deny")),
+                    }
+                }
+                "allow" | "deny" => {
+                    if args.first().map(|a| a.to_ascii_lowercase()) != Some("from".into()) || args.len() < 2 {
+                        return Err(syntax("expected `from` and addresses"));
+                    }
+                    let patterns = args[1..].iter()
+                        .map(|a| HostPattern::parse(a).ok_or_else(|| syntax(&format!("invalid address {:?}", a))))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if directive == "allow" { &mut rules.allow } else { &mut rules.deny }.extend(patterns);
+                }
+                "require" => {
+                    let kind = args.first().map(|a| a.to_ascii_lowercase()).unwrap_or_default();
+                    let values: Vec<String> = args.iter().skip(1).map(|a| a.to_string()).collect();
+                    let requirement = match kind.as_str() {
+                        "valid-user" => Requirement::ValidUser,
+                        "user" if !values.is_empty() => Requirement::Users(values),
+                        "group" if !values.is_empty() => Requirement::Groups(values),
+                        "ip" if !values.is_empty() => Requirement::Ip(values.iter()
+                            .map(|a| HostPattern::parse(a).ok_or_else(|| syntax(&format!("invalid address {:?}", a))))
+                            .collect::<Result<_, _>>()?),
+                        _ => return Err(syntax("expected valid-user, user, group or ip")),
+                    };
+                    rules.requirements.push(requirement);
+                }
+                "satisfy" => {
+                    rules.satisfy_any = match args.first().map(|a| a.to_ascii_lowercase()).as_deref() {
+                        Some("any") => true,
+                        Some("all") => false,
+                        _ => return Err(syntax("satisfy must be any or all")),
+                    }
+                }
+                "authname" => rules.realm = args.join(" ").trim_matches('"').to_owned(),
+                "authgroupfile" => {
+                    let path = base_dir.join(args.first().ok_or_else(|| syntax("expected a path"))?);
+                    let contents = std::fs::read_to_string(&path).map_err(|e| AccessError::Io(path, e))?;
+                    for line in contents.lines() {
+                        if let Some((group, users)) = line.split_once(':') {
+                            rules.groups.entry(group.trim().to_owned()).or_default()
+                                .extend(users.split_whitespace().map(|u| u.to_owned()));
+                        }
+                    }
+                }
+                _ => return Err(syntax(&format!("unknown directive {:?}", directive))),
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Read and parse the rules file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, AccessError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| AccessError::Io(path.to_owned(), e))?;
+        AccessRules::parse(&text, path.parent().unwrap_or(Path::new(".")))
+    }
+
+    fn host_allowed(&self, ip: Option<IpAddr>) -> bool {
+        let allowed = self.allow.iter().any(|p| p.matches(ip));
+        let denied = self.deny.iter().any(|p| p.matches(ip));
+        match self.order {
+            // allowed by default, `allow` overrides `deny`
+            Order::DenyAllow => allowed || !denied,
+            // denied by default, `deny` overrides `allow`
+            Order::AllowDeny => allowed && !denied,
+        }
+    }
+
+    fn requirement_met(&self, requirement: &Requirement, user: Option<&str>, ip: Option<IpAddr>) -> bool {
+        match (requirement, user) {
+            (Requirement::Ip(patterns), _) => patterns.iter().any(|p| p.matches(ip)),
+            (_, None) => false,
+            (Requirement::ValidUser, Some(_)) => true,
+            (Requirement::Users(users), Some(user)) => users.iter().any(|u| u == user),
+            (Requirement::Groups(groups), Some(user)) => groups.iter()
+                .any(|g| self.groups.get(g).is_some_and(|members| members.iter().any(|m| m == user))),
+        }
+    }
+
+    /// Check the request's `REMOTE_ADDR` (or [`ClientInfo`] address) & `REMOTE_USER` against
+    /// the rules. They're taken from the [`RemoteAddr`] & [`CgiMeta`] extensions, never from
+    /// headers, which the client could send.
+    pub fn check(&self, request: &Request) -> Decision {
+        let ip = match request.extensions().get::<ClientInfo>() {
+            Some(client) => Some(client.ip),
+            None => request.extensions().get::<RemoteAddr>().map(RemoteAddr::ip),
+        };
+        let user = request.extensions().get::<CgiMeta>().and_then(|meta| meta.remote_user.as_deref()).filter(|u| !u.is_empty());
+
+        let host_ok = self.host_allowed(ip);
+        let require_ok = self.requirements.is_empty() || self.requirements.iter().any(|r| self.requirement_met(r, user, ip));
+        let needs_user = self.requirements.iter().any(|r| !matches!(r, Requirement::Ip(_)));
+
+        let allowed = if self.satisfy_any && !self.requirements.is_empty() {
+            host_ok || require_ok
+        } else {
+            host_ok && require_ok
+        };
+        if allowed {
+            Decision::Allow
+        } else if (host_ok || self.satisfy_any) && !require_ok && needs_user && user.is_none() {
+            Decision::Unauthenticated
+        } else {
+            Decision::Deny
+        }
+    }
+}
+
+/// Cut off a `#` comment which starts the line or follows whitespace, outside of quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut prev = None;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted && prev.is_none_or(char::is_whitespace) => return &line[..idx],
+            _ => {}
+        }
+        prev = Some(c);
+    }
+    line
+}
+
+/// Enforce the rules in a file, re-reading it for every request. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    path: PathBuf,
+}
+
+impl AccessControl {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AccessControl { path: path.into() }
+    }
+
+    /// Call `next` if the rules allow the request, otherwise return a `401` or `403`. If the
+    /// rules can't be read, the error is printed to stderr and the request gets a `500`.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        let rules = match AccessRules::load(&self.path) {
+            Ok(rules) => rules,
+            Err(err) => {
+                eprintln!("{}", err);
+                return text_response(500, "Access rules could not be loaded");
+            }
+        };
+        match rules.check(&request) {
+            Decision::Allow => next(request),
+            Decision::Deny => text_response(403, "Forbidden"),
+            Decision::Unauthenticated => crate::unauthorized_basic(&rules.realm),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(ip: &str, user: Option<&str>) -> Request {
+        let mut req = crate::testing::CgiRequestBuilder::new().env("REMOTE_ADDR", ip);
+        if let Some(user) = user {
+            req = req.env("REMOTE_USER", user);
+        }
+        req.build()
+    }
+
+    fn rules(text: &str) -> AccessRules {
+        AccessRules::parse(text, Path::new(".")).unwrap()
+    }
+
+    #[test]
+    fn test_host_rules() {
+        let r = rules("order deny,allow\ndeny from all\nallow from 192.0.2.0/24 ::1");
+        assert_eq!(r.check(&request("192.0.2.7", None)), Decision::Allow);
+        assert_eq!(r.check(&request("::ffff:192.0.2.7", None)), Decision::Allow);
+        assert_eq!(r.check(&request("::1", None)), Decision::Allow);
+        assert_eq!(r.check(&request("198.51.100.1", None)), Decision::Deny);
+
+        let r = rules("order allow,deny\nallow from all\ndeny from 10.0.0.0/8 # internal");
+        assert_eq!(r.check(&request("10.1.2.3", None)), Decision::Deny);
+        assert_eq!(r.check(&request("192.0.2.1", None)), Decision::Allow);
+
+        // Without any allow or deny lines, the order decides
+        assert_eq!(rules("").check(&request("192.0.2.1", None)), Decision::Allow);
+        assert_eq!(rules("order allow,deny").check(&request("192.0.2.1", None)), Decision::Deny);
+
+        assert!(matches!(AccessRules::parse("allow 1.2.3.4", Path::new(".")), Err(AccessError::Syntax(1, _))));
+        assert!(matches!(AccessRules::parse("\nallow from 1.2.3.400", Path::new(".")), Err(AccessError::Syntax(2, _))));
+    }
+
+    #[test]
+    fn test_require() {
+        let dir = std::env::temp_dir().join(format!("cgi-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("groups"), "admins: alice bob\n").unwrap();
+        std::fs::write(dir.join("access.conf"), "authgroupfile groups\nrequire group admins\nrequire user carol\n").unwrap();
+
+        let r = AccessRules::load(dir.join("access.conf")).unwrap();
+        assert_eq!(r.check(&request("192.0.2.1", Some("bob"))), Decision::Allow);
+        assert_eq!(rules("# members\nauthname \"Team #1\" # realm").realm, "Team #1");
+        assert_eq!(r.check(&request("192.0.2.1", Some("carol"))), Decision::Allow);
+        assert_eq!(r.check(&request("192.0.2.1", Some("dave"))), Decision::Deny);
+        assert_eq!(r.check(&request("192.0.2.1", None)), Decision::Unauthenticated);
+
+        let access = AccessControl::new(dir.join("access.conf"));
+        let resp = access.handle(request("192.0.2.1", None), |_| crate::empty_response(200));
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers()["www-authenticate"], "Basic realm=\"Restricted\", charset=\"UTF-8\"");
+        assert_eq!(access.handle(request("192.0.2.1", Some("alice")), |_| crate::empty_response(200)).status(), 200);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Either from the internal network, or logged in
+        let r = rules("order deny,allow\ndeny from all\nallow from 10.0.0.0/8\nrequire valid-user\nsatisfy any");
+        assert_eq!(r.check(&request("10.0.0.1", None)), Decision::Allow);
+        assert_eq!(r.check(&request("192.0.2.1", Some("x"))), Decision::Allow);
+        assert_eq!(r.check(&request("192.0.2.1", None)), Decision::Unauthenticated);
+    }
+
+    #[test]
+    fn test_spoofed_headers() {
+        let request = crate::testing::CgiRequestBuilder::new()
+            .env("REMOTE_ADDR", "203.0.113.9")
+            .header("X-CGI-Remote-User", "alice")
+            .header("X-CGI-Remote-Addr", "10.0.0.1")
+            .build();
+        assert!(!request.headers().contains_key("x-cgi-remote-user"));
+        assert_eq!(request.headers()["x-cgi-remote-addr"], "203.0.113.9");
+        assert_eq!(rules("require user alice").check(&request), Decision::Unauthenticated);
+        assert_eq!(rules("order deny,allow\ndeny from all\nallow from 10.0.0.0/8").check(&request), Decision::Deny);
+
+        let mut request = http::Request::builder().header("x-cgi-remote-user", "alice").header("x-cgi-remote-addr", "10.0.0.1").body(vec![]).unwrap();
+        assert_eq!(rules("require user alice").check(&request), Decision::Unauthenticated);
+        request.extensions_mut().insert(CgiMeta { remote_user: Some("alice".to_owned()), ..CgiMeta::default() });
+        assert_eq!(rules("require user alice").check(&request), Decision::Allow);
+    }
+}
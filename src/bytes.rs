@@ -0,0 +1,83 @@
+//! Requests & responses with [`Bytes`] bodies (`bytes` feature).
+//!
+//! A [`Bytes`] can be sliced and cloned without copying, e.g. to hand parts of an upload to
+//! other code, or to forward a body which is kept in a cache. [`handle`] calls the handler
+//! with a [`BytesRequest`]; the body read from stdin becomes the `Bytes` without a copy.
+//!
+//! ```rust,no_run
+//! use bytes::Bytes;
+//!
+//! cgi::bytes::handle(|request: cgi::bytes::BytesRequest| {
+//!     // The first line of the body, still sharing its memory
+//!     let body = request.body();
+//!     let end = body.iter().position(|&b| b == b'\n').unwrap_or(body.len());
+//!     let first_line: Bytes = body.slice(..end);
+//!     http::Response::new(first_line)
+//! })
+//! ```
+
+use ::bytes::Bytes;
+
+use crate::{binary_response, IntoResponse, Request, Response};
+
+/// A request with a [`Bytes`] body
+pub type BytesRequest = http::Request<Bytes>;
+
+/// A response with a [`Bytes`] body
+pub type BytesResponse = http::Response<Bytes>;
+
+/// Like [`handle`](crate::handle), with the body of the request as [`Bytes`]
+pub fn handle<F, R>(func: F)
+    where F: FnOnce(BytesRequest) -> R,
+          R: IntoResponse
+{
+    crate::Options::new().handle(|request| func(from_request(request)))
+}
+
+/// The request with its body as [`Bytes`], without copying it
+pub fn from_request(request: Request) -> BytesRequest {
+    request.map(Bytes::from)
+}
+
+/// The response with its body as a `Vec`, which only copies it if the memory is shared
+pub fn into_response(response: BytesResponse) -> Response {
+    response.map(Vec::from)
+}
+
+/// The response, see [`into_response`]
+impl IntoResponse for BytesResponse {
+    fn into_response(self) -> Response {
+        into_response(self)
+    }
+}
+
+/// `200 OK` with the bytes as `application/octet-stream`
+impl IntoResponse for Bytes {
+    fn into_response(self) -> Response {
+        binary_response(200, "application/octet-stream", Vec::from(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let request = crate::testing::CgiRequestBuilder::new().method("POST").body("text/plain", "hello world").build();
+        let address = request.body().as_ptr();
+        let request = from_request(request);
+        assert_eq!(request.body().as_ptr(), address);
+        assert_eq!(request.headers()["content-length"], "11");
+
+        let hello = request.body().slice(..5);
+        let response = http::Response::new(hello).into_response();
+        assert_eq!(response.body(), b"hello");
+
+        let body = Bytes::from(b"unique".to_vec());
+        let address = body.as_ptr();
+        let response = into_response(http::Response::new(body));
+        assert_eq!(response.body().as_ptr(), address);
+        assert_eq!(Bytes::from_static(b"abc").into_response().headers()["content-type"], "application/octet-stream");
+    }
+}
@@ -0,0 +1,234 @@
+//! A short-lived cache of `GET` responses on disk.
+//!
+//! Even caching a page for a few seconds helps a lot when it's expensive to make and many
+//! people read it at once: [`MicroCache`] serves stored responses without calling the handler
+//! at all. Responses are stored per URL, and per value of the request headers named in their
+//! `Vary` header, in a [`FileStore`].
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use cgi::cache::MicroCache;
+//! use cgi::store::FileStore;
+//!
+//! fn main() {
+//!     let cache = MicroCache::new(FileStore::new("/tmp/my-cgi-store")).ttl(Duration::from_secs(5));
+//!     cgi::handle(|request: cgi::Request| cache.handle(request, |request| {
+//!         let mut response = cgi::html_response(200, "<h1>Front page</h1>");
+//!         response.headers_mut().insert("vary", "accept-language".parse().unwrap());
+//!         response
+//!     }))
+//! }
+//! ```
+//!
+//! Only `200 OK` responses to `GET` & `HEAD` requests are stored, and not when they set
+//! cookies, are `Cache-Control: private` or `no-store`, or `Vary: *`. A `max-age` (or
+//! `s-maxage`) shorter than the configured TTL is respected. Streamed responses (like
+//! [`file_response`](crate::file_response) or an event stream) aren't stored either.
+//! Requests with credentials (an `Authorization` or `Cookie` header, or a `REMOTE_USER`
+//! authenticated by the server) always go to the handler, and their responses aren't stored.
+//!
+//! Served responses have an `Age` header, and `X-Cache: HIT` or `MISS`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::single_flight::{is_shareable, is_storable};
+use crate::store::FileStore;
+use crate::{client, clone_response, serialize_response, Request, Response};
+
+/// Caches `GET` responses for a short time. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct MicroCache {
+    store: FileStore,
+    ttl: Duration,
+}
+
+impl MicroCache {
+    /// Responses are stored for 10 seconds by default
+    pub fn new(store: FileStore) -> Self {
+        MicroCache { store, ttl: Duration::from_secs(10) }
+    }
+
+    /// The longest a response is served from the cache
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Serve the request from the cache, or call `next` and store its response if possible
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        if !is_shareable(&request) {
+            return next(request);
+        }
+
+        let host = request.headers().get(http::header::HOST).map(|h| String::from_utf8_lossy(h.as_bytes())).unwrap_or_default();
+        let url_key = format!("micro-cache {} {}{}", request.method(), host, request.uri());
+        let vary_key = format!("{} vary", url_key);
+        // Which headers the last response for this URL varied by
+        let vary: Vec<String> = match self.store.get(&vary_key) {
+            Ok(Some(vary)) => String::from_utf8_lossy(&vary).split(',').filter(|h| !h.is_empty()).map(|h| h.to_owned()).collect(),
+            _ => vec![],
+        };
+
+        let no_cache = directives(request.headers()).iter().any(|d| d == "no-cache");
+        if !no_cache {
+            if let Some(response) = self.stored(&variant_key(&url_key, &vary, request.headers())) {
+                return response;
+            }
+        }
+
+        let headers = request.headers().clone();
+        let mut response = next(request);
+        if let Some(ttl) = self.ttl_for(&response) {
+            let vary = vary_headers(&response);
+            let key = variant_key(&url_key, &vary, &headers);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let mut stored = format!("{}\n", now).into_bytes();
+            stored.extend(serialize_response(clone_response(&response)));
+            let result = self.store.set(&vary_key, vary.join(",").as_bytes(), Some(ttl))
+                .and_then(|()| self.store.set(&key, &stored, Some(ttl)));
+            if let Err(err) = result {
+                eprintln!("Could not store response in the cache: {}", err);
+            }
+        }
+        response.headers_mut().insert("x-cache", http::HeaderValue::from_static("MISS"));
+        response
+    }
+
+    fn stored(&self, key: &str) -> Option<Response> {
+        let stored = self.store.get(key).ok()??;
+        let newline = stored.iter().position(|&b| b == b'\n')?;
+        let stored_at: u64 = std::str::from_utf8(&stored[..newline]).ok()?.parse().ok()?;
+        let mut response = client::parse_output(&stored[newline + 1..]).ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        response.headers_mut().insert(http::header::AGE, now.saturating_sub(stored_at).into());
+        response.headers_mut().insert("x-cache", http::HeaderValue::from_static("HIT"));
+        Some(response)
+    }
+
+    // How long to store the response for, if at all
+    fn ttl_for(&self, response: &Response) -> Option<Duration> {
+        if !is_storable(response) || vary_headers(response).iter().any(|h| h == "*") {
+            return None;
+        }
+        let directives = directives(response.headers());
+        let max_age = |name: &str| directives.iter()
+            .find_map(|d| d.strip_prefix(name)?.strip_prefix('=')?.trim_matches('"').parse::<u64>().ok());
+        let ttl = match max_age("s-maxage").or_else(|| max_age("max-age")) {
+            Some(max_age) => self.ttl.min(Duration::from_secs(max_age)),
+            None => self.ttl,
+        };
+        (!ttl.is_zero() && !directives.iter().any(|d| d == "no-cache")).then_some(ttl)
+    }
+}
+
+// Lowercase `Cache-Control` directives
+fn directives(headers: &http::HeaderMap) -> Vec<String> {
+    headers.get_all(http::header::CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect()
+}
+
+// Lowercase, sorted names from `Vary`
+fn vary_headers(response: &Response) -> Vec<String> {
+    let mut names: Vec<String> = response.headers().get_all(http::header::VARY).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|h| h.trim().to_ascii_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn variant_key(url_key: &str, vary: &[String], headers: &http::HeaderMap) -> String {
+    let mut key = url_key.to_owned();
+    for name in vary {
+        let values: Vec<String> = headers.get_all(name.as_str()).iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .collect();
+        key.push_str(&format!("\n{}: {}", name, values.join(", ")));
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_micro_cache() {
+        let dir = std::env::temp_dir().join(format!("cgi-micro-cache-{}", std::process::id()));
+        let cache = MicroCache::new(FileStore::new(&dir));
+        let request = |uri: &str, lang: &str| http::Request::builder().uri(uri).header("accept-language", lang).body(vec![]).unwrap();
+        let calls = Cell::new(0);
+        let handler = |request: Request| {
+            calls.set(calls.get() + 1);
+            let lang = request.headers()["accept-language"].to_str().unwrap().to_owned();
+            let mut response = crate::text_response(200, format!("page in {}", lang));
+            response.headers_mut().insert("vary", "Accept-Language".parse().unwrap());
+            response
+        };
+
+        assert_eq!(cache.handle(request("/", "en"), handler).headers()["x-cache"], "MISS");
+        let hit = cache.handle(request("/", "en"), handler);
+        assert_eq!(hit.headers()["x-cache"], "HIT");
+        assert_eq!(hit.headers()["age"], "0");
+        assert_eq!(hit.body(), b"page in en");
+        assert_eq!(calls.get(), 1);
+
+        assert_eq!(cache.handle(request("/", "de"), handler).body(), b"page in de");
+        assert_eq!(cache.handle(request("/", "de"), handler).headers()["x-cache"], "HIT");
+        assert_eq!(calls.get(), 2);
+
+        // Not stored
+        let no_store = |_| {
+            let mut response = crate::text_response(200, "x");
+            response.headers_mut().insert("cache-control", "max-age=0".parse().unwrap());
+            response
+        };
+        cache.handle(request("/fresh", "en"), no_store);
+        assert_eq!(cache.handle(request("/fresh", "en"), no_store).headers()["x-cache"], "MISS");
+
+        let mut authorized = request("/", "en");
+        authorized.headers_mut().insert("authorization", "Basic eDp5".parse().unwrap());
+        assert!(!cache.handle(authorized, handler).headers().contains_key("x-cache"));
+
+        // A logged-in page is neither served from nor stored in the cache
+        let mut logged_in = request("/", "en");
+        logged_in.headers_mut().insert("cookie", "session=alice".parse().unwrap());
+        let personal = |_| crate::text_response(200, "alice's page");
+        let response = cache.handle(logged_in, personal);
+        assert_eq!(response.body(), b"alice's page");
+        assert!(!response.headers().contains_key("x-cache"));
+        assert_eq!(cache.handle(request("/", "en"), handler).body(), b"page in en");
+        let mut remote_user = crate::testing::CgiRequestBuilder::new().env("REMOTE_USER", "bob").build();
+        *remote_user.uri_mut() = "/".parse().unwrap();
+        remote_user.headers_mut().insert("accept-language", "en".parse().unwrap());
+        assert!(!cache.handle(remote_user, personal).headers().contains_key("x-cache"));
+
+        let set_cookie = |_| {
+            let mut response = crate::text_response(200, "welcome");
+            response.headers_mut().insert("set-cookie", "session=carol".parse().unwrap());
+            response
+        };
+        cache.handle(request("/login", "en"), set_cookie);
+        assert_eq!(cache.handle(request("/login", "en"), set_cookie).headers()["x-cache"], "MISS");
+
+        let streamed = |_| crate::stream::streaming_response(200, "text/plain", |out| out.write_all(b"streamed body"));
+        for _ in 0..2 {
+            let mut response = cache.handle(request("/streamed", "en"), streamed);
+            assert_eq!(response.headers()["x-cache"], "MISS");
+            let mut body = Vec::new();
+            crate::stream::take_body_writer(&mut response).unwrap().write_to(&mut body).unwrap();
+            assert_eq!(body, b"streamed body");
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! Run another CGI programme with a [`Request`], and parse its output into a [`Response`].
+//!
+//! Useful to wrap or gradually replace an existing CGI script, or to compare against it (see
+//! [`shadow`](crate::shadow)).
+//!
+//! ```rust,no_run
+//! cgi::handle(|request: cgi::Request| {
+//!     cgi::client::run_cgi("/usr/lib/cgi-bin/legacy.pl", &request)
+//!         .unwrap_or_else(|_| cgi::empty_response(502))
+//! })
+//! ```
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::{Request, Response};
+
+/// Run the CGI programme at `path` with `request`.
+///
+/// The environment is rebuilt from the request: the `X-CGI-` headers become the CGI
+/// meta-variables again (e.g. `X-CGI-Remote-Addr` → `REMOTE_ADDR`), and every other header
+/// an `HTTP_` variable. The body is sent on stdin.
+pub fn run_cgi(path: impl AsRef<Path>, request: &Request) -> io::Result<Response> {
+    let mut child = Command::new(path.as_ref())
+        .env_clear()
+        .envs(cgi_env(request))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let body = request.body().clone();
+    // Write in another thread, so a programme writing output before reading all its input
+    // can't deadlock
+    let writer = std::thread::spawn(move || stdin.write_all(&body));
+    let output = child.wait_with_output()?;
+    // The programme doesn't have to read its input
+    let _ = writer.join();
+
+    parse_output(&output.stdout)
+}
+
+/// The CGI environment for `request`, as `(name, value)` pairs.
+pub fn cgi_env(request: &Request) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = Vec::new();
+    let mut set = |name: String, value: String| {
+        if !env.iter().any(|(n, _)| *n == name) {
+            env.push((name, value));
+        }
+    };
+
+    for (name, value) in request.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+        let name = name.as_str().to_ascii_uppercase().replace('-', "_");
+        match name.strip_prefix("X_CGI_") {
+            Some(meta_var) => set(meta_var.to_owned(), value),
+            None => set(format!("HTTP_{}", name), value),
+        }
+    }
+
+    set("GATEWAY_INTERFACE".into(), "CGI/1.1".into());
+    set("REQUEST_METHOD".into(), request.method().as_str().into());
+    set("QUERY_STRING".into(), request.uri().query().unwrap_or("").into());
+    set("SCRIPT_NAME".into(), request.uri().path().into());
+    set("SERVER_PROTOCOL".into(), format!("{:?}", request.version()));
+    if !request.body().is_empty() {
+        set("CONTENT_LENGTH".into(), request.body().len().to_string());
+    }
+    env
+}
+
+/// Parse the output of a CGI programme (a header block, a blank line, and the body).
+///
+/// The status is taken from the `Status` header, or is `302 Found` if there's only a
+/// `Location`, and `200 OK` otherwise. A local redirect (only a `Location` with a path, and no
+/// body) gets the [`LocalRedirect`](crate::LocalRedirect) extension.
+pub fn parse_output(output: &[u8]) -> io::Result<Response> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    let mut response = http::Response::builder();
+    let mut status = None;
+    let mut has_location = false;
+    let mut other_headers = false;
+    let mut local = false;
+    let mut rest = output;
+    loop {
+        let end = rest.iter().position(|&b| b == b'\n').ok_or_else(|| invalid("CGI output has no end of headers"))?;
+        let line = &rest[..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        rest = &rest[end + 1..];
+        if line.is_empty() {
+            break;
+        }
+
+        let line = std::str::from_utf8(line).map_err(|_| invalid("CGI header is not UTF-8"))?;
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("invalid CGI header line"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("status") {
+            let code = value.split_whitespace().next().unwrap_or("");
+            status = Some(code.parse::<u16>().map_err(|_| invalid("invalid CGI Status"))?);
+        } else {
+            if name.eq_ignore_ascii_case("location") {
+                has_location = true;
+                local = value.starts_with('/') && !value.starts_with("//");
+            } else {
+                other_headers = true;
+            }
+            response = response.header(name, value);
+        }
+    }
+
+    if local && status.is_none() && !other_headers && rest.is_empty() {
+        response = response.extension(crate::LocalRedirect);
+    }
+    let status = status.unwrap_or(if has_location { 302 } else { 200 });
+    response.status(status).body(rest.to_vec()).map_err(|e| invalid(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgi_env() {
+        let req = http::Request::builder()
+            .method("POST")
+            .uri("/cgi-bin/script/extra?a=1")
+            .version(http::Version::HTTP_11)
+            .header("user-agent", "Test")
+            .header("x-cgi-script-name", "/cgi-bin/script")
+            .header("x-cgi-path-info", "/extra")
+            .body(b"data".to_vec())
+            .unwrap();
+        let env = cgi_env(&req);
+        let get = |name: &str| env.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+        assert_eq!(get("HTTP_USER_AGENT"), Some("Test"));
+        assert_eq!(get("SCRIPT_NAME"), Some("/cgi-bin/script"));
+        assert_eq!(get("PATH_INFO"), Some("/extra"));
+        assert_eq!(get("QUERY_STRING"), Some("a=1"));
+        assert_eq!(get("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(get("SERVER_PROTOCOL"), Some("HTTP/1.1"));
+        assert_eq!(get("CONTENT_LENGTH"), Some("4"));
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let resp = parse_output(b"Content-Type: text/plain\r\nStatus: 404 Not Found\r\n\r\nNope").unwrap();
+        assert_eq!(resp.status(), 404);
+        assert_eq!(resp.headers()["content-type"], "text/plain");
+        assert_eq!(resp.body(), b"Nope");
+
+        let local = parse_output(b"Location: /elsewhere\n\n").unwrap();
+        assert_eq!(local.status(), 302);
+        assert!(local.extensions().get::<crate::LocalRedirect>().is_some());
+        let client = parse_output(b"Location: https://example.com/\nStatus: 301\n\n").unwrap();
+        assert!(client.extensions().get::<crate::LocalRedirect>().is_none());
+        assert_eq!(parse_output(b"X-A: b\n\n").unwrap().status(), 200);
+        assert!(parse_output(b"no headers").is_err());
+
+        let ours = crate::serialize_response(crate::text_response(201, "Hi"));
+        let resp = parse_output(&ours).unwrap();
+        assert_eq!(resp.status(), 201);
+        assert_eq!(resp.body(), b"Hi");
+    }
+}
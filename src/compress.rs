@@ -0,0 +1,293 @@
+//! Compress responses for clients which accept it.
+//!
+//! Requires the `gzip`, `brotli` or `zstd` feature, for the encodings of the same name.
+//! [`Compression`] compresses text-like response bodies (HTML, CSS, JavaScript, JSON, XML, SVG,
+//! ...) above a minimum size with the encoding the request's `Accept-Encoding` prefers: the
+//! highest q-value wins, and for equal q-values brotli comes before zstd before gzip.
+//!
+//! It sets `Content-Encoding` & `Content-Length`, adds `Accept-Encoding` to `Vary`, and weakens
+//! a strong `ETag`, as the bytes differ from the uncompressed body.
+//!
+//! ```rust,no_run
+//! use cgi::compress::Compression;
+//!
+//! let compression = Compression::new().min_size(512);
+//! cgi::handle(|request: cgi::Request| compression.handle(request, |_request| {
+//!     cgi::html_response(200, "<!DOCTYPE html><title>Hello</title>".repeat(100))
+//! }))
+//! ```
+//!
+//! Responses which already have a `Content-Encoding`, partial (`206`) and streaming responses,
+//! and responses with `Cache-Control: no-transform` are left alone.
+
+#[cfg(feature = "gzip")]
+use std::io::Write;
+
+use crate::negotiate::parse_qlist;
+use crate::stream::BodyWriter;
+use crate::{Request, Response};
+
+/// A content coding the response can be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Encoding {
+    /// The enabled encodings, most preferred first
+    pub const ALL: &'static [Encoding] = &[
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli,
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd,
+        #[cfg(feature = "gzip")]
+        Encoding::Gzip,
+    ];
+
+    /// The name in `Accept-Encoding` & `Content-Encoding`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn matches(&self, coding: &str) -> bool {
+        coding.eq_ignore_ascii_case(self.as_str())
+            || (self.as_str() == "gzip" && coding.eq_ignore_ascii_case("x-gzip"))
+    }
+
+    fn encode(&self, body: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => {
+                let params = brotli::enc::BrotliEncoderParams { quality: level.min(11) as i32, ..Default::default() };
+                let mut output = Vec::new();
+                brotli::BrotliCompress(&mut &body[..], &mut output, &params)?;
+                Ok(output)
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::encode_all(body, level.clamp(1, 22) as i32),
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level.min(9)));
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compresses responses. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: usize,
+    level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new()
+    }
+}
+
+impl Compression {
+    /// Bodies of 1 KiB and more, at level 6
+    pub fn new() -> Self {
+        Compression { min_size: 1024, level: 6 }
+    }
+
+    /// Smaller bodies aren't worth the effort: the saving is eaten by the gzip header
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// From 0 (fastest) to 9 (smallest) for gzip, up to 11 for brotli and 1 to 22 for zstd
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// The encoding the client prefers, if it accepts any
+    pub fn choose(&self, request: &Request) -> Option<Encoding> {
+        let accepted = parse_qlist(request, "accept-encoding");
+        let q = |encoding: Encoding| accepted.iter()
+            .find(|(coding, _)| encoding.matches(coding))
+            .or_else(|| accepted.iter().find(|(coding, _)| *coding == "*"))
+            .map_or(0.0, |(_, q)| *q);
+        Encoding::ALL.iter().copied()
+            .map(|encoding| (encoding, q(encoding)))
+            .filter(|(_, q)| *q > 0.0)
+            // The first of the best, as `max_by` would return the last
+            .fold(None, |best: Option<(Encoding, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            })
+            .map(|(encoding, _)| encoding)
+    }
+
+    /// Call `next`, then compress its response if possible
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        let encoding = self.choose(&request);
+        let mut response = next(request);
+        if !self.compressible(&response) {
+            return response;
+        }
+        append_vary(&mut response);
+        let Some(encoding) = encoding else { return response };
+
+        match encoding.encode(response.body(), self.level) {
+            Ok(body) if body.len() < response.body().len() => {
+                let headers = response.headers_mut();
+                headers.insert(http::header::CONTENT_ENCODING, http::HeaderValue::from_static(encoding.as_str()));
+                headers.insert(http::header::CONTENT_LENGTH, body.len().into());
+                if let Some(etag) = headers.get(http::header::ETAG).and_then(|e| e.to_str().ok()).filter(|e| e.starts_with('"')) {
+                    if let Ok(weak) = format!("W/{}", etag).parse() {
+                        headers.insert(http::header::ETAG, weak);
+                    }
+                }
+                *response.body_mut() = body;
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("Could not compress the response: {}", err),
+        }
+        response
+    }
+
+    fn compressible(&self, response: &Response) -> bool {
+        let headers = response.headers();
+        let no_transform = headers.get_all(http::header::CACHE_CONTROL).iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.split(',').any(|d| d.trim().eq_ignore_ascii_case("no-transform")));
+        let content_type = headers.get(http::header::CONTENT_TYPE).and_then(|ct| ct.to_str().ok()).unwrap_or("");
+        response.body().len() >= self.min_size
+            && response.status() != http::StatusCode::PARTIAL_CONTENT
+            && !headers.contains_key(http::header::CONTENT_ENCODING)
+            && !headers.contains_key(http::header::CONTENT_RANGE)
+            && response.extensions().get::<BodyWriter>().is_none()
+            && !no_transform
+            && compressible_type(content_type)
+    }
+}
+
+// Text & other formats which compress well; images, video, archives etc. are compressed already
+fn compressible_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(essence.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "application/wasm"
+            | "application/x-javascript" | "application/manifest+json" | "image/svg+xml" | "image/x-icon"
+            | "font/ttf" | "font/otf" | "application/vnd.ms-fontobject")
+}
+
+fn append_vary(response: &mut Response) {
+    let varies = response.headers().get_all(http::header::VARY).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+        response.headers_mut().append(http::header::VARY, http::HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "brotli", feature = "gzip"))]
+    use std::io::Read;
+    use crate::testing::CgiRequestBuilder;
+
+    fn request(accept_encoding: &str) -> Request {
+        CgiRequestBuilder::new().header("Accept-Encoding", accept_encoding).build()
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_choose() {
+        let compression = Compression::new();
+        assert_eq!(compression.choose(&request("deflate, gzip;q=0.5")), Some(Encoding::Gzip));
+        assert_eq!(compression.choose(&request("x-gzip")), Some(Encoding::Gzip));
+        assert_eq!(compression.choose(&request("gzip;q=0")), None);
+        assert_eq!(compression.choose(&request("identity")), None);
+        assert_eq!(compression.choose(&CgiRequestBuilder::new().build()), None);
+    }
+
+    #[test]
+    #[cfg(all(feature = "brotli", feature = "zstd", feature = "gzip"))]
+    fn test_choose_preference() {
+        let compression = Compression::new();
+        assert_eq!(compression.choose(&request("gzip, deflate, br, zstd")), Some(Encoding::Brotli));
+        assert_eq!(compression.choose(&request("gzip, br;q=0.9, zstd;q=0.95")), Some(Encoding::Gzip));
+        assert_eq!(compression.choose(&request("br;q=0.5, zstd")), Some(Encoding::Zstd));
+        assert_eq!(compression.choose(&request("*")), Some(Encoding::Brotli));
+        assert_eq!(compression.choose(&request("*, br;q=0")), Some(Encoding::Zstd));
+        assert_eq!(compression.choose(&request("*, br;q=0, zstd;q=0, gzip;q=0")), None);
+    }
+
+    #[test]
+    #[cfg(feature = "brotli")]
+    fn test_compression_brotli() {
+        let compression = Compression::new().min_size(100);
+        let html = "<p>Hello World</p>".repeat(50);
+        let response = compression.handle(request("br"), |_| crate::html_response(200, html.clone()));
+        assert_eq!(response.headers()["content-encoding"], "br");
+        let mut decoded = String::new();
+        brotli::Decompressor::new(response.body().as_slice(), 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, html);
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_compression_zstd() {
+        let compression = Compression::new().min_size(100);
+        let html = "<p>Hello World</p>".repeat(50);
+        let response = compression.handle(request("zstd"), |_| crate::html_response(200, html.clone()));
+        assert_eq!(response.headers()["content-encoding"], "zstd");
+        assert_eq!(zstd::decode_all(response.body().as_slice()).unwrap(), html.as_bytes());
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compression() {
+        let compression = Compression::new().min_size(100);
+        let html = "<p>Hello World</p>".repeat(50);
+        let run = |accept_encoding: &str, response: Response| compression.handle(request(accept_encoding), |_| response);
+
+        let mut response = crate::html_response(200, html.clone());
+        response.headers_mut().insert(http::header::ETAG, "\"v1\"".parse().unwrap());
+        let response = run("gzip, deflate", response);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["content-length"], response.body().len().to_string().as_str());
+        assert_eq!(response.headers()["vary"], "Accept-Encoding");
+        assert_eq!(response.headers()["etag"], "W/\"v1\"");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(response.body().as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, html);
+
+        // Not accepted: uncompressed, but it still varies
+        let response = run("identity", crate::html_response(200, html.clone()));
+        assert!(!response.headers().contains_key("content-encoding"));
+        assert_eq!(response.headers()["vary"], "Accept-Encoding");
+
+        // Too small, not text, or not to be transformed
+        assert!(!run("gzip", crate::html_response(200, "<p>Hi</p>")).headers().contains_key("vary"));
+        assert!(!run("gzip", crate::binary_response(200, "image/png", vec![0; 1000])).headers().contains_key("content-encoding"));
+        let mut response = crate::text_response(200, html.clone());
+        response.headers_mut().insert(http::header::CACHE_CONTROL, "public, no-transform".parse().unwrap());
+        assert!(!run("gzip", response).headers().contains_key("content-encoding"));
+    }
+}
@@ -0,0 +1,240 @@
+//! Conditional requests for safe updates (optimistic concurrency).
+//!
+//! Two clients read a resource, both change it, and both write it back: the first update is
+//! silently lost. To prevent that, clients send the `ETag` (or `Last-Modified` date) they read
+//! in `If-Match` (or `If-Unmodified-Since`), and the write only goes ahead if the resource
+//! hasn't changed since. Otherwise it fails with `412 Precondition Failed`, and the client can
+//! read it again.
+//!
+//! ```rust,no_run
+//! use cgi::conditional::{etag_for, Validators};
+//!
+//! cgi::handle(|request: cgi::Request| {
+//!     let current = std::fs::read("/srv/data/note.txt").unwrap();
+//!     let validators = Validators::new().etag(etag_for(&current));
+//!     if let Err(err) = validators.check_write(&request) {
+//!         return err.into();
+//!     }
+//!     std::fs::write("/srv/data/note.txt", request.body()).unwrap();
+//!     let mut response = cgi::empty_response(204);
+//!     Validators::new().etag(etag_for(request.body())).set_headers(&mut response);
+//!     response
+//! })
+//! ```
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::date::{http_date, parse_http_date};
+use crate::store::fnv1a;
+use crate::{text_response, Request, Response};
+
+/// A strong `ETag` for some content, e.g. `"5c3f6a0c1d4e2b7a9f8e6d5c4b3a2918"`. The same
+/// content always gets the same tag.
+pub fn etag_for(content: &[u8]) -> String {
+    format!("\"{:016x}{:016x}\"", fnv1a(content, 0xcbf29ce484222325), fnv1a(content, 0x84222325cbf29ce4))
+}
+
+/// A strong `ETag` for a version counter, e.g. `"v42"`, for resources which count their
+/// updates
+pub fn etag_for_version(version: u64) -> String {
+    format!("\"v{}\"", version)
+}
+
+/// Why a write shouldn't go ahead. Converts into a `412` or `428` response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreconditionError {
+    /// The resource has been changed
+    Failed,
+    /// The request has no `If-Match` or `If-Unmodified-Since`
+    Required,
+}
+
+impl fmt::Display for PreconditionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreconditionError::Failed => write!(f, "Precondition Failed: the resource has been changed"),
+            PreconditionError::Required => write!(f, "Precondition Required: send If-Match with the current ETag"),
+        }
+    }
+}
+
+impl std::error::Error for PreconditionError {}
+
+impl From<PreconditionError> for Response {
+    fn from(err: PreconditionError) -> Response {
+        let status = match err {
+            PreconditionError::Failed => 412,
+            PreconditionError::Required => 428,
+        };
+        text_response(status, err.to_string())
+    }
+}
+
+/// The current validators of a resource: its `ETag` and modification time.
+///
+/// A resource without either is treated as not existing, so `If-Match: *` fails.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Validators::default()
+    }
+
+    /// The current entity tag, including the quotes (and `W/` if it's weak)
+    pub fn etag(mut self, etag: impl Into<String>) -> Self {
+        self.etag = Some(etag.into());
+        self
+    }
+
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        self.last_modified = Some(time);
+        self
+    }
+
+    /// The validators of a file, from its size & modification time. A missing file has none.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Validators::new()),
+            Err(e) => return Err(e),
+        };
+        let modified = metadata.modified()?;
+        let nanos = modified.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        Ok(Validators::new()
+            .etag(format!("\"{:x}-{:x}\"", metadata.len(), nanos))
+            .last_modified(modified))
+    }
+
+    fn exists(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+
+    /// Evaluate `If-Match` and `If-Unmodified-Since` (RFC 9110 section 13.2.2) for a write.
+    pub fn check_write(&self, request: &Request) -> Result<(), PreconditionError> {
+        let header = |name| request.headers().get_all(name).iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>();
+
+        let if_match = header(http::header::IF_MATCH);
+        let passed = if !if_match.is_empty() {
+            let tags: Vec<&str> = if_match.iter().flat_map(|v| v.split(',')).map(|t| t.trim()).collect();
+            if tags.contains(&"*") {
+                self.exists()
+            } else {
+                // Strong comparison: weak tags never match
+                self.etag.as_deref().is_some_and(|current| !current.starts_with("W/") && tags.contains(&current))
+            }
+        } else if let Some(since) = header(http::header::IF_UNMODIFIED_SINCE).first().and_then(|d| parse_http_date(d)) {
+            // An invalid date is ignored, and so is the header without a modification time;
+            // dates only have a resolution of seconds
+            let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            self.last_modified.is_none_or(|modified| seconds(modified) <= seconds(since))
+        } else {
+            true
+        };
+
+        if passed {
+            Ok(())
+        } else {
+            Err(PreconditionError::Failed)
+        }
+    }
+
+    /// Evaluate `If-None-Match` and `If-Modified-Since` for a `GET` or `HEAD`: `true` if the
+    /// client's copy is current, so it can be answered with `304 Not Modified`.
+    pub fn not_modified(&self, request: &Request) -> bool {
+        let header = |name| request.headers().get_all(name).iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>();
+
+        let if_none_match = header(http::header::IF_NONE_MATCH);
+        if !if_none_match.is_empty() {
+            let tags: Vec<&str> = if_none_match.iter().flat_map(|v| v.split(',')).map(|t| t.trim()).collect();
+            if tags.contains(&"*") {
+                return self.exists();
+            }
+            // Weak comparison: `W/` is ignored on both sides
+            let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_owned();
+            return self.etag.as_deref().is_some_and(|current| tags.iter().any(|t| opaque(t) == opaque(current)));
+        }
+        match (header(http::header::IF_MODIFIED_SINCE).first().and_then(|d| parse_http_date(d)), self.last_modified) {
+            (Some(since), Some(modified)) => {
+                let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                seconds(modified) <= seconds(since)
+            }
+            _ => false,
+        }
+    }
+
+    /// Set the `ETag` & `Last-Modified` headers, e.g. on the response to a read, or a write
+    pub fn set_headers(&self, response: &mut Response) {
+        if let Some(value) = self.etag.as_ref().and_then(|e| e.parse().ok()) {
+            response.headers_mut().insert(http::header::ETAG, value);
+        }
+        if let Some(value) = self.last_modified.and_then(|t| http_date(t).parse().ok()) {
+            response.headers_mut().insert(http::header::LAST_MODIFIED, value);
+        }
+    }
+}
+
+/// Fail if the request has neither `If-Match` nor `If-Unmodified-Since`, to make sure clients
+/// don't update blindly
+pub fn require_precondition(request: &Request) -> Result<(), PreconditionError> {
+    let headers = request.headers();
+    if headers.contains_key(http::header::IF_MATCH) || headers.contains_key(http::header::IF_UNMODIFIED_SINCE) {
+        Ok(())
+    } else {
+        Err(PreconditionError::Required)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn request(header: &str, value: &str) -> Request {
+        http::Request::builder().method("PUT").header(header, value).body(vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_check_write() {
+        let current = Validators::new().etag(etag_for(b"v1")).last_modified(UNIX_EPOCH + Duration::from_secs(784111777));
+        assert_eq!(etag_for(b"v1"), etag_for(b"v1"));
+        assert_ne!(etag_for(b"v1"), etag_for(b"v2"));
+
+        assert!(current.check_write(&request("if-match", &etag_for(b"v1"))).is_ok());
+        assert!(current.check_write(&request("if-match", &format!("\"x\", {}", etag_for(b"v1")))).is_ok());
+        assert_eq!(current.check_write(&request("if-match", &etag_for(b"v0"))), Err(PreconditionError::Failed));
+        assert!(current.check_write(&request("if-match", "*")).is_ok());
+        assert!(Validators::new().check_write(&request("if-match", "*")).is_err());
+        assert!(Validators::new().etag("W/\"a\"").check_write(&request("if-match", "W/\"a\"")).is_err());
+
+        assert!(current.check_write(&request("if-unmodified-since", "Sun, 06 Nov 1994 08:49:37 GMT")).is_ok());
+        assert!(current.check_write(&request("if-unmodified-since", "Sun, 06 Nov 1994 08:49:36 GMT")).is_err());
+        assert!(current.check_write(&request("if-unmodified-since", "not a date")).is_ok());
+        assert!(Validators::new().etag("\"a\"").check_write(&request("if-unmodified-since", "Sun, 06 Nov 1994 08:49:36 GMT")).is_ok());
+        assert!(current.check_write(&request("x-other", "")).is_ok());
+
+        assert_eq!(Response::from(require_precondition(&request("x-other", "")).unwrap_err()).status(), 428);
+        assert!(require_precondition(&request("if-match", "*")).is_ok());
+
+        assert!(current.not_modified(&request("if-none-match", &format!("W/{}", etag_for(b"v1")))));
+        assert!(!current.not_modified(&request("if-none-match", &etag_for(b"v0"))));
+        assert!(current.not_modified(&request("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")));
+        assert!(!current.not_modified(&request("if-modified-since", "Sun, 06 Nov 1994 08:49:36 GMT")));
+        assert!(!current.not_modified(&request("x-other", "")));
+
+        let mut response = crate::empty_response(200);
+        Validators::new().etag(etag_for_version(3)).last_modified(UNIX_EPOCH).set_headers(&mut response);
+        assert_eq!(response.headers()["etag"], "\"v3\"");
+        assert_eq!(response.headers()["last-modified"], "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+}
@@ -0,0 +1,243 @@
+//! Typed configuration from environment variables (`serde` feature).
+//!
+//! CGI programmes are configured by the web server's environment (`SetEnv` in Apache,
+//! `fastcgi_param` in nginx). [`from_env`] deserializes the variables starting with a prefix
+//! into a struct: `APP_DATABASE_URL` becomes the field `database_url` for the prefix `APP_`.
+//!
+//! ```rust,no_run
+//! #[derive(serde::Deserialize)]
+//! struct Config {
+//!     database_url: String,
+//!     #[serde(default)]
+//!     debug: bool,
+//!     admins: Vec<String>,
+//!     cache_seconds: Option<u64>,
+//! }
+//!
+//! let config: Config = cgi::config::from_env_or_exit("APP_");
+//! cgi::handle(|request: cgi::Request| format!("{} admins", config.admins.len()))
+//! ```
+//!
+//! Values are parsed for the field's type: numbers, `bool`s (`true`/`false`, `1`/`0`,
+//! `yes`/`no`, `on`/`off`), unit enum variants, and lists separated by commas. An empty value
+//! of an `Option` field is `None`. The configuration is flat: nested structs aren't supported.
+//!
+//! Errors name the variable, e.g. `APP_CACHE_SECONDS: invalid value "ten": invalid digit
+//! found in string` or `APP_DATABASE_URL isn't set`. A [`ConfigError`] becomes a `500` like
+//! any [`Error`](crate::Error), and [`from_env_or_exit`] answers with it and exits.
+
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+
+use crate::Response;
+
+/// The environment variables don't match the configuration struct.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    message: String,
+    // A required field without a variable
+    missing: Option<&'static str>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl de::Error for ConfigError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ConfigError { message: msg.to_string(), missing: None }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        ConfigError { message: format!("missing field `{}`", field), missing: Some(field) }
+    }
+}
+
+impl From<ConfigError> for Response {
+    fn from(err: ConfigError) -> Self {
+        crate::Error::handler(err).into()
+    }
+}
+
+/// Deserialize the environment variables starting with `prefix` (e.g. `APP_`) into `T`
+pub fn from_env<T: DeserializeOwned>(prefix: &str) -> Result<T, ConfigError> {
+    #[cfg(feature = "dotenv")]
+    crate::dotenv::load_once();
+    let vars = std::env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    from_vars(prefix, vars)
+}
+
+/// Like [`from_env`], but an error is logged and answered with a `500` (see
+/// [`set_error_response`](crate::set_error_response)), and the programme exits
+pub fn from_env_or_exit<T: DeserializeOwned>(prefix: &str) -> T {
+    from_env(prefix).unwrap_or_else(|err| {
+        crate::write_response(err.into());
+        std::process::exit(1)
+    })
+}
+
+/// Deserialize the `(name, value)` pairs starting with `prefix` into `T`
+pub fn from_vars<T, I>(prefix: &str, vars: I) -> Result<T, ConfigError>
+    where T: DeserializeOwned,
+          I: IntoIterator<Item = (String, String)>
+{
+    let vars = vars.into_iter()
+        .filter_map(|(name, value)| {
+            let field = name.strip_prefix(prefix).filter(|field| !field.is_empty())?.to_ascii_lowercase();
+            Some((field, Value { name, value }))
+        });
+    let map = de::value::MapDeserializer::new(vars);
+    T::deserialize(map).map_err(|mut err| {
+        if let Some(field) = err.missing {
+            err.message = format!("{}{} isn't set", prefix, field.to_ascii_uppercase());
+        }
+        err
+    })
+}
+
+// The value of the variable `name`
+struct Value {
+    name: String,
+    value: String,
+}
+
+impl Value {
+    fn parse<T>(&self) -> Result<T, ConfigError>
+        where T: std::str::FromStr,
+              T::Err: fmt::Display
+    {
+        self.value.trim().parse().map_err(|err| de::Error::custom(format!("{}: invalid value {:?}: {}", self.name, self.value, err)))
+    }
+}
+
+impl<'de> IntoDeserializer<'de, ConfigError> for Value {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident: $type:ty,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+                visitor.$visit(self.parse::<$type>()?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = ConfigError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_string(self.value)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        match self.value.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => visitor.visit_bool(true),
+            "false" | "0" | "no" | "off" => visitor.visit_bool(false),
+            _ => Err(de::Error::custom(format!("{}: invalid value {:?}: expected true or false", self.name, self.value))),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8: i8,
+        deserialize_i16 => visit_i16: i16,
+        deserialize_i32 => visit_i32: i32,
+        deserialize_i64 => visit_i64: i64,
+        deserialize_u8 => visit_u8: u8,
+        deserialize_u16 => visit_u16: u16,
+        deserialize_u32 => visit_u32: u32,
+        deserialize_u64 => visit_u64: u64,
+        deserialize_f32 => visit_f32: f32,
+        deserialize_f64 => visit_f64: f64,
+        deserialize_char => visit_char: char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        if self.value.trim().is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        let items: Vec<Value> = self.value.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| Value { name: self.name.clone(), value: item.to_owned() })
+            .collect();
+        visitor.visit_seq(de::value::SeqDeserializer::new(items.into_iter()))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_enum(self.value.trim().to_owned().into_deserializer())
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde::Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Mode {
+        Live,
+        Test,
+    }
+
+    #[derive(Debug, serde::Deserialize, PartialEq)]
+    struct Config {
+        database_url: String,
+        #[serde(default)]
+        debug: bool,
+        admins: Vec<String>,
+        cache_seconds: Option<u64>,
+        mode: Mode,
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let config: Config = from_vars("APP_", vars(&[
+            ("APP_DATABASE_URL", "postgres://db"), ("APP_DEBUG", "on"), ("APP_ADMINS", "ann, bob"),
+            ("APP_CACHE_SECONDS", ""), ("APP_MODE", "test"), ("APP_UNUSED", "x"), ("PATH", "/bin"),
+        ])).unwrap();
+        assert_eq!(config, Config {
+            database_url: "postgres://db".into(),
+            debug: true,
+            admins: vec!["ann".into(), "bob".into()],
+            cache_seconds: None,
+            mode: Mode::Test,
+        });
+
+        let err = from_vars::<Config, _>("APP_", vars(&[("APP_ADMINS", ""), ("APP_MODE", "live")])).unwrap_err();
+        assert_eq!(err.to_string(), "invalid configuration: APP_DATABASE_URL isn't set");
+        let err = from_vars::<Config, _>("APP_", vars(&[
+            ("APP_DATABASE_URL", "x"), ("APP_ADMINS", ""), ("APP_MODE", "live"), ("APP_CACHE_SECONDS", "ten"),
+        ])).unwrap_err();
+        assert_eq!(err.to_string(), "invalid configuration: APP_CACHE_SECONDS: invalid value \"ten\": invalid digit found in string");
+        assert_eq!(Response::from(err).status(), 500);
+    }
+}
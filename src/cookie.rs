@@ -0,0 +1,251 @@
+//! Cookies sent by the client.
+//!
+//! [`handle`](crate::handle) parses the `Cookie` header into [`Cookies`], which are in the
+//! request's extensions:
+//!
+//! ```rust
+//! use cgi::cookie::Cookies;
+//!
+//! let request = cgi::testing::CgiRequestBuilder::new().header("Cookie", "theme=dark; name=J%C3%BCrgen").build();
+//! let cookies = request.extensions().get::<Cookies>().unwrap();
+//! assert_eq!(cookies.get("theme"), Some("dark"));
+//! assert_eq!(cookies.get("name"), Some("Jürgen"));
+//! ```
+//!
+//! For requests which didn't come from `handle`, use [`Cookies::from_headers`], or
+//! [`RequestExt::cookies`](crate::RequestExt::cookies) which works for both.
+//!
+//! To set cookies, build a [`Cookie`] and add it with
+//! [`ResponseExt::add_cookie`](crate::ResponseExt::add_cookie). Each gets its own
+//! `Set-Cookie` header.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use cgi::ResponseExt;
+//! use cgi::cookie::{Cookie, SameSite};
+//!
+//! let mut response = cgi::text_response(200, "Logged in");
+//! response.add_cookie(&Cookie::new("session", "abc123").max_age(Duration::from_secs(3600)).http_only().secure());
+//! response.add_cookie(&Cookie::new("theme", "dark").same_site(SameSite::Strict));
+//! response.add_cookie(&Cookie::removal("old"));
+//! assert_eq!(response.headers().get_all("set-cookie").iter().count(), 3);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::date::HttpDate;
+use crate::urlencoded::{percent_decode, percent_encode};
+
+/// The cookies of a request, by name. Values are percent-decoded, and surrounding quotes are
+/// removed. If a name appears more than once, the first value is kept: browsers send the
+/// cookie with the most specific path first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cookies(HashMap<String, String>);
+
+impl Cookies {
+    /// Parse all `Cookie` headers
+    pub fn from_headers(headers: &http::HeaderMap) -> Self {
+        let mut cookies = HashMap::new();
+        for header in headers.get_all(http::header::COOKIE) {
+            for pair in String::from_utf8_lossy(header.as_bytes()).split(';') {
+                let Some((name, value)) = pair.split_once('=') else { continue };
+                let name = name.trim();
+                if name.is_empty() {
+                    continue;
+                }
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                cookies.entry(name.to_owned()).or_insert_with(|| percent_decode(value, false));
+            }
+        }
+        Cookies(cookies)
+    }
+
+    /// The value of the cookie `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|v| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// All cookies, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Also sent on cross-site requests. Browsers require `Secure` for this, so it's added.
+    None,
+}
+
+/// A cookie to set, see [`ResponseExt::add_cookie`](crate::ResponseExt::add_cookie). The
+/// `Display` output is the `Set-Cookie` header value. `;`, whitespace & control characters
+/// are left out of the name, domain & path, so they can't add attributes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub max_age: Option<Duration>,
+    pub expires: Option<SystemTime>,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// A session cookie (deleted when the browser closes) for the path `/`. The value is
+    /// percent-encoded as needed, matching [`Cookies`]' decoding.
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            max_age: None,
+            expires: None,
+            domain: None,
+            path: Some("/".into()),
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie which makes the browser delete the cookie `name` (for the path `/`)
+    pub fn removal(name: impl Into<String>) -> Self {
+        Cookie::new(name, "").max_age(Duration::ZERO).expires(SystemTime::UNIX_EPOCH)
+    }
+
+    /// How long the browser keeps the cookie
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// When the cookie expires, for old browsers which don't know `Max-Age`
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// The host the cookie is sent to, including its subdomains
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// The path the cookie is sent for, or `None` for the path of the current request
+    pub fn path(mut self, path: Option<&str>) -> Self {
+        self.path = path.map(|p| p.to_owned());
+        self
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    /// Hide the cookie from JavaScript
+    pub fn http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+// Bytes allowed in a cookie value without encoding (RFC 6265 cookie-octet, except `%`)
+const COOKIE_OCTETS: &[u8] = b"!#$&'()*+/:<=>?@[]^_`{|}~";
+
+// Without the characters which would end the attribute, or the header
+fn attribute(value: &str) -> String {
+    value.chars().filter(|c| *c != ';' && !c.is_whitespace() && !c.is_control()).collect()
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", attribute(&self.name).replace('=', ""), percent_encode(&self.value, COOKIE_OCTETS))?;
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", HttpDate(expires))?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", attribute(domain))?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", attribute(path))?;
+        }
+        if self.secure || self.same_site == Some(SameSite::None) {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => f.write_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => f.write_str("; SameSite=Lax"),
+            Some(SameSite::None) => f.write_str("; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.append("cookie", "a=1; b=\"quoted value\";  c=%E2%82%AC+; invalid; =x; a=2".parse().unwrap());
+        headers.append("cookie", "d=".parse().unwrap());
+        let cookies = Cookies::from_headers(&headers);
+        assert_eq!(cookies.get("a"), Some("1"));
+        assert_eq!(cookies.get("b"), Some("quoted value"));
+        assert_eq!(cookies.get("c"), Some("€+"));
+        assert_eq!(cookies.get("d"), Some(""));
+        assert!(!cookies.contains("invalid"));
+        assert_eq!(cookies.len(), 4);
+        assert!(Cookies::from_headers(&http::HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let cookie = Cookie::new("id", "a b;c%€").max_age(Duration::from_secs(60)).domain("example.com")
+            .http_only().same_site(SameSite::None);
+        assert_eq!(cookie.to_string(), "id=a%20b%3Bc%25%E2%82%AC; Max-Age=60; Domain=example.com; Path=/; Secure; HttpOnly; SameSite=None");
+        assert_eq!(Cookie::removal("id").path(None).to_string(), "id=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(Cookie::new("a; b=c", "1").domain("example.com; SameSite=None").path(Some("/x;\r\n Secure")).to_string(),
+            "abc=1; Domain=example.comSameSite=None; Path=/xSecure");
+
+        // What the browser sends back
+        let mut headers = http::HeaderMap::new();
+        let encoded = cookie.to_string();
+        let (pair, _) = encoded.split_once(';').unwrap();
+        headers.insert("cookie", pair.parse().unwrap());
+        assert_eq!(Cookies::from_headers(&headers).get("id"), Some("a b;c%€"));
+    }
+}
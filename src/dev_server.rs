@@ -0,0 +1,273 @@
+//! A small HTTP server for trying out a handler locally, without setting up a web server.
+//!
+//! [`run`] listens on an address, and turns every HTTP request into the CGI meta-variables a
+//! web server would set (`SCRIPT_NAME`, `PATH_INFO`, `QUERY_STRING`, `REMOTE_ADDR`, ...), so
+//! the handler gets the same [`Request`](crate::Request) as under Apache. The handler is any
+//! [`Handler`]: a closure, or a struct set up once.
+//!
+//! ```rust,no_run
+//! fn handler(request: cgi::Request) -> cgi::Response {
+//!     cgi::text_response(200, format!("You asked for {}", request.uri()))
+//! }
+//!
+//! fn main() {
+//!     if std::env::var_os("REQUEST_METHOD").is_some() {
+//!         cgi::handle(handler)
+//!     } else {
+//!         cgi::dev_server::run("127.0.0.1:8000", handler).unwrap()
+//!     }
+//! }
+//! ```
+//!
+//! [Local redirects](crate::local_redirect) are followed, as a `GET` of the new path.
+//!
+//! The server is meant for development only: it handles one request at a time, closes the
+//! connection after each response and has no limits on request sizes.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{call_handler, empty_response, parse_request, reset_request_state, run_after_response, Handler, LocalRedirect, Response};
+
+// Like Apache's `LimitInternalRecursion`
+const MAX_LOCAL_REDIRECTS: usize = 10;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Listen on `addr` and call `handler` for every request, until the programme is stopped.
+/// The script is mounted at `/`, so the whole path is `PATH_INFO`.
+pub fn run<A, H>(addr: A, handler: H) -> io::Result<()>
+    where A: ToSocketAddrs,
+          H: Handler
+{
+    #[cfg(feature = "dotenv")]
+    crate::dotenv::load_once();
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    eprintln!("Listening on http://{}/", local);
+    for stream in listener.incoming() {
+        let result = stream.and_then(|mut stream| {
+            let remote = stream.peer_addr()?;
+            serve_connection(&mut stream, local, remote, &handler)
+        });
+        if let Err(err) = result {
+            eprintln!("Connection failed: {}", err);
+        }
+    }
+    Ok(())
+}
+
+fn serve_connection<S, H>(stream: &mut S, local: SocketAddr, remote: SocketAddr, handler: &H) -> io::Result<()>
+    where S: Read + Write,
+          H: Handler
+{
+    let mut reader = BufReader::new(&mut *stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), protocol) = (parts.next(), parts.next(), parts.next().unwrap_or("HTTP/0.9")) else {
+        return Err(invalid("invalid request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let head = method == "HEAD";
+
+    let mut env_vars = HashMap::new();
+    env_vars.insert("GATEWAY_INTERFACE".to_owned(), "CGI/1.1".to_owned());
+    env_vars.insert("REQUEST_METHOD".to_owned(), method.to_owned());
+    env_vars.insert("SCRIPT_NAME".to_owned(), "".to_owned());
+    env_vars.insert("PATH_INFO".to_owned(), path.to_owned());
+    env_vars.insert("QUERY_STRING".to_owned(), query.to_owned());
+    env_vars.insert("SERVER_PROTOCOL".to_owned(), protocol.to_owned());
+    env_vars.insert("SERVER_SOFTWARE".to_owned(), format!("cgi-dev-server/{}", env!("CARGO_PKG_VERSION")));
+    env_vars.insert("SERVER_NAME".to_owned(), local.ip().to_string());
+    env_vars.insert("SERVER_PORT".to_owned(), local.port().to_string());
+    env_vars.insert("REMOTE_ADDR".to_owned(), remote.ip().to_string());
+    env_vars.insert("REMOTE_PORT".to_owned(), remote.port().to_string());
+    env_vars.insert("REQUEST_SCHEME".to_owned(), "http".to_owned());
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(|| invalid("invalid header"))?;
+        let name = name.trim().to_ascii_uppercase().replace('-', "_");
+        let var = match name.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => name,
+            _ => format!("HTTP_{}", name),
+        };
+        // Repeated headers are joined, like web servers do
+        env_vars.entry(var)
+            .and_modify(|v: &mut String| { v.push_str(", "); v.push_str(value.trim()) })
+            .or_insert_with(|| value.trim().to_owned());
+    }
+
+    let chunked = env_vars.get("HTTP_TRANSFER_ENCODING").is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    if env_vars.get("HTTP_EXPECT").is_some_and(|e| e.eq_ignore_ascii_case("100-continue")) {
+        reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let body = if chunked {
+        read_chunked(&mut reader)?
+    } else {
+        let length: u64 = match env_vars.get("CONTENT_LENGTH") {
+            Some(length) => length.trim().parse().map_err(|_| invalid("invalid Content-Length"))?,
+            None => 0,
+        };
+        let mut body = Vec::new();
+        (&mut reader).take(length).read_to_end(&mut body)?;
+        body
+    };
+    env_vars.insert("CONTENT_LENGTH".to_owned(), body.len().to_string());
+    drop(reader);
+
+    reset_request_state();
+    let run = |env_vars: HashMap<String, String>, body: Vec<u8>| {
+        catch_unwind(AssertUnwindSafe(|| call_handler(parse_request(env_vars, body), |request| handler.call(request)))).unwrap_or_else(|_| empty_response(500))
+    };
+    let mut response = run(env_vars.clone(), body);
+    let mut redirects = 0;
+    while response.extensions().get::<LocalRedirect>().is_some() {
+        redirects += 1;
+        let location = response.headers().get(http::header::LOCATION).and_then(|l| l.to_str().ok()).unwrap_or("/");
+        if redirects > MAX_LOCAL_REDIRECTS {
+            eprintln!("Too many local redirects, the last to {}", location);
+            response = empty_response(500);
+            break;
+        }
+        let (path, query) = location.split_once('?').unwrap_or((location, ""));
+        let mut env_vars = env_vars.clone();
+        env_vars.insert("REQUEST_METHOD".to_owned(), "GET".to_owned());
+        env_vars.insert("PATH_INFO".to_owned(), path.to_owned());
+        env_vars.insert("QUERY_STRING".to_owned(), query.to_owned());
+        env_vars.insert("CONTENT_LENGTH".to_owned(), "0".to_owned());
+        env_vars.remove("CONTENT_TYPE");
+        response = run(env_vars, Vec::new());
+    }
+    crate::stream::buffer_body(&mut response);
+    stream.write_all(&serialize_http(response, head))?;
+    stream.flush()?;
+    run_after_response();
+    Ok(())
+}
+
+// The body of a `Transfer-Encoding: chunked` request (trailers are ignored)
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+    // Trailers, up to the empty line
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            return Ok(body);
+        }
+    }
+}
+
+// Responses to `HEAD` requests only have the headers
+fn serialize_http(response: Response, head: bool) -> Vec<u8> {
+    let (parts, body) = response.into_parts();
+    // Without the body, the length of the `GET` response
+    let length = match parts.headers.get(http::header::CONTENT_LENGTH).and_then(|l| l.to_str().ok()) {
+        Some(length) if head => length.to_owned(),
+        _ => body.len().to_string(),
+    };
+    let mut output = format!("HTTP/1.1 {} {}\r\n", parts.status.as_str(), parts.status.canonical_reason().unwrap_or(""));
+    for (name, value) in &parts.headers {
+        if name != http::header::CONNECTION && name != http::header::CONTENT_LENGTH {
+            output.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value.as_bytes())));
+        }
+    }
+    output.push_str(&format!("content-length: {}\r\nconnection: close\r\n\r\n", length));
+    let mut output = output.into_bytes();
+    if !head {
+        output.extend(body);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Request;
+
+    struct Conn {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.input.read(buf) }
+    }
+
+    impl Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.output.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn serve(input: &str) -> String {
+        let mut conn = Conn { input: io::Cursor::new(input.as_bytes().to_vec()), output: Vec::new() };
+        let handler = |request: Request| {
+            let h = |name: &str| request.headers().get(name).map(|v| v.to_str().unwrap().to_owned()).unwrap_or_default();
+            let summary = format!("{} {} {} {} {} {}", request.method(), request.uri(), h("x-cgi-path-info"),
+                h("x-cgi-remote-addr"), h("accept"), String::from_utf8_lossy(request.body()));
+            crate::text_response(201, summary)
+        };
+        serve_connection(&mut conn, "127.0.0.1:8000".parse().unwrap(), "10.0.0.1:5555".parse().unwrap(), &handler).unwrap();
+        String::from_utf8(conn.output).unwrap()
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        let output = serve("POST /a/b?x=1 HTTP/1.1\r\nHost: localhost\r\nAccept: text/html\r\naccept: */*\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n"), "{}", output);
+        assert!(output.contains("\r\ncontent-length: 64\r\nconnection: close\r\n\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nPOST http://localhost/a/b?x=1 /a/b 10.0.0.1 text/html, */* hello"), "{}", output);
+
+        let output = serve("PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n3;ext\r\nabc\r\n2\r\nde\r\n0\r\nTrailer: x\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\n"), "{}", output);
+        assert!(output.ends_with("PUT http://127.0.0.1:8000/ / 10.0.0.1  abcde"), "{}", output);
+
+        let output = serve("HEAD /x HTTP/1.0\r\n\r\n");
+        assert!(output.ends_with("content-length: 42\r\nconnection: close\r\n\r\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_local_redirect() {
+        let handler = |request: Request| match request.headers()["x-cgi-path-info"].to_str().unwrap() {
+            "/old" => crate::local_redirect("/new?from=old"),
+            "/loop" => crate::local_redirect("/loop"),
+            _ => crate::text_response(200, format!("{} {}", request.method(), request.uri())),
+        };
+        let serve = |input: &str| {
+            let mut conn = Conn { input: io::Cursor::new(input.as_bytes().to_vec()), output: Vec::new() };
+            serve_connection(&mut conn, "127.0.0.1:8000".parse().unwrap(), "10.0.0.1:5555".parse().unwrap(), &handler).unwrap();
+            String::from_utf8(conn.output).unwrap()
+        };
+        let output = serve("POST /old HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nGET http://127.0.0.1:8000/new?from=old"), "{}", output);
+        assert!(serve("GET /loop HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 500 "));
+    }
+}
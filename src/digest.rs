@@ -0,0 +1,220 @@
+//! Verify the request body against `Content-Digest` (RFC 9530), `Digest` (RFC 3230) and
+//! `Content-MD5` headers.
+//!
+//! Requires the `digest` feature.
+//!
+//! ```rust,no_run
+//! #[cgi::main]
+//! fn main(request: cgi::Request) -> cgi::Response {
+//!     if let Err(e) = cgi::digest::verify_digest(&request) {
+//!         return e.into();
+//!     }
+//!     cgi::text_response(200, "Upload received")
+//! }
+//! ```
+
+use std::fmt;
+
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::structured::{self, ListEntry};
+use crate::{base64, Request, Response};
+
+/// Digest algorithms which can be verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+    /// Only for the legacy `Digest` & `Content-MD5` headers
+    Md5,
+}
+
+impl DigestAlgorithm {
+    /// Hash `body` with this algorithm
+    pub fn hash(&self, body: &[u8]) -> Vec<u8> {
+        match self {
+            DigestAlgorithm::Sha256 => Sha256::digest(body).to_vec(),
+            DigestAlgorithm::Sha512 => Sha512::digest(body).to_vec(),
+            DigestAlgorithm::Md5 => Md5::digest(body).to_vec(),
+        }
+    }
+
+    // The name, as used in `Content-Digest` & `Digest`, case insensitive
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha-256" => Some(DigestAlgorithm::Sha256),
+            "sha-512" => Some(DigestAlgorithm::Sha512),
+            "md5" => Some(DigestAlgorithm::Md5),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha-256",
+            DigestAlgorithm::Sha512 => "sha-512",
+            DigestAlgorithm::Md5 => "md5",
+        }
+    }
+}
+
+/// Why a request body failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DigestError {
+    /// No digest header was sent (only from [`require_digest`])
+    Missing,
+    /// A digest header was sent, but only with algorithms we don't support
+    UnsupportedAlgorithm,
+    /// The header couldn't be parsed
+    Malformed(&'static str),
+    /// The body doesn't match the digest
+    Mismatch { header: &'static str, algorithm: DigestAlgorithm },
+}
+
+impl fmt::Display for DigestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DigestError::Missing => write!(f, "no digest header sent"),
+            DigestError::UnsupportedAlgorithm => write!(f, "no supported digest algorithm"),
+            DigestError::Malformed(header) => write!(f, "malformed {} header", header),
+            DigestError::Mismatch { header, algorithm } => write!(f, "{} {} does not match the request body", header, algorithm.name()),
+        }
+    }
+}
+
+impl std::error::Error for DigestError {}
+
+/// A `400 Bad Request` with the error as the text body
+impl From<DigestError> for Response {
+    fn from(err: DigestError) -> Response {
+        crate::text_response(400, err.to_string())
+    }
+}
+
+/// Check the body against any `Content-Digest`, `Digest` or `Content-MD5` headers.
+///
+/// Every supported digest which is present must match. A request without any digest header
+/// is accepted, use [`require_digest`] to reject those.
+pub fn verify_digest(request: &Request) -> Result<(), DigestError> {
+    let body = request.body();
+    let mut supported = false;
+    let mut present = false;
+
+    for (header, value) in header_values(request, "content-digest") {
+        present = true;
+        for (name, expected) in parse_content_digest(&value).ok_or(DigestError::Malformed(header))? {
+            supported |= check(header, &name, &expected, body)?;
+        }
+    }
+
+    for (header, value) in header_values(request, "digest") {
+        present = true;
+        for item in value.split(',') {
+            let (name, expected) = item.trim().split_once('=').ok_or(DigestError::Malformed(header))?;
+            let expected = base64::decode(expected.trim()).ok_or(DigestError::Malformed(header))?;
+            supported |= check(header, name.trim(), &expected, body)?;
+        }
+    }
+
+    for (header, value) in header_values(request, "content-md5") {
+        present = true;
+        let expected = base64::decode(value.trim()).ok_or(DigestError::Malformed(header))?;
+        supported |= check(header, "md5", &expected, body)?;
+    }
+
+    if present && !supported {
+        return Err(DigestError::UnsupportedAlgorithm);
+    }
+    Ok(())
+}
+
+/// Like [`verify_digest`], but a request without any digest header is an error too.
+pub fn require_digest(request: &Request) -> Result<(), DigestError> {
+    let headers = request.headers();
+    if !(headers.contains_key("content-digest") || headers.contains_key("digest") || headers.contains_key("content-md5")) {
+        return Err(DigestError::Missing);
+    }
+    verify_digest(request)
+}
+
+/// A `Content-Digest` header value for `body`, e.g. for a response.
+///
+/// ```rust
+/// use cgi::digest::{content_digest, DigestAlgorithm};
+/// assert_eq!(content_digest(DigestAlgorithm::Sha256, b"{\"hello\": \"world\"}"),
+///            "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:");
+/// ```
+pub fn content_digest(algorithm: DigestAlgorithm, body: &[u8]) -> String {
+    format!("{}=:{}:", algorithm.name(), base64::encode(&algorithm.hash(body)))
+}
+
+fn header_values(request: &Request, name: &'static str) -> Vec<(&'static str, String)> {
+    request.headers().get_all(name).iter()
+        .map(|v| (name, String::from_utf8_lossy(v.as_bytes()).into_owned()))
+        .collect()
+}
+
+// Returns whether the algorithm was supported (and matched)
+fn check(header: &'static str, name: &str, expected: &[u8], body: &[u8]) -> Result<bool, DigestError> {
+    match DigestAlgorithm::from_name(name) {
+        // md5 isn't allowed in Content-Digest
+        Some(DigestAlgorithm::Md5) if header == "content-digest" => Ok(false),
+        Some(algorithm) => {
+            if algorithm.hash(body) == expected {
+                Ok(true)
+            } else {
+                Err(DigestError::Mismatch { header, algorithm })
+            }
+        }
+        None => Ok(false),
+    }
+}
+
+// `sha-256=:base64:, sha-512=:base64:`
+fn parse_content_digest(value: &str) -> Option<Vec<(String, Vec<u8>)>> {
+    structured::parse_dictionary(value).ok()?.into_iter()
+        .map(|(name, entry)| match entry {
+            ListEntry::Item(item) => Some((name, item.bare_item.as_bytes()?.to_vec())),
+            ListEntry::InnerList(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)], body: &str) -> Request {
+        let mut req = http::Request::builder();
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        req.body(body.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_content_digest() {
+        let body = "{\"hello\": \"world\"}";
+        let good = "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:";
+        assert_eq!(verify_digest(&request(&[("Content-Digest", good)], body)), Ok(()));
+        assert_eq!(verify_digest(&request(&[("Content-Digest", good)], "{}")),
+            Err(DigestError::Mismatch { header: "content-digest", algorithm: DigestAlgorithm::Sha256 }));
+        assert_eq!(verify_digest(&request(&[("Content-Digest", "unixsum=:AAAA:")], body)), Err(DigestError::UnsupportedAlgorithm));
+        assert_eq!(verify_digest(&request(&[("Content-Digest", "sha-256=nope")], body)), Err(DigestError::Malformed("content-digest")));
+    }
+
+    #[test]
+    fn test_legacy_headers() {
+        // md5("hello") = 5d41402abc4b2a76b9719d911017c592
+        let md5 = "XUFAKrxLKna5cZ2REBfFkg==";
+        assert_eq!(verify_digest(&request(&[("Content-MD5", md5)], "hello")), Ok(()));
+        assert!(verify_digest(&request(&[("Content-MD5", md5)], "hullo")).is_err());
+        assert_eq!(verify_digest(&request(&[("Digest", &format!("SHA=abc, MD5={}", md5))], "hello")), Ok(()));
+        assert_eq!(verify_digest(&request(&[], "hello")), Ok(()));
+        assert_eq!(require_digest(&request(&[], "hello")), Err(DigestError::Missing));
+
+        let resp: Response = DigestError::Missing.into();
+        assert_eq!(resp.status(), 400);
+    }
+}
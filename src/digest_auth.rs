@@ -0,0 +1,352 @@
+//! HTTP Digest authentication (RFC 7616). Requires the `digest-auth` feature.
+//!
+//! Unlike Basic authentication, the password is never sent, only a hash of it together with a
+//! nonce from the server. Use it where requests might go over plain HTTP, and the TLS
+//! termination isn't under your control. (Where it is, prefer Basic over HTTPS: Digest needs
+//! the plain text password, or a hash which is as good as one, on the server.)
+//!
+//! Nonces are made from a timestamp and signed with a server secret, so there's nothing to
+//! store between requests. An expired nonce is answered with `stale=true`, and the browser
+//! retries with a new one without asking the user again. As nonces aren't tracked, a captured
+//! request can be replayed until its nonce expires.
+//!
+//! ```rust,no_run
+//! use cgi::digest_auth::DigestAuth;
+//!
+//! fn main() {
+//!     let auth = DigestAuth::new("Members", b"a long random server secret", |user| {
+//!         (user == "alice").then(|| "correct horse battery staple".to_owned())
+//!     });
+//!     cgi::handle(|request: cgi::Request| auth.handle(request, |request| {
+//!         let user = request.headers()["x-cgi-remote-user"].to_str().unwrap().to_owned();
+//!         cgi::text_response(200, format!("Hello {}", user))
+//!     }))
+//! }
+//! ```
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::meta::{AuthType, CgiMeta};
+use crate::{random, text_response, Request, Response};
+
+/// A hash algorithm for Digest authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    /// Only for old clients, MD5 is broken
+    Md5,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Md5 => "MD5",
+        }
+    }
+
+    fn hash(self, data: &str) -> String {
+        let digest = match self {
+            Algorithm::Sha256 => Sha256::digest(data.as_bytes()).to_vec(),
+            Algorithm::Md5 => Md5::digest(data.as_bytes()).to_vec(),
+        };
+        hex(&digest)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Compare without leaking where the first difference is
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+type Lookup = dyn Fn(&str) -> Option<String>;
+
+/// Digest authentication, see the [module docs](self).
+pub struct DigestAuth {
+    realm: String,
+    secret: Vec<u8>,
+    lookup: Box<Lookup>,
+    algorithms: Vec<Algorithm>,
+    nonce_lifetime: Duration,
+}
+
+/// Why a request wasn't authenticated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DigestAuthError {
+    /// No `Authorization: Digest` header
+    Missing,
+    /// The header couldn't be parsed, or is for another realm, URI or algorithm
+    Invalid,
+    /// The nonce is valid, but has expired
+    Stale,
+    /// Unknown user, or wrong password
+    WrongCredentials,
+}
+
+impl DigestAuth {
+    /// `lookup` returns the password of a user, or `None` for an unknown user. `secret` signs
+    /// the nonces; it has to be the same for all processes. By default SHA-256 & MD5 are
+    /// offered, and nonces are valid for 5 minutes.
+    pub fn new<F>(realm: &str, secret: &[u8], lookup: F) -> Self
+        where F: Fn(&str) -> Option<String> + 'static
+    {
+        DigestAuth {
+            realm: realm.to_owned(),
+            secret: secret.to_vec(),
+            lookup: Box::new(lookup),
+            algorithms: vec![Algorithm::Sha256, Algorithm::Md5],
+            nonce_lifetime: Duration::from_secs(300),
+        }
+    }
+
+    /// The algorithms to offer, in order of preference
+    pub fn algorithms(mut self, algorithms: &[Algorithm]) -> Self {
+        self.algorithms = algorithms.to_vec();
+        self
+    }
+
+    /// How long a nonce is valid
+    pub fn nonce_lifetime(mut self, lifetime: Duration) -> Self {
+        self.nonce_lifetime = lifetime;
+        self
+    }
+
+    fn mac(&self, data: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(data.as_bytes());
+        hex(&mac.finalize().into_bytes()[..16])
+    }
+
+    /// A new nonce: `<timestamp>-<random>-<signature>`
+    fn nonce(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let data = format!("{}-{}", now, random::hex(8));
+        format!("{}-{}", data, self.mac(&data))
+    }
+
+    // The same for every request, it only has to be returned unchanged
+    fn opaque(&self) -> String {
+        self.mac("opaque")
+    }
+
+    /// The `WWW-Authenticate` challenges, one per algorithm
+    pub fn challenges(&self, stale: bool) -> Vec<String> {
+        let nonce = self.nonce();
+        self.algorithms.iter().map(|algorithm| {
+            format!("Digest realm=\"{}\", qop=\"auth, auth-int\", algorithm={}, nonce=\"{}\", opaque=\"{}\"{}",
+                self.realm.replace(['"', '\\'], ""), algorithm.name(), nonce, self.opaque(),
+                if stale { ", stale=true" } else { "" })
+        }).collect()
+    }
+
+    /// Check the request's `Authorization` header, returning the user name
+    pub fn authenticate(&self, request: &Request) -> Result<String, DigestAuthError> {
+        let header = request.headers().get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(DigestAuthError::Missing)?;
+        let (scheme, params) = header.split_once(' ').ok_or(DigestAuthError::Missing)?;
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return Err(DigestAuthError::Missing);
+        }
+        let params = parse_params(params).ok_or(DigestAuthError::Invalid)?;
+        let param = |name: &str| params.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str());
+        let required = |name: &str| param(name).ok_or(DigestAuthError::Invalid);
+
+        let (algorithm_name, session) = match param("algorithm").unwrap_or("MD5") {
+            a if a.len() > 5 && a[a.len() - 5..].eq_ignore_ascii_case("-sess") => (&a[..a.len() - 5], true),
+            a => (a, false),
+        };
+        let algorithm = *self.algorithms.iter().find(|a| a.name().eq_ignore_ascii_case(algorithm_name)).ok_or(DigestAuthError::Invalid)?;
+        let user = required("username")?;
+        let nonce = required("nonce")?;
+        let uri = required("uri")?;
+        let qop = required("qop")?;
+        let nc = required("nc")?;
+        let cnonce = required("cnonce")?;
+        let response = required("response")?;
+        if required("realm")? != self.realm || param("opaque").is_some_and(|o| o != self.opaque()) {
+            return Err(DigestAuthError::Invalid);
+        }
+        let request_uri = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        if uri != request_uri {
+            return Err(DigestAuthError::Invalid);
+        }
+
+        let password = (self.lookup)(user);
+        // Compute the hash even for an unknown user, so it takes about as long
+        let mut ha1 = algorithm.hash(&format!("{}:{}:{}", user, self.realm, password.as_deref().unwrap_or("")));
+        if session {
+            ha1 = algorithm.hash(&format!("{}:{}:{}", ha1, nonce, cnonce));
+        }
+        let ha2 = match qop {
+            "auth" => algorithm.hash(&format!("{}:{}", request.method(), uri)),
+            "auth-int" => {
+                let body = match algorithm {
+                    Algorithm::Sha256 => hex(&Sha256::digest(request.body())),
+                    Algorithm::Md5 => hex(&Md5::digest(request.body())),
+                };
+                algorithm.hash(&format!("{}:{}:{}", request.method(), uri, body))
+            }
+            _ => return Err(DigestAuthError::Invalid),
+        };
+        let expected = algorithm.hash(&format!("{}:{}:{}:{}:{}:{}", ha1, nonce, nc, cnonce, qop, ha2));
+        if password.is_none() || !constant_time_eq(&expected, &response.to_ascii_lowercase()) {
+            return Err(DigestAuthError::WrongCredentials);
+        }
+
+        // Only check the nonce once the response matched, so a stale nonce is only reported
+        // to clients which know the password
+        let (data, signature) = nonce.rsplit_once('-').ok_or(DigestAuthError::Invalid)?;
+        if !constant_time_eq(&self.mac(data), signature) {
+            return Err(DigestAuthError::Invalid);
+        }
+        let timestamp: u64 = data.split('-').next().and_then(|t| t.parse().ok()).ok_or(DigestAuthError::Invalid)?;
+        let age = SystemTime::now().duration_since(UNIX_EPOCH + Duration::from_secs(timestamp)).unwrap_or(Duration::ZERO);
+        if age > self.nonce_lifetime {
+            return Err(DigestAuthError::Stale);
+        }
+        Ok(user.to_owned())
+    }
+
+    /// Call `next` if the request is authenticated, with the user name in
+    /// `X-CGI-Remote-User` and `X-CGI-Auth-Type: Digest`, and in the [`CgiMeta`] extension
+    /// (like the web server would set `REMOTE_USER` & `AUTH_TYPE`). Otherwise answer
+    /// `401 Unauthorized` with challenges.
+    pub fn handle<F>(&self, mut request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        let stale = match self.authenticate(&request) {
+            Ok(user) => match http::HeaderValue::from_str(&user) {
+                Ok(value) => {
+                    request.headers_mut().insert("x-cgi-remote-user", value);
+                    request.headers_mut().insert("x-cgi-auth-type", http::HeaderValue::from_static("Digest"));
+                    let mut meta = request.extensions_mut().remove::<CgiMeta>().unwrap_or_default();
+                    meta.remote_user = Some(user);
+                    meta.auth_type = Some(AuthType::Digest);
+                    request.extensions_mut().insert(meta);
+                    return next(request);
+                }
+                Err(_) => false,
+            },
+            Err(DigestAuthError::Stale) => true,
+            Err(_) => false,
+        };
+
+        let mut response = text_response(401, "Unauthorized");
+        for challenge in self.challenges(stale) {
+            if let Ok(value) = challenge.parse() {
+                response.headers_mut().append(http::header::WWW_AUTHENTICATE, value);
+            }
+        }
+        response
+    }
+}
+
+// `name=token, name="quoted \"string\""`
+fn parse_params(input: &str) -> Option<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (_, '\\') => value.push(chars.next()?.1),
+                        (i, '"') => break i + 1,
+                        (_, c) => value.push(c),
+                    }
+                };
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_owned(), &after[end..])
+            }
+        };
+        params.push((name.trim().to_owned(), value));
+        let after = after.trim_start();
+        rest = match after.strip_prefix(',') {
+            Some(next) => next.trim_start(),
+            None if after.is_empty() => after,
+            None => return None,
+        };
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> DigestAuth {
+        DigestAuth::new("http-auth@example.org", b"secret", |user| (user == "Mufasa").then(|| "Circle of Life".to_owned()))
+    }
+
+    fn authorization(auth: &DigestAuth, algorithm: Algorithm, nonce: &str, password: &str) -> String {
+        let ha1 = algorithm.hash(&format!("Mufasa:http-auth@example.org:{}", password));
+        let ha2 = algorithm.hash("GET:/dir/index.html");
+        let response = algorithm.hash(&format!("{}:{}:00000001:f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ:auth:{}", ha1, nonce, ha2));
+        format!("Digest username=\"Mufasa\", realm=\"http-auth@example.org\", uri=\"/dir/index.html\", algorithm={}, \
+                 nonce=\"{}\", nc=00000001, cnonce=\"f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ\", qop=auth, \
+                 response=\"{}\", opaque=\"{}\"", algorithm.name(), nonce, response, auth.opaque())
+    }
+
+    fn request(authorization: &str) -> Request {
+        http::Request::builder().uri("/dir/index.html").header("authorization", authorization).body(vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_rfc_example() {
+        // RFC 7616 section 3.9.1
+        let ha1 = Algorithm::Sha256.hash("Mufasa:http-auth@example.org:Circle of Life");
+        let ha2 = Algorithm::Sha256.hash("GET:/dir/index.html");
+        let response = Algorithm::Sha256.hash(&format!(
+            "{}:7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v:00000001:f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ:auth:{}", ha1, ha2));
+        assert_eq!(response, "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1");
+
+        assert_eq!(parse_params("a=b, c=\"d, \\\"e\\\"\",f=g").unwrap(), vec![
+            ("a".to_owned(), "b".to_owned()),
+            ("c".to_owned(), "d, \"e\"".to_owned()),
+            ("f".to_owned(), "g".to_owned()),
+        ]);
+        assert!(parse_params("a=\"unterminated").is_none());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = auth();
+        let nonce = auth.nonce();
+        assert_eq!(auth.authenticate(&request(&authorization(&auth, Algorithm::Sha256, &nonce, "Circle of Life"))), Ok("Mufasa".to_owned()));
+        assert_eq!(auth.authenticate(&request(&authorization(&auth, Algorithm::Md5, &nonce, "Circle of Life"))), Ok("Mufasa".to_owned()));
+        assert_eq!(auth.authenticate(&request(&authorization(&auth, Algorithm::Sha256, &nonce, "wrong"))), Err(DigestAuthError::WrongCredentials));
+        assert_eq!(auth.authenticate(&request(&authorization(&auth, Algorithm::Sha256, "1-ab-forged", "Circle of Life"))), Err(DigestAuthError::Invalid));
+        assert_eq!(auth.authenticate(&request("Basic TXVmYXNhOkNpcmNsZQ==")), Err(DigestAuthError::Missing));
+
+        let old = format!("1-00-{}", auth.mac("1-00"));
+        assert_eq!(auth.authenticate(&request(&authorization(&auth, Algorithm::Sha256, &old, "Circle of Life"))), Err(DigestAuthError::Stale));
+        let resp = auth.handle(request(&authorization(&auth, Algorithm::Sha256, &old, "Circle of Life")), |_| unreachable!());
+        assert_eq!(resp.status(), 401);
+        let challenges: Vec<_> = resp.headers().get_all("www-authenticate").iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(challenges.len(), 2);
+        assert!(challenges[0].contains("algorithm=SHA-256") && challenges[0].ends_with(", stale=true"));
+
+        let resp = auth.handle(request(&authorization(&auth, Algorithm::Sha256, &nonce, "Circle of Life")), |req| {
+            let meta = req.extensions().get::<CgiMeta>().unwrap();
+            assert_eq!((meta.remote_user.as_deref(), meta.auth_type.as_ref()), (Some("Mufasa"), Some(&AuthType::Digest)));
+            crate::text_response(200, req.headers()["x-cgi-remote-user"].to_str().unwrap())
+        });
+        assert_eq!(resp.body(), b"Mufasa");
+    }
+}
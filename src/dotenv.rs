@@ -0,0 +1,199 @@
+//! Load environment variables from a `.env` file (`dotenv` feature).
+//!
+//! On shared hosting, the server's configuration often can't be edited to set the variables a
+//! programme needs, and for local runs with the [dev server](crate::dev_server) they'd have to
+//! be exported in every shell. With this feature, [`handle`](crate::handle) & co, the servers
+//! and `config::from_env` first load `.env` from the current directory (which is the script's
+//! directory under Apache), or the file set with [`set_path`]. A missing file is fine; an
+//! invalid one is reported on stderr.
+//!
+//! ```text
+//! # Comments and blank lines are ignored
+//! APP_DATABASE_URL=postgres://localhost/app
+//! export APP_GREETING="Hello\nWorld"   # double quotes: \n, \", \\ & \$ are escapes
+//! APP_PASSWORD='p4$$w0rd'              # single quotes: taken literally
+//! ```
+//!
+//! Variables which are already set, e.g. by the web server, aren't overridden. CGI
+//! meta-variables (`REQUEST_METHOD`, `REMOTE_USER`, `HTTP_*` …) are never set from the file, so
+//! it can't forge parts of a request. The file usually holds secrets: keep it outside the
+//! document root, or make sure the server refuses to serve it.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Once, RwLock};
+
+static PATH: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+// The variables of RFC 3875 and the common extensions which describe the request
+const META_VARIABLES: &[&str] = &[
+    "AUTH_TYPE", "CONTENT_LENGTH", "CONTENT_TYPE", "CONTEXT_DOCUMENT_ROOT", "CONTEXT_PREFIX",
+    "DOCUMENT_ROOT", "GATEWAY_INTERFACE", "HTTPS", "PATH_INFO", "PATH_TRANSLATED", "QUERY_STRING",
+    "REDIRECT_STATUS", "REDIRECT_URL", "REMOTE_ADDR", "REMOTE_HOST", "REMOTE_IDENT", "REMOTE_PORT",
+    "REMOTE_USER", "REQUEST_METHOD", "REQUEST_SCHEME", "REQUEST_URI", "SCRIPT_FILENAME",
+    "SCRIPT_NAME", "SERVER_ADDR", "SERVER_NAME", "SERVER_PORT", "SERVER_PROTOCOL",
+    "SERVER_SOFTWARE", "UNIQUE_ID",
+];
+
+/// Load this file instead of `.env` in the current directory. Call it before
+/// [`handle`](crate::handle): the file is only loaded once.
+pub fn set_path(path: impl Into<PathBuf>) {
+    *PATH.write().unwrap_or_else(|e| e.into_inner()) = Some(path.into());
+}
+
+/// Set the variables of the `.env` file at `path` which aren't set yet, now. An invalid file
+/// is an `InvalidData` error naming the line, and sets nothing.
+pub fn load(path: impl AsRef<Path>) -> io::Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    let vars = parse(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    for (name, value) in vars {
+        if !is_meta_variable(&name) && std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+    Ok(())
+}
+
+// Load the file set with `set_path` or `.env`, the first time only
+pub(crate) fn load_once() {
+    static LOAD: Once = Once::new();
+    LOAD.call_once(|| {
+        let path = PATH.read().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_else(|| PathBuf::from(".env"));
+        match load(&path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => eprintln!("Couldn't load {}: {}", path.display(), err),
+            _ => {}
+        }
+    });
+}
+
+fn is_meta_variable(name: &str) -> bool {
+    name.starts_with("HTTP_") || META_VARIABLES.contains(&name)
+}
+
+fn parse(contents: &str) -> Result<Vec<(String, String)>, String> {
+    let mut vars = Vec::new();
+    let mut lines = contents.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: &str| format!("line {}: {}", index + 1, message);
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let (name, value) = line.split_once('=').ok_or_else(|| invalid("expected NAME=value"))?;
+        let name = name.trim_end();
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+            return Err(invalid("invalid variable name"));
+        }
+        let value = value.trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                // Quoted values may span lines
+                let mut value = value[1..].to_owned();
+                let (value, rest) = loop {
+                    if let Some(end) = closing_quote(&value, quote) {
+                        let rest = value[end + 1..].to_owned();
+                        value.truncate(end);
+                        break (value, rest);
+                    }
+                    let (_, next) = lines.next().ok_or_else(|| invalid("unterminated quoted value"))?;
+                    value.push('\n');
+                    value.push_str(next);
+                };
+                let rest = rest.trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    return Err(invalid("unexpected characters after the quoted value"));
+                }
+                if quote == '"' { unescape(&value) } else { value }
+            }
+            // `#` starts a comment after whitespace only, as in `a#b`
+            _ => value.find(" #").or_else(|| value.find("\t#")).map_or(value, |comment| &value[..comment]).trim_end().to_owned(),
+        };
+        vars.push((name.to_owned(), value));
+    }
+    Ok(vars)
+}
+
+// The index of the unescaped `quote` ending a value
+fn closing_quote(value: &str, quote: char) -> Option<usize> {
+    let mut escaped = false;
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quote == '"' => escaped = true,
+            _ if c == quote => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some(c @ ('"' | '\\' | '$')) => result.push(c),
+            Some(c) => {
+                result.push('\\');
+                result.push(c);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let vars = parse("# config\n\
+            \n\
+            PLAIN = some value  # comment\n\
+            export HASH=a#b\n\
+            EMPTY=\n\
+            DOUBLE=\"Hello\\n\\\"World\\\" \\d\" # comment\n\
+            SINGLE='p4$$ \\n'\n\
+            MULTI=\"one\n\
+            two\"\n").unwrap();
+        let vars: Vec<(&str, &str)> = vars.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect();
+        assert_eq!(vars, [
+            ("PLAIN", "some value"),
+            ("HASH", "a#b"),
+            ("EMPTY", ""),
+            ("DOUBLE", "Hello\n\"World\" \\d"),
+            ("SINGLE", "p4$$ \\n"),
+            ("MULTI", "one\ntwo"),
+        ]);
+
+        assert_eq!(parse("A=1\nNO_EQUALS\n").unwrap_err(), "line 2: expected NAME=value");
+        assert_eq!(parse("1A=1").unwrap_err(), "line 1: invalid variable name");
+        assert_eq!(parse("A=\"open").unwrap_err(), "line 1: unterminated quoted value");
+        assert_eq!(parse("A='x' y").unwrap_err(), "line 1: unexpected characters after the quoted value");
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("cgi-dotenv-{}", std::process::id()));
+        std::fs::write(&path, "CGI_DOTENV_TEST_NEW=new\nCGI_DOTENV_TEST_SET=new\nREMOTE_USER=admin\nHTTP_COOKIE=a=b\n").unwrap();
+        std::env::set_var("CGI_DOTENV_TEST_SET", "old");
+        load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(std::env::var("CGI_DOTENV_TEST_NEW").as_deref(), Ok("new"));
+        assert_eq!(std::env::var("CGI_DOTENV_TEST_SET").as_deref(), Ok("old"));
+        assert!(is_meta_variable("REMOTE_USER") && is_meta_variable("HTTP_COOKIE"));
+        assert!(!is_meta_variable("APP_SECRET"));
+        assert_eq!(load(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
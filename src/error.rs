@@ -0,0 +1,247 @@
+//! The error type of this crate.
+
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::sync::{Once, OnceLock, RwLock};
+
+use crate::{empty_response, html_response, IntoResponse, Response};
+
+/// What can go wrong when running a CGI programme.
+///
+/// A handler can return `Result<_, cgi::Error>`; an error is turned into a response by the
+/// function set with [`set_error_response`], which by default logs it to `stderr` and answers
+/// with [`Error::status`] and no body.
+///
+/// While debugging, set the environment variable `CGI_DEBUG=1` (e.g. with Apache's `SetEnv`):
+/// errors, and panics of the handler (as with [`set_catch_panics`](crate::set_catch_panics)),
+/// are then answered with an HTML page showing the error, its sources and a backtrace. Don't
+/// set it in production, the page can reveal details of the programme.
+///
+/// ```rust,no_run
+/// fn handler(request: cgi::Request) -> Result<String, cgi::Error> {
+///     let greeting = std::fs::read_to_string("greeting.txt")?;
+///     let name = request.uri().query().ok_or_else(|| cgi::Error::handler("no name given"))?;
+///     Ok(format!("{} {}", greeting.trim(), name))
+/// }
+///
+/// cgi::handle(handler)
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// A CGI meta-variable isn't set, usually because the programme wasn't started by a web
+    /// server
+    MissingVar(String),
+    /// A CGI meta-variable can't be used, e.g. an unknown `SERVER_PROTOCOL`
+    InvalidVar { name: String, value: String },
+    /// The request body ended before `CONTENT_LENGTH` bytes
+    IncompleteBody { expected: u64, received: u64 },
+    /// The request body is larger than the limit set with
+    /// [`Options::max_body`](crate::Options::max_body) (or
+    /// [`Options::read_to_eof`](crate::Options::read_to_eof), where `length` is the bytes read
+    /// until it was over)
+    BodyTooLarge { limit: u64, length: u64 },
+    /// The request body didn't arrive within the time set with
+    /// [`set_read_timeout`](crate::set_read_timeout)
+    Timeout,
+    /// Reading the request or writing the response failed
+    Io(io::Error),
+    /// The handler panicked with this message (see
+    /// [`set_catch_panics`](crate::set_catch_panics))
+    Panic(String),
+    /// An error of the handler
+    Handler(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Wrap any error (or message) of a handler
+    pub fn handler(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Error::Handler(err.into())
+    }
+
+    /// The status of the response for this error: `400 Bad Request` for an invalid
+    /// meta-variable or an incomplete body (which come from the request), `413 Content Too
+    /// Large` for a body over the limit, `408 Request Timeout` for a timeout, otherwise
+    /// `500 Internal Server Error`
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            Error::InvalidVar { .. } | Error::IncompleteBody { .. } => http::StatusCode::BAD_REQUEST,
+            Error::BodyTooLarge { .. } => http::StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Timeout => http::StatusCode::REQUEST_TIMEOUT,
+            _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingVar(name) => write!(f, "the CGI variable {} isn't set", name),
+            Error::InvalidVar { name, value } => write!(f, "invalid CGI variable {}={:?}", name, value),
+            Error::IncompleteBody { expected, received } => write!(f, "the request body ended after {} of {} bytes", received, expected),
+            Error::BodyTooLarge { limit, length } => write!(f, "the request body of {} bytes is larger than {} bytes", length, limit),
+            Error::Timeout => write!(f, "timed out reading the request body"),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Panic(message) => write!(f, "the handler panicked: {}", message),
+            Error::Handler(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Handler(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(err: http::Error) -> Self {
+        Error::handler(err)
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::handler(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::handler(message)
+    }
+}
+
+impl From<Box<dyn std::error::Error + Send + Sync>> for Error {
+    fn from(err: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        Error::Handler(err)
+    }
+}
+
+static ERROR_RESPONSE: RwLock<fn(&Error) -> Response> = RwLock::new(default_error_response);
+
+fn default_error_response(err: &Error) -> Response {
+    eprintln!("{}", err);
+    if debug_mode() {
+        let backtrace = match err {
+            Error::Panic(_) => PANIC_BACKTRACE.with(|b| b.borrow_mut().take()),
+            _ => None,
+        };
+        return debug_page(err, &backtrace.unwrap_or_else(Backtrace::force_capture));
+    }
+    empty_response(err.status())
+}
+
+// Whether `CGI_DEBUG` is set (and not `0`)
+pub(crate) fn debug_mode() -> bool {
+    static DEBUG: OnceLock<bool> = OnceLock::new();
+    *DEBUG.get_or_init(|| std::env::var_os("CGI_DEBUG").is_some_and(|v| !v.is_empty() && v != "0"))
+}
+
+thread_local! {
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+// Keep the backtrace of the latest panic of each thread for the debug page, in addition to
+// what the panic hook already does
+pub(crate) fn capture_panic_backtraces() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANIC_BACKTRACE.with(|b| *b.borrow_mut() = Some(Backtrace::force_capture()));
+            previous(info);
+        }));
+    });
+}
+
+fn debug_page(err: &Error, backtrace: &Backtrace) -> Response {
+    use crate::files::escape_html;
+
+    let status = err.status();
+    let mut page = format!("<!DOCTYPE html>\n<html><head><title>{}</title></head><body>\n<h1>{}</h1>\n<p>{}</p>\n",
+        status, status, escape_html(&err.to_string()));
+    let mut source = std::error::Error::source(err);
+    if source.is_some() {
+        page.push_str("<h2>Caused by</h2>\n<ol>\n");
+        while let Some(err) = source {
+            page.push_str(&format!("<li>{}</li>\n", escape_html(&err.to_string())));
+            source = err.source();
+        }
+        page.push_str("</ol>\n");
+    }
+    page.push_str(&format!("<h2>Backtrace</h2>\n<pre>{}</pre>\n</body></html>\n", escape_html(&backtrace.to_string())));
+    html_response(status, page)
+}
+
+/// Set how an [`Error`] becomes a response, e.g. to show an error page. This applies to the
+/// whole programme.
+///
+/// ```rust
+/// cgi::set_error_response(|err| {
+///     eprintln!("Error: {}", err);
+///     cgi::html_response(err.status(), "<h1>Sorry, something went wrong</h1>")
+/// });
+/// ```
+pub fn set_error_response(f: fn(&Error) -> Response) {
+    *ERROR_RESPONSE.write().unwrap_or_else(|e| e.into_inner()) = f;
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let f = *ERROR_RESPONSE.read().unwrap_or_else(|e| e.into_inner());
+        f(&self)
+    }
+}
+
+impl From<Error> for Response {
+    fn from(err: Error) -> Self {
+        err.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error() {
+        let err = Error::from(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+        assert_eq!(err.to_string(), "I/O error: closed");
+        assert!(std::error::Error::source(&err).is_some());
+        assert_eq!(err.into_response().status(), 500);
+
+        let err = Error::InvalidVar { name: "SERVER_PROTOCOL".into(), value: "HTTP/9".into() };
+        assert_eq!(err.to_string(), "invalid CGI variable SERVER_PROTOCOL=\"HTTP/9\"");
+        assert_eq!(Response::from(err).status(), 400);
+        assert_eq!(Error::BodyTooLarge { limit: 10, length: 20 }.status(), 413);
+
+        let result: Result<String, Error> = Err("no name".into());
+        let response = result.into_response();
+        assert_eq!(response.status(), 500);
+        assert!(response.body().is_empty());
+    }
+
+    #[test]
+    fn test_debug_page() {
+        let err = Error::from(io::Error::new(io::ErrorKind::NotFound, "<missing>"));
+        let page = debug_page(&err, &Backtrace::force_capture());
+        assert_eq!(page.status(), 500);
+        assert_eq!(page.headers()["content-type"], "text/html; charset=utf-8");
+        let page = String::from_utf8(page.into_body()).unwrap();
+        assert!(page.contains("<p>I/O error: &lt;missing&gt;</p>"));
+        assert!(page.contains("<li>&lt;missing&gt;</li>"));
+        assert!(page.contains("<h2>Backtrace</h2>"));
+    }
+}
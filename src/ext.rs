@@ -0,0 +1,329 @@
+//! Convenience methods on requests & responses.
+
+use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use crate::text_response;
+
+use crate::base64;
+use crate::cookie::{Cookie, Cookies};
+use crate::urlencoded;
+
+/// Extra methods for [`Request`](crate::Request) (and any other `http::Request`).
+///
+/// ```rust
+/// use cgi::RequestExt;
+///
+/// let request = cgi::testing::CgiRequestBuilder::new().query("tag=a&tag=b&q=hello+world").build();
+/// assert_eq!(request.query_get("q").as_deref(), Some("hello world"));
+/// assert_eq!(request.query_all("tag"), ["a", "b"]);
+/// ```
+pub trait RequestExt {
+    /// The decoded `key=value` pairs of the query string, in order. `+` is a space, and a key
+    /// without `=` has an empty value.
+    fn query_pairs(&self) -> Vec<(String, String)>;
+
+    /// Every value of each key in the query string
+    fn query_map(&self) -> HashMap<String, Vec<String>> {
+        let mut map: HashMap<String, Vec<String>> = HashMap::new();
+        for (key, value) in self.query_pairs() {
+            map.entry(key).or_default().push(value);
+        }
+        map
+    }
+
+    /// The first value of `key` in the query string
+    fn query_get(&self, key: &str) -> Option<String> {
+        self.query_pairs().into_iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// All values of `key` in the query string
+    fn query_all(&self, key: &str) -> Vec<String> {
+        self.query_pairs().into_iter().filter(|(k, _)| k == key).map(|(_, v)| v).collect()
+    }
+
+    /// The request's cookies: the [`Cookies`] extension if there is one (which
+    /// [`handle`](crate::handle) adds), otherwise parsed from the `Cookie` headers
+    fn cookies(&self) -> Cookies;
+
+    /// The value of the cookie `name`
+    fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().get(name).map(|v| v.to_owned())
+    }
+
+    /// The username & password of Basic authentication (RFC 7617) in the `Authorization`
+    /// header, or `None` if there aren't any (or they aren't valid UTF-8). Answer with
+    /// [`unauthorized_basic`](crate::unauthorized_basic) to ask for them.
+    ///
+    /// Apache only passes the header to CGI programmes with `CGIPassAuth On`.
+    ///
+    /// ```rust
+    /// use cgi::RequestExt;
+    ///
+    /// let request = cgi::testing::CgiRequestBuilder::new().header("Authorization", "Basic YWRtaW46czNjcjN0").build();
+    /// assert_eq!(request.basic_auth(), Some(("admin".to_owned(), "s3cr3t".to_owned())));
+    /// ```
+    fn basic_auth(&self) -> Option<(String, String)>;
+
+    /// The token of `Authorization: Bearer <token>` (RFC 6750). Answer with
+    /// [`bearer_challenge`](crate::bearer_challenge) if it's missing or not accepted.
+    ///
+    /// Apache only passes the header to CGI programmes with `CGIPassAuth On`.
+    fn bearer_token(&self) -> Option<&str>;
+
+    /// Deserialize the query string into `T`. Requires the `serde` feature.
+    ///
+    /// ```rust,no_run
+    /// use cgi::RequestExt;
+    ///
+    /// #[derive(serde::Deserialize)]
+    /// struct Search {
+    ///     q: String,
+    ///     page: Option<u32>,
+    /// }
+    ///
+    /// cgi::handle(|request: cgi::Request| {
+    ///     let search: Search = match request.query() {
+    ///         Ok(search) => search,
+    ///         Err(err) => return err.into(),
+    ///     };
+    ///     cgi::text_response(200, format!("{} (page {})", search.q, search.page.unwrap_or(1)))
+    /// })
+    /// ```
+    #[cfg(feature = "serde")]
+    fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, QueryError>;
+
+    /// Deserialize the JSON body into `T`, if the `Content-Type` is `application/json` (or
+    /// another `+json` type). Requires the `json` feature.
+    ///
+    /// ```rust,no_run
+    /// use cgi::RequestExt;
+    ///
+    /// #[derive(serde::Deserialize)]
+    /// struct Webhook {
+    ///     event: String,
+    /// }
+    ///
+    /// cgi::handle(|request: cgi::Request| {
+    ///     match request.json::<Webhook>() {
+    ///         Ok(hook) => cgi::text_response(200, format!("Got {}", hook.event)),
+    ///         Err(err) => err.into(),
+    ///     }
+    /// })
+    /// ```
+    #[cfg(feature = "json")]
+    fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, JsonError>;
+}
+
+impl<B: AsRef<[u8]>> RequestExt for http::Request<B> {
+    fn query_pairs(&self) -> Vec<(String, String)> {
+        urlencoded::parse(self.uri().query().unwrap_or(""))
+    }
+
+    fn cookies(&self) -> Cookies {
+        self.extensions().get::<Cookies>().cloned().unwrap_or_else(|| Cookies::from_headers(self.headers()))
+    }
+
+    fn basic_auth(&self) -> Option<(String, String)> {
+        let credentials = auth_credentials(self.headers(), "Basic")?;
+        let decoded = String::from_utf8(base64::decode(credentials)?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some((user.to_owned(), password.to_owned()))
+    }
+
+    fn bearer_token(&self) -> Option<&str> {
+        auth_credentials(self.headers(), "Bearer")
+    }
+
+    #[cfg(feature = "serde")]
+    fn query<T: serde::de::DeserializeOwned>(&self) -> Result<T, QueryError> {
+        serde_urlencoded::from_str(self.uri().query().unwrap_or("")).map_err(|e| QueryError(e.to_string()))
+    }
+
+    #[cfg(feature = "json")]
+    fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, JsonError> {
+        let content_type = self.headers().get(http::header::CONTENT_TYPE).and_then(|ct| ct.to_str().ok()).unwrap_or("");
+        let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if essence != "application/json" && !essence.ends_with("+json") {
+            return Err(JsonError::ContentType(content_type.to_owned()));
+        }
+        serde_json::from_slice(self.body().as_ref()).map_err(|e| JsonError::Invalid(e.to_string()))
+    }
+}
+
+// The credentials of the `Authorization` header if it uses `scheme` (case-insensitive)
+fn auth_credentials<'a>(headers: &'a http::HeaderMap, scheme: &str) -> Option<&'a str> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?.trim();
+    let (name, credentials) = value.split_once(' ')?;
+    name.eq_ignore_ascii_case(scheme).then(|| credentials.trim()).filter(|c| !c.is_empty())
+}
+
+/// Extra methods for [`Response`](crate::Response).
+pub trait ResponseExt {
+    /// Append a `Set-Cookie` header for `cookie`, keeping any cookies already set
+    fn add_cookie(&mut self, cookie: &Cookie);
+}
+
+impl<B> ResponseExt for http::Response<B> {
+    fn add_cookie(&mut self, cookie: &Cookie) {
+        match cookie.to_string().parse() {
+            Ok(value) => { self.headers_mut().append(http::header::SET_COOKIE, value); }
+            Err(_) => eprintln!("Invalid cookie {:?} not set", cookie.name),
+        }
+    }
+}
+
+/// The query string doesn't match the type it's deserialized into, e.g. a field is missing or
+/// isn't a number. Converts into a `400 Bad Request` response with the message.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError(pub String);
+
+#[cfg(feature = "serde")]
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid query string: {}", self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for QueryError {}
+
+#[cfg(feature = "serde")]
+impl From<QueryError> for crate::Response {
+    fn from(err: QueryError) -> Self {
+        text_response(400, err.to_string())
+    }
+}
+
+/// Why a JSON body couldn't be deserialized.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+    /// The `Content-Type` isn't JSON (with the type that was sent)
+    ContentType(String),
+    /// The body isn't valid JSON, or doesn't match the type (with serde's message, which
+    /// includes the line & column)
+    Invalid(String),
+}
+
+#[cfg(feature = "json")]
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::ContentType(content_type) => write!(f, "expected a JSON body, not {:?}", content_type),
+            JsonError::Invalid(message) => write!(f, "invalid JSON body: {}", message),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl std::error::Error for JsonError {}
+
+/// `415 Unsupported Media Type` for the wrong `Content-Type`, otherwise `400 Bad Request`
+#[cfg(feature = "json")]
+impl From<JsonError> for crate::Response {
+    fn from(err: JsonError) -> Self {
+        let status = if matches!(err, JsonError::ContentType(_)) { 415 } else { 400 };
+        text_response(status, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let request = http::Request::builder().uri("/x?a=1&b=%C3%BC&a=2&flag").body(vec![]).unwrap();
+        assert_eq!(request.query_get("a").as_deref(), Some("1"));
+        assert_eq!(request.query_all("a"), ["1", "2"]);
+        assert_eq!(request.query_get("b").as_deref(), Some("ü"));
+        assert_eq!(request.query_get("flag").as_deref(), Some(""));
+        assert_eq!(request.query_get("missing"), None);
+        assert_eq!(request.query_map()["a"], ["1", "2"]);
+
+        let request = http::Request::builder().uri("/x").body(vec![]).unwrap();
+        assert!(request.query_pairs().is_empty());
+    }
+
+    #[test]
+    fn test_cookies() {
+        let request = http::Request::builder().header("cookie", "a=1; b=2").body(vec![]).unwrap();
+        assert_eq!(request.cookie("b").as_deref(), Some("2"));
+        let request = crate::testing::CgiRequestBuilder::new().header("Cookie", "c=3").build();
+        assert_eq!(request.cookies().iter().collect::<Vec<_>>(), [("c", "3")]);
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let request = |auth: &str| http::Request::builder().header("authorization", auth).body(vec![]).unwrap();
+        assert_eq!(request("basic  dTpwOnc=").basic_auth(), Some(("u".to_owned(), "p:w".to_owned())));
+        assert_eq!(request("Basic bm9jb2xvbg==").basic_auth(), None);
+        assert_eq!(request("Bearer dTpwOnc=").basic_auth(), None);
+        assert_eq!(request("Basic !!!").basic_auth(), None);
+
+        let response = crate::unauthorized_basic("Admin \"area\"");
+        assert_eq!(response.status(), 401);
+        assert_eq!(response.headers()["www-authenticate"], "Basic realm=\"Admin area\", charset=\"UTF-8\"");
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let request = |auth: &str| http::Request::builder().header("authorization", auth).body(vec![]).unwrap();
+        assert_eq!(request("Bearer mF_9.B5f-4.1JqM").bearer_token(), Some("mF_9.B5f-4.1JqM"));
+        assert_eq!(request("bearer  abc ").bearer_token(), Some("abc"));
+        assert_eq!(request("Bearer").bearer_token(), None);
+        assert_eq!(request("Basic abc").bearer_token(), None);
+
+        let response = crate::bearer_challenge("api", crate::BearerError::InsufficientScope("admin".into()));
+        assert_eq!(response.status(), 403);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer realm=\"api\", error=\"insufficient_scope\", scope=\"admin\"");
+        assert_eq!(crate::bearer_challenge("api", crate::BearerError::InvalidRequest).status(), 400);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_query_deserialize() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Search {
+            q: String,
+            page: Option<u32>,
+        }
+
+        let request = |uri: &str| http::Request::builder().uri(uri).body(vec![]).unwrap();
+        let search: Search = request("/?q=rust+cgi&page=2").query().unwrap();
+        assert_eq!(search, Search { q: "rust cgi".into(), page: Some(2) });
+        assert_eq!(request("/?q=x").query::<Search>().unwrap().page, None);
+
+        let err = request("/?page=2").query::<Search>().unwrap_err();
+        assert_eq!(err.to_string(), "invalid query string: missing field `q`");
+        let err = request("/?q=x&page=two").query::<Search>().unwrap_err();
+        assert_eq!(crate::Response::from(err).status(), 400);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Hook {
+            event: String,
+            id: u64,
+        }
+
+        let request = |content_type: &str, body: &str| http::Request::builder()
+            .header("content-type", content_type)
+            .body(body.as_bytes().to_vec()).unwrap();
+        let hook: Hook = request("application/json; charset=utf-8", r#"{"event": "push", "id": 7}"#).json().unwrap();
+        assert_eq!(hook, Hook { event: "push".into(), id: 7 });
+        assert!(request("application/vnd.api+json", r#"{"event": "x", "id": 1}"#).json::<Hook>().is_ok());
+
+        let err = request("text/plain", "{}").json::<Hook>().unwrap_err();
+        assert_eq!(crate::Response::from(err).status(), 415);
+        let err = request("application/json", r#"{"event": "push", "id": "7"}"#).json::<Hook>().unwrap_err();
+        assert!(err.to_string().contains("invalid type: string \"7\", expected u64 at line 1"), "{}", err);
+        assert_eq!(crate::Response::from(err).status(), 400);
+    }
+}
@@ -0,0 +1,329 @@
+//! Typed handler inputs.
+//!
+//! A type implementing [`FromRequest`] can be taken from a request, or else turns into an
+//! error response (usually `400 Bad Request`). [`with`] makes a handler from a function taking
+//! extractors, so the checks don't clutter the handler itself. Several extractors can be
+//! combined in a tuple, and `Option<T>` makes one optional.
+//!
+//! ```rust,no_run
+//! use cgi::cookie::Cookies;
+//! use cgi::extract;
+//!
+//! cgi::handle(extract::with(|(method, cookies): (http::Method, Option<Cookies>)| {
+//!     let theme = cookies.as_ref().and_then(|c| c.get("theme")).unwrap_or("light");
+//!     cgi::text_response(200, format!("{} with the {} theme", method, theme))
+//! }))
+//! ```
+//!
+//! [`Query`], [`Form`] & [`Path`] require the `serde` feature, [`Json`] the `json` feature.
+
+#[cfg(feature = "serde")]
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use crate::{text_response, QueryError};
+#[cfg(feature = "json")]
+use crate::JsonError;
+use crate::cookie::Cookies;
+use crate::meta::{CgiMeta, RemoteAddr};
+use crate::router::PathParams;
+use crate::{RequestExt, Request, Response};
+
+/// A value taken from a request. See the [module docs](self).
+pub trait FromRequest: Sized {
+    /// Why the value couldn't be taken, as the response to send instead
+    type Rejection: Into<Response>;
+
+    fn from_request(request: &Request) -> Result<Self, Self::Rejection>;
+}
+
+/// Make a handler from a function taking extractors
+pub fn with<T, F>(f: F) -> impl Fn(Request) -> Response
+    where T: FromRequest,
+          F: Fn(T) -> Response
+{
+    move |request| match T::from_request(&request) {
+        Ok(value) => f(value),
+        Err(rejection) => rejection.into(),
+    }
+}
+
+/// The query string, deserialized with serde.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Query<T> {
+    type Rejection = QueryError;
+
+    fn from_request(request: &Request) -> Result<Self, QueryError> {
+        request.query().map(Query)
+    }
+}
+
+/// An `application/x-www-form-urlencoded` body, deserialized with serde. Another
+/// `Content-Type` is answered with `415 Unsupported Media Type`.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Form<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Form<T> {
+    type Rejection = FormError;
+
+    fn from_request(request: &Request) -> Result<Self, FormError> {
+        let content_type = request.headers().get(http::header::CONTENT_TYPE).and_then(|ct| ct.to_str().ok()).unwrap_or("");
+        if !content_type.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Err(FormError::ContentType(content_type.to_owned()));
+        }
+        serde_urlencoded::from_bytes(request.body()).map(Form).map_err(|e| FormError::Invalid(e.to_string()))
+    }
+}
+
+/// A JSON body, deserialized with serde. See [`RequestExt::json`].
+///
+/// ```rust,no_run
+/// use cgi::extract::{self, Json, Query};
+///
+/// #[derive(serde::Deserialize)]
+/// struct Page {
+///     page: Option<u32>,
+/// }
+///
+/// #[derive(serde::Deserialize)]
+/// struct Comment {
+///     text: String,
+/// }
+///
+/// cgi::handle(extract::with(|(Query(page), Json(comment)): (Query<Page>, Json<Comment>)| {
+///     cgi::text_response(200, format!("{} on page {}", comment.text, page.page.unwrap_or(1)))
+/// }))
+/// ```
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Json<T> {
+    type Rejection = JsonError;
+
+    fn from_request(request: &Request) -> Result<Self, JsonError> {
+        request.json().map(Json)
+    }
+}
+
+/// The [`PathParams`] of a [`Router`](crate::router::Router) route, deserialized with serde
+/// into a struct with a field for each parameter.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+#[cfg(feature = "serde")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Path<T> {
+    type Rejection = PathError;
+
+    fn from_request(request: &Request) -> Result<Self, PathError> {
+        let pairs: Vec<(String, String)> = request.extensions().get::<PathParams>()
+            .map(|params| params.iter().map(|(n, v)| (n.to_owned(), v.to_owned())).collect())
+            .unwrap_or_default();
+        serde_urlencoded::from_str(&crate::urlencoded::serialize(&pairs)).map(Path).map_err(|e| PathError(e.to_string()))
+    }
+}
+
+/// The parameters of the route. Without a router, there are none.
+impl FromRequest for PathParams {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.extensions().get::<PathParams>().cloned().unwrap_or_default())
+    }
+}
+
+/// All request headers
+impl FromRequest for http::HeaderMap {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.headers().clone())
+    }
+}
+
+impl FromRequest for http::Method {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.method().clone())
+    }
+}
+
+impl FromRequest for http::Uri {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.uri().clone())
+    }
+}
+
+impl FromRequest for Cookies {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(request.cookies())
+    }
+}
+
+/// The client's address. Without `REMOTE_ADDR`, it's answered with `500 Internal Server
+/// Error`, as the web server is misconfigured.
+impl FromRequest for RemoteAddr {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        request.extensions().get::<RemoteAddr>().copied().ok_or_else(|| crate::empty_response(500))
+    }
+}
+
+/// All meta-variables. Like [`RemoteAddr`], it's `500 Internal Server Error` if they're missing.
+impl FromRequest for CgiMeta {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        request.extensions().get::<CgiMeta>().cloned().ok_or_else(|| crate::empty_response(500))
+    }
+}
+
+/// The whole request
+impl FromRequest for Request {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        let mut copy = crate::clone_request(request);
+        *copy.extensions_mut() = request.extensions().clone();
+        Ok(copy)
+    }
+}
+
+/// `None` instead of an error response
+impl<T: FromRequest> FromRequest for Option<T> {
+    type Rejection = Response;
+
+    fn from_request(request: &Request) -> Result<Self, Response> {
+        Ok(T::from_request(request).ok())
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),+) => {
+        /// Every extractor, in order; the first error is returned
+        impl<$($name: FromRequest),+> FromRequest for ($($name,)+) {
+            type Rejection = Response;
+
+            fn from_request(request: &Request) -> Result<Self, Response> {
+                Ok(($($name::from_request(request).map_err(Into::into)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+impl_tuple!(A, B, C, D, E, F);
+
+/// Why a form body couldn't be deserialized.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormError {
+    /// The `Content-Type` isn't `application/x-www-form-urlencoded` (with the type that was sent)
+    ContentType(String),
+    /// The body doesn't match the type (with serde's message)
+    Invalid(String),
+}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormError::ContentType(content_type) => write!(f, "expected a form body, not {:?}", content_type),
+            FormError::Invalid(message) => write!(f, "invalid form body: {}", message),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for FormError {}
+
+/// `415 Unsupported Media Type` for the wrong `Content-Type`, otherwise `400 Bad Request`
+#[cfg(feature = "serde")]
+impl From<FormError> for Response {
+    fn from(err: FormError) -> Self {
+        let status = if matches!(err, FormError::ContentType(_)) { 415 } else { 400 };
+        text_response(status, err.to_string())
+    }
+}
+
+/// The path parameters don't match the type they're deserialized into. Converts into a `400
+/// Bad Request` response with the message.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathError(pub String);
+
+#[cfg(feature = "serde")]
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid path: {}", self.0)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for PathError {}
+
+#[cfg(feature = "serde")]
+impl From<PathError> for Response {
+    fn from(err: PathError) -> Self {
+        text_response(400, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    #[test]
+    fn test_extract() {
+        let request = CgiRequestBuilder::new().header("X-Token", "abc").build();
+        let (method, headers, params): (http::Method, http::HeaderMap, PathParams) = FromRequest::from_request(&request).unwrap();
+        assert_eq!(method, "GET");
+        assert_eq!(headers["x-token"], "abc");
+        assert!(params.is_empty());
+        assert_eq!(RemoteAddr::from_request(&request).unwrap().to_string(), "127.0.0.1");
+        assert_eq!(CgiMeta::from_request(&request).unwrap().server_name, "localhost");
+        let copy = Request::from_request(&request).unwrap();
+        assert!(copy.extensions().get::<Cookies>().is_some());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_extract_serde() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Item {
+            id: u32,
+        }
+
+        let router = crate::router::Router::new()
+            .post("/items/:id", with(|(Path(item), Form(form), query): (Path<Item>, Form<Item>, Option<Query<Item>>)| {
+                crate::text_response(200, format!("{} {} {:?}", item.id, form.id, query.map(|q| q.0.id)))
+            }));
+        let run = |path: &str, content_type: &str, body: &str| {
+            let response = CgiRequestBuilder::new().method("POST").path_info(path).body(content_type, body).run(|r| router.handle(r));
+            (response.status().as_u16(), String::from_utf8(response.body().clone()).unwrap())
+        };
+        assert_eq!(run("/items/7", "application/x-www-form-urlencoded", "id=8"), (200, "7 8 None".into()));
+        assert_eq!(run("/items/x", "application/x-www-form-urlencoded", "id=8").0, 400);
+        assert_eq!(run("/items/7", "application/x-www-form-urlencoded", "id=").0, 400);
+        assert_eq!(run("/items/7", "text/plain", "id=8").0, 415);
+    }
+}
@@ -0,0 +1,375 @@
+//! Run a handler as a persistent FastCGI worker. Requires the `fastcgi` feature.
+//!
+//! Starting a new process for every request is the biggest cost of plain CGI. With FastCGI,
+//! the web server starts the programme once and sends it one request after another over a
+//! socket. The handler is the same function as for [`handle`](crate::handle), it just gets
+//! called many times.
+//!
+//! ```rust,no_run
+//! # #[cfg(unix)]
+//! # fn main() {
+//! // Started by the web server (e.g. mod_fcgid or spawn-fcgi), with the listening socket
+//! // as stdin
+//! cgi::fastcgi::run(|request: cgi::Request| cgi::text_response(200, "Hello World")).unwrap();
+//! # }
+//! # #[cfg(not(unix))]
+//! # fn main() {}
+//! ```
+//!
+//! Or listen on a socket yourself, for nginx's `fastcgi_pass 127.0.0.1:9000`:
+//!
+//! ```rust,no_run
+//! let listener = std::net::TcpListener::bind("127.0.0.1:9000").unwrap();
+//! cgi::fastcgi::serve(listener.incoming(), |request: cgi::Request| cgi::text_response(200, "Hello World")).unwrap();
+//! ```
+//!
+//! Connections are handled one at a time, and each handles one request at a time; for
+//! concurrency, let the web server start several workers. A panic in the handler is answered
+//! with a `500`, and the worker carries on.
+
+use std::collections::HashMap;
+use std::io::{self, IoSlice, Read, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::Duration;
+
+use crate::{call_handler, empty_response, parse_request, reset_request_state, run_after_response, serialize_parts, write_all_vectored, Request, Response};
+
+const VERSION: u8 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+const UNKNOWN_TYPE: u8 = 11;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+
+const REQUEST_COMPLETE: u8 = 0;
+const CANT_MPX_CONN: u8 = 1;
+const UNKNOWN_ROLE: u8 = 3;
+
+// Accepting fails this many times in a row before `serve` gives up
+const MAX_ACCEPT_ERRORS: u32 = 10;
+
+struct Record {
+    kind: u8,
+    request_id: u16,
+    content: Vec<u8>,
+}
+
+fn read_record(stream: &mut impl Read) -> io::Result<Option<Record>> {
+    let mut header = [0; 8];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if header[0] != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported FastCGI version"));
+    }
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding = header[6] as usize;
+    let mut content = vec![0; length + padding];
+    stream.read_exact(&mut content)?;
+    content.truncate(length);
+    Ok(Some(Record { kind: header[1], request_id: u16::from_be_bytes([header[2], header[3]]), content }))
+}
+
+fn write_record(stream: &mut impl Write, kind: u8, request_id: u16, content: &[u8]) -> io::Result<()> {
+    let padding = (8 - content.len() % 8) % 8;
+    let [id_hi, id_lo] = request_id.to_be_bytes();
+    let [len_hi, len_lo] = (content.len() as u16).to_be_bytes();
+    let header = [VERSION, kind, id_hi, id_lo, len_hi, len_lo, padding as u8, 0];
+    write_all_vectored(stream, &mut [IoSlice::new(&header), IoSlice::new(content), IoSlice::new(&[0; 8][..padding])])
+}
+
+// A stream (e.g. `STDOUT`) is split into records, and ended with an empty one. The data can be
+// in several parts, which aren't copied together.
+fn write_stream(stream: &mut impl Write, kind: u8, request_id: u16, parts: &[&[u8]]) -> io::Result<()> {
+    for chunk in parts.iter().flat_map(|part| part.chunks(0xfff8)) {
+        write_record(stream, kind, request_id, chunk)?;
+    }
+    write_record(stream, kind, request_id, &[])
+}
+
+fn end_request(stream: &mut impl Write, request_id: u16, protocol_status: u8) -> io::Result<()> {
+    write_record(stream, END_REQUEST, request_id, &[0, 0, 0, 0, protocol_status, 0, 0, 0])
+}
+
+// Lengths are 1 byte, or 4 bytes with the high bit set
+fn read_length(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let first = *data.get(*pos)?;
+    if first < 0x80 {
+        *pos += 1;
+        Some(first as usize)
+    } else {
+        let bytes = data.get(*pos..*pos + 4)?;
+        *pos += 4;
+        Some((u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) & 0x7fff_ffff) as usize)
+    }
+}
+
+// Values are kept as bytes, they needn't be UTF-8
+fn decode_pairs(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut pairs = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (Some(name_len), Some(value_len)) = (read_length(data, &mut pos), read_length(data, &mut pos)) else { break };
+        let (Some(name), Some(value)) = (data.get(pos..pos + name_len), data.get(pos + name_len..pos + name_len + value_len)) else { break };
+        pairs.push((String::from_utf8_lossy(name).into_owned(), value.to_vec()));
+        pos += name_len + value_len;
+    }
+    pairs
+}
+
+fn encode_pair(out: &mut Vec<u8>, name: &str, value: &str) {
+    for len in [name.len(), value.len()] {
+        if len < 0x80 {
+            out.push(len as u8);
+        } else {
+            out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Handle the requests on one connection, until the web server closes it (or doesn't ask to
+/// keep it open).
+pub fn serve_connection<S, F>(stream: &mut S, handler: &F) -> io::Result<()>
+    where S: Read + Write,
+          F: Fn(Request) -> Response
+{
+    // The request being received: its ID, whether to keep the connection, params & stdin
+    let mut current: Option<(u16, bool)> = None;
+    let mut params = Vec::new();
+    let mut stdin = Vec::new();
+
+    while let Some(record) = read_record(stream)? {
+        match record.kind {
+            BEGIN_REQUEST => {
+                let role = u16::from_be_bytes([record.content.first().copied().unwrap_or(0), record.content.get(1).copied().unwrap_or(0)]);
+                let keep_conn = record.content.get(2).is_some_and(|flags| flags & KEEP_CONN != 0);
+                if current.is_some() {
+                    end_request(stream, record.request_id, CANT_MPX_CONN)?;
+                } else if role != RESPONDER {
+                    end_request(stream, record.request_id, UNKNOWN_ROLE)?;
+                    if !keep_conn {
+                        return Ok(());
+                    }
+                } else {
+                    current = Some((record.request_id, keep_conn));
+                    params.clear();
+                    stdin.clear();
+                }
+            }
+            ABORT_REQUEST if current.is_some_and(|(id, _)| id == record.request_id) => {
+                let (id, keep_conn) = current.take().expect("checked above");
+                end_request(stream, id, REQUEST_COMPLETE)?;
+                if !keep_conn {
+                    return Ok(());
+                }
+            }
+            PARAMS if current.is_some_and(|(id, _)| id == record.request_id) => params.extend_from_slice(&record.content),
+            STDIN if current.is_some_and(|(id, _)| id == record.request_id) => {
+                if !record.content.is_empty() {
+                    stdin.extend_from_slice(&record.content);
+                    continue;
+                }
+                // The end of stdin, so the whole request is here
+                let (id, keep_conn) = current.take().expect("checked above");
+                let env: HashMap<String, Vec<u8>> = decode_pairs(&params).into_iter().collect();
+                let (head, body) = respond(env, std::mem::take(&mut stdin), handler);
+                write_stream(stream, STDOUT, id, &[&head, &body])?;
+                end_request(stream, id, REQUEST_COMPLETE)?;
+                stream.flush()?;
+                run_after_response();
+                if !keep_conn {
+                    return Ok(());
+                }
+            }
+            GET_VALUES => {
+                let mut values = Vec::new();
+                for (name, _) in decode_pairs(&record.content) {
+                    match name.as_str() {
+                        "FCGI_MAX_CONNS" | "FCGI_MAX_REQS" => encode_pair(&mut values, &name, "1"),
+                        "FCGI_MPXS_CONNS" => encode_pair(&mut values, &name, "0"),
+                        _ => {}
+                    }
+                }
+                write_record(stream, GET_VALUES_RESULT, 0, &values)?;
+            }
+            kind if record.request_id == 0 => write_record(stream, UNKNOWN_TYPE, 0, &[kind, 0, 0, 0, 0, 0, 0, 0])?,
+            // Records of other requests, or ones we don't need (e.g. `DATA`)
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// The head & the body of the response
+fn respond<F>(env: HashMap<String, Vec<u8>>, stdin: Vec<u8>, handler: &F) -> (Vec<u8>, Vec<u8>)
+    where F: Fn(Request) -> Response
+{
+    reset_request_state();
+    let mut response = catch_unwind(AssertUnwindSafe(|| call_handler(parse_request(env, stdin), handler))).unwrap_or_else(|_| empty_response(500));
+    crate::stream::buffer_body(&mut response);
+    serialize_parts(response)
+}
+
+/// Serve every connection from `incoming`, e.g. `TcpListener::incoming()`. Errors on a
+/// connection are printed to stderr, and the next one is served.
+///
+/// An error accepting a connection is printed too, and accepting is retried after a pause
+/// which grows with each error in a row. After 10 in a row (e.g. the socket isn't listening),
+/// the last error is returned.
+pub fn serve<I, S, F>(incoming: I, handler: F) -> io::Result<()>
+    where I: IntoIterator<Item = io::Result<S>>,
+          S: Read + Write,
+          F: Fn(Request) -> Response
+{
+    #[cfg(feature = "dotenv")]
+    crate::dotenv::load_once();
+    let mut accept_errors = 0;
+    for stream in incoming {
+        match stream {
+            Ok(mut stream) => {
+                accept_errors = 0;
+                if let Err(err) = serve_connection(&mut stream, &handler) {
+                    eprintln!("FastCGI connection failed: {}", err);
+                }
+            }
+            Err(err) => {
+                accept_errors += 1;
+                if accept_errors >= MAX_ACCEPT_ERRORS {
+                    return Err(err);
+                }
+                eprintln!("Could not accept a FastCGI connection: {}", err);
+                std::thread::sleep(Duration::from_millis(20) * accept_errors);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serve on the listening socket the web server passed as stdin (`FCGI_LISTENSOCK_FILENO`),
+/// which is how FastCGI applications are usually started.
+///
+/// Fails right away if stdin isn't a socket (e.g. the programme was started from a shell),
+/// and otherwise like [`serve`].
+#[cfg(unix)]
+pub fn run<F>(handler: F) -> io::Result<()>
+    where F: Fn(Request) -> Response
+{
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: the FastCGI spec says file descriptor 0 is the listening socket, and nothing
+    // else uses stdin in a FastCGI application. The listener takes ownership of it.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(0) };
+    if tcp.local_addr().is_ok() {
+        return serve(tcp.incoming(), handler);
+    }
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(std::os::unix::io::IntoRawFd::into_raw_fd(tcp)) };
+    if let Err(err) = unix.local_addr() {
+        return Err(io::Error::new(err.kind(), format!("stdin is not the FastCGI listening socket: {}", err)));
+    }
+    serve(unix.incoming(), handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A connection from the test's point of view: what the server reads, and what it wrote
+    struct Conn {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.input.read(buf) }
+    }
+
+    impl Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.output.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn request(input: &mut Vec<u8>, id: u16, keep_conn: bool, env: &[(&str, &str)], body: &[u8]) {
+        write_record(input, BEGIN_REQUEST, id, &[0, 1, keep_conn as u8, 0, 0, 0, 0, 0]).unwrap();
+        let mut params = Vec::new();
+        for (name, value) in env {
+            encode_pair(&mut params, name, value);
+        }
+        write_stream(input, PARAMS, id, &[&params]).unwrap();
+        write_stream(input, STDIN, id, &[body]).unwrap();
+    }
+
+    fn records(mut output: &[u8]) -> Vec<Record> {
+        std::iter::from_fn(|| read_record(&mut output).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_pairs() {
+        let long = "x".repeat(200);
+        let mut encoded = Vec::new();
+        encode_pair(&mut encoded, "SHORT", &long);
+        encode_pair(&mut encoded, "B", "");
+        assert_eq!(decode_pairs(&encoded), vec![("SHORT".to_owned(), long.into_bytes()), ("B".to_owned(), vec![])]);
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        let env = [("REQUEST_METHOD", "POST"), ("SCRIPT_NAME", "/app"), ("PATH_INFO", "/x"), ("CONTENT_LENGTH", "5")];
+        let mut input = Vec::new();
+        request(&mut input, 1, true, &env, b"hello");
+        request(&mut input, 2, false, &[("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/panic")], b"");
+        let mut conn = Conn { input: io::Cursor::new(input), output: Vec::new() };
+
+        serve_connection(&mut conn, &|request: Request| {
+            assert_ne!(request.uri().path(), "/panic", "handler panics");
+            crate::text_response(200, format!("{} {}", request.uri(), String::from_utf8_lossy(request.body())))
+        }).unwrap();
+
+        let records = records(&conn.output);
+        let kinds: Vec<_> = records.iter().map(|r| (r.kind, r.request_id)).collect();
+        // The head & the body in records of their own
+        assert_eq!(kinds, [(STDOUT, 1), (STDOUT, 1), (STDOUT, 1), (END_REQUEST, 1), (STDOUT, 2), (STDOUT, 2), (END_REQUEST, 2)]);
+        let head = String::from_utf8(records[0].content.clone()).unwrap();
+        assert!(head.starts_with("Status: 200 OK\n") && head.ends_with("\n\n"));
+        assert_eq!(records[1].content, b"/app/x hello");
+        assert!(records[2].content.is_empty());
+        assert!(String::from_utf8_lossy(&records[4].content).starts_with("Status: 500"));
+        assert_eq!(records[3].content, [0, 0, 0, 0, REQUEST_COMPLETE, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_state_of_panicked_request() {
+        let mut input = Vec::new();
+        request(&mut input, 1, true, &[("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/panic")], b"");
+        request(&mut input, 2, false, &[("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/next")], b"");
+        let mut conn = Conn { input: io::Cursor::new(input), output: Vec::new() };
+
+        serve_connection(&mut conn, &|request: Request| {
+            assert!(crate::server_timing::metrics().is_empty(), "metrics of the previous request");
+            crate::server_timing::record("db", None, None);
+            assert_ne!(request.uri().path(), "/panic", "handler panics");
+            crate::empty_response(204)
+        }).unwrap();
+
+        let records = records(&conn.output);
+        assert!(String::from_utf8_lossy(&records[3].content).starts_with("Status: 204"));
+    }
+
+    #[test]
+    fn test_serve_accept_errors() {
+        let incoming = std::iter::repeat_with(|| Err::<Conn, _>(io::Error::other("not listening")));
+        let err = serve(incoming, |_: Request| crate::empty_response(204)).unwrap_err();
+        assert_eq!(err.to_string(), "not listening");
+    }
+}
@@ -0,0 +1,400 @@
+//! Serving static files.
+//!
+//! [`serve_dir`] maps `PATH_INFO` onto a directory, so `/cgi-bin/app/css/site.css` serves
+//! `css/site.css`. [`serve_file`] serves a single file. Both set the `Content-Type` from the
+//! extension, answer conditional requests with `304 Not Modified` (or `412`), and support
+//! ranges (see [`range`](crate::range)).
+//!
+//! ```rust,no_run
+//! cgi::handle(|request: cgi::Request| {
+//!     if request.uri().path().starts_with("/cgi-bin/app/static/") {
+//!         return cgi::serve_dir("/srv/app", &request);
+//!     }
+//!     cgi::text_response(200, "Hello World")
+//! })
+//! ```
+//!
+//! Paths with `..`, and hidden files & directories (starting with `.`, like `.htaccess` or
+//! `.git`), are `404 Not Found`, as are symbolic links leading out of the directory. A
+//! directory is served by its `index.html`, after a redirect adding the trailing `/`.
+//!
+//! [`ServeDir`] can list the files of directories without an index, as HTML or JSON:
+//!
+//! ```rust,no_run
+//! use cgi::files::{Listing, ServeDir};
+//!
+//! let files = ServeDir::new("/srv/share").listing(Listing::Html);
+//! cgi::handle(|request: cgi::Request| files.serve(&request))
+//! ```
+
+use std::fmt::Write;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::conditional::Validators;
+use crate::date::http_date;
+use crate::reporting::json_string;
+use crate::router::path_info;
+use crate::{empty_response, Error, IntoResponse, Request, Response};
+
+/// The file for a `GET` or `HEAD` request. Other methods get `405 Method Not Allowed`, and a
+/// file which doesn't exist `404 Not Found`.
+pub fn serve_file(path: impl AsRef<Path>, request: &Request) -> Response {
+    let path = path.as_ref();
+    if request.method() != http::Method::GET && request.method() != http::Method::HEAD {
+        let mut response = empty_response(405);
+        response.headers_mut().insert(http::header::ALLOW, http::HeaderValue::from_static("GET, HEAD"));
+        return response;
+    }
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => {}
+        Ok(_) => return empty_response(404),
+        Err(err) => return io_error(err),
+    }
+
+    let validators = match Validators::from_file(path) {
+        Ok(validators) => validators,
+        Err(err) => return io_error(err),
+    };
+    if let Err(err) = validators.check_write(request) {
+        return err.into();
+    }
+    if validators.not_modified(request) {
+        let mut response = empty_response(304);
+        validators.set_headers(&mut response);
+        return response;
+    }
+
+    let content_type = crate::mime::from_path(path).unwrap_or("application/octet-stream");
+    crate::range::file_response(request, path, content_type).unwrap_or_else(io_error)
+}
+
+/// A `200` response with the file at `path`, with the `Content-Type` from the extension and
+/// the `Content-Length`. The file is written in large chunks while the response is written,
+/// rather than read into memory first; with the `mmap` feature, it's memory-mapped. A file
+/// which doesn't exist is `404 Not Found`, one which can't be read `403 Forbidden`.
+///
+/// Unlike [`serve_file`], it doesn't answer conditional or range requests.
+///
+/// ```rust,no_run
+/// cgi::handle(|request: cgi::Request| cgi::file_response("/srv/downloads/installer.iso"))
+/// ```
+pub fn file_response(path: impl AsRef<Path>) -> Response {
+    let path = path.as_ref();
+    let open = || {
+        let file = std::fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let len = metadata.len();
+        let mut response = http::Response::builder()
+            .status(200)
+            .header(http::header::CONTENT_TYPE, crate::mime::from_path(path).unwrap_or("application/octet-stream"))
+            .header(http::header::CONTENT_LENGTH, len)
+            .body(Vec::new())
+            .unwrap();
+        response.extensions_mut().insert(crate::stream::BodyWriter::new(move |out| write_file(file, 0..len, out)));
+        Ok(response)
+    };
+    open().unwrap_or_else(io_error)
+}
+
+/// The file `PATH_INFO` points to under `root`, see the [module docs](self)
+pub fn serve_dir(root: impl AsRef<Path>, request: &Request) -> Response {
+    ServeDir::new(root).serve(request)
+}
+
+/// The format of directory listings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listing {
+    /// A table with links
+    Html,
+    /// An array of objects with `name`, `type` (`file` or `directory`), `size` (for files,
+    /// in bytes) and `modified` (UNIX seconds)
+    Json,
+}
+
+/// Serves a directory, like [`serve_dir`], with options.
+#[derive(Debug, Clone)]
+pub struct ServeDir {
+    root: PathBuf,
+    index: Option<String>,
+    listing: Option<Listing>,
+}
+
+impl ServeDir {
+    /// Directories are served by their `index.html`, and aren't listed
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        ServeDir { root: root.as_ref().to_path_buf(), index: Some("index.html".into()), listing: None }
+    }
+
+    /// The file serving a directory, or `None` to always list directories
+    pub fn index(mut self, name: Option<&str>) -> Self {
+        self.index = name.map(|n| n.to_owned());
+        self
+    }
+
+    /// List directories without an index file
+    pub fn listing(mut self, format: Listing) -> Self {
+        self.listing = Some(format);
+        self
+    }
+
+    /// The file or directory `PATH_INFO` points to
+    pub fn serve(&self, request: &Request) -> Response {
+        let path_info = path_info(request);
+        let Some(path) = resolve(&self.root, &path_info) else {
+            return empty_response(404);
+        };
+        if !path.is_dir() {
+            return serve_file(path, request);
+        }
+        if !path_info.ends_with('/') {
+            return add_slash(request);
+        }
+        if let Some(index) = self.index.as_ref().map(|index| path.join(index)).filter(|index| index.is_file()) {
+            return serve_file(index, request);
+        }
+        match self.listing {
+            Some(format) if request.method() == http::Method::GET || request.method() == http::Method::HEAD => {
+                let top = path == self.root.canonicalize().unwrap_or_default();
+                list(&path, format, top).unwrap_or_else(io_error)
+            }
+            Some(_) => serve_file(&path, request),
+            None => empty_response(404),
+        }
+    }
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+// The listing of a directory: subdirectories first, then files, by name; hidden ones left out
+fn list(path: &Path, format: Listing, top: bool) -> io::Result<Response> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follows symbolic links, like serving them does
+        let Ok(metadata) = std::fs::metadata(entry.path()) else { continue };
+        entries.push(Entry { name, is_dir: metadata.is_dir(), size: metadata.len(), modified: metadata.modified().ok() });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(match format {
+        Listing::Html => crate::html_response(200, list_html(&entries, top)),
+        Listing::Json => crate::binary_response(200, "application/json", list_json(&entries).into_bytes()),
+    })
+}
+
+fn list_html(entries: &[Entry], top: bool) -> String {
+    let mut html = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index</title></head><body>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n");
+    if !top {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { String::new() } else { human_size(entry.size) };
+        let modified = entry.modified.map(http_date).unwrap_or_default();
+        // `./`, so a name like `javascript:…` can't be taken for a scheme
+        let href = crate::encode_uri_part(entry.name.as_bytes(), b"%?#;");
+        let _ = writeln!(html, "<tr><td><a href=\"./{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            escape_html(&href), slash, escape_html(&entry.name), slash, size, modified);
+    }
+    html.push_str("</table>\n</body></html>\n");
+    html
+}
+
+fn list_json(entries: &[Entry]) -> String {
+    let items: Vec<String> = entries.iter().map(|entry| {
+        let mut item = format!("{{\"name\":{},\"type\":\"{}\"", json_string(&entry.name), if entry.is_dir { "directory" } else { "file" });
+        if !entry.is_dir {
+            let _ = write!(item, ",\"size\":{}", entry.size);
+        }
+        if let Some(modified) = entry.modified {
+            let _ = write!(item, ",\"modified\":{}", modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+        }
+        item.push('}');
+        item
+    }).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// The path under `root` for `path_info`, or `None` if it would be outside of it or hidden
+fn resolve(root: &Path, path_info: &str) -> Option<PathBuf> {
+    let relative = Path::new(path_info.trim_start_matches('/'));
+    if path_info.contains(['\0', '\\']) {
+        return None;
+    }
+    let mut path = root.to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) if !name.to_string_lossy().starts_with('.') => path.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    // Symbolic links could still lead elsewhere
+    let canonical = path.canonicalize().ok()?;
+    canonical.starts_with(root.canonicalize().ok()?).then_some(canonical)
+}
+
+fn add_slash(request: &Request) -> Response {
+    match request.uri().query() {
+        Some(query) => crate::moved_permanently(&format!("{}/?{}", request.uri().path(), query)),
+        None => crate::moved_permanently(&format!("{}/", request.uri().path())),
+    }
+}
+
+// The size of the writes of a file's contents
+const CHUNK: usize = 1024 * 1024;
+
+// Write the `range` of `file` to `out`, in large chunks. With the `mmap` feature, the file is
+// memory-mapped, so it's written without being copied into this process's memory.
+pub(crate) fn write_file(file: std::fs::File, range: std::ops::Range<u64>, out: &mut dyn io::Write) -> io::Result<()> {
+    if range.is_empty() {
+        return Ok(());
+    }
+    #[cfg(all(feature = "mmap", unix))]
+    if let Ok(len) = usize::try_from(file.metadata()?.len()) {
+        if let Ok(map) = crate::mmap::Mmap::map(&file, len) {
+            let body = usize::try_from(range.start).ok()
+                .zip(usize::try_from(range.end).ok())
+                .and_then(|(start, end)| map.as_slice().get(start..end))
+                .ok_or(io::ErrorKind::UnexpectedEof)?;
+            for chunk in body.chunks(CHUNK) {
+                out.write_all(chunk)?;
+            }
+            return Ok(());
+        }
+    }
+    let mut file = file;
+    io::Seek::seek(&mut file, io::SeekFrom::Start(range.start))?;
+    let mut reader = io::BufReader::with_capacity(CHUNK, io::Read::take(file, range.end - range.start));
+    io::copy(&mut reader, out)?;
+    Ok(())
+}
+
+pub(crate) fn io_error(err: io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::NotFound => empty_response(404),
+        io::ErrorKind::PermissionDenied => empty_response(403),
+        _ => Error::Io(err).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    #[test]
+    fn test_file_response() {
+        let path = std::env::temp_dir().join(format!("cgi-file-response-{}.txt", std::process::id()));
+        let contents = "line\n".repeat(300_000);
+        std::fs::write(&path, &contents).unwrap();
+        let mut response = file_response(&path);
+        assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+        assert_eq!(response.headers()["content-length"], contents.len().to_string().as_str());
+        crate::stream::buffer_body(&mut response);
+        assert!(response.body() == contents.as_bytes());
+
+        let mut out = Vec::new();
+        write_file(std::fs::File::open(&path).unwrap(), 5..14, &mut out).unwrap();
+        assert_eq!(out, b"line\nline");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file_response(&path).status(), 404);
+        assert_eq!(file_response(std::env::temp_dir()).status(), 404);
+    }
+
+    #[test]
+    fn test_serve_dir() {
+        let root = std::env::temp_dir().join(format!("cgi-files-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("site.css"), "body { color: red }").unwrap();
+        std::fs::write(root.join("docs/index.html"), "<h1>Docs</h1>").unwrap();
+        std::fs::write(root.join(".secret"), "password").unwrap();
+        let get = |path_info: &str| CgiRequestBuilder::new().path_info(path_info).run(|r| serve_dir(&root, &r));
+
+        let response = get("/site.css");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/css; charset=utf-8");
+        assert_eq!(response.body(), b"body { color: red }");
+        assert_eq!(get("/docs/").body(), b"<h1>Docs</h1>");
+        let redirect = get("/docs");
+        assert_eq!(redirect.status(), 301);
+        assert_eq!(redirect.headers()["location"], "/cgi-bin/test/docs/");
+
+        assert_eq!(get("/missing.css").status(), 404);
+        assert_eq!(get("/.secret").status(), 404);
+        assert_eq!(get("/docs/../site.css").status(), 404);
+        assert_eq!(get("/../etc/passwd").status(), 404);
+
+        let etag = response.headers()["etag"].to_str().unwrap();
+        let cached = CgiRequestBuilder::new().path_info("/site.css").header("If-None-Match", etag).run(|r| serve_dir(&root, &r));
+        assert_eq!(cached.status(), 304);
+        let post = CgiRequestBuilder::new().method("POST").path_info("/site.css").run(|r| serve_dir(&root, &r));
+        assert_eq!(post.status(), 405);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_listing() {
+        let root = std::env::temp_dir().join(format!("cgi-listing-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub dir")).unwrap();
+        std::fs::write(root.join("b.txt"), "hello").unwrap();
+        std::fs::write(root.join("<a>.bin"), vec![0; 2048]).unwrap();
+        std::fs::write(root.join(".hidden"), "").unwrap();
+        #[cfg(unix)]
+        std::fs::write(root.join("javascript:alert(1)"), "").unwrap();
+        let get = |files: &ServeDir, path_info: &str| CgiRequestBuilder::new().path_info(path_info).run(|r| files.serve(&r));
+
+        assert_eq!(get(&ServeDir::new(&root), "/").status(), 404);
+
+        let html = get(&ServeDir::new(&root).listing(Listing::Html), "/");
+        let html = String::from_utf8(html.into_body()).unwrap();
+        assert!(html.contains("<a href=\"./sub%20dir/\">sub dir/</a>"));
+        assert!(html.contains("<a href=\"./%3Ca%3E.bin\">&lt;a&gt;.bin</a></td><td>2.0 KiB</td>"));
+        #[cfg(unix)]
+        assert!(html.contains("<a href=\"./javascript:alert(1)\">javascript:alert(1)</a>"));
+        assert!(!html.contains("hidden") && !html.contains("../"));
+        assert!(html.find("sub dir").unwrap() < html.find("b.txt").unwrap());
+        assert!(get(&ServeDir::new(&root).listing(Listing::Html), "/sub dir/").body().windows(3).any(|w| w == b"../"));
+
+        let json = get(&ServeDir::new(&root).listing(Listing::Json), "/");
+        assert_eq!(json.headers()["content-type"], "application/json");
+        let json = String::from_utf8(json.into_body()).unwrap();
+        assert!(json.starts_with("[{\"name\":\"sub dir\",\"type\":\"directory\",\"modified\":"));
+        assert!(json.contains("{\"name\":\"b.txt\",\"type\":\"file\",\"size\":5,\"modified\":"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+//! Feature flags and A/B tests, configured in a file.
+//!
+//! Each line of the file defines a flag, and how many visitors get it:
+//!
+//! ```text
+//! dark_mode on
+//! legacy_export off
+//! new_checkout 25%                 # for a quarter of visitors
+//! button_colour red:1 blue:1       # half see red, half blue
+//! ```
+//!
+//! Visitors are put into stable buckets by hashing the flag name with an ID: the user ID if
+//! there is one (by default `REMOTE_USER`), otherwise a random ID kept in a cookie. So the same
+//! visitor always gets the same variant, and flags are independent of each other. The
+//! assignment is logged for every request, so results can be analysed afterwards.
+//!
+//! ```rust,no_run
+//! use cgi::flags::FeatureFlags;
+//!
+//! fn main() {
+//!     let flags = FeatureFlags::load("/etc/my-cgi/flags.conf").unwrap();
+//!     cgi::handle(|request: cgi::Request| flags.handle(request, |request, flags| {
+//!         if flags.enabled("new_checkout") {
+//!             cgi::text_response(200, "the new checkout")
+//!         } else {
+//!             cgi::text_response(200, "the old checkout")
+//!         }
+//!     }))
+//! }
+//! ```
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::meta::CgiMeta;
+use crate::store::fnv1a;
+use crate::{append_log_line, random, Request, Response};
+
+/// A flags file couldn't be read or parsed.
+#[derive(Debug)]
+pub enum FlagsError {
+    Io(PathBuf, std::io::Error),
+    /// Line number (from 1) and description
+    Syntax(usize, String),
+}
+
+impl fmt::Display for FlagsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlagsError::Io(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            FlagsError::Syntax(line, msg) => write!(f, "flags line {}: {}", line, msg),
+        }
+    }
+}
+
+impl std::error::Error for FlagsError {}
+
+// Weighted variants; a plain flag is `on` & `off`
+#[derive(Debug, Clone, PartialEq)]
+struct Flag {
+    name: String,
+    variants: Vec<(String, f64)>,
+}
+
+const BUCKETS: u64 = 10_000;
+
+impl Flag {
+    fn variant(&self, id: &str) -> &str {
+        let total: f64 = self.variants.iter().map(|(_, w)| w).sum();
+        let mut bytes = self.name.as_bytes().to_vec();
+        bytes.push(0);
+        bytes.extend_from_slice(id.as_bytes());
+        let bucket = (fnv1a(&bytes, 0xcbf29ce484222325) % BUCKETS) as f64 / BUCKETS as f64;
+
+        let mut upper = 0.0;
+        for (name, weight) in &self.variants {
+            upper += weight / total;
+            if bucket < upper {
+                return name;
+            }
+        }
+        self.variants.last().map(|(name, _)| name.as_str()).unwrap_or("off")
+    }
+}
+
+type UserId = dyn Fn(&Request) -> Option<String>;
+
+/// Flag definitions, see the [module docs](self).
+pub struct FeatureFlags {
+    flags: Vec<Flag>,
+    cookie: String,
+    user_id: Box<UserId>,
+    log: Option<PathBuf>,
+}
+
+impl FeatureFlags {
+    /// Parse flag definitions
+    pub fn parse(text: &str) -> Result<Self, FlagsError> {
+        let mut flags = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let syntax = |msg: String| FlagsError::Syntax(idx + 1, msg);
+            let mut words = line.split('#').next().unwrap_or("").split_whitespace();
+            let name = match words.next() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let spec: Vec<&str> = words.collect();
+
+            let variants = match spec.as_slice() {
+                ["on"] => vec![("on".to_owned(), 1.0)],
+                ["off"] => vec![("off".to_owned(), 1.0)],
+                [percent] if percent.ends_with('%') => {
+                    let p: f64 = percent.trim_end_matches('%').parse().ok().filter(|p| (0.0..=100.0).contains(p))
+                        .ok_or_else(|| syntax(format!("invalid percentage {:?}", percent)))?;
+                    vec![("on".to_owned(), p), ("off".to_owned(), 100.0 - p)]
+                }
+                [] => return Err(syntax(format!("no value for {:?}", name))),
+                variants => variants.iter().map(|v| {
+                    let (variant, weight) = v.split_once(':').unwrap_or((v, "1"));
+                    let weight: f64 = weight.parse().ok().filter(|w| *w >= 0.0)
+                        .ok_or_else(|| syntax(format!("invalid weight in {:?}", v)))?;
+                    Ok((variant.to_owned(), weight))
+                }).collect::<Result<_, _>>()?,
+            };
+            if variants.iter().map(|(_, w)| w).sum::<f64>() <= 0.0 {
+                return Err(syntax(format!("all weights of {:?} are 0", name)));
+            }
+            flags.push(Flag { name, variants });
+        }
+
+        Ok(FeatureFlags {
+            flags,
+            cookie: "cgi_bucket".into(),
+            user_id: Box::new(|request| request.extensions().get::<CgiMeta>()
+                .and_then(|meta| meta.remote_user.clone())
+                .filter(|user| !user.is_empty())),
+            log: None,
+        })
+    }
+
+    /// Read and parse the flags file at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FlagsError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| FlagsError::Io(path.to_owned(), e))?;
+        FeatureFlags::parse(&text)
+    }
+
+    /// The cookie keeping the ID of visitors without a user ID (default `cgi_bucket`)
+    pub fn cookie(mut self, name: &str) -> Self {
+        self.cookie = name.to_owned();
+        self
+    }
+
+    /// How to find the user ID of a request (default `REMOTE_USER`)
+    pub fn user_id<F>(mut self, f: F) -> Self
+        where F: Fn(&Request) -> Option<String> + 'static
+    {
+        self.user_id = Box::new(f);
+        self
+    }
+
+    /// Append assignments to this file, instead of printing them to stderr
+    pub fn log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log = Some(path.into());
+        self
+    }
+
+    /// The flags for a visitor with this ID
+    pub fn assign(&self, id: &str) -> Flags {
+        Flags {
+            id: id.to_owned(),
+            variants: self.flags.iter().map(|flag| (flag.name.clone(), flag.variant(id).to_owned())).collect(),
+        }
+    }
+
+    /// The flags for the visitor making `request`, and whether the ID is new (and so should
+    /// be set in the cookie)
+    pub fn for_request(&self, request: &Request) -> (Flags, bool) {
+        if let Some(id) = (self.user_id)(request) {
+            return (self.assign(&format!("user:{}", id)), false);
+        }
+        let cookie = request.headers().get_all(http::header::COOKIE).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie)
+            .map(|(_, value)| value.to_owned())
+            .filter(|value| !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric()));
+        match cookie {
+            Some(id) => (self.assign(&id), false),
+            None => (self.assign(&random::hex(16)), true),
+        }
+    }
+
+    /// Call `next` with the request's flags, log the assignment, and set the cookie for a new
+    /// visitor.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request, &Flags) -> Response
+    {
+        let (flags, new) = self.for_request(&request);
+        let line = format!("flags {} {}\n", request.uri().path(), flags);
+        let mut response = next(request, &flags);
+
+        if new {
+            let cookie = format!("{}={}; Path=/; Max-Age=31536000; HttpOnly; SameSite=Lax", self.cookie, flags.id);
+            if let Ok(value) = cookie.parse() {
+                response.headers_mut().append(http::header::SET_COOKIE, value);
+            }
+        }
+
+        match &self.log {
+            Some(path) => if let Err(err) = append_log_line(path, &line) {
+                eprintln!("Could not write flags log: {}. {}", err, line.trim_end());
+            },
+            None => eprint!("{}", line),
+        }
+        response
+    }
+}
+
+/// The flags assigned to one visitor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flags {
+    id: String,
+    variants: Vec<(String, String)>,
+}
+
+impl Flags {
+    /// The ID the flags were assigned with
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Whether `name` is defined, and not `off`
+    pub fn enabled(&self, name: &str) -> bool {
+        self.variant(name).is_some_and(|v| v != "off")
+    }
+
+    /// The variant of `name` (`on` or `off` for a plain flag)
+    pub fn variant(&self, name: &str) -> Option<&str> {
+        self.variants.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// `id=<id> name=variant …`, as logged
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "id={}", self.id)?;
+        for (name, variant) in &self.variants {
+            write!(f, " {}={}", name, variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        let flags = FeatureFlags::parse("a on\nb off # retired\n\nc 30%\nd red:1 blue:3 green:0\n").unwrap();
+        let assigned = flags.assign("visitor");
+        assert!(assigned.enabled("a"));
+        assert!(!assigned.enabled("b"));
+        assert!(!assigned.enabled("missing"));
+        assert_eq!(assigned, flags.assign("visitor"));
+
+        let mut on = 0;
+        let mut blue = 0;
+        for i in 0..1000 {
+            let assigned = flags.assign(&i.to_string());
+            on += assigned.enabled("c") as usize;
+            blue += (assigned.variant("d") == Some("blue")) as usize;
+            assert_ne!(assigned.variant("d"), Some("green"));
+        }
+        assert!((250..350).contains(&on), "{}", on);
+        assert!((700..800).contains(&blue), "{}", blue);
+
+        assert!(matches!(FeatureFlags::parse("a 120%"), Err(FlagsError::Syntax(1, _))));
+        assert!(matches!(FeatureFlags::parse("a\n"), Err(FlagsError::Syntax(1, _))));
+        assert!(matches!(FeatureFlags::parse("a x:0"), Err(FlagsError::Syntax(1, _))));
+    }
+
+    #[test]
+    fn test_handle() {
+        let path = std::env::temp_dir().join(format!("cgi-flags-{}.log", std::process::id()));
+        let flags = FeatureFlags::parse("a 50%").unwrap().log(&path);
+
+        let request = http::Request::builder().uri("/page").body(vec![]).unwrap();
+        let resp = flags.handle(request, |_, flags| crate::text_response(200, flags.variant("a").unwrap()));
+        let cookie = resp.headers()["set-cookie"].to_str().unwrap();
+        assert!(cookie.starts_with("cgi_bucket="));
+        let id = cookie["cgi_bucket=".len()..].split(';').next().unwrap();
+
+        let request = http::Request::builder().uri("/page").header("cookie", format!("x=1; cgi_bucket={}", id)).body(vec![]).unwrap();
+        let again = flags.handle(request, |_, flags| crate::text_response(200, flags.variant("a").unwrap()));
+        assert_eq!(again.body(), resp.body());
+        assert!(!again.headers().contains_key("set-cookie"));
+
+        let request = crate::testing::CgiRequestBuilder::new().env("REMOTE_USER", "alice").build();
+        assert_eq!(flags.for_request(&request).0.id(), "user:alice");
+        let spoofed = http::Request::builder().header("x-cgi-remote-user", "alice").body(vec![]).unwrap();
+        assert_ne!(flags.for_request(&spoofed).0.id(), "user:alice");
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected = format!("flags /page id={} a={}\n", id, String::from_utf8_lossy(resp.body()));
+        assert_eq!(log, expected.repeat(2));
+    }
+}
@@ -0,0 +1,56 @@
+//! Handlers which are structs.
+
+use crate::{IntoResponse, Request, Response};
+
+/// Something which answers requests: closures and functions taking a [`Request`] and returning
+/// an [`IntoResponse`], or a struct which is set up once, e.g. with the configuration and a
+/// database connection. Run it with [`serve_handler`](crate::serve_handler).
+///
+/// ```rust,no_run
+/// struct App {
+///     greeting: String,
+/// }
+///
+/// impl cgi::Handler for App {
+///     fn call(&self, request: cgi::Request) -> cgi::Response {
+///         cgi::text_response(200, format!("{} {}", self.greeting, request.uri().path()))
+///     }
+/// }
+///
+/// let greeting = std::fs::read_to_string("greeting.txt").unwrap();
+/// cgi::serve_handler(App { greeting })
+/// ```
+pub trait Handler {
+    fn call(&self, request: Request) -> Response;
+}
+
+impl<F, R> Handler for F
+    where F: Fn(Request) -> R,
+          R: IntoResponse
+{
+    fn call(&self, request: Request) -> Response {
+        self(request).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    struct Prefix(&'static str);
+
+    impl Handler for Prefix {
+        fn call(&self, request: Request) -> Response {
+            crate::text_response(200, format!("{}{}", self.0, request.method()))
+        }
+    }
+
+    #[test]
+    fn test_handler() {
+        let prefix = Prefix("> ");
+        assert_eq!(CgiRequestBuilder::new().run(|request| prefix.call(request)).body(), b"> GET");
+        let closure = |_: Request| "closure";
+        assert_eq!(Handler::call(&closure, CgiRequestBuilder::new().build()).body(), b"closure");
+    }
+}
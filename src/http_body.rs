@@ -0,0 +1,199 @@
+//! Responses with [`http_body::Body`](::http_body::Body) bodies (`http-body` feature).
+//!
+//! Body types of the hyper ecosystem can be returned from handlers directly: responses with a
+//! [`Full`], [`Empty`], [`StreamBody`], [`BoxBody`] or [`UnsyncBoxBody`] body implement
+//! [`IntoResponse`], and [`into_response`] converts a response with any other body.
+//!
+//! ```rust,no_run
+//! use bytes::Bytes;
+//! use http_body_util::Full;
+//!
+//! cgi::handle(|_request: cgi::Request| {
+//!     http::Response::new(Full::new(Bytes::from("Hello World")))
+//! })
+//! ```
+//!
+//! A body whose exact size is known (like `Full`) is collected into a plain [`Response`]. Any
+//! other body is streamed as a [`streaming_response`](crate::stream::streaming_response): its
+//! frames are written as they come, each flushed right away, and trailers are dropped. The
+//! body is polled on the current thread; with the `tokio` feature that's on a single-threaded
+//! tokio runtime, so streams using tokio's timers & I/O work, otherwise something else has to
+//! wake it (e.g. a channel fed by another thread).
+
+use std::future::Future;
+use std::io::{self, Write};
+
+use ::bytes::Buf;
+use ::http_body::Body;
+use http_body_util::{combinators::{BoxBody, UnsyncBoxBody}, BodyExt, Empty, Full, StreamBody};
+
+use crate::stream::BodyWriter;
+use crate::{Error, IntoResponse, Response};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The response with its body collected, or streamed if its size isn't known. An error while
+/// collecting the body is answered like [`Error::Handler`].
+pub fn into_response<B>(response: http::Response<B>) -> Response
+    where B: Body + Send + 'static,
+          B::Data: Send,
+          B::Error: Into<BoxError>
+{
+    let (parts, body) = response.into_parts();
+    if body.size_hint().exact().is_some() {
+        return match block_on(body.collect()) {
+            Ok(collected) => Response::from_parts(parts, collected.to_bytes().into()),
+            Err(err) => Error::Handler(err.into()).into_response(),
+        };
+    }
+    let mut response = Response::from_parts(parts, Vec::new());
+    response.extensions_mut().insert(BodyWriter::new(move |out| block_on(write_body(body, out))));
+    response
+}
+
+async fn write_body<B>(body: B, out: &mut dyn Write) -> io::Result<()>
+    where B: Body,
+          B::Error: Into<BoxError>
+{
+    let mut body = std::pin::pin!(body);
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|err| io::Error::other(err.into()))?;
+        if let Ok(mut data) = frame.into_data() {
+            while data.has_remaining() {
+                let chunk = data.chunk();
+                out.write_all(chunk)?;
+                let len = chunk.len();
+                data.advance(len);
+            }
+            out.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+fn block_on<Fut: Future>(future: Fut) -> Fut::Output {
+    crate::block_on(future)
+}
+
+// Poll on this thread, parking it until the future is woken
+#[cfg(not(feature = "tokio"))]
+fn block_on<Fut: Future>(future: Fut) -> Fut::Output {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct Unpark(std::thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+/// The response, see [`into_response`]
+impl<D> IntoResponse for http::Response<Full<D>>
+    where D: Buf + Send + 'static
+{
+    fn into_response(self) -> Response {
+        into_response(self)
+    }
+}
+
+/// The response, see [`into_response`]
+impl<D> IntoResponse for http::Response<Empty<D>>
+    where D: Buf + Send + 'static
+{
+    fn into_response(self) -> Response {
+        into_response(self)
+    }
+}
+
+/// The response, see [`into_response`]
+impl<S> IntoResponse for http::Response<StreamBody<S>>
+    where StreamBody<S>: Body + Send + 'static,
+          <StreamBody<S> as Body>::Data: Send,
+          <StreamBody<S> as Body>::Error: Into<BoxError>
+{
+    fn into_response(self) -> Response {
+        into_response(self)
+    }
+}
+
+/// The response, see [`into_response`]
+impl<D, E> IntoResponse for http::Response<BoxBody<D, E>>
+    where D: Buf + Send + 'static,
+          E: Into<BoxError> + 'static
+{
+    fn into_response(self) -> Response {
+        into_response(self)
+    }
+}
+
+/// The response, see [`into_response`]
+impl<D, E> IntoResponse for http::Response<UnsyncBoxBody<D, E>>
+    where D: Buf + Send + 'static,
+          E: Into<BoxError> + 'static
+{
+    fn into_response(self) -> Response {
+        into_response(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use ::bytes::Bytes;
+    use ::http_body::Frame;
+
+    // Frames of unknown total size, then possibly an error
+    struct Chunks(Vec<Result<&'static str, &'static str>>);
+
+    impl Body for Chunks {
+        type Data = Bytes;
+        type Error = BoxError;
+
+        fn poll_frame(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+            if self.0.is_empty() {
+                return Poll::Ready(None);
+            }
+            Poll::Ready(Some(match self.0.remove(0) {
+                Ok(data) => Ok(Frame::data(Bytes::from_static(data.as_bytes()))),
+                Err(err) => Err(err.into()),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_into_response() {
+        let response = http::Response::builder().status(201).body(Full::new(Bytes::from("full"))).unwrap().into_response();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.body(), b"full");
+        assert!(response.extensions().get::<BodyWriter>().is_none());
+        assert!(http::Response::new(Empty::<Bytes>::new()).into_response().body().is_empty());
+
+        let mut response = http::Response::new(Chunks(vec![Ok("one "), Ok("two")]).boxed()).into_response();
+        assert!(response.body().is_empty());
+        let mut out = Vec::new();
+        crate::stream::take_body_writer(&mut response).unwrap().write_to(&mut out).unwrap();
+        assert_eq!(out, b"one two");
+
+        let mut response = into_response(http::Response::new(Chunks(vec![Ok("partial"), Err("failed")])));
+        let mut out = Vec::new();
+        let err = crate::stream::take_body_writer(&mut response).unwrap().write_to(&mut out).unwrap_err();
+        assert_eq!((out.as_slice(), err.to_string().as_str()), (&b"partial"[..], "failed"));
+    }
+}
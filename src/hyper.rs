@@ -0,0 +1,262 @@
+//! Run the same handler as CGI or behind a hyper server. Requires the `hyper` feature.
+//!
+//! [`from_hyper`] and [`to_hyper`] convert between hyper's requests & responses and
+//! [`Request`]/[`Response`]. [`serve`] runs a handler as an HTTP server, and [`run`] decides at
+//! runtime: under a web server (when `REQUEST_METHOD` is set) it's CGI, otherwise it listens on
+//! an address.
+//!
+//! ```rust,no_run
+//! fn handler(request: cgi::Request) -> cgi::Response {
+//!     cgi::text_response(200, format!("Hello from {}", request.uri()))
+//! }
+//!
+//! fn main() {
+//!     cgi::hyper::run("127.0.0.1:3000", handler).unwrap();
+//! }
+//! ```
+//!
+//! Behind hyper, the request has the `X-CGI-` headers a web server would provide for the
+//! request line and client address, with the handler mounted at `/` (so `X-CGI-Script-Name` is
+//! empty). Like with CGI, its URI is absolute (from the `Host` header, or the local address),
+//! and it has a [`RequestId`](crate::meta::RequestId). Handlers are called on tokio's blocking
+//! thread pool, like [`handle`](crate::handle) calls them: `HEAD` requests, panics and the
+//! request ID are handled the same way, and the [`after_response`](crate::after_response)
+//! hooks run once the response is handed to hyper.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::hyper::body::{Body, Bytes, Incoming};
+use ::hyper::server::conn::http1;
+use ::hyper::service::service_fn;
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+
+use crate::cookie::Cookies;
+use crate::{call_handler, empty_response, reset_request_state, run_after_response, Handler, Request, Response};
+
+/// Collect the body of a hyper request, making a [`Request`] (with the [`Cookies`] extension,
+/// like [`handle`](crate::handle))
+pub async fn from_hyper<B: Body>(request: http::Request<B>) -> Result<Request, B::Error> {
+    let (parts, body) = request.into_parts();
+    let body = body.collect().await?.to_bytes();
+    let mut request = Request::from_parts(parts, body.to_vec());
+    let cookies = Cookies::from_headers(request.headers());
+    request.extensions_mut().insert(cookies);
+    Ok(request)
+}
+
+/// A hyper response with the body of `response` (written out first for a
+/// [`streaming_response`](crate::stream::streaming_response))
+pub fn to_hyper(mut response: Response) -> http::Response<Full<Bytes>> {
+    crate::stream::buffer_body(&mut response);
+    response.map(|body| Full::new(Bytes::from(body)))
+}
+
+// The `X-CGI-` headers which `parse_request` would add for these, replacing any the client sent
+fn add_cgi_headers(request: &mut Request, local: SocketAddr, remote: SocketAddr) {
+    // The meta-variables the URI & the request ID are made from, as in `parse_request`
+    let mut env_vars: HashMap<String, Vec<u8>> = HashMap::new();
+    env_vars.insert("SERVER_NAME".to_owned(), local.ip().to_string().into_bytes());
+    env_vars.insert("SERVER_PORT".to_owned(), local.port().to_string().into_bytes());
+    env_vars.insert("REQUEST_SCHEME".to_owned(), b"http".to_vec());
+    for (name, header) in [("HTTP_HOST", http::header::HOST.as_str()), ("HTTP_X_REQUEST_ID", "x-request-id")] {
+        if let Some(value) = request.headers().get(header) {
+            env_vars.insert(name.to_owned(), value.as_bytes().to_vec());
+        }
+    }
+    if request.uri().authority().is_none() {
+        let path_and_query = request.uri().path_and_query().map_or("/", |p| p.as_str());
+        let uri = crate::request_authority(&env_vars)
+            .and_then(|authority| format!("{}://{}{}", crate::request_scheme(&env_vars), authority, path_and_query).parse().ok());
+        if let Some(uri) = uri {
+            *request.uri_mut() = uri;
+        }
+    }
+    request.extensions_mut().insert(crate::meta::RequestId::from_vars(&env_vars));
+
+    let spoofed: Vec<http::HeaderName> = request.headers().keys().filter(|name| name.as_str().starts_with("x-cgi-")).cloned().collect();
+    for name in spoofed {
+        request.headers_mut().remove(name);
+    }
+    let mut vars = vec![
+        ("x-cgi-gateway-interface", "CGI/1.1".to_owned()),
+        ("x-cgi-request-method", request.method().to_string()),
+        ("x-cgi-script-name", String::new()),
+        ("x-cgi-path-info", request.uri().path().to_owned()),
+        ("x-cgi-query-string", request.uri().query().unwrap_or("").to_owned()),
+        ("x-cgi-server-protocol", format!("{:?}", request.version())),
+        ("x-cgi-server-name", local.ip().to_string()),
+        ("x-cgi-server-port", local.port().to_string()),
+        ("x-cgi-remote-addr", remote.ip().to_string()),
+        ("x-cgi-request-scheme", "http".to_owned()),
+    ];
+    if let Some(content_type) = request.headers().get(http::header::CONTENT_TYPE) {
+        vars.push(("x-cgi-content-type", String::from_utf8_lossy(content_type.as_bytes()).into_owned()));
+    }
+    vars.push(("x-cgi-content-length", request.body().len().to_string()));
+    for (name, value) in vars {
+        if let Ok(value) = value.parse() {
+            request.headers_mut().insert(name, value);
+        }
+    }
+    let remote = crate::meta::RemoteAddr(remote.ip().to_canonical(), Some(remote.port()));
+    request.extensions_mut().insert(remote);
+    let cgi_meta = crate::meta::CgiMeta {
+        content_length: Some(request.body().len() as u64),
+        content_type: request.headers().get(http::header::CONTENT_TYPE).map(|ct| String::from_utf8_lossy(ct.as_bytes()).into_owned()),
+        gateway_interface: Some("CGI/1.1".to_owned()),
+        path_info: request.uri().path().to_owned(),
+        query_string: request.uri().query().unwrap_or("").to_owned(),
+        remote_addr: Some(remote),
+        request_method: request.method().clone(),
+        request_scheme: Some("http".to_owned()),
+        server_name: local.ip().to_string(),
+        server_port: local.port(),
+        server_protocol: Some(request.version()),
+        ..Default::default()
+    };
+    request.extensions_mut().insert(cgi_meta);
+}
+
+// Call the handler on the blocking thread pool. The response is sent back before the
+// `after_response` hooks run, on the same thread as the handler which added them.
+async fn respond<H>(request: Request, handler: Arc<H>) -> Response
+    where H: Handler + Send + Sync + 'static
+{
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    tokio::task::spawn_blocking(move || {
+        // Threads of the blocking pool are reused
+        reset_request_state();
+        let mut response = catch_unwind(AssertUnwindSafe(|| call_handler(request, |request| handler.call(request)))).unwrap_or_else(|_| empty_response(500));
+        crate::stream::buffer_body(&mut response);
+        let _ = sender.send(response);
+        run_after_response();
+    });
+    receiver.await.unwrap_or_else(|_| empty_response(500))
+}
+
+/// Serve `handler` over HTTP/1 on `addr`, until the programme is stopped. Errors accepting a
+/// connection (e.g. too many open files) are printed to stderr, and accepting continues after
+/// a short pause.
+pub async fn serve<A, H>(addr: A, handler: H) -> io::Result<()>
+    where A: tokio::net::ToSocketAddrs,
+          H: Handler + Send + Sync + 'static
+{
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                eprintln!("Could not accept a connection: {}", err);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        let service = service_fn(move |request: http::Request<Incoming>| {
+            let handler = handler.clone();
+            async move {
+                let response = match from_hyper(request).await {
+                    Ok(mut request) => {
+                        add_cgi_headers(&mut request, local, remote);
+                        respond(request, handler).await
+                    }
+                    Err(_) => empty_response(400),
+                };
+                Ok::<_, Infallible>(to_hyper(response))
+            }
+        });
+        tokio::spawn(async move {
+            if let Err(err) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                eprintln!("Connection failed: {}", err);
+            }
+        });
+    }
+}
+
+/// Run `handler` as CGI if this programme was started by a web server, otherwise serve it
+/// with hyper on `addr`.
+pub fn run<A, H>(addr: A, handler: H) -> io::Result<()>
+    where A: ToSocketAddrs,
+          H: Handler + Send + Sync + 'static
+{
+    if std::env::var_os("REQUEST_METHOD").is_some() {
+        crate::serve_handler(handler);
+        return Ok(());
+    }
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(serve(&addrs[..], handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let hyper_request = http::Request::builder().method("POST").uri("/a?b=c")
+            .header("content-type", "text/plain")
+            .header("host", "example.com:3000")
+            .header("x-request-id", "abc-123")
+            .header("x-cgi-remote-user", "alice")
+            .body(Full::new(Bytes::from_static(b"hello"))).unwrap();
+        let mut request = runtime.block_on(from_hyper(hyper_request)).unwrap();
+        assert_eq!(request.body(), b"hello");
+
+        add_cgi_headers(&mut request, "127.0.0.1:3000".parse().unwrap(), "10.0.0.2:4444".parse().unwrap());
+        assert_eq!(request.uri(), "http://example.com:3000/a?b=c");
+        assert_eq!(request.extensions().get::<crate::meta::RequestId>().unwrap().as_str(), "abc-123");
+        assert_eq!(request.headers()["x-cgi-path-info"], "/a");
+        assert_eq!(request.headers()["x-cgi-query-string"], "b=c");
+        assert_eq!(request.headers()["x-cgi-remote-addr"], "10.0.0.2");
+        assert!(!request.headers().contains_key("x-cgi-remote-user"));
+        assert_eq!(request.extensions().get::<crate::meta::RemoteAddr>().unwrap().to_string(), "10.0.0.2:4444");
+        assert_eq!(request.extensions().get::<crate::meta::CgiMeta>().unwrap().server_port, 3000);
+        assert_eq!(request.headers()["x-cgi-content-length"], "5");
+        assert_eq!(request.headers()["x-cgi-server-protocol"], "HTTP/1.1");
+
+        // Without a `Host`, the local address
+        let mut request = http::Request::builder().uri("/x").body(vec![]).unwrap();
+        add_cgi_headers(&mut request, "127.0.0.1:3000".parse().unwrap(), "10.0.0.2:4444".parse().unwrap());
+        assert_eq!(request.uri(), "http://127.0.0.1:3000/x");
+        assert_eq!(request.extensions().get::<crate::meta::RequestId>().unwrap().as_str().len(), 16);
+
+        let response = to_hyper(crate::stream::streaming_response(201, "text/plain", |out| out.write_all(b"streamed")));
+        assert_eq!(response.status(), 201);
+        let body = runtime.block_on(response.into_body().collect()).unwrap().to_bytes();
+        assert_eq!(&body[..], b"streamed");
+    }
+
+    #[test]
+    fn test_respond() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let hook_ran = Arc::new(AtomicBool::new(false));
+        let handler = {
+            let hook_ran = hook_ran.clone();
+            move |_: Request| {
+                let hook_ran = hook_ran.clone();
+                crate::after_response(move || hook_ran.store(true, Ordering::SeqCst));
+                crate::text_response(200, "body")
+            }
+        };
+        let request = http::Request::builder().method("HEAD").body(vec![]).unwrap();
+        let response = runtime.block_on(respond(request, Arc::new(handler)));
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers()["content-length"], "4");
+        // Shutting down waits for the blocking thread, which runs the hook
+        drop(runtime);
+        assert!(hook_ran.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,202 @@
+//! Safe retries of unsafe requests with an `Idempotency-Key` header. Requires the
+//! `idempotency` feature.
+//!
+//! When a client doesn't get the response to a `POST` (a timeout, a dropped connection), it
+//! can't know whether the payment was made or the order placed. With an `Idempotency-Key`,
+//! it can simply send the request again: [`Idempotency`] stores the response to the first
+//! request, and replays it for retries with the same key, instead of calling the handler
+//! again.
+//!
+//! ```rust,no_run
+//! use cgi::idempotency::Idempotency;
+//! use cgi::store::FileStore;
+//!
+//! fn main() {
+//!     let idempotency = Idempotency::new(FileStore::new("/tmp/my-cgi-store"));
+//!     cgi::handle(|request: cgi::Request| idempotency.handle(request, |request| {
+//!         cgi::text_response(201, "Order placed")
+//!     }))
+//! }
+//! ```
+//!
+//! Keys are chosen by the client, so they're only unique per client: only requests with a
+//! `REMOTE_USER` (authenticated by the web server) are handled this way, with the keys of each
+//! user kept apart. Anonymous requests are passed to the handler as they are.
+//!
+//! Reusing a key for a different request (another method, URI or body) is answered with
+//! `409 Conflict`, as is a retry while the first request is still being handled. Server errors
+//! (`5xx`) aren't stored, so they can be retried. A streamed body is written into the response
+//! first, to be stored with it.
+
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::meta::CgiMeta;
+use crate::store::FileStore;
+use crate::{client, clone_response, serialize_response, text_response, Request, Response};
+
+/// Stores & replays responses by `Idempotency-Key`. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Idempotency {
+    store: FileStore,
+    ttl: Duration,
+}
+
+impl Idempotency {
+    /// Responses are kept for 24 hours by default
+    pub fn new(store: FileStore) -> Self {
+        Idempotency { store, ttl: Duration::from_secs(24 * 60 * 60) }
+    }
+
+    /// How long a response is replayed for
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Handle a request with an `Idempotency-Key`, an unsafe method (e.g. `POST`) and a
+    /// `REMOTE_USER` once, and replay the stored response for retries. Other requests are
+    /// passed to `next`.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        let key = match request.headers().get("idempotency-key").and_then(|v| v.to_str().ok()) {
+            Some(key) if !request.method().is_safe() => key,
+            _ => return next(request),
+        };
+        if key.is_empty() || key.len() > 255 {
+            return text_response(400, "Invalid Idempotency-Key");
+        }
+        // Keys are only unique per client. `REMOTE_USER`, not a header the client could send.
+        let user = match request.extensions().get::<CgiMeta>().and_then(|meta| meta.remote_user.as_deref()) {
+            Some(user) if !user.is_empty() => user,
+            _ => return next(request),
+        };
+        let store_key = format!("idempotency {} {}", user, key);
+        let fingerprint = fingerprint(&request);
+
+        let _lock = match self.store.try_lock(&format!("{} lock", store_key), Some(self.ttl)) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => return text_response(409, "A request with this Idempotency-Key is still being processed"),
+            Err(err) => {
+                eprintln!("Could not lock idempotency key: {}", err);
+                return text_response(503, "Service Unavailable");
+            }
+        };
+
+        match self.store.get(&store_key) {
+            Ok(Some(stored)) => {
+                let (stored_fingerprint, output) = match stored.iter().position(|&b| b == b'\n') {
+                    Some(idx) => (&stored[..idx], &stored[idx + 1..]),
+                    None => (&stored[..], &[][..]),
+                };
+                if stored_fingerprint != fingerprint.as_bytes() {
+                    return text_response(409, "This Idempotency-Key was used for a different request");
+                }
+                match client::parse_output(output) {
+                    Ok(mut response) => {
+                        response.headers_mut().insert("idempotent-replayed", http::HeaderValue::from_static("true"));
+                        return response;
+                    }
+                    Err(err) => eprintln!("Could not parse stored response: {}", err),
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                eprintln!("Could not read idempotency key: {}", err);
+                return text_response(503, "Service Unavailable");
+            }
+        }
+
+        let mut response = next(request);
+        if !response.status().is_server_error() {
+            // The replay needs the whole body
+            crate::stream::buffer_body(&mut response);
+            let mut stored = fingerprint.into_bytes();
+            stored.push(b'\n');
+            stored.extend(serialize_response(clone_response(&response)));
+            if let Err(err) = self.store.set(&store_key, &stored, Some(self.ttl)) {
+                eprintln!("Could not store response for idempotency key: {}", err);
+            }
+        }
+        response
+    }
+}
+
+// Which request a key was used for
+fn fingerprint(request: &Request) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{} {}\n", request.method(), request.uri()));
+    hasher.update(request.body());
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_idempotency() {
+        let dir = std::env::temp_dir().join(format!("cgi-idempotency-{}", std::process::id()));
+        let idempotency = Idempotency::new(FileStore::new(&dir));
+        let request = |key: &str, body: &str| {
+            let mut request = http::Request::builder()
+                .method("POST")
+                .uri("/orders")
+                .header("idempotency-key", key)
+                .body(body.as_bytes().to_vec())
+                .unwrap();
+            request.extensions_mut().insert(CgiMeta { remote_user: Some("alice".to_owned()), ..CgiMeta::default() });
+            request
+        };
+        let calls = Cell::new(0);
+        let handler = |_| {
+            calls.set(calls.get() + 1);
+            text_response(201, format!("order {}", calls.get()))
+        };
+
+        let first = idempotency.handle(request("abc", "item=1"), handler);
+        assert_eq!(first.status(), 201);
+        let retry = idempotency.handle(request("abc", "item=1"), handler);
+        assert_eq!(retry.status(), 201);
+        assert_eq!(retry.body(), b"order 1");
+        assert_eq!(retry.headers()["idempotent-replayed"], "true");
+        assert_eq!(calls.get(), 1);
+
+        assert_eq!(idempotency.handle(request("abc", "item=2"), handler).status(), 409);
+        assert_eq!(idempotency.handle(request("def", "item=2"), handler).body(), b"order 2");
+
+        // In progress in another process
+        let _lock = idempotency.store.try_lock("idempotency alice xyz lock", None).unwrap().unwrap();
+        assert_eq!(idempotency.handle(request("xyz", ""), handler).status(), 409);
+
+        // Keys of other users, not of a spoofed `X-CGI-Remote-User`
+        let as_user = |user: &str, spoofed: &str| {
+            let mut request = request("abc", "item=1");
+            request.headers_mut().insert("x-cgi-remote-user", spoofed.parse().unwrap());
+            request.extensions_mut().insert(CgiMeta { remote_user: Some(user.to_owned()), ..CgiMeta::default() });
+            request
+        };
+        assert_eq!(idempotency.handle(as_user("bob", ""), handler).body(), b"order 3");
+        assert_eq!(idempotency.handle(as_user("mallory", "bob"), handler).body(), b"order 4");
+        assert_eq!(idempotency.handle(as_user("bob", "mallory"), handler).body(), b"order 3");
+
+        // A streamed body is stored too
+        let streamed = |_| crate::stream::streaming_response(201, "text/plain", |out| out.write_all(b"streamed"));
+        assert_eq!(idempotency.handle(request("stream", ""), streamed).body(), b"streamed");
+        assert_eq!(idempotency.handle(request("stream", ""), handler).body(), b"streamed");
+
+        // Anonymous requests aren't replayed
+        let mut anonymous = request("abc", "item=1");
+        anonymous.extensions_mut().remove::<CgiMeta>();
+        assert_eq!(idempotency.handle(anonymous, handler).body(), b"order 5");
+
+        // Server errors aren't stored
+        idempotency.handle(request("err", ""), |_| crate::empty_response(500));
+        assert_eq!(idempotency.handle(request("err", ""), handler).status(), 201);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
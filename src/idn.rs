@@ -0,0 +1,376 @@
+//! Internationalised domain names (IDNA) and punycode (RFC 3492).
+//!
+//! HTTP headers such as `Location` may only contain ASCII, so a hostname like `bücher.example`
+//! has to be sent as `xn--bcher-kva.example`. [`to_ascii`] and [`to_unicode`] convert between
+//! the two forms, and [`to_ascii_url`] does the same for a whole URL (percent-encoding any
+//! other non-ASCII characters), so the result can always be used as a header value.
+//!
+//! Only lowercasing is applied before encoding, not the full UTS #46 mapping table.
+//!
+//! ```rust
+//! assert_eq!(cgi::idn::to_ascii("Bücher.example").unwrap(), "xn--bcher-kva.example");
+//! assert_eq!(cgi::idn::to_unicode("xn--bcher-kva.example"), "bücher.example");
+//! ```
+
+use std::fmt;
+
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+const ACE_PREFIX: &str = "xn--";
+
+/// Why a hostname or URL could not be converted to ASCII.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdnError {
+    /// A label (the part between two dots) was empty
+    EmptyLabel,
+    /// A label was longer than 63 bytes once encoded
+    LabelTooLong(String),
+    /// The whole hostname was longer than 253 bytes once encoded
+    HostTooLong,
+    /// The punycode encoding overflowed
+    Overflow,
+    /// A label mixes letters from scripts which are easily confused (e.g. Latin and Cyrillic)
+    MixedScript(String),
+}
+
+impl fmt::Display for IdnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdnError::EmptyLabel => write!(f, "hostname contains an empty label"),
+            IdnError::LabelTooLong(label) => write!(f, "hostname label {:?} is too long", label),
+            IdnError::HostTooLong => write!(f, "hostname is too long"),
+            IdnError::Overflow => write!(f, "punycode overflow"),
+            IdnError::MixedScript(label) => write!(f, "hostname label {:?} mixes scripts", label),
+        }
+    }
+}
+
+impl std::error::Error for IdnError {}
+
+/// Options for [`to_ascii_with`].
+#[derive(Debug, Clone, Default)]
+pub struct IdnOptions {
+    /// Reject labels mixing Latin, Greek and Cyrillic letters, a common trick to make a
+    /// lookalike of a well known domain (e.g. a Cyrillic `а` in `pаypal`).
+    pub reject_mixed_script: bool,
+}
+
+/// Convert a hostname to its ASCII (punycode) form, e.g. `bücher.example` →
+/// `xn--bcher-kva.example`. ASCII hostnames are only lowercased.
+pub fn to_ascii(host: &str) -> Result<String, IdnError> {
+    to_ascii_with(host, &IdnOptions::default())
+}
+
+/// Like [`to_ascii`], with [`IdnOptions`].
+pub fn to_ascii_with(host: &str, options: &IdnOptions) -> Result<String, IdnError> {
+    // IPv6 literals are passed through as they are
+    if host.starts_with('[') {
+        return Ok(host.to_owned());
+    }
+
+    let (host, trailing_dot) = match host.strip_suffix('.') {
+        Some(h) if !h.is_empty() => (h, true),
+        _ => (host, false),
+    };
+
+    let mut labels = Vec::new();
+    for label in host.split('.') {
+        if label.is_empty() {
+            return Err(IdnError::EmptyLabel);
+        }
+        let label = label.to_lowercase();
+        let encoded = if label.is_ascii() {
+            label
+        } else {
+            if options.reject_mixed_script && is_mixed_script(&label) {
+                return Err(IdnError::MixedScript(label));
+            }
+            format!("{}{}", ACE_PREFIX, encode(&label).ok_or(IdnError::Overflow)?)
+        };
+        if encoded.len() > 63 {
+            return Err(IdnError::LabelTooLong(encoded));
+        }
+        labels.push(encoded);
+    }
+
+    let mut result = labels.join(".");
+    if result.len() > 253 {
+        return Err(IdnError::HostTooLong);
+    }
+    if trailing_dot {
+        result.push('.');
+    }
+    Ok(result)
+}
+
+/// Convert a hostname to its Unicode form for display, e.g. `xn--bcher-kva.example` →
+/// `bücher.example`. Labels which aren't valid punycode are left as they are.
+pub fn to_unicode(host: &str) -> String {
+    host.split('.')
+        .map(|label| {
+            let lower = label.to_ascii_lowercase();
+            lower.strip_prefix(ACE_PREFIX)
+                .and_then(decode)
+                .unwrap_or(lower)
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Make a URL (absolute, or a relative reference) safe to put in a header: the host is
+/// converted with [`to_ascii`] and any other non-ASCII characters are percent-encoded as
+/// UTF-8.
+///
+/// ```rust
+/// assert_eq!(cgi::idn::to_ascii_url("https://bücher.example:8080/straße?q=ü").unwrap(),
+///            "https://xn--bcher-kva.example:8080/stra%C3%9Fe?q=%C3%BC");
+/// ```
+pub fn to_ascii_url(url: &str) -> Result<String, IdnError> {
+    to_ascii_url_with(url, &IdnOptions::default())
+}
+
+/// Like [`to_ascii_url`], with [`IdnOptions`].
+pub fn to_ascii_url_with(url: &str, options: &IdnOptions) -> Result<String, IdnError> {
+    let (prefix, rest) = match url.find("//") {
+        Some(idx) if url[..idx].chars().all(|c| c.is_ascii_alphanumeric() || "+-.:".contains(c)) => url.split_at(idx + 2),
+        _ => return Ok(percent_encode_non_ascii(url)),
+    };
+
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+
+    let (userinfo, hostport) = match authority.rfind('@') {
+        Some(idx) => authority.split_at(idx + 1),
+        None => ("", authority),
+    };
+
+    let (host, port) = if hostport.starts_with('[') {
+        match hostport.find(']') {
+            Some(idx) => hostport.split_at(idx + 1),
+            None => (hostport, ""),
+        }
+    } else {
+        match hostport.rfind(':') {
+            Some(idx) => hostport.split_at(idx),
+            None => (hostport, ""),
+        }
+    };
+
+    let host = if host.is_empty() { String::new() } else { to_ascii_with(host, options)? };
+
+    Ok(format!("{}{}{}{}{}", prefix, percent_encode_non_ascii(userinfo), host, port, percent_encode_non_ascii(path)))
+}
+
+fn percent_encode_non_ascii(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii() {
+            result.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                result.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    result
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Script {
+    Common,
+    Latin,
+    Greek,
+    Cyrillic,
+    Other,
+}
+
+fn script(c: char) -> Script {
+    match c as u32 {
+        0x30..=0x39 | 0x2D => Script::Common,
+        0x41..=0x5A | 0x61..=0x7A | 0xC0..=0x24F | 0x1E00..=0x1EFF => Script::Latin,
+        0x370..=0x3FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x400..=0x52F => Script::Cyrillic,
+        _ => Script::Other,
+    }
+}
+
+fn is_mixed_script(label: &str) -> bool {
+    let mut seen = None;
+    for s in label.chars().map(script) {
+        if !matches!(s, Script::Latin | Script::Greek | Script::Cyrillic) {
+            continue;
+        }
+        match seen {
+            None => seen = Some(s),
+            Some(prev) if prev != s => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn adapt(mut delta: u32, num_points: u32, first_time: bool) -> u32 {
+    delta /= if first_time { DAMP } else { 2 };
+    delta += delta / num_points;
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+    k + (((BASE - T_MIN + 1) * delta) / (delta + SKEW))
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        T_MIN
+    } else if k >= bias + T_MAX {
+        T_MAX
+    } else {
+        k - bias
+    }
+}
+
+fn encode_digit(d: u32) -> char {
+    (if d < 26 { b'a' + d as u8 } else { b'0' + (d - 26) as u8 }) as char
+}
+
+fn decode_digit(c: char) -> Option<u32> {
+    match c {
+        'a'..='z' => Some(c as u32 - 'a' as u32),
+        'A'..='Z' => Some(c as u32 - 'A' as u32),
+        '0'..='9' => Some(c as u32 - '0' as u32 + 26),
+        _ => None,
+    }
+}
+
+/// Encode a single label with punycode (without the `xn--` prefix). `None` on overflow.
+pub fn encode(input: &str) -> Option<String> {
+    let input: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.iter().filter(|&&c| c < 0x80).map(|&c| c as u8 as char).collect();
+    let basic_len = output.len() as u32;
+    let mut handled = basic_len;
+    if basic_len > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+
+    while (handled as usize) < input.len() {
+        let m = *input.iter().filter(|&&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &c in &input {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic_len);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+
+    Some(output)
+}
+
+/// Decode a single punycode label (without the `xn--` prefix). `None` if it's invalid.
+pub fn decode(input: &str) -> Option<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(idx) => (&input[..idx], &input[idx + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+
+    let mut output: Vec<char> = basic.chars().collect();
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut chars = extended.chars().peekable();
+
+    while chars.peek().is_some() {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = decode_digit(chars.next()?)?;
+            i = i.checked_add(digit.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+
+    Some(output.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_punycode() {
+        assert_eq!(encode("bücher").unwrap(), "bcher-kva");
+        assert_eq!(encode("münchen").unwrap(), "mnchen-3ya");
+        assert_eq!(encode("例え").unwrap(), "r8jz45g");
+        assert_eq!(decode("bcher-kva").unwrap(), "bücher");
+        assert_eq!(decode("r8jz45g").unwrap(), "例え");
+        assert_eq!(decode("ab-!"), None);
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(to_ascii("www.Bücher.example.").unwrap(), "www.xn--bcher-kva.example.");
+        assert_eq!(to_ascii("EXAMPLE.com").unwrap(), "example.com");
+        assert_eq!(to_ascii("[::1]").unwrap(), "[::1]");
+        assert_eq!(to_ascii("a..b"), Err(IdnError::EmptyLabel));
+        assert_eq!(to_unicode("www.XN--bcher-kva.example"), "www.bücher.example");
+
+        let strict = IdnOptions { reject_mixed_script: true };
+        // Cyrillic 'а' in an otherwise Latin label
+        assert!(matches!(to_ascii_with("p\u{430}ypal.com", &strict), Err(IdnError::MixedScript(_))));
+        assert!(to_ascii_with("p\u{430}ypal.com", &IdnOptions::default()).is_ok());
+        assert!(to_ascii_with("bücher.example", &strict).is_ok());
+    }
+
+    #[test]
+    fn test_url() {
+        assert_eq!(to_ascii_url("http://user@bücher.example/").unwrap(), "http://user@xn--bcher-kva.example/");
+        assert_eq!(to_ascii_url("http://[::1]:80/ä").unwrap(), "http://[::1]:80/%C3%A4");
+        assert_eq!(to_ascii_url("/relative/ä").unwrap(), "/relative/%C3%A4");
+    }
+}
@@ -0,0 +1,95 @@
+//! Conversions of handler return values into responses.
+
+use crate::{binary_response, empty_response, text_response, Response};
+
+/// A value a handler can return. [`handle`](crate::handle) and the other entry points accept
+/// handlers returning any of these.
+///
+/// ```rust,no_run
+/// use http::StatusCode;
+///
+/// fn find(id: &str) -> Result<String, (StatusCode, &'static str)> {
+///     match id {
+///         "1" => Ok("The first item".to_owned()),
+///         _ => Err((StatusCode::NOT_FOUND, "No such item")),
+///     }
+/// }
+///
+/// cgi::handle(|request: cgi::Request| find(request.uri().query().unwrap_or("")))
+/// ```
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+/// `200 OK` with the text as `text/plain`
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        text_response(200, self)
+    }
+}
+
+/// `200 OK` with the text as `text/plain`
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        text_response(200, self)
+    }
+}
+
+/// `200 OK` with the bytes as `application/octet-stream`
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        binary_response(200, "application/octet-stream", self)
+    }
+}
+
+/// The status, without a body
+impl IntoResponse for http::StatusCode {
+    fn into_response(self) -> Response {
+        empty_response(self)
+    }
+}
+
+/// The response of the second element, with the status replaced
+impl<T: IntoResponse> IntoResponse for (http::StatusCode, T) {
+    fn into_response(self) -> Response {
+        let mut response = self.1.into_response();
+        *response.status_mut() = self.0;
+        response
+    }
+}
+
+/// Either response
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    #[test]
+    fn test_into_response() {
+        let response = "Hello".into_response();
+        assert_eq!((response.status(), response.body().as_slice()), (StatusCode::OK, b"Hello".as_slice()));
+        assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+        assert_eq!(vec![1, 2].into_response().headers()["content-type"], "application/octet-stream");
+        assert_eq!(StatusCode::NO_CONTENT.into_response().status(), 204);
+
+        let result: Result<String, (StatusCode, String)> = Err((StatusCode::CONFLICT, "Taken".into()));
+        let response = result.into_response();
+        assert_eq!((response.status(), response.body().as_slice()), (StatusCode::CONFLICT, b"Taken".as_slice()));
+        assert_eq!(Ok::<_, StatusCode>(empty_response(201)).into_response().status(), 201);
+    }
+}
@@ -32,10 +32,68 @@
 //! }
 //! ```
 //!
+//! To answer errors yourself, e.g. with an error page or another status, name a function
+//! turning the error into a response with `on_error`:
+//!
+//! ```rust,no_run
+//! #[cgi::main(on_error = error_page)]
+//! fn main(request: cgi::Request) -> Result<cgi::Response, std::io::Error> {
+//!     let greeting = std::fs::read_to_string("greeting.txt")?;
+//!     Ok(cgi::text_response(200, greeting))
+//! }
+//!
+//! fn error_page(err: std::io::Error) -> cgi::Response {
+//!     let status = if err.kind() == std::io::ErrorKind::NotFound { 404 } else { 500 };
+//!     cgi::html_response(status, "<h1>Sorry, something went wrong</h1>")
+//! }
+//! ```
+//!
+//! Other return types which implement [`IntoResponse`] work too, e.g. a plain `String`:
+//!
+//! ```rust,no_run
+//! #[cgi::main]
+//! fn main(request: cgi::Request) -> String {
+//!     format!("You asked for {}", request.uri())
+//! }
+//! ```
+//!
+//! Setup which the handler needs, e.g. the configuration, can be done by a function named with
+//! `state`. `main` gets a reference to its result (see [`handle_with_state`]):
+//!
+//! ```rust,no_run
+//! struct Config {
+//!     greeting: String,
+//! }
+//!
+//! fn load_config() -> Config {
+//!     Config { greeting: std::env::var("GREETING").unwrap_or_else(|_| "Hello".into()) }
+//! }
+//!
+//! #[cgi::main(state = load_config)]
+//! fn main(config: &Config, request: cgi::Request) -> String {
+//!     format!("{} {}", config.greeting, request.uri().path())
+//! }
+//! ```
+//!
 //! It will parse & extract the CGI environmental variables and the HTTP request body to create
-//! an `Request`, call your function to create a response, and convert your `Response` into the
-//! correct format and print to stdout. If this programme is not called as CGI (e.g. missing
-//! required environmental variables), it will panic.
+//! an `Request` (with an absolute URI like `https://example.com/cgi-bin/app/path?query`, the
+//! scheme from `HTTPS`, `REQUEST_SCHEME` or `SERVER_PORT` and the authority from the `Host`
+//! header or `SERVER_NAME`), call your function to create a response, and convert your
+//! `Response` into the correct format and print to stdout. If this programme is not called as
+//! CGI (e.g. missing required environmental variables), it will panic.
+//!
+//! With the `tokio` feature, `main` can also be an `async fn`. It's run on a single-threaded
+//! tokio runtime, so async database & HTTP clients can be used directly. With the `pollster`
+//! feature instead, it's run by a minimal executor, for async code which doesn't need tokio's
+//! I/O or timers.
+//!
+//! ```rust,ignore
+//! #[cgi::main]
+//! async fn main(request: cgi::Request) -> cgi::Response {
+//!     let greeting = fetch_greeting().await;
+//!     cgi::text_response(200, greeting)
+//! }
+//! ```
 //!
 //! It is also possible to call the `cgi::handle` function directly inside your `main` function:
 //!
@@ -48,13 +106,157 @@
 //! ```
 //!
 //! Several shortcut functions are provided (such as [`html_response`]/[`binary_response`]).
+//!
+//! # NPH scripts
+//!
+//! A script whose name starts with `nph-` (non-parsed headers) talks HTTP to the client
+//! directly, without the web server checking its output. Responses are then written with an
+//! HTTP status line (`HTTP/1.1 200 OK`), `\r\n` line endings and a `Date` header. Such a
+//! script also answers `Expect: 100-continue` with an interim `100 Continue` before the body is
+//! read; with [`handle_streaming`], only if the handler starts to read it, so it can reject a
+//! large upload with `413` or `417` first. A [streamed](stream) response body without a
+//! `Content-Length` is sent with `Transfer-Encoding: chunked` to HTTP/1.1 clients.
+//!
+//! # Windows
+//!
+//! Responses are written byte for byte, also on Windows: stdin & stdout are put into binary
+//! mode, so the C runtime doesn't translate line endings (e.g. for C libraries sharing them).
+//! Meta-variable names are matched case-insensitively, and the script name which IIS adds to
+//! `PATH_INFO` is removed again.
+//!
+//! # Optional features
+//!
+//! * `brotli`: `cgi::compress` with brotli, which compresses HTML & text better than gzip
+//! * `bytes`: `cgi::bytes`, requests & responses with `Bytes` bodies, sliced without copying
+//! * `clamd`: `cgi::scan::Clamd`, a ClamAV client for scanning uploads
+//! * `digest`: `cgi::digest`, verify `Content-Digest`/`Digest`/`Content-MD5` request headers
+//! * `digest-auth`: `cgi::digest_auth`, HTTP Digest authentication (RFC 7616)
+//! * `dotenv`: `cgi::dotenv`, load environment variables from a `.env` file before the request
+//! * `fastcgi`: `cgi::fastcgi`, run the same handler as a persistent FastCGI worker
+//! * `gzip`: `cgi::compress`, gzip response bodies for clients which accept it
+//! * `http-body`: `cgi::http_body`, responses with `http_body::Body` bodies such as `Full` &
+//!   `StreamBody`
+//! * `hyper`: `cgi::hyper`, conversions to & from hyper types, and running a handler as CGI or
+//!   with hyper
+//! * `idempotency`: `cgi::idempotency`, replay the responses to retried requests with an
+//!   `Idempotency-Key`
+//! * `json`: `RequestExt::json` to deserialize JSON request bodies, and `json_response`
+//! * `jwt`: `cgi::jwt`, verify JSON Web Tokens signed with HS256 or RS256
+//! * `logging`: `cgi::logging`, a stderr logger for the `log` crate, with the script name &
+//!   request ID
+//! * `mail`: `cgi::mail`, send email via `sendmail` or SMTP, safe against header injection
+//! * `mmap`: memory-map the files of `file_response`, `serve_file` & `serve_dir` (on Unix)
+//! * `normalize`: `cgi::normalize`, NFC normalization of query, form & path values
+//! * `pollster`: `cgi::handle_async` and `#[cgi::main]` on an `async fn main` without tokio
+//! * `secure-cookies`: `cgi::secure_cookie`, signed & encrypted cookies keyed from a secret
+//! * `serde`: `RequestExt::query`, deserialize the query string into a struct, and
+//!   `cgi::config` for typed configuration from environment variables
+//! * `signatures`: `cgi::signatures`, HTTP Message Signatures (RFC 9421)
+//! * `spam`: `cgi::spam`, honeypot, timing token & throttling checks for forms
+//! * `statsd`: `cgi::metrics::Statsd`, send the metrics of requests to a StatsD server
+//! * `tokio`: `cgi::handle_async` and `#[cgi::main]` on an `async fn main`
+//! * `tracing`: a `tracing` span per request with the method, path, client address & status,
+//!   and an event with the latency when it's answered
+//! * `tus`: `cgi::tus`, resumable uploads with the tus protocol
+//! * `zstd`: `cgi::compress` with Zstandard
 
 
 use std::io::{Read, Write, stdin};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
 
 pub extern crate http;
+// So that the code generated by `#[cgi::test]` also works in this crate's tests
+#[cfg(test)]
+extern crate self as cgi;
+
+pub mod access;
+mod base64;
+#[cfg(feature = "bytes")]
+pub mod bytes;
+pub mod cache;
+pub mod client;
+#[cfg(any(feature = "brotli", feature = "gzip", feature = "zstd"))]
+pub mod compress;
+pub mod conditional;
+#[cfg(feature = "serde")]
+pub mod config;
+pub mod cookie;
+mod date;
+pub mod dev_server;
+#[cfg(feature = "digest")]
+pub mod digest;
+#[cfg(feature = "digest-auth")]
+pub mod digest_auth;
+#[cfg(feature = "dotenv")]
+pub mod dotenv;
+mod error;
+mod ext;
+pub mod extract;
+#[cfg(feature = "fastcgi")]
+pub mod fastcgi;
+pub mod files;
+pub mod flags;
+#[cfg(feature = "http-body")]
+pub mod http_body;
+#[cfg(feature = "hyper")]
+pub mod hyper;
+#[cfg(feature = "idempotency")]
+pub mod idempotency;
+pub mod idn;
+mod handler;
+mod into_response;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod limit;
+pub mod link;
+#[cfg(feature = "logging")]
+pub mod logging;
+#[cfg(feature = "mail")]
+pub mod mail;
+pub mod meta;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+pub mod multipart;
+pub mod negotiate;
+#[cfg(feature = "normalize")]
+pub mod normalize;
+pub mod prefer;
+pub mod progress;
+pub mod proxy;
+mod random;
+pub mod range;
+#[cfg(feature = "tracing")]
+mod request_span;
+pub mod reporting;
+pub mod router;
+pub mod scan;
+pub mod scgi;
+#[cfg(feature = "secure-cookies")]
+pub mod secure_cookie;
+pub mod server_timing;
+pub mod session;
+pub mod shadow;
+pub mod single_flight;
+#[cfg(feature = "signatures")]
+pub mod signatures;
+#[cfg(feature = "spam")]
+pub mod spam;
+pub mod sse;
+pub mod store;
+pub mod stream;
+pub mod structured;
+pub mod testing;
+pub mod throttle;
+#[cfg(feature = "tus")]
+pub mod tus;
+pub mod upstream;
+mod urlencoded;
 
 /// A `Vec<u8>` Request from http
 pub type Request = http::Request<Vec<u8>>;
@@ -69,30 +271,805 @@ pub type Response = http::Response<Vec<u8>>;
 /// to create `Request`, and convert your `Response` into the correct format and
 /// print to stdout. If this programme is not called as CGI (e.g. missing required
 /// environmental variables), it will panic.
-pub fn handle<F>(func: F)
-    where F: FnOnce(Request) -> Response
+///
+/// The function can return anything which implements [`IntoResponse`], e.g. a `String` or a
+/// `Result` of responses. For a struct implementing [`Handler`], use [`serve_handler`].
+pub fn handle<F, R>(func: F)
+    where F: FnOnce(Request) -> R,
+          R: IntoResponse
 {
-    let env_vars: HashMap<String, String> = std::env::vars().collect();
+    handle_with_options(&Options::new(), |_, _| {}, |request| func(request).into_response())
+}
 
-    // How many bytes do we have to read for request body
-    // A general stdin().read_to_end() can block if the webserver doesn't close things
-    let content_length: usize = env_vars.get("CONTENT_LENGTH")
+/// Like [`handle`], with a [`Handler`], e.g. a struct which is set up once with the
+/// configuration and a database connection
+pub fn serve_handler<H: Handler>(handler: H) {
+    handle_with_options(&Options::new(), |_, _| {}, |request| handler.call(request))
+}
+
+/// Like [`handle`], calling `func` through `middlewares`, the first one outermost. See
+/// [`middleware`] for an example.
+pub fn handle_with<F, R>(middlewares: &[&dyn middleware::Middleware], func: F)
+    where F: FnOnce(Request) -> R,
+          R: IntoResponse
+{
+    handle_with_options(&Options::new(), |_, _| {}, |request| middleware::apply(middlewares, request, |request| func(request).into_response()))
+}
+
+/// Like [`handle`], passing `state` to the handler along with the request. Expensive setup
+/// (parsing the configuration, loading templates...) happens once before, and is handed over
+/// explicitly instead of in globals. With the macro, it's `#[cgi::main(state = function)]`.
+///
+/// ```rust,no_run
+/// struct Config {
+///     greeting: String,
+/// }
+///
+/// let config = Config { greeting: std::fs::read_to_string("greeting.txt").unwrap() };
+/// cgi::handle_with_state(config, |config: &Config, request: cgi::Request| {
+///     format!("{} {}", config.greeting, request.uri().path())
+/// })
+/// ```
+pub fn handle_with_state<S, F, R>(state: S, func: F)
+    where F: FnOnce(&S, Request) -> R,
+          R: IntoResponse
+{
+    handle_with_options(&Options::new(), |_, _| {}, |request| func(&state, request))
+}
+
+/// Like [`handle`], with an async function. With the `tokio` feature, it's run to completion
+/// on a single-threaded tokio runtime, with all drivers (I/O, timers) that the enabled tokio
+/// features provide. Otherwise, with the `pollster` feature, it's run by pollster, which only
+/// suits futures that don't need a runtime.
+#[cfg(any(feature = "tokio", feature = "pollster"))]
+pub fn handle_async<F, Fut>(func: F)
+    where F: FnOnce(Request) -> Fut,
+          Fut: std::future::Future,
+          Fut::Output: IntoResponse
+{
+    handle_with_options(&Options::new(), |_, _| {}, |request| block_on(func(request)))
+}
+
+#[cfg(feature = "tokio")]
+fn block_on<Fut: std::future::Future>(future: Fut) -> Fut::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("could not start the tokio runtime")
+        .block_on(future)
+}
+
+#[cfg(all(feature = "pollster", not(feature = "tokio")))]
+fn block_on<Fut: std::future::Future>(future: Fut) -> Fut::Output {
+    pollster::block_on(future)
+}
+
+/// Like [`handle`], but errors are returned instead of panicking: a missing or invalid CGI
+/// meta-variable (e.g. when not started by a web server), a request body shorter than
+/// `CONTENT_LENGTH`, or a failure to write the response.
+///
+/// ```rust,no_run
+/// if let Err(err) = cgi::try_handle(|_request: cgi::Request| "Hello World") {
+///     eprintln!("{}", err);
+///     std::process::exit(1);
+/// }
+/// ```
+pub fn try_handle<F, R>(func: F) -> Result<(), Error>
+    where F: FnOnce(Request) -> R,
+          R: IntoResponse
+{
+    set_binary_mode();
+    let env_vars = env_vars();
+    let content_length = match env_vars.get("CONTENT_LENGTH") {
+        Some(cl) if !cl.is_empty() => std::str::from_utf8(cl).ok().and_then(|cl| cl.parse::<usize>().ok())
+            .ok_or_else(|| Error::InvalidVar { name: "CONTENT_LENGTH".into(), value: String::from_utf8_lossy(cl).into_owned() })?,
+        _ => 0,
+    };
+
+    let nph = nph_protocol(&env_vars);
+    if nph.is_some() && content_length > 0 && expects_continue(&env_vars) {
+        send_continue()?;
+    }
+    let stdin_contents = read_body(stdin(), content_length, read_timeout(), |_, _| {})?;
+    let request = try_parse_request(env_vars, stdin_contents)?;
+
+    let mut response = call_handler(request, |request| func(request).into_response());
+    if let Some(protocol) = nph {
+        response.extensions_mut().insert(Nph(protocol));
+    }
+    try_write_response(response)?;
+    Ok(())
+}
+
+/// A request whose body is read from stdin while the handler runs, see [`handle_streaming`].
+pub type StreamingRequest = http::Request<RequestBody>;
+
+/// The request body on stdin, limited to `CONTENT_LENGTH` bytes. If it ends before, reading
+/// fails with [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof), wrapping an
+/// [`Error::IncompleteBody`].
+#[derive(Debug)]
+pub struct RequestBody {
+    inner: std::io::Take<std::io::Stdin>,
+    content_length: u64,
+    // An NPH script sends `100 Continue` when the body is first read
+    continue_pending: bool,
+}
+
+impl RequestBody {
+    /// How many bytes of the body haven't been read yet
+    pub fn remaining(&self) -> u64 {
+        self.inner.limit()
+    }
+
+    /// Whether the client waits for `100 Continue` before sending the body, which is sent
+    /// when it's first read (only for NPH scripts; otherwise the web server takes care of it)
+    pub fn expects_continue(&self) -> bool {
+        self.continue_pending
+    }
+}
+
+impl Read for RequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.continue_pending && !buf.is_empty() {
+            self.continue_pending = false;
+            send_continue()?;
+        }
+        let n = self.inner.read(buf)?;
+        if n == 0 && self.inner.limit() > 0 && !buf.is_empty() {
+            let received = self.content_length - self.inner.limit();
+            let err = Error::IncompleteBody { expected: self.content_length, received };
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, err));
+        }
+        Ok(n)
+    }
+}
+
+/// Like [`handle`], but the request body isn't read into memory first: the handler reads it
+/// from [`RequestBody`], e.g. to write a large upload straight to a file.
+///
+/// An invalid `CONTENT_LENGTH` is answered with `400 Bad Request`, without calling the handler.
+///
+/// The handler can also answer without reading the body, e.g. with `413 Content Too Large`
+/// if `Content-Length` is over a limit. For [NPH scripts](crate#nph-scripts), the client then
+/// doesn't even send it if it asked with `Expect: 100-continue`.
+///
+/// ```rust,no_run
+/// cgi::handle_streaming(|mut request: cgi::StreamingRequest| {
+///     let mut file = std::fs::File::create("/tmp/upload").unwrap();
+///     let written = std::io::copy(request.body_mut(), &mut file).unwrap();
+///     cgi::text_response(200, format!("Stored {} bytes", written))
+/// })
+/// ```
+pub fn handle_streaming<F, R>(func: F)
+    where F: FnOnce(StreamingRequest) -> R,
+          R: IntoResponse
+{
+    set_binary_mode();
+    let env_vars = env_vars();
+    let nph = nph_protocol(&env_vars);
+    let content_length = match env_vars.get("CONTENT_LENGTH") {
+        Some(cl) if !cl.is_empty() => match std::str::from_utf8(cl).ok().and_then(|cl| cl.parse::<u64>().ok()) {
+            Some(cl) => cl,
+            None => {
+                let mut response = Error::InvalidVar { name: "CONTENT_LENGTH".into(), value: String::from_utf8_lossy(cl).into_owned() }.into_response();
+                if let Some(protocol) = nph {
+                    response.extensions_mut().insert(Nph(protocol));
+                }
+                write_response(response);
+                return;
+            }
+        },
+        _ => 0,
+    };
+
+    let continue_pending = nph.is_some() && content_length > 0 && expects_continue(&env_vars);
+
+    let request = parse_request(env_vars, Vec::new())
+        .map(|_| RequestBody { inner: stdin().take(content_length), content_length, continue_pending });
+    let mut response = call_handler(request, |request| func(request).into_response());
+    if let Some(protocol) = nph {
+        response.extensions_mut().insert(Nph(protocol));
+    }
+    write_response(response);
+}
+
+/// Writes the response while the handler runs, see [`handle_with_writer`].
+///
+/// [`send_head`](Self::send_head) writes the status, the headers and the start of the body
+/// right away; writing to the `ResponseWriter` then appends to the body. The output is only
+/// pushed to the web server with [`flush`](Write::flush). For an [NPH
+/// script](crate#nph-scripts) answering HTTP/1.1, a body without `Content-Length` is sent
+/// with `Transfer-Encoding: chunked`. For a `HEAD` request, the body is dropped.
+pub struct ResponseWriter {
+    out: Box<dyn Write>,
+    nph: Option<String>,
+    head_only: bool,
+    started: bool,
+    chunked: bool,
+}
+
+impl std::fmt::Debug for ResponseWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ResponseWriter").field("started", &self.started).field("chunked", &self.chunked).finish()
+    }
+}
+
+impl ResponseWriter {
+    fn new(out: Box<dyn Write>, nph: Option<String>, head_only: bool) -> Self {
+        ResponseWriter { out, nph, head_only, started: false, chunked: false }
+    }
+
+    /// Write the status & headers of `head` and flush them with its body. It can only be
+    /// sent once. A `Content-Length` (e.g. of [`html_response`]) is removed, since the body
+    /// isn't complete yet.
+    pub fn send_head(&mut self, mut head: Response) -> std::io::Result<()> {
+        if self.started {
+            return Err(std::io::Error::other("the response head has already been sent"));
+        }
+        stream::take_body_writer(&mut head);
+        head.headers_mut().remove(http::header::CONTENT_LENGTH);
+        let body = std::mem::take(head.body_mut());
+        if let Some(protocol) = &self.nph {
+            head.extensions_mut().insert(Nph(protocol.clone()));
+            self.chunked = !self.head_only && nph_chunked(&mut head);
+        }
+        write_head(&mut head, &mut self.out)?;
+        self.started = true;
+        self.write_all(&body)?;
+        self.flush()
+    }
+
+    /// Whether the head has been sent
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    // End the body
+    fn finish(&mut self) -> std::io::Result<()> {
+        if self.chunked {
+            stream::ChunkedWriter::new(&mut self.out).finish()?;
+        }
+        self.out.flush()
+    }
+}
+
+impl Write for ResponseWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ResponseWriter { started: false, .. } => Err(std::io::Error::other("the response head hasn't been sent")),
+            ResponseWriter { head_only: true, .. } => Ok(buf.len()),
+            ResponseWriter { chunked: true, out, .. } => stream::ChunkedWriter::new(out).write(buf),
+            ResponseWriter { out, .. } => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Like [`handle`], but the handler writes the response with a [`ResponseWriter`] while it
+/// runs, so the browser can show the start of a page during slow work.
+///
+/// If the handler fails before it has sent the head, the error is answered as usual (see
+/// [`set_error_response`]); afterwards, the response is just cut short. A handler which
+/// doesn't send anything is answered with `204 No Content`.
+///
+/// ```rust,no_run
+/// use std::io::Write;
+///
+/// cgi::handle_with_writer(|request: cgi::Request, writer: &mut cgi::ResponseWriter| {
+///     writer.send_head(cgi::html_response(200, "<!DOCTYPE html><p>Searching…</p>"))?;
+///     for result in ["one", "two"] {
+///         std::thread::sleep(std::time::Duration::from_secs(1));
+///         write!(writer, "<p>{}</p>", result)?;
+///         writer.flush()?;
+///     }
+///     Ok(())
+/// })
+/// ```
+pub fn handle_with_writer<F>(func: F)
+    where F: FnOnce(Request, &mut ResponseWriter) -> Result<(), Error>
+{
+    set_binary_mode();
+    let env_vars = env_vars();
+    let content_length: usize = var_str(&env_vars, "CONTENT_LENGTH")
         .and_then(|cl| cl.parse::<usize>().ok()).unwrap_or(0);
 
-    let mut stdin_contents = vec![0; content_length];
-    stdin().read_exact(&mut stdin_contents).unwrap();
+    let nph = nph_protocol(&env_vars);
+    let body = if nph.is_some() && content_length > 0 && expects_continue(&env_vars) {
+        send_continue().map_err(Error::from)
+    } else {
+        Ok(())
+    };
+    let request = body.and_then(|()| read_body(stdin(), content_length, read_timeout(), |_, _| {}))
+        .map(|body| parse_request(env_vars, body));
+    let mut writer = ResponseWriter::new(Box::new(std::io::BufWriter::with_capacity(OUTPUT_BUFFER, std::io::stdout())), nph.clone(), false);
+    let mut completed = false;
+    let mut response = match request {
+        Ok(request) => {
+            writer.head_only = request.method() == http::Method::HEAD;
+            call_handler(request, |request| match func(request, &mut writer) {
+                Ok(()) => {
+                    completed = true;
+                    empty_response(204)
+                }
+                Err(err) => err.into_response(),
+            })
+        }
+        Err(err) => err.into_response(),
+    };
+
+    if !writer.started {
+        if let Some(protocol) = nph {
+            response.extensions_mut().insert(Nph(protocol));
+        }
+        write_response(response);
+        return;
+    }
+    if completed {
+        if let Err(err) = writer.finish() {
+            eprintln!("Could not write the response: {}", err);
+        }
+    }
+    run_after_response();
+}
+
+/// Like [`handle`], calling `progress(bytes_read, content_length)` while the request body is
+/// read, before `func` is called. See [`progress`] for an example.
+pub fn handle_with_progress<P, F, R>(progress: P, func: F)
+    where P: FnMut(u64, u64),
+          F: FnOnce(Request) -> R,
+          R: IntoResponse
+{
+    handle_with_options(&Options::new(), progress, func)
+}
+
+/// Settings of a single [`handle`] call, which `#[cgi::main(...)]` takes as arguments. They
+/// add to the programme-wide settings, e.g. `catch_panics(false)` doesn't undo
+/// [`set_catch_panics`].
+///
+/// ```rust,no_run
+/// cgi::Options::new()
+///     .max_body(1024 * 1024)
+///     .catch_panics(true)
+///     .handle(|request: cgi::Request| format!("{} bytes", request.body().len()));
+/// ```
+///
+/// The same with the macro:
+///
+/// ```rust,no_run
+/// #[cgi::main(max_body = 1024 * 1024, catch_panics)]
+/// fn main(request: cgi::Request) -> String {
+///     format!("{} bytes", request.body().len())
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    max_body: Option<u64>,
+    catch_panics: bool,
+    line_ending: Option<LineEnding>,
+    nph: bool,
+    truncated_body: bool,
+    read_to_eof: Option<u64>,
+}
+
+impl Options {
+    /// The defaults: no limit, panics aren't caught, and the programme-wide line ending
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    /// Answer a request with a body of more than `max_body` bytes with `413 Content Too Large`
+    /// ([`Error::BodyTooLarge`]), without reading it
+    pub fn max_body(mut self, max_body: u64) -> Self {
+        self.max_body = Some(max_body);
+        self
+    }
+
+    /// Answer a panicking handler with a `500`, see [`set_catch_panics`]
+    pub fn catch_panics(mut self, catch_panics: bool) -> Self {
+        self.catch_panics = catch_panics;
+        self
+    }
+
+    /// The [`LineEnding`] of the response, unless it has its own
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = Some(line_ending);
+        self
+    }
+
+    /// Write an HTTP response like an NPH script (see [NPH scripts](crate#nph-scripts)), even
+    /// if the script's name doesn't start with `nph-`
+    pub fn nph(mut self, nph: bool) -> Self {
+        self.nph = nph;
+        self
+    }
+
+    /// Call the handler with a request body which ended before `CONTENT_LENGTH`, marked with
+    /// a [`TruncatedBody`] extension, rather than answering `400 Bad Request`
+    pub fn truncated_body(mut self, truncated_body: bool) -> Self {
+        self.truncated_body = truncated_body;
+        self
+    }
+
+    /// Without `CONTENT_LENGTH`, read the request body until stdin ends, answering a body of
+    /// more than `limit` bytes (or [`max_body`](Self::max_body)) with `413 Content Too Large`.
+    /// Some servers pass chunked request bodies like this; otherwise they're ignored.
+    ///
+    /// The web server has to close stdin after the body, or reading waits forever: set a
+    /// [read timeout](set_read_timeout) to be safe.
+    pub fn read_to_eof(mut self, limit: u64) -> Self {
+        self.read_to_eof = Some(limit);
+        self
+    }
+
+    /// Like [`handle`], with these options
+    pub fn handle<F, R>(&self, func: F)
+        where F: FnOnce(Request) -> R,
+              R: IntoResponse
+    {
+        handle_with_options(self, |_, _| {}, func)
+    }
+
+    /// Like [`handle_async`], with these options. Requires the `tokio` or `pollster` feature.
+    #[cfg(any(feature = "tokio", feature = "pollster"))]
+    pub fn handle_async<F, Fut>(&self, func: F)
+        where F: FnOnce(Request) -> Fut,
+              Fut: std::future::Future,
+              Fut::Output: IntoResponse
+    {
+        self.handle(|request| block_on(func(request)))
+    }
+}
+
+fn handle_with_options<P, F, R>(options: &Options, progress: P, func: F)
+    where P: FnMut(u64, u64),
+          F: FnOnce(Request) -> R,
+          R: IntoResponse
+{
+    let started = std::time::Instant::now();
+    set_binary_mode();
+    let env_vars = env_vars();
+
+    // How many bytes do we have to read for request body
+    // A general stdin().read_to_end() can block if the webserver doesn't close things, so it's
+    // only done when asked to
+    let content_length = var_str(&env_vars, "CONTENT_LENGTH").and_then(|cl| cl.parse::<usize>().ok());
+    let to_eof = options.read_to_eof.filter(|_| content_length.is_none())
+        .map(|limit| options.max_body.map_or(limit, |max_body| limit.min(max_body)));
+    let content_length = content_length.unwrap_or(0);
+
+    let nph = nph_protocol(&env_vars).or_else(|| options.nph.then(|| server_protocol(&env_vars)));
+    let finish = |mut response: Response, mut metrics: metrics::Metrics| {
+        if let Some(protocol) = &nph {
+            response.extensions_mut().insert(Nph(protocol.clone()));
+        }
+        if let Some(line_ending) = options.line_ending {
+            if response.extensions().get::<LineEnding>().is_none() {
+                response.extensions_mut().insert(line_ending);
+            }
+        }
+        metrics.response_body = match response.extensions().get::<stream::BodyWriter>() {
+            Some(_) => response.headers().get(http::header::CONTENT_LENGTH).and_then(|cl| cl.to_str().ok()?.parse().ok()),
+            None => Some(response.body().len() as u64),
+        };
+        let writing = std::time::Instant::now();
+        write_response(response);
+        metrics.serialize = writing.elapsed();
+        metrics::export(&metrics);
+    };
+
+    if let Some(limit) = options.max_body.filter(|&limit| content_length as u64 > limit) {
+        finish(Error::BodyTooLarge { limit, length: content_length as u64 }.into_response(), metrics::Metrics::default());
+        return;
+    }
+    let body = if nph.is_some() && content_length > 0 && expects_continue(&env_vars) {
+        send_continue().map_err(Error::from)
+    } else {
+        Ok(())
+    };
+    let body = body.and_then(|()| match to_eof {
+        Some(limit) => read_body_to_eof(stdin(), limit, read_timeout(), progress).map(|body| (body, None)),
+        None => read_body_up_to(stdin(), content_length, Some(content_length), read_timeout(), progress)
+            .and_then(|body| match TruncatedBody::check(&body, content_length) {
+                Some(truncated) if !options.truncated_body => Err(truncated.into()),
+                truncated => Ok((body, truncated)),
+            }),
+    });
+    let (stdin_contents, truncated) = match body {
+        Ok(body) => body,
+        Err(err) => {
+            finish(err.into_response(), metrics::Metrics { parse: started.elapsed(), ..Default::default() });
+            return;
+        }
+    };
+
+    let mut request = parse_request(env_vars, stdin_contents);
+    if let Some(truncated) = truncated {
+        request.extensions_mut().insert(truncated);
+    }
+    let mut metrics = metrics::Metrics { parse: started.elapsed(), request_body: request.body().len() as u64, ..Default::default() };
+    request.extensions_mut().insert(metrics.clone());
+
+    let catch_panics = options.catch_panics || CATCH_PANICS.load(Ordering::Relaxed);
+    let handling = std::time::Instant::now();
+    let response = call_handler_with(request, catch_panics, |request| func(request).into_response());
+    metrics.handler = handling.elapsed();
+    finish(response, metrics);
+}
+
+// Milliseconds, 0 for none
+static READ_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Give up reading the request body after `timeout`, with a `408 Request Timeout` (or
+/// [`Error::Timeout`] from [`try_handle`]), rather than waiting for a stalled client forever.
+/// There's no timeout by default. This applies to the whole programme, except
+/// [`handle_streaming`], where the handler reads the body.
+///
+/// A body which ends before `CONTENT_LENGTH` is a `400 Bad Request` (or
+/// [`Error::IncompleteBody`]) either way, unless [`Options::truncated_body`] is set.
+pub fn set_read_timeout(timeout: Option<std::time::Duration>) {
+    let millis = timeout.map_or(0, |t| u64::try_from(t.as_millis()).unwrap_or(u64::MAX).max(1));
+    READ_TIMEOUT.store(millis, Ordering::Relaxed);
+}
+
+fn read_timeout() -> Option<std::time::Duration> {
+    let millis = READ_TIMEOUT.load(Ordering::Relaxed);
+    (millis > 0).then(|| std::time::Duration::from_millis(millis))
+}
+
+// Read `content_length` bytes of body, an error if it ends before
+fn read_body<R, P>(reader: R, content_length: usize, timeout: Option<std::time::Duration>, progress: P) -> Result<Vec<u8>, Error>
+    where R: Read + Send + 'static,
+          P: FnMut(u64, u64)
+{
+    let body = read_body_up_to(reader, content_length, Some(content_length), timeout, progress)?;
+    match TruncatedBody::check(&body, content_length) {
+        Some(truncated) => Err(truncated.into()),
+        None => Ok(body),
+    }
+}
+
+// Read up to `limit` bytes of body, fewer if it ends before. `length` is the size the body
+// should have, if it's known. With a timeout, the reading happens on another thread, which is
+// left blocked if it runs out; the programme exits after the response anyway.
+fn read_body_up_to<R, P>(reader: R, limit: usize, length: Option<usize>, timeout: Option<std::time::Duration>, mut progress: P) -> Result<Vec<u8>, Error>
+    where R: Read + Send + 'static,
+          P: FnMut(u64, u64)
+{
+    let total = length.unwrap_or(0) as u64;
+    let Some(timeout) = timeout.filter(|_| limit > 0) else {
+        let mut body = Vec::with_capacity(length.unwrap_or(0));
+        progress::ProgressReader::new(reader, total, progress).take(limit as u64).read_to_end(&mut body)?;
+        return Ok(body);
+    };
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut reader = reader.take(limit as u64);
+        loop {
+            let mut chunk = vec![0; 64 * 1024];
+            match reader.read(&mut chunk) {
+                Ok(n) => {
+                    chunk.truncate(n);
+                    if sender.send(Ok(chunk)).is_err() || n == 0 {
+                        return;
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    let _ = sender.send(Err(err));
+                    return;
+                }
+            }
+        }
+    });
+
+    let deadline = std::time::Instant::now() + timeout;
+    let mut body = Vec::with_capacity(length.unwrap_or(0));
+    while body.len() < limit {
+        match receiver.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())) {
+            Ok(Ok(chunk)) if chunk.is_empty() => break,
+            Ok(Ok(chunk)) => {
+                body.extend_from_slice(&chunk);
+                progress(body.len() as u64, total);
+            }
+            Ok(Err(err)) => return Err(err.into()),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(body)
+}
+
+// Read the body until stdin ends, an error if it's longer than `limit`
+fn read_body_to_eof<R, P>(reader: R, limit: u64, timeout: Option<std::time::Duration>, progress: P) -> Result<Vec<u8>, Error>
+    where R: Read + Send + 'static,
+          P: FnMut(u64, u64)
+{
+    let body = read_body_up_to(reader, usize::try_from(limit.saturating_add(1)).unwrap_or(usize::MAX), None, timeout, progress)?;
+    if body.len() as u64 > limit {
+        return Err(Error::BodyTooLarge { limit, length: body.len() as u64 });
+    }
+    Ok(body)
+}
+
+/// A request body which ended before `CONTENT_LENGTH`, in the request's extensions when
+/// [`Options::truncated_body`] lets the handler have it.
+///
+/// Web servers usually abort the request when the client stops sending, but some pass on
+/// what arrived. Otherwise, the request is answered with `400 Bad Request`
+/// ([`Error::IncompleteBody`]).
+///
+/// ```rust,no_run
+/// cgi::Options::new().truncated_body(true).handle(|request: cgi::Request| {
+///     if let Some(truncated) = request.extensions().get::<cgi::TruncatedBody>() {
+///         return cgi::text_response(400, format!("Only {} of {} bytes arrived", truncated.received, truncated.expected));
+///     }
+///     cgi::text_response(200, "Complete")
+/// })
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedBody {
+    /// `CONTENT_LENGTH`
+    pub expected: u64,
+    /// The size of the body
+    pub received: u64,
+}
+
+impl TruncatedBody {
+    fn check(body: &[u8], content_length: usize) -> Option<Self> {
+        (body.len() < content_length).then_some(TruncatedBody { expected: content_length as u64, received: body.len() as u64 })
+    }
+}
+
+impl From<TruncatedBody> for Error {
+    fn from(truncated: TruncatedBody) -> Self {
+        Error::IncompleteBody { expected: truncated.expected, received: truncated.received }
+    }
+}
+
+// The response of a non-parsed header script is a complete HTTP response, with this protocol
+// in the status line
+#[derive(Clone)]
+struct Nph(String);
+
+// The protocol of the request if this is an NPH script: by convention, its name starts with `nph-`
+fn nph_protocol<V: AsRef<[u8]>>(env_vars: &HashMap<String, V>) -> Option<String> {
+    let script = var_str(env_vars, "SCRIPT_NAME")?.rsplit('/').next()?;
+    if !script.starts_with("nph-") {
+        return None;
+    }
+    Some(server_protocol(env_vars))
+}
+
+fn server_protocol<V: AsRef<[u8]>>(env_vars: &HashMap<String, V>) -> String {
+    let protocol = var_str(env_vars, "SERVER_PROTOCOL").filter(|p| *p == "HTTP/1.0" || *p == "HTTP/1.1");
+    protocol.unwrap_or("HTTP/1.0").to_owned()
+}
+
+// Whether the client waits for `100 Continue` before it sends the body (only HTTP/1.1 clients may)
+fn expects_continue<V: AsRef<[u8]>>(env_vars: &HashMap<String, V>) -> bool {
+    var_str(env_vars, "HTTP_EXPECT").is_some_and(|e| e.trim().eq_ignore_ascii_case("100-continue"))
+        && var_str(env_vars, "SERVER_PROTOCOL") == Some("HTTP/1.1")
+}
+
+fn send_continue() -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    stdout.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    stdout.flush()
+}
+
+// The size of the buffer in front of stdout
+const OUTPUT_BUFFER: usize = 64 * 1024;
+
+// Write the response to stdout, then run the `after_response` hooks
+fn write_response(response: Response) {
+    if let Err(err) = try_write_response(response) {
+        eprintln!("Could not write the response: {}", err);
+    }
+}
+
+fn try_write_response(mut response: Response) -> std::io::Result<()> {
+    let throttle = response.extensions().get::<throttle::Throttle>().copied();
+    let body_writer = stream::take_body_writer(&mut response);
+    let chunked = body_writer.is_some() && nph_chunked(&mut response);
+
+    // The body is written from where it is, without copying it next to the head; a large one
+    // goes past the buffer
+    let mut stdout = std::io::BufWriter::with_capacity(OUTPUT_BUFFER, std::io::stdout().lock());
+    let mut out: Box<dyn Write> = match throttle {
+        Some(throttle) => Box::new(throttle::ThrottledWriter::new(&mut stdout, throttle)),
+        None => Box::new(&mut stdout),
+    };
+    let mut result = write_serialized(response, &mut out);
+    if let (Ok(()), Some(body_writer)) = (&result, body_writer) {
+        result = if chunked {
+            let mut chunks = stream::ChunkedWriter::new(&mut out);
+            body_writer.write_to(&mut chunks).and_then(|()| chunks.finish().map(drop))
+        } else {
+            body_writer.write_to(&mut out)
+        };
+    }
+    let result = result.and_then(|()| out.flush());
+    drop(out);
+
+    run_after_response();
+    result
+}
+
+thread_local! {
+    static AFTER_RESPONSE: RefCell<Vec<Box<dyn FnOnce()>>> = RefCell::new(Vec::new());
+}
+
+/// Run `f` after [`handle`] has written the response to stdout, for work the client doesn't
+/// need to wait for (e.g. logging, or [`shadow`]ing requests).
+///
+/// The response has been flushed by then, but many web servers only finish the request once
+/// the CGI programme exits, so this shouldn't take long either.
+pub fn after_response<F>(f: F)
+    where F: FnOnce() + 'static
+{
+    AFTER_RESPONSE.with(|hooks| hooks.borrow_mut().push(Box::new(f)));
+}
+
+pub(crate) fn run_after_response() {
+    while let Some(hook) = AFTER_RESPONSE.with(|hooks| hooks.borrow_mut().pop()) {
+        hook();
+    }
+}
 
-    let request = parse_request(env_vars, stdin_contents);
+// Forget what an earlier request on this thread left behind (e.g. if it panicked, or its
+// response couldn't be written), for servers handling one request after another
+pub(crate) fn reset_request_state() {
+    AFTER_RESPONSE.with(|hooks| hooks.borrow_mut().clear());
+    server_timing::take_metrics();
+}
 
-    let response = func(request);
+// `http::Request` isn't `Clone`, because of the extensions, which aren't copied
+pub(crate) fn clone_request(request: &Request) -> Request {
+    let mut copy = http::Request::builder()
+        .method(request.method().clone())
+        .uri(request.uri().clone())
+        .version(request.version())
+        .body(request.body().clone())
+        .expect("copying a valid request");
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
 
-    let output = serialize_response(response);
+// Append a line to a log file, locked so lines from concurrent processes don't interleave
+pub(crate) fn append_log_line(path: &std::path::Path, line: &str) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.lock()?;
+    file.write_all(line.as_bytes())
+}
 
-    std::io::stdout().write_all(&output).unwrap();
+pub(crate) fn clone_response(response: &Response) -> Response {
+    let mut copy = http::Response::builder()
+        .status(response.status())
+        .version(response.version())
+        .body(response.body().clone())
+        .expect("copying a valid response");
+    *copy.headers_mut() = response.headers().clone();
+    copy
 }
 
+pub use error::{set_error_response, Error};
+pub use ext::{RequestExt, ResponseExt};
+pub use files::{file_response, serve_dir, serve_file};
+pub use handler::Handler;
+pub use into_response::IntoResponse;
+#[cfg(feature = "serde")]
+pub use ext::QueryError;
+#[cfg(feature = "json")]
+pub use ext::JsonError;
+
 #[doc(inline)]
 pub use cgi_attributes::main;
+#[doc(inline)]
+pub use cgi_attributes::test;
 
 pub fn err_to_500<E>(res: Result<Response, E>) -> Response {
     res.unwrap_or(empty_response(500))
@@ -200,106 +1177,817 @@ pub fn binary_response<'a, T>(status_code: T, content_type: impl Into<Option<&'a
 }
 
 
-fn parse_request(env_vars: HashMap<String, String>, stdin: Vec<u8>) -> Request {
-    let mut req = http::Request::builder();
-
-    let method = env_vars.get("REQUEST_METHOD").expect("no REQUEST_METHOD set");
-    req = req.method(method.as_str());
-    let path_info = env_vars.get("PATH_INFO").map(|p| p.as_str()).unwrap_or("");
-    let mut uri = format!("{}{}", env_vars["SCRIPT_NAME"], path_info);
-    let query_string = env_vars.get("QUERY_STRING").map(|p| p.as_str()).unwrap_or("");
-    if query_string != "" {
-        uri.push_str(&format!("?{}", query_string));
-    };
-    req = req.uri(uri.as_str());
-
-    if let Some(v) = env_vars.get("SERVER_PROTOCOL") {
-        if v == "HTTP/0.9" {
-            req = req.version(http::version::Version::HTTP_09);
-        } else if v == "HTTP/1.0" {
-            req = req.version(http::version::Version::HTTP_10);
-        } else if v == "HTTP/1.1" {
-            req = req.version(http::version::Version::HTTP_11);
-        } else if v == "HTTP/2.0" {
+/// Serialize `value` as JSON, and send it with that status code and `Content-Type:
+/// application/json`. If it can't be serialized, the error is printed to stderr and a `500` is
+/// sent instead. Requires the `json` feature.
+///
+/// ```rust
+/// let response = cgi::json_response(201, &serde_json::json!({"id": 7}));
+/// assert_eq!(response.headers()["content-type"], "application/json");
+/// assert_eq!(response.body(), br#"{"id":7}"#);
+/// ```
+#[cfg(feature = "json")]
+pub fn json_response<T, V>(status_code: T, value: &V) -> Response
+    where http::StatusCode: TryFrom<T>,
+          <http::StatusCode as TryFrom<T>>::Error: Into<http::Error>,
+          V: serde::Serialize + ?Sized
+{
+    match serde_json::to_vec(value) {
+        Ok(body) => binary_response(status_code, "application/json", body),
+        Err(err) => {
+            eprintln!("Could not serialize the JSON response: {}", err);
+            empty_response::<u16>(500)
+        }
+    }
+}
+
+/// `401 Unauthorized` asking for a username & password with Basic authentication for `realm`
+/// (quotes & backslashes are removed from it). See
+/// [`RequestExt::basic_auth`](crate::RequestExt::basic_auth).
+pub fn unauthorized_basic(realm: &str) -> Response {
+    let mut response = text_response(401, "Unauthorized");
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm.replace(['"', '\\'], ""));
+    if let Ok(value) = http::HeaderValue::from_str(&challenge) {
+        response.headers_mut().insert(http::header::WWW_AUTHENTICATE, value);
+    }
+    response
+}
+
+/// Why a request's bearer token (RFC 6750) isn't accepted, see [`bearer_challenge`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BearerError {
+    /// There's no token: `401 Unauthorized` without an error code
+    Missing,
+    /// The request is malformed: `400 Bad Request`
+    InvalidRequest,
+    /// The token is expired, revoked or wrong: `401 Unauthorized`
+    InvalidToken,
+    /// The token is valid, but lacks this scope: `403 Forbidden`
+    InsufficientScope(String),
+}
+
+/// A response with a `WWW-Authenticate: Bearer` challenge for `realm` (left out if it's empty),
+/// and the status & error code for `error`. See
+/// [`RequestExt::bearer_token`](crate::RequestExt::bearer_token).
+///
+/// ```rust
+/// use cgi::{BearerError, RequestExt};
+///
+/// fn handler(request: cgi::Request) -> cgi::Response {
+///     match request.bearer_token() {
+///         Some("let-me-in") => cgi::text_response(200, "Hook received"),
+///         Some(_) => cgi::bearer_challenge("hooks", BearerError::InvalidToken),
+///         None => cgi::bearer_challenge("hooks", BearerError::Missing),
+///     }
+/// }
+///
+/// let response = cgi::testing::CgiRequestBuilder::new().header("Authorization", "Bearer nope").run(handler);
+/// assert_eq!(response.status(), 401);
+/// assert_eq!(response.headers()["www-authenticate"], "Bearer realm=\"hooks\", error=\"invalid_token\"");
+/// ```
+pub fn bearer_challenge(realm: &str, error: BearerError) -> Response {
+    let quote = |s: &str| s.replace(['"', '\\'], "");
+    let mut params = Vec::new();
+    if !realm.is_empty() {
+        params.push(format!("realm=\"{}\"", quote(realm)));
+    }
+    let status = match &error {
+        BearerError::Missing => 401,
+        BearerError::InvalidRequest => {
+            params.push("error=\"invalid_request\"".to_owned());
+            400
+        }
+        BearerError::InvalidToken => {
+            params.push("error=\"invalid_token\"".to_owned());
+            401
+        }
+        BearerError::InsufficientScope(scope) => {
+            params.push("error=\"insufficient_scope\"".to_owned());
+            params.push(format!("scope=\"{}\"", quote(scope)));
+            403
+        }
+    };
+    let challenge = if params.is_empty() { "Bearer".to_owned() } else { format!("Bearer {}", params.join(", ")) };
+    let mut response = empty_response(status);
+    if let Ok(value) = http::HeaderValue::from_str(&challenge) {
+        response.headers_mut().insert(http::header::WWW_AUTHENTICATE, value);
+    }
+    response
+}
+
+/// A redirect to `location`, with an empty body.
+///
+/// `location` can be absolute (`https://example.com/`) or relative (`/login`); an
+/// internationalised host name is converted to punycode (see [`idn::to_ascii_url`]), and spaces
+/// and other non-ASCII characters are percent-encoded. A status which isn't a redirect (e.g.
+/// `304`), or a location which is empty, contains control characters or has an invalid host,
+/// is logged to stderr and gives a `500 Internal Server Error` instead.
+///
+/// ```rust
+/// let response = cgi::redirect(302, "/login?next=/account");
+/// assert_eq!(response.headers()["location"], "/login?next=/account");
+/// ```
+pub fn redirect<T>(status_code: T, location: &str) -> Response
+    where http::StatusCode: TryFrom<T>,
+          <http::StatusCode as TryFrom<T>>::Error: Into<http::Error>
+{
+    let mut response = empty_response(status_code);
+    if !matches!(response.status().as_u16(), 300..=303 | 307 | 308) {
+        eprintln!("{} isn't a redirect status", response.status());
+        return empty_response::<u16>(500);
+    }
+    if location.is_empty() || location.chars().any(|c| c.is_control()) {
+        eprintln!("Invalid redirect location {:?}", location);
+        return empty_response::<u16>(500);
+    }
+    let location = if location.is_ascii() {
+        encode_uri_part(location.as_bytes(), b"")
+    } else {
+        match idn::to_ascii_url(location) {
+            Ok(ascii) => encode_uri_part(ascii.as_bytes(), b""),
+            Err(err) => {
+                eprintln!("Invalid redirect location {:?}: {}", location, err);
+                return empty_response::<u16>(500);
+            }
+        }
+    };
+    response.headers_mut().insert(http::header::LOCATION, http::HeaderValue::from_str(&location).unwrap());
+    response
+}
+
+/// `303 See Other`: after a `POST`, get the result at `location`
+pub fn see_other(location: &str) -> Response {
+    redirect(303, location)
+}
+
+/// `301 Moved Permanently`: the resource is at `location` from now on; clients may change a
+/// `POST` into a `GET`
+pub fn moved_permanently(location: &str) -> Response {
+    redirect(301, location)
+}
+
+/// `307 Temporary Redirect`: repeat the same request at `location` this time
+pub fn temporary_redirect(location: &str) -> Response {
+    redirect(307, location)
+}
+
+/// `308 Permanent Redirect`: the resource is at `location` from now on, with the same method
+pub fn permanent_redirect(location: &str) -> Response {
+    redirect(308, location)
+}
+
+/// Marks a [`local_redirect`] response, so it's written without a status, other headers or
+/// a body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalRedirect;
+
+/// A local redirect (RFC 3875 section 6.2.2): the web server serves `path` (with an optional
+/// query) instead, as if the client had asked for it, without the client knowing.
+///
+/// The path has to start with `/`. It's a `302 Found` with a `Location` and the
+/// [`LocalRedirect`] extension; where the output isn't CGI (e.g. with
+/// [`hyper`](crate::hyper)), it's an ordinary redirect.
+///
+/// ```rust
+/// let response = cgi::local_redirect("/static/maintenance.html");
+/// assert_eq!(response.headers()["location"], "/static/maintenance.html");
+/// ```
+pub fn local_redirect(path: &str) -> Response {
+    if !path.starts_with('/') || path.starts_with("//") || path.contains('#') {
+        eprintln!("Invalid local redirect {:?}: it must be an absolute path", path);
+        return empty_response::<u16>(500);
+    }
+    let mut response = redirect(302, path);
+    if response.status() == http::StatusCode::FOUND {
+        response.extensions_mut().insert(LocalRedirect);
+    }
+    response
+}
+
+/// The header asking the web server to send a file itself, set with [`set_sendfile_header`].
+///
+/// The web server has to be configured to accept it (e.g. `XSendFile On` & `XSendFilePath` with
+/// Apache's mod_xsendfile); otherwise the client gets an empty response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendfileHeader {
+    /// `X-Sendfile`, for Apache with mod_xsendfile, and lighttpd
+    #[default]
+    XSendfile,
+    /// `X-Accel-Redirect`, for nginx: the path is the URI of an `internal` location, not a file
+    XAccelRedirect,
+    /// `X-LIGHTTPD-send-file`, for older lighttpd versions
+    XLighttpdSendFile,
+}
+
+impl SendfileHeader {
+    fn name(self) -> &'static str {
+        match self {
+            SendfileHeader::XSendfile => "x-sendfile",
+            SendfileHeader::XAccelRedirect => "x-accel-redirect",
+            SendfileHeader::XLighttpdSendFile => "x-lighttpd-send-file",
+        }
+    }
+}
+
+static SENDFILE_HEADER: AtomicU8 = AtomicU8::new(SendfileHeader::XSendfile as u8);
+
+/// Set the header [`sendfile_response`] uses. This applies to the whole programme.
+pub fn set_sendfile_header(header: SendfileHeader) {
+    SENDFILE_HEADER.store(header as u8, Ordering::Relaxed);
+}
+
+/// An empty `200` response telling the web server to send the file at `path`, so large files
+/// don't go through this process. The `Content-Type` is guessed from the extension.
+///
+/// ```rust,no_run
+/// cgi::set_sendfile_header(cgi::SendfileHeader::XAccelRedirect);
+/// cgi::handle(|request: cgi::Request| {
+///     // Checking permissions etc.
+///     cgi::sendfile_response("/protected/report.pdf")
+/// })
+/// ```
+pub fn sendfile_response(path: impl AsRef<std::path::Path>) -> Response {
+    let path = path.as_ref();
+    let header = match SENDFILE_HEADER.load(Ordering::Relaxed) {
+        1 => SendfileHeader::XAccelRedirect,
+        2 => SendfileHeader::XLighttpdSendFile,
+        _ => SendfileHeader::XSendfile,
+    };
+    let Ok(value) = http::HeaderValue::from_bytes(path.as_os_str().as_encoded_bytes()) else {
+        eprintln!("Can't send the file {:?} with {}", path, header.name());
+        return empty_response(500);
+    };
+    http::Response::builder()
+        .status(200)
+        .header(http::header::CONTENT_TYPE, mime::from_path(path).unwrap_or("application/octet-stream"))
+        .header(header.name(), value)
+        .body(Vec::new())
+        .unwrap()
+}
+
+// The environment, with the values as bytes: they needn't be UTF-8. Variables with a name
+// which isn't UTF-8 can't be CGI meta-variables, and are skipped.
+// The names are upper-cased on Windows, where they're case-insensitive.
+fn env_vars() -> HashMap<String, Vec<u8>> {
+    #[cfg(feature = "dotenv")]
+    dotenv::load_once();
+    std::env::vars_os()
+        .filter_map(|(name, value)| {
+            let name = name.into_string().ok()?;
+            #[cfg(windows)]
+            let name = name.to_ascii_uppercase();
+            Some((name, value.into_encoded_bytes()))
+        })
+        .collect()
+}
+
+// Rust's stdin & stdout never translate line endings, but the C runtime does in text mode,
+// which is the default on Windows: switch it off for C libraries using the same streams.
+#[cfg(windows)]
+fn set_binary_mode() {
+    use std::os::raw::c_int;
+    extern "C" {
+        fn _setmode(fd: c_int, mode: c_int) -> c_int;
+    }
+    const O_BINARY: c_int = 0x8000;
+    // SAFETY: only changes the mode of the C runtime's stdin & stdout
+    unsafe {
+        _setmode(0, O_BINARY);
+        _setmode(1, O_BINARY);
+    }
+}
+
+#[cfg(not(windows))]
+fn set_binary_mode() {}
+
+// A meta-variable, if it's set and valid UTF-8
+fn var_str<'a, V: AsRef<[u8]>>(env_vars: &'a HashMap<String, V>, name: &str) -> Option<&'a str> {
+    std::str::from_utf8(env_vars.get(name)?.as_ref()).ok()
+}
+
+// Percent-encode the bytes which can't be in a URI. The web server has decoded `SCRIPT_NAME` &
+// `PATH_INFO`, so `%`, `?` & `#` are escaped there too; some send non-ASCII bytes as they are.
+fn encode_uri_part(bytes: &[u8], escape: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_graphic() && !b"\"<>\\^`{|}".contains(&b) && !escape.contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn parse_request<V: AsRef<[u8]>>(env_vars: HashMap<String, V>, stdin: Vec<u8>) -> Request {
+    try_parse_request(env_vars, stdin).unwrap_or_else(|err| panic!("{}", err))
+}
+
+// Meta-variables which aren't UTF-8 are kept as bytes in the headers (`HeaderValue::as_bytes`),
+// and percent-encoded in the URI.
+fn try_parse_request<V: AsRef<[u8]>>(env_vars: HashMap<String, V>, stdin: Vec<u8>) -> Result<Request, Error> {
+    let var = |name: &str| env_vars.get(name).map(|v| v.as_ref()).ok_or_else(|| Error::MissingVar(name.to_owned()));
+    let invalid = |name: &str, value: &[u8]| Error::InvalidVar { name: name.to_owned(), value: String::from_utf8_lossy(value).into_owned() };
+
+    let mut req = http::Request::builder();
+
+    let method = var("REQUEST_METHOD")?;
+    req = req.method(http::Method::from_bytes(method).map_err(|_| invalid("REQUEST_METHOD", method))?);
+    let script_name = var("SCRIPT_NAME")?;
+    let mut path_info = env_vars.get("PATH_INFO").map(|p| p.as_ref()).unwrap_or(b"");
+    // IIS includes the script name in `PATH_INFO` (unless `allowPathInfo` is set)
+    if var_str(&env_vars, "SERVER_SOFTWARE").is_some_and(|s| s.starts_with("Microsoft-IIS/")) {
+        path_info = path_info.strip_prefix(script_name).unwrap_or(path_info);
+    }
+    let mut uri = encode_uri_part(&[script_name, path_info].concat(), b"%?#");
+    let query_string = env_vars.get("QUERY_STRING").map(|p| p.as_ref()).unwrap_or(b"");
+    if !query_string.is_empty() {
+        uri.push_str(&format!("?{}", encode_uri_part(query_string, b"#")));
+    };
+    if let Some(authority) = request_authority(&env_vars) {
+        uri = format!("{}://{}{}", request_scheme(&env_vars), authority, uri);
+    }
+    req = req.uri(http::Uri::try_from(uri.as_str()).map_err(|_| invalid("PATH_INFO", path_info))?);
+
+    if let Some(v) = env_vars.get("SERVER_PROTOCOL").map(|v| v.as_ref()) {
+        if v == b"HTTP/0.9" {
+            req = req.version(http::version::Version::HTTP_09);
+        } else if v == b"HTTP/1.0" {
+            req = req.version(http::version::Version::HTTP_10);
+        } else if v == b"HTTP/1.1" {
+            req = req.version(http::version::Version::HTTP_11);
+        } else if v == b"HTTP/2.0" {
             req = req.version(http::version::Version::HTTP_2);
         } else {
-            unimplemented!("Unsupport HTTP SERVER_PROTOCOL {:?}", v);
+            return Err(invalid("SERVER_PROTOCOL", v));
         }
     }
 
-    for key in env_vars.keys().filter(|k| k.starts_with("HTTP_")) {
+    let split = SPLIT_HEADERS.load(Ordering::Relaxed);
+    // A client's `X-CGI-` headers would pass for the meta-variable headers below
+    for key in env_vars.keys().filter(|k| k.starts_with("HTTP_") && !k.starts_with("HTTP_X_CGI_")) {
         let header: String = key.chars().skip(5).map(|c| if c == '_' { '-' } else { c }).collect();
-        req = req.header(header.as_str(), env_vars[key].as_str().trim());
+        let value = env_vars[key].as_ref().trim_ascii();
+        let name = http::HeaderName::try_from(header).map_err(|_| invalid(key, value))?;
+        let values = if split && LIST_HEADERS.contains(&name.as_str()) { split_list(value) } else { vec![value] };
+        for value in values {
+            req = req.header(&name, http::HeaderValue::from_bytes(value).map_err(|_| invalid(key, value))?);
+        }
+    }
+    // Web servers pass these two request headers as meta-variables without `HTTP_`
+    if !env_vars.contains_key("HTTP_CONTENT_TYPE") {
+        req = add_header(req, &env_vars, "CONTENT_TYPE", "Content-Type")?;
+    }
+    if !env_vars.contains_key("HTTP_CONTENT_LENGTH") {
+        req = add_header(req, &env_vars, "CONTENT_LENGTH", "Content-Length")?;
     }
 
 
-    req = add_header(req, &env_vars, "AUTH_TYPE", "X-CGI-Auth-Type");
-    req = add_header(req, &env_vars, "CONTENT_LENGTH", "X-CGI-Content-Length");
-    req = add_header(req, &env_vars, "CONTENT_TYPE", "X-CGI-Content-Type");
-    req = add_header(req, &env_vars, "GATEWAY_INTERFACE", "X-CGI-Gateway-Interface");
-    req = add_header(req, &env_vars, "PATH_INFO", "X-CGI-Path-Info");
-    req = add_header(req, &env_vars, "PATH_TRANSLATED", "X-CGI-Path-Translated");
-    req = add_header(req, &env_vars, "QUERY_STRING", "X-CGI-Query-String");
-    req = add_header(req, &env_vars, "REMOTE_ADDR", "X-CGI-Remote-Addr");
-    req = add_header(req, &env_vars, "REMOTE_HOST", "X-CGI-Remote-Host");
-    req = add_header(req, &env_vars, "REMOTE_IDENT", "X-CGI-Remote-Ident");
-    req = add_header(req, &env_vars, "REMOTE_USER", "X-CGI-Remote-User");
-    req = add_header(req, &env_vars, "REQUEST_METHOD", "X-CGI-Request-Method");
-    req = add_header(req, &env_vars, "SCRIPT_NAME", "X-CGI-Script-Name");
-    req = add_header(req, &env_vars, "SERVER_PORT", "X-CGI-Server-Port");
-    req = add_header(req, &env_vars, "SERVER_PROTOCOL", "X-CGI-Server-Protocol");
-    req = add_header(req, &env_vars, "SERVER_SOFTWARE", "X-CGI-Server-Software");
+    req = add_header(req, &env_vars, "AUTH_TYPE", "X-CGI-Auth-Type")?;
+    req = add_header(req, &env_vars, "CONTENT_LENGTH", "X-CGI-Content-Length")?;
+    req = add_header(req, &env_vars, "CONTENT_TYPE", "X-CGI-Content-Type")?;
+    req = add_header(req, &env_vars, "GATEWAY_INTERFACE", "X-CGI-Gateway-Interface")?;
+    req = add_header(req, &env_vars, "HTTPS", "X-CGI-Https")?;
+    if env_vars.contains_key("PATH_INFO") {
+        let value = http::HeaderValue::from_bytes(path_info).map_err(|_| invalid("PATH_INFO", path_info))?;
+        req = req.header("X-CGI-Path-Info", value);
+    }
+    req = add_header(req, &env_vars, "PATH_TRANSLATED", "X-CGI-Path-Translated")?;
+    req = add_header(req, &env_vars, "QUERY_STRING", "X-CGI-Query-String")?;
+    req = add_header(req, &env_vars, "REMOTE_ADDR", "X-CGI-Remote-Addr")?;
+    req = add_header(req, &env_vars, "REMOTE_HOST", "X-CGI-Remote-Host")?;
+    req = add_header(req, &env_vars, "REMOTE_IDENT", "X-CGI-Remote-Ident")?;
+    req = add_header(req, &env_vars, "REMOTE_USER", "X-CGI-Remote-User")?;
+    req = add_header(req, &env_vars, "REQUEST_METHOD", "X-CGI-Request-Method")?;
+    req = add_header(req, &env_vars, "REQUEST_SCHEME", "X-CGI-Request-Scheme")?;
+    req = add_header(req, &env_vars, "SCRIPT_NAME", "X-CGI-Script-Name")?;
+    req = add_header(req, &env_vars, "SERVER_NAME", "X-CGI-Server-Name")?;
+    req = add_header(req, &env_vars, "SERVER_PORT", "X-CGI-Server-Port")?;
+    req = add_header(req, &env_vars, "SERVER_PROTOCOL", "X-CGI-Server-Protocol")?;
+    req = add_header(req, &env_vars, "SERVER_SOFTWARE", "X-CGI-Server-Software")?;
 
-    req.body(stdin).unwrap()
+    let mut req = req.body(stdin)?;
+    let cookies = cookie::Cookies::from_headers(req.headers());
+    req.extensions_mut().insert(cookies);
+    let mut cgi_meta = meta::CgiMeta::from_vars(&env_vars);
+    cgi_meta.path_info = String::from_utf8_lossy(path_info).into_owned();
+    if let Some(remote) = cgi_meta.remote_addr {
+        req.extensions_mut().insert(remote);
+    }
+    req.extensions_mut().insert(cgi_meta);
+    req.extensions_mut().insert(meta::RequestId::from_vars(&env_vars));
+    Ok(req)
 
 }
 
-// add the CGI request meta-variables as X-CGI- headers
-fn add_header(req: http::request::Builder, env_vars: &HashMap<String, String>, meta_var: &str, target_header: &str) -> http::request::Builder {
-    if let Some(var) = env_vars.get(meta_var) {
-        req.header(target_header, var.as_str())
+/// Who answers `HEAD` requests, set with [`set_head_handling`].
+///
+/// Either way, the body of the response to a `HEAD` request isn't written (RFC 3875 section
+/// 4.3.2), and a response with a body gets a `Content-Length` if it hasn't one, so it has the
+/// headers a `GET` would have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeadHandling {
+    /// The handler gets the `HEAD` request
+    #[default]
+    Head,
+    /// The handler gets a `GET` request, so only `GET` has to be implemented. `CgiMeta` and
+    /// the `X-CGI-Request-Method` header still say `HEAD`.
+    Get,
+}
+
+static HEAD_AS_GET: AtomicBool = AtomicBool::new(false);
+
+/// Set how `HEAD` requests are handled. This applies to the whole programme.
+pub fn set_head_handling(head_handling: HeadHandling) {
+    HEAD_AS_GET.store(head_handling == HeadHandling::Get, Ordering::Relaxed);
+}
+
+static CATCH_PANICS: AtomicBool = AtomicBool::new(false);
+
+/// Answer a panicking handler with [`Error::Panic`], i.e. a `500 Internal Server Error` (see
+/// [`set_error_response`]) and a line on stderr, instead of exiting without a response, which
+/// web servers report as "premature end of script headers". This applies to the whole
+/// programme. In debug mode (`CGI_DEBUG`, see [`Error`]) panics are always caught.
+///
+/// Only the handler is covered: a panic while a [`BodyWriter`](stream::BodyWriter) writes the
+/// body comes after the headers, and still ends the programme.
+///
+/// ```rust,no_run
+/// cgi::set_catch_panics(true);
+/// cgi::handle(|request: cgi::Request| -> cgi::Response {
+///     let name = request.headers().get("x-name").unwrap(); // a 500 if the header is missing
+///     cgi::text_response(200, format!("Hello {}", name.to_str().unwrap()))
+/// });
+/// ```
+pub fn set_catch_panics(catch: bool) {
+    CATCH_PANICS.store(catch, Ordering::Relaxed);
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload.downcast_ref::<&str>().map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string())
+}
+
+static ECHO_REQUEST_ID: AtomicBool = AtomicBool::new(false);
+
+/// Add the [`RequestId`](meta::RequestId) of the request to the response, as its
+/// `X-Request-Id` header (unless the handler set one), so a client can quote it. This applies
+/// to the whole programme.
+pub fn set_echo_request_id(echo: bool) {
+    ECHO_REQUEST_ID.store(echo, Ordering::Relaxed);
+}
+
+// Call `func`, taking care of `HEAD` requests (see `HeadHandling`), panics (see
+// `set_catch_panics`) and the request ID (see `set_echo_request_id`)
+fn call_handler<B, F>(request: http::Request<B>, func: F) -> Response
+    where F: FnOnce(http::Request<B>) -> Response
+{
+    call_handler_with(request, CATCH_PANICS.load(Ordering::Relaxed), func)
+}
+
+fn call_handler_with<B, F>(mut request: http::Request<B>, catch_panics: bool, func: F) -> Response
+    where F: FnOnce(http::Request<B>) -> Response
+{
+    let head = request.method() == http::Method::HEAD;
+    if head && HEAD_AS_GET.load(Ordering::Relaxed) {
+        *request.method_mut() = http::Method::GET;
+    }
+    let debug = error::debug_mode();
+    if debug {
+        error::capture_panic_backtraces();
+    }
+    #[cfg(feature = "logging")]
+    let _context = logging::start_request(&request);
+    #[cfg(feature = "tracing")]
+    let span = request_span::RequestSpan::new(&request);
+    let request_id = ECHO_REQUEST_ID.load(Ordering::Relaxed)
+        .then(|| request.extensions().get::<meta::RequestId>().and_then(|id| http::HeaderValue::from_str(id.as_str()).ok()))
+        .flatten();
+    let mut response = if debug || catch_panics {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| func(request)))
+            .unwrap_or_else(|payload| Error::Panic(panic_message(&*payload)).into_response())
     } else {
-        req
+        func(request)
+    };
+    if head {
+        stream::take_body_writer(&mut response);
+        let body = std::mem::take(response.body_mut());
+        if !body.is_empty() && !response.headers().contains_key(http::header::CONTENT_LENGTH) {
+            response.headers_mut().insert(http::header::CONTENT_LENGTH, body.len().into());
+        }
     }
+    if let Some(id) = request_id {
+        response.headers_mut().entry("x-request-id").or_insert(id);
+    }
+    #[cfg(feature = "tracing")]
+    span.finish(&response);
+    response
 }
 
-/// Convert the Request into the appropriate stdout format
-fn serialize_response(response: Response) -> Vec<u8> {
-    let mut output = String::new();
-    output.push_str("Status: ");
-    output.push_str(response.status().as_str());
-    if let Some(reason) = response.status().canonical_reason() {
-        output.push_str(" ");
-        output.push_str(reason);
+/// What to do with request headers which the web server joined into one meta-variable.
+///
+/// A request can repeat a header, but a meta-variable can't be repeated, so servers join the
+/// values, usually with `, `. That's fine for most headers, but e.g. `Cookie: a=1, b=2` parses
+/// as a single cookie `a`. With [`MergedHeaders::Split`] (see [`set_merged_headers`]), the
+/// values of `Cookie` and of headers which are comma-separated lists (like `Accept` or
+/// `If-None-Match`) become separate headers again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergedHeaders {
+    /// Leave the value as the web server passed it
+    #[default]
+    Keep,
+    /// Split the value at commas (outside of quoted strings) into several headers
+    Split,
+}
+
+static SPLIT_HEADERS: AtomicBool = AtomicBool::new(false);
+
+/// Set how joined request headers are parsed, see [`MergedHeaders`]. Call it before
+/// [`handle`], as the request is parsed before the handler runs.
+///
+/// ```rust,no_run
+/// cgi::set_merged_headers(cgi::MergedHeaders::Split);
+/// cgi::handle(|request: cgi::Request| {
+///     let cookies = request.headers().get_all("cookie").iter().count();
+///     cgi::text_response(200, format!("{} cookie headers", cookies))
+/// })
+/// ```
+pub fn set_merged_headers(merged_headers: MergedHeaders) {
+    SPLIT_HEADERS.store(merged_headers == MergedHeaders::Split, Ordering::Relaxed);
+}
+
+// The headers which are split with `MergedHeaders::Split`: `Cookie` (a cookie can't contain a
+// comma) and the list-based fields of RFC 9110 & others
+const LIST_HEADERS: &[&str] = &[
+    "accept", "accept-charset", "accept-encoding", "accept-language", "cache-control",
+    "connection", "cookie", "forwarded", "if-match", "if-none-match", "pragma", "te", "trailer",
+    "transfer-encoding", "upgrade", "via", "x-forwarded-for",
+];
+
+// Split a header value at the commas outside of quoted strings, without empty elements
+fn split_list(value: &[u8]) -> Vec<&[u8]> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, &b) in value.iter().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' if quoted => escaped = true,
+            b'"' => quoted = !quoted,
+            b',' if !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
     }
-    output.push_str("\n");
+    parts.push(&value[start..]);
+    parts.into_iter().map(|p| p.trim_ascii()).filter(|p| !p.is_empty()).collect()
+}
 
-    {
-        let headers = response.headers();
-        let mut keys: Vec<&http::header::HeaderName> = headers.keys().collect();
-        keys.sort_by_key(|h| h.as_str());
-        for key in keys {
-            output.push_str(key.as_str());
-            output.push_str(": ");
-            output.push_str(headers.get(key).unwrap().to_str().unwrap());
-            output.push_str("\n");
+// `https` if `HTTPS` is on, `REQUEST_SCHEME` says so, or (without either) on port 443
+fn request_scheme<V: AsRef<[u8]>>(env_vars: &HashMap<String, V>) -> &'static str {
+    let https = match (var_str(env_vars, "HTTPS"), var_str(env_vars, "REQUEST_SCHEME")) {
+        (Some(https), _) if https.eq_ignore_ascii_case("on") || https == "1" => true,
+        (_, Some(scheme)) => scheme.eq_ignore_ascii_case("https"),
+        (Some(_), None) => false,
+        (None, None) => var_str(env_vars, "SERVER_PORT") == Some("443"),
+    };
+    if https { "https" } else { "http" }
+}
+
+// The `Host` header, or else `SERVER_NAME` with `SERVER_PORT` unless it's the scheme's default
+fn request_authority<V: AsRef<[u8]>>(env_vars: &HashMap<String, V>) -> Option<http::uri::Authority> {
+    if let Some(host) = var_str(env_vars, "HTTP_HOST").and_then(|h| h.trim().parse().ok()) {
+        return Some(host);
+    }
+    let server_name = var_str(env_vars, "SERVER_NAME").filter(|n| !n.is_empty())?;
+    let authority = match var_str(env_vars, "SERVER_PORT") {
+        Some(port) if !port.is_empty() && port != if request_scheme(env_vars) == "https" { "443" } else { "80" } =>
+            format!("{}:{}", server_name, port),
+        _ => server_name.to_owned(),
+    };
+    authority.parse().ok()
+}
+
+// add the CGI request meta-variables as X-CGI- headers
+fn add_header<V: AsRef<[u8]>>(req: http::request::Builder, env_vars: &HashMap<String, V>, meta_var: &str, target_header: &str) -> Result<http::request::Builder, Error> {
+    match env_vars.get(meta_var).map(|v| v.as_ref()) {
+        Some(var) => match http::HeaderValue::from_bytes(var) {
+            Ok(value) => Ok(req.header(target_header, value)),
+            Err(_) => Err(Error::InvalidVar { name: meta_var.to_owned(), value: String::from_utf8_lossy(var).into_owned() }),
+        },
+        None => Ok(req),
+    }
+}
+
+/// How the lines of the response header are terminated.
+///
+/// RFC 3875 allows a bare `\n`, which is the default, but some servers & proxies only accept
+/// `\r\n` as in HTTP itself. Set it for the whole programme with [`set_line_ending`], or for a
+/// single response by inserting it as an extension:
+///
+/// ```rust
+/// let mut response = cgi::text_response(200, "Hello World");
+/// response.extensions_mut().insert(cgi::LineEnding::CrLf);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// `\n`
+    #[default]
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
         }
     }
+}
 
-    output.push_str("\n");
+static CRLF: AtomicBool = AtomicBool::new(false);
 
-    let mut output = output.into_bytes();
+/// Set the [`LineEnding`] of all responses which don't have their own.
+pub fn set_line_ending(line_ending: LineEnding) {
+    CRLF.store(line_ending == LineEnding::CrLf, Ordering::Relaxed);
+}
 
-    let (_, mut body) = response.into_parts();
+/// The order in which the headers of the response are written.
+///
+/// By default they're sorted by name, so the output is the same however the handler (or
+/// middleware) built the response, e.g. for tests comparing it. Writing them in the order they
+/// were inserted skips the sorting. As with [`LineEnding`], set it for the whole programme with
+/// [`set_header_order`], or insert it as an extension of a single response. NPH responses always
+/// keep the insertion order.
+///
+/// ```rust
+/// let mut response = cgi::html_response(200, "<h1>Hello</h1>");
+/// response.extensions_mut().insert(cgi::HeaderOrder::Insertion);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderOrder {
+    /// Sorted by name; the values of a repeated header stay in their order
+    #[default]
+    Sorted,
+    /// In the order the names were first inserted, with the values of a name together
+    Insertion,
+}
 
-    output.append(&mut body);
+static INSERTION_ORDER: AtomicBool = AtomicBool::new(false);
 
+/// Set the [`HeaderOrder`] of all responses which don't have their own.
+pub fn set_header_order(header_order: HeaderOrder) {
+    INSERTION_ORDER.store(header_order == HeaderOrder::Insertion, Ordering::Relaxed);
+}
+
+// The names of `headers` in order, without collecting & sorting them: each step looks for the
+// smallest name after the previous one, which is quick for the few headers of a response
+fn sorted_names(headers: &http::HeaderMap) -> impl Iterator<Item = &http::header::HeaderName> {
+    let mut previous: Option<&http::header::HeaderName> = None;
+    std::iter::from_fn(move || {
+        let next = headers.keys()
+            .filter(|name| previous.is_none_or(|previous| name.as_str() > previous.as_str()))
+            .min_by_key(|name| name.as_str())?;
+        previous = Some(next);
+        Some(next)
+    })
+}
+
+// The response in the stdout format, for when it's needed in memory
+fn serialize_response(response: Response) -> Vec<u8> {
+    let mut output = Vec::new();
+    write_serialized(response, &mut output).expect("writing to a Vec");
     output
 }
 
+// Write the response in the stdout format: the head, then the body, in one vectored write
+// (if `out` supports it) rather than copying them together
+fn write_serialized<W: Write + ?Sized>(response: Response, out: &mut W) -> std::io::Result<()> {
+    let (head, body) = serialize_parts(response);
+    write_all_vectored(out, &mut [std::io::IoSlice::new(&head), std::io::IoSlice::new(&body)])
+}
+
+// The head in the stdout format, and the body which follows it
+fn serialize_parts(mut response: Response) -> (Vec<u8>, Vec<u8>) {
+    // Room for the status line & the headers, so the head is a single allocation
+    let capacity = 128 + response.headers().iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum::<usize>();
+    let mut head = Vec::with_capacity(capacity);
+    let body_follows = write_head(&mut response, &mut head).expect("writing to a Vec");
+    let body = if body_follows { response.into_body() } else { Vec::new() };
+    (head, body)
+}
+
+// Like the unstable `Write::write_all_vectored`
+pub(crate) fn write_all_vectored<W: Write + ?Sized>(out: &mut W, mut bufs: &mut [std::io::IoSlice]) -> std::io::Result<()> {
+    std::io::IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(n) => std::io::IoSlice::advance_slices(&mut bufs, n),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// Write the status & headers, and whether the body follows
+fn write_head<W: Write + ?Sized>(response: &mut Response, out: &mut W) -> std::io::Result<bool> {
+    if let Some(Nph(protocol)) = response.extensions_mut().remove::<Nph>() {
+        write_nph_head(response, &protocol, out)?;
+        return Ok(true);
+    }
+    let newline = response.extensions().get::<LineEnding>().copied()
+        .unwrap_or(if CRLF.load(Ordering::Relaxed) { LineEnding::CrLf } else { LineEnding::Lf })
+        .as_str();
+    if response.extensions().get::<LocalRedirect>().is_some() {
+        if let Some(location) = response.headers().get(http::header::LOCATION) {
+            // Nothing but the `Location`, or the web server would send the redirect to the client
+            out.write_all(b"Location: ")?;
+            out.write_all(location.as_bytes())?;
+            out.write_all(newline.as_bytes())?;
+            out.write_all(newline.as_bytes())?;
+            return Ok(false);
+        }
+    }
+    out.write_all(b"Status: ")?;
+    out.write_all(response.status().as_str().as_bytes())?;
+    if let Some(reason) = response.status().canonical_reason() {
+        out.write_all(b" ")?;
+        out.write_all(reason.as_bytes())?;
+    }
+    out.write_all(newline.as_bytes())?;
+
+    let headers = response.headers();
+    let order = response.extensions().get::<HeaderOrder>().copied()
+        .unwrap_or(if INSERTION_ORDER.load(Ordering::Relaxed) { HeaderOrder::Insertion } else { HeaderOrder::Sorted });
+    let write_header = |out: &mut W, name: &http::header::HeaderName, value: &http::HeaderValue| {
+        out.write_all(name.as_str().as_bytes())?;
+        out.write_all(b": ")?;
+        out.write_all(value.as_bytes())?;
+        out.write_all(newline.as_bytes())
+    };
+    // Every value of a repeated header (e.g. `Set-Cookie`) on its own line, in order
+    match order {
+        HeaderOrder::Sorted => for name in sorted_names(headers) {
+            for value in headers.get_all(name) {
+                write_header(out, name, value)?;
+            }
+        },
+        HeaderOrder::Insertion => for (name, value) in headers {
+            write_header(out, name, value)?;
+        },
+    }
+
+    out.write_all(newline.as_bytes())?;
+    Ok(true)
+}
+
+// Whether the streamed body of an NPH response goes out in chunks, which tell an HTTP/1.1 client
+// where it ends without a `Content-Length`; adds the `Transfer-Encoding` header if so
+fn nph_chunked(response: &mut Response) -> bool {
+    let status = response.status();
+    let chunked = response.extensions().get::<Nph>().is_some_and(|Nph(protocol)| protocol == "HTTP/1.1")
+        && !response.headers().contains_key(http::header::CONTENT_LENGTH)
+        && !response.headers().contains_key(http::header::TRANSFER_ENCODING)
+        && !status.is_informational() && status != http::StatusCode::NO_CONTENT && status != http::StatusCode::NOT_MODIFIED;
+    if chunked {
+        response.headers_mut().insert(http::header::TRANSFER_ENCODING, http::HeaderValue::from_static("chunked"));
+    }
+    chunked
+}
+
+// The head of an HTTP response, for NPH scripts
+fn write_nph_head<W: Write + ?Sized>(response: &Response, protocol: &str, out: &mut W) -> std::io::Result<()> {
+    let status = response.status();
+    out.write_all(protocol.as_bytes())?;
+    out.write_all(b" ")?;
+    out.write_all(status.as_str().as_bytes())?;
+    out.write_all(b" ")?;
+    out.write_all(status.canonical_reason().unwrap_or("").as_bytes())?;
+    out.write_all(b"\r\n")?;
+    for (name, value) in response.headers() {
+        out.write_all(name.as_str().as_bytes())?;
+        out.write_all(b": ")?;
+        out.write_all(value.as_bytes())?;
+        out.write_all(b"\r\n")?;
+    }
+    // Last, as if it had been inserted
+    if !response.headers().contains_key(http::header::DATE) {
+        write!(out, "date: {}\r\n", date::HttpDate(std::time::SystemTime::now()))?;
+    }
+    out.write_all(b"\r\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    // Not `cgi::test` from the glob import
+    use core::prelude::v1::test;
 
     fn env(input: Vec<(&str, &str)>) -> HashMap<String, String> {
         input.into_iter().map(|(a, b)| (a.to_owned(), b.to_owned())).collect()
@@ -323,6 +2011,67 @@ mod tests {
         assert_eq!(req.body(), &vec![] as &Vec<u8>);
     }
 
+    #[test]
+    fn test_parse_request_bytes() {
+        let env_vars: HashMap<String, Vec<u8>> = [
+            ("REQUEST_METHOD", &b"GET"[..]), ("SCRIPT_NAME", b"/s"), ("PATH_INFO", b"/caf\xe9 50%?#"),
+            ("QUERY_STRING", b"q=\xff\xfe&x=%20"), ("HTTP_X_NAME", b"J\xfcrgen"),
+        ].into_iter().map(|(k, v)| (k.to_owned(), v.to_vec())).collect();
+        let req = parse_request(env_vars, vec![]);
+        assert_eq!(req.uri().path(), "/s/caf%E9%2050%25%3F%23");
+        assert_eq!(req.uri().query(), Some("q=%FF%FE&x=%20"));
+        assert_eq!(req.headers()["x-cgi-path-info"].as_bytes(), b"/caf\xe9 50%?#");
+        assert_eq!(req.headers()["x-name"].as_bytes(), b"J\xfcrgen");
+    }
+
+    #[test]
+    fn test_parse_request_absolute_uri() {
+        let uri = |vars: Vec<(&str, &str)>| {
+            let mut vars = vars;
+            vars.extend([("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/s"), ("QUERY_STRING", "a=1")]);
+            parse_request(env(vars), vec![]).uri().to_string()
+        };
+        assert_eq!(uri(vec![("HTTP_HOST", "example.com:8080"), ("HTTPS", "on")]), "https://example.com:8080/s?a=1");
+        assert_eq!(uri(vec![("SERVER_NAME", "example.com"), ("SERVER_PORT", "443")]), "https://example.com/s?a=1");
+        assert_eq!(uri(vec![("SERVER_NAME", "example.com"), ("SERVER_PORT", "443"), ("REQUEST_SCHEME", "http")]), "http://example.com:443/s?a=1");
+        assert_eq!(uri(vec![("HTTP_HOST", "bad host"), ("SERVER_NAME", "example.com"), ("SERVER_PORT", "80")]), "http://example.com/s?a=1");
+        assert_eq!(uri(vec![]), "/s?a=1");
+    }
+
+    #[test]
+    fn test_parse_request_iis() {
+        let req = parse_request(env(vec![
+            ("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/app.exe"), ("PATH_INFO", "/app.exe/items/1"),
+            ("SERVER_SOFTWARE", "Microsoft-IIS/10.0"),
+        ]), vec![]);
+        assert_eq!(req.uri().path(), "/app.exe/items/1");
+        assert_eq!(req.headers()["x-cgi-path-info"], "/items/1");
+
+        let req = parse_request(env(vec![("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/app"), ("PATH_INFO", "/app/1")]), vec![]);
+        assert_eq!(req.uri().path(), "/app/app/1");
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(split_list(b"a=1, b=2"), [&b"a=1"[..], b"b=2"]);
+        assert_eq!(split_list(b"\"x,y\", W/\"z\\\",\" ,, "), [&b"\"x,y\""[..], b"W/\"z\\\",\""]);
+        assert_eq!(split_list(b"text/html"), [&b"text/html"[..]]);
+        assert!(split_list(b" ").is_empty());
+        assert!(LIST_HEADERS.contains(&"cookie") && !LIST_HEADERS.contains(&"if-modified-since"));
+    }
+
+    #[test]
+    fn test_parse_request_errors() {
+        let err = try_parse_request(env(vec![("SCRIPT_NAME", "/s")]), vec![]).unwrap_err();
+        assert!(matches!(&err, Error::MissingVar(name) if name == "REQUEST_METHOD"), "{:?}", err);
+        let err = try_parse_request(env(vec![("REQUEST_METHOD", "GET")]), vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "the CGI variable SCRIPT_NAME isn't set");
+        let err = try_parse_request(env(vec![("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/s"), ("SERVER_PROTOCOL", "HTTP/9")]), vec![]).unwrap_err();
+        assert_eq!(err.status(), 400);
+        let err = try_parse_request(env(vec![("REQUEST_METHOD", "GET"), ("SCRIPT_NAME", "/s"), ("HTTP_X_BAD", "a\x01b")]), vec![]).unwrap_err();
+        assert!(matches!(&err, Error::InvalidVar { name, .. } if name == "HTTP_X_BAD"), "{:?}", err);
+    }
+
     fn test_serialized_response(resp: http::response::Builder, body: &str, expected_output: &str) {
         let resp: Response = resp.body(String::from(body).into_bytes()).unwrap();
         let output = serialize_response(resp);
@@ -335,6 +2084,271 @@ mod tests {
         assert_eq!(output, expected_output);
     }
 
+    #[test]
+    fn test_serialized_response_repeated() {
+        test_serialized_response(
+            http::Response::builder().status(200)
+                .header("Set-Cookie", "a=1")
+                .header("Vary", "Accept")
+                .header("Set-Cookie", "b=2; HttpOnly")
+                .header("Vary", "Cookie"),
+            "",
+            "Status: 200 OK\nset-cookie: a=1\nset-cookie: b=2; HttpOnly\nvary: Accept\nvary: Cookie\n\n"
+        );
+    }
+
+    #[test]
+    fn test_serialized_response_insertion_order() {
+        test_serialized_response(
+            http::Response::builder().status(200)
+                .header("Vary", "Accept")
+                .header("Set-Cookie", "a=1")
+                .header("Content-Type", "text/plain")
+                .header("Vary", "Cookie")
+                .extension(HeaderOrder::Insertion),
+            "",
+            "Status: 200 OK\nvary: Accept\nvary: Cookie\nset-cookie: a=1\ncontent-type: text/plain\n\n"
+        );
+        let mut headers = http::HeaderMap::new();
+        for name in ["x-b", "x-c", "x-a", "x-b"] {
+            headers.append(name, http::HeaderValue::from_static("1"));
+        }
+        assert_eq!(sorted_names(&headers).map(|name| name.as_str()).collect::<Vec<_>>(), ["x-a", "x-b", "x-c"]);
+    }
+
+    #[test]
+    fn test_redirect() {
+        let response = see_other("/items/1");
+        assert_eq!(response.status(), 303);
+        assert_eq!(response.headers()["location"], "/items/1");
+        assert!(response.body().is_empty());
+        assert_eq!(moved_permanently("https://example.com/").status(), 301);
+        assert_eq!(temporary_redirect("/a").status(), 307);
+        assert_eq!(permanent_redirect("/a").status(), 308);
+        assert_eq!(redirect(302, "/söme path").headers()["location"], "/s%C3%B6me%20path");
+        assert_eq!(redirect(302, "https://Bücher.example/straße ü").headers()["location"], "https://xn--bcher-kva.example/stra%C3%9Fe%20%C3%BC");
+
+        assert_eq!(redirect(304, "/a").status(), 500);
+        assert_eq!(redirect(200, "/a").status(), 500);
+        assert_eq!(see_other("").status(), 500);
+        assert_eq!(see_other("/a\r\nSet-Cookie: x=1").status(), 500);
+    }
+
+    #[test]
+    fn test_head_handling() {
+        let head = || testing::CgiRequestBuilder::new().method("HEAD").build();
+        let echo_method = |request: Request| string_response(200, request.method().as_str());
+        let response = call_handler(head(), echo_method);
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers()["content-length"], "4");
+        let response = call_handler(head(), |_| Response::new(b"abc".to_vec()));
+        assert_eq!(response.headers()["content-length"], "3");
+
+        set_head_handling(HeadHandling::Get);
+        let response = call_handler(head(), echo_method);
+        set_head_handling(HeadHandling::Head);
+        assert_eq!(response.headers()["content-length"], "3");
+        assert!(response.body().is_empty());
+        assert_eq!(call_handler(testing::CgiRequestBuilder::new().build(), echo_method).body(), b"GET");
+    }
+
+    #[test]
+    fn test_echo_request_id() {
+        let request = || testing::CgiRequestBuilder::new().header("X-Request-Id", "abc").build();
+        set_echo_request_id(true);
+        let response = call_handler(request(), |_| empty_response(204));
+        let own = call_handler(request(), |_| http::Response::builder().header("X-Request-Id", "mine").body(Vec::new()).unwrap());
+        set_echo_request_id(false);
+        assert_eq!(response.headers()["x-request-id"], "abc");
+        assert_eq!(own.headers()["x-request-id"], "mine");
+        assert!(!call_handler(request(), |_| empty_response(204)).headers().contains_key("x-request-id"));
+    }
+
+    #[test]
+    fn test_catch_panics() {
+        set_catch_panics(true);
+        let response = call_handler(testing::CgiRequestBuilder::new().build(), |_| panic!("no {}", "luck"));
+        set_catch_panics(false);
+        assert_eq!(response.status(), 500);
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&String::from("owned")), "owned");
+        if !error::debug_mode() {
+            assert!(std::panic::catch_unwind(|| call_handler(testing::CgiRequestBuilder::new().build(), |_| panic!())).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_body() {
+        let mut calls = Vec::new();
+        let body = read_body(std::io::Cursor::new(b"hello world".to_vec()), 5, None, |read, total| calls.push((read, total))).unwrap();
+        assert_eq!(body, b"hello");
+        assert_eq!(calls, [(5, 5)]);
+        let err = read_body(std::io::Cursor::new(b"hi".to_vec()), 5, None, |_, _| {}).unwrap_err();
+        assert!(matches!(err, Error::IncompleteBody { expected: 5, received: 2 }), "{:?}", err);
+        let body = read_body_up_to(std::io::Cursor::new(b"hi".to_vec()), 5, Some(5), None, |_, _| {}).unwrap();
+        assert_eq!(TruncatedBody::check(&body, 5), Some(TruncatedBody { expected: 5, received: 2 }));
+        assert_eq!(TruncatedBody::check(b"hello", 5), None);
+
+        let mut calls = Vec::new();
+        let body = read_body_to_eof(std::io::Cursor::new(b"hello".to_vec()), 5, None, |read, total| calls.push((read, total))).unwrap();
+        assert_eq!((body.as_slice(), calls.as_slice()), (&b"hello"[..], &[(5, 0)][..]));
+        let err = read_body_to_eof(std::io::Cursor::new(b"hello!".to_vec()), 5, None, |_, _| {}).unwrap_err();
+        assert!(matches!(err, Error::BodyTooLarge { limit: 5, length: 6 }), "{:?}", err);
+
+        let timeout = Some(std::time::Duration::from_millis(50));
+        assert_eq!(read_body(std::io::Cursor::new(b"hello".to_vec()), 5, timeout, |_, _| {}).unwrap(), b"hello");
+        let err = read_body(std::io::Cursor::new(b"hi".to_vec()), 5, timeout, |_, _| {}).unwrap_err();
+        assert_eq!(err.status(), 400);
+        assert_eq!(read_body_up_to(std::io::Cursor::new(b"hi".to_vec()), 5, Some(5), timeout, |_, _| {}).unwrap(), b"hi");
+
+        // A client which sends part of the body, then stalls
+        struct Stalled(bool);
+        impl Read for Stalled {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if std::mem::replace(&mut self.0, true) {
+                    std::thread::sleep(std::time::Duration::from_secs(10));
+                }
+                buf[0] = b'x';
+                Ok(1)
+            }
+        }
+        let err = read_body(Stalled(false), 5, timeout, |_, _| {}).unwrap_err();
+        assert!(matches!(err, Error::Timeout));
+        assert_eq!(err.status(), 408);
+    }
+
+    #[test]
+    fn test_nph() {
+        let vars = |script: &str, protocol: &str, expect: &str| env(vec![("SCRIPT_NAME", script), ("SERVER_PROTOCOL", protocol), ("HTTP_EXPECT", expect)]);
+        assert_eq!(nph_protocol(&vars("/cgi-bin/nph-upload", "HTTP/1.1", "")), Some("HTTP/1.1".to_owned()));
+        assert_eq!(nph_protocol(&vars("/cgi-bin/nph-upload", "INCLUDED", "")), Some("HTTP/1.0".to_owned()));
+        assert_eq!(nph_protocol(&vars("/nph-dir/upload", "HTTP/1.1", "")), None);
+        assert!(expects_continue(&vars("/nph-upload", "HTTP/1.1", "100-Continue")));
+        assert!(!expects_continue(&vars("/nph-upload", "HTTP/1.0", "100-continue")));
+
+        let mut response = text_response(413, "Too large");
+        response.headers_mut().insert(http::header::DATE, "Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap());
+        response.extensions_mut().insert(Nph("HTTP/1.1".into()));
+        assert_eq!(String::from_utf8(serialize_response(response)).unwrap(),
+            "HTTP/1.1 413 Payload Too Large\r\ncontent-length: 9\r\ncontent-type: text/plain; charset=utf-8\r\ndate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\nToo large");
+
+        let mut response = text_response(200, "");
+        response.extensions_mut().insert(Nph("HTTP/1.0".into()));
+        let output = String::from_utf8(serialize_response(response)).unwrap();
+        assert!(output.starts_with("HTTP/1.0 200 OK\r\ncontent-length: 0\r\n") && output.contains("\r\ndate: ") && output.ends_with(" GMT\r\n\r\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_response_writer() {
+        #[derive(Clone, Default)]
+        struct Shared(std::rc::Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let written = |nph: Option<&str>, head_only: bool| {
+            let out = Shared::default();
+            let mut writer = ResponseWriter::new(Box::new(out.clone()), nph.map(str::to_owned), head_only);
+            assert!(writer.write_all(b"early").is_err());
+            let mut head = html_response(200, "<p>");
+            head.headers_mut().insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("text/html"));
+            head.headers_mut().insert(http::header::DATE, http::HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"));
+            writer.send_head(head).unwrap();
+            assert!(writer.is_started() && writer.send_head(empty_response(200)).is_err());
+            writer.write_all(b"done").unwrap();
+            writer.finish().unwrap();
+            let output = out.0.borrow().clone();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(written(None, false), "Status: 200 OK\ncontent-type: text/html\ndate: Sun, 06 Nov 1994 08:49:37 GMT\n\n<p>done");
+        assert_eq!(written(Some("HTTP/1.1"), false), "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ndate: Sun, 06 Nov 1994 08:49:37 GMT\r\ntransfer-encoding: chunked\r\n\r\n3\r\n<p>\r\n4\r\ndone\r\n0\r\n\r\n");
+        assert_eq!(written(Some("HTTP/1.0"), true), "HTTP/1.0 200 OK\r\ncontent-type: text/html\r\ndate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n");
+    }
+
+    #[test]
+    fn test_write_all_vectored() {
+        // Takes at most 3 bytes at a time, from the first two buffers
+        struct Slow(Vec<u8>);
+        impl Write for Slow {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(3);
+                self.0.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+            fn write_vectored(&mut self, bufs: &[std::io::IoSlice]) -> std::io::Result<usize> {
+                let mut written = 0;
+                for buf in bufs.iter().take(2) {
+                    written += self.write(&buf[..buf.len().min(3 - written)])?;
+                }
+                Ok(written)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut out = Slow(Vec::new());
+        write_all_vectored(&mut out, &mut [std::io::IoSlice::new(b""), std::io::IoSlice::new(b"head\n"), std::io::IoSlice::new(b"body")]).unwrap();
+        assert_eq!(out.0, b"head\nbody");
+
+        let (head, body) = serialize_parts(text_response(200, "hi"));
+        assert_eq!((head.as_slice(), body.as_slice()), (&b"Status: 200 OK\ncontent-length: 2\ncontent-type: text/plain; charset=utf-8\n\n"[..], &b"hi"[..]));
+    }
+
+    #[test]
+    fn test_nph_chunked() {
+        let streamed = |protocol: &str, status: u16| {
+            let mut response = stream::streaming_response(status, "text/plain", |_| Ok(()));
+            response.extensions_mut().insert(Nph(protocol.into()));
+            let chunked = nph_chunked(&mut response);
+            (chunked, response.headers().get(http::header::TRANSFER_ENCODING).cloned())
+        };
+        assert_eq!(streamed("HTTP/1.1", 200), (true, Some(http::HeaderValue::from_static("chunked"))));
+        assert_eq!(streamed("HTTP/1.0", 200), (false, None));
+        assert_eq!(streamed("HTTP/1.1", 304), (false, None));
+        let mut response = text_response(200, "known length");
+        response.extensions_mut().insert(Nph("HTTP/1.1".into()));
+        assert!(!nph_chunked(&mut response));
+    }
+
+    #[test]
+    fn test_local_redirect() {
+        let mut response = local_redirect("/other/script?a=1");
+        response.headers_mut().insert("x-ignored", "1".parse().unwrap());
+        *response.body_mut() = b"ignored".to_vec();
+        assert_eq!(serialize_response(response), b"Location: /other/script?a=1\n\n");
+        assert_eq!(local_redirect("https://example.com/").status(), 500);
+        assert_eq!(local_redirect("//example.com/").status(), 500);
+        assert_eq!(local_redirect("/a\nb").status(), 500);
+    }
+
+    #[test]
+    fn test_sendfile_response() {
+        let response = sendfile_response("/srv/files/report.pdf");
+        assert_eq!(response.headers()["x-sendfile"], "/srv/files/report.pdf");
+        assert_eq!(response.headers()["content-type"], "application/pdf");
+        assert!(response.body().is_empty());
+
+        set_sendfile_header(SendfileHeader::XAccelRedirect);
+        let response = sendfile_response("/protected/report.pdf");
+        set_sendfile_header(SendfileHeader::XSendfile);
+        assert_eq!(response.headers()["x-accel-redirect"], "/protected/report.pdf");
+        assert!(!response.headers().contains_key("x-sendfile"));
+        assert_eq!(sendfile_response("/tmp/a\nb").status(), 500);
+    }
+
+    #[test]
+    fn test_serialized_response_crlf() {
+        test_serialized_response(
+            http::Response::builder().status(200).header("Content-Type", "text/plain").extension(LineEnding::CrLf),
+            "Hello\nWorld",
+            "Status: 200 OK\r\ncontent-type: text/plain\r\n\r\nHello\nWorld"
+        );
+    }
+
     #[test]
     fn test_serialized_response1() {
         test_serialized_response(
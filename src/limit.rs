@@ -0,0 +1,126 @@
+//! Limit how many copies of this CGI programme run at the same time.
+//!
+//! Every request is a new process, so a burst of traffic can start hundreds of them.
+//! [`ConcurrencyLimit`] keeps a directory of lock files, one per allowed instance. A process
+//! which can't lock any of them answers `503 Service Unavailable` with a `Retry-After`
+//! header straight away, without calling the handler. Locks are released by the OS when the
+//! process exits, so a crashed instance never leaves a slot taken.
+//!
+//! ```rust,no_run
+//! use cgi::limit::ConcurrencyLimit;
+//!
+//! fn main() {
+//!     let limit = ConcurrencyLimit::new("/tmp/my-cgi-slots", 8);
+//!     cgi::handle(|request: cgi::Request| limit.handle(request, |request| {
+//!         cgi::text_response(200, "Hello World")
+//!     }))
+//! }
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+
+use crate::{empty_response, Request, Response};
+
+/// Allow at most `max` instances at once. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    dir: PathBuf,
+    max: usize,
+    retry_after: u64,
+}
+
+/// A taken slot, released when dropped (or when the process exits).
+#[derive(Debug)]
+pub struct Slot {
+    _file: File,
+    index: usize,
+}
+
+impl Slot {
+    /// Which slot this is, from `0` to `max - 1`
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Lock files are kept in `dir`, which is created if needed.
+    pub fn new(dir: impl Into<PathBuf>, max: usize) -> Self {
+        ConcurrencyLimit { dir: dir.into(), max, retry_after: 1 }
+    }
+
+    /// The `Retry-After` (in seconds) sent when over the limit, default 1
+    pub fn retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = seconds;
+        self
+    }
+
+    /// Try to take a free slot. `Ok(None)` when they're all in use.
+    pub fn acquire(&self) -> std::io::Result<Option<Slot>> {
+        std::fs::create_dir_all(&self.dir)?;
+        // Start at a different slot in each process, so they don't all contend on slot 0
+        let start = std::process::id() as usize % self.max.max(1);
+        for i in 0..self.max {
+            let index = (start + i) % self.max;
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(self.dir.join(format!("slot-{}.lock", index)))?;
+            if file.try_lock().is_ok() {
+                return Ok(Some(Slot { _file: file, index }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Call `next` with the request if a slot is free, otherwise return a `503`.
+    ///
+    /// If the lock directory can't be used, the error is printed to stderr and the request is
+    /// handled anyway, rather than taking the whole site down.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        match self.acquire() {
+            Ok(Some(_slot)) => next(request),
+            Ok(None) => {
+                let mut response = empty_response(503);
+                response.headers_mut().insert(http::header::RETRY_AFTER, self.retry_after.into());
+                response
+            }
+            Err(err) => {
+                eprintln!("Could not use lock directory {:?}: {}", self.dir, err);
+                next(request)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let dir = std::env::temp_dir().join(format!("cgi-limit-{}", std::process::id()));
+        let limit = ConcurrencyLimit::new(&dir, 2).retry_after(5);
+
+        let first = limit.acquire().unwrap().unwrap();
+        let second = limit.acquire().unwrap().unwrap();
+        assert_ne!(first.index(), second.index());
+        assert!(limit.acquire().unwrap().is_none());
+
+        let req = http::Request::builder().body(vec![]).unwrap();
+        let resp = limit.handle(req, |_| empty_response(200));
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers()[http::header::RETRY_AFTER], "5");
+
+        drop(first);
+        let req = http::Request::builder().body(vec![]).unwrap();
+        assert_eq!(limit.handle(req, |_| empty_response(200)).status(), 200);
+
+        drop(second);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
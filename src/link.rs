@@ -0,0 +1,256 @@
+//! The `Link` header (RFC 8288): relations to other resources.
+//!
+//! Build links for preloading, canonical URLs and pagination, and parse the links a client
+//! sent.
+//!
+//! ```rust
+//! use cgi::link::{self, Link};
+//!
+//! let mut response = cgi::html_response(200, "<h1>Page 2</h1>");
+//! link::add_links(&mut response, &[
+//!     Link::preload("/style.css", "style"),
+//!     Link::new("/articles?page=3").rel("next"),
+//!     Link::canonical("https://example.com/articles?page=2"),
+//! ]).unwrap();
+//! assert_eq!(response.headers()["link"],
+//!     "</style.css>; rel=preload; as=style, </articles?page=3>; rel=next, <https://example.com/articles?page=2>; rel=canonical");
+//! ```
+
+use std::fmt;
+
+use crate::Response;
+
+/// One link: a target URI with parameters like `rel`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub target: String,
+    /// Parameter names are lowercase. The value of `rel` can be several space separated
+    /// relation types.
+    pub params: Vec<(String, String)>,
+}
+
+impl Link {
+    pub fn new(target: impl Into<String>) -> Self {
+        Link { target: target.into(), params: Vec::new() }
+    }
+
+    /// Add a parameter
+    pub fn param(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.params.push((name.to_ascii_lowercase(), value.into()));
+        self
+    }
+
+    /// Add a relation type (e.g. `next`)
+    pub fn rel(mut self, rel: &str) -> Self {
+        match self.params.iter_mut().find(|(name, _)| name == "rel") {
+            Some((_, value)) => {
+                value.push(' ');
+                value.push_str(rel);
+            }
+            None => self.params.push(("rel".into(), rel.into())),
+        }
+        self
+    }
+
+    /// `rel=preload` with the destination (`as`), e.g. `style`, `script`, `font` or `image`.
+    /// Fonts are fetched in CORS mode, so they also get `crossorigin`.
+    pub fn preload(target: impl Into<String>, destination: &str) -> Self {
+        let link = Link::new(target).rel("preload").param("as", destination);
+        if destination == "font" { link.param("crossorigin", "anonymous") } else { link }
+    }
+
+    /// `rel=prefetch`, for a resource the next page will probably need
+    pub fn prefetch(target: impl Into<String>) -> Self {
+        Link::new(target).rel("prefetch")
+    }
+
+    /// `rel=canonical`, the preferred URL of this page
+    pub fn canonical(target: impl Into<String>) -> Self {
+        Link::new(target).rel("canonical")
+    }
+
+    /// The value of a parameter
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the link has the relation type `rel` (compared case-insensitively)
+    pub fn has_rel(&self, rel: &str) -> bool {
+        self.get("rel").is_some_and(|rels| rels.split_whitespace().any(|r| r.eq_ignore_ascii_case(rel)))
+    }
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// A link which can't be sent: a parameter name isn't a token, or the target or a value has
+/// control characters (with the parameter name, or the target).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidLink(pub String);
+
+impl fmt::Display for InvalidLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid link {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidLink {}
+
+/// `<target>; name=value; name="quoted value"`
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // `<` & `>` can't appear in a URI reference, so these would have been invalid anyway
+        write!(f, "<{}>", self.target.replace(['<', '>'], ""))?;
+        for (name, value) in &self.params {
+            if is_token(value) {
+                write!(f, "; {}={}", name, value)?;
+            } else {
+                write!(f, "; {}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The value of a `Link` header with all of `links`
+pub fn link_header(links: &[Link]) -> String {
+    links.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Append `links` to the response in one `Link` header. If any of them is invalid, nothing is
+/// added.
+pub fn add_links(response: &mut Response, links: &[Link]) -> Result<(), InvalidLink> {
+    if links.is_empty() {
+        return Ok(());
+    }
+    for link in links {
+        if link.target.chars().any(char::is_control) {
+            return Err(InvalidLink(link.target.clone()));
+        }
+        if let Some((name, _)) = link.params.iter().find(|(name, value)| !is_token(name) || value.chars().any(char::is_control)) {
+            return Err(InvalidLink(name.clone()));
+        }
+    }
+    let value = link_header(links).parse().expect("links without control characters");
+    response.headers_mut().append(http::header::LINK, value);
+    Ok(())
+}
+
+/// Parse the value of a `Link` header. Invalid links are skipped.
+pub fn parse(value: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return links;
+        }
+        let parsed = rest.strip_prefix('<')
+            .and_then(|r| r.split_once('>'))
+            .map(|(target, after)| (Link::new(target.trim()), after));
+        let (mut link, mut after) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                // Skip to the next link
+                rest = skip_past_comma(rest);
+                continue;
+            }
+        };
+
+        // Parameters
+        loop {
+            after = after.trim_start();
+            let Some(param) = after.strip_prefix(';') else { break };
+            let param = param.trim_start();
+            let name_end = param.find(|c: char| c == '=' || c == ';' || c == ',' || c.is_whitespace()).unwrap_or(param.len());
+            let name = param[..name_end].to_ascii_lowercase();
+            let mut value_rest = param[name_end..].trim_start();
+            let mut value = String::new();
+            if let Some(v) = value_rest.strip_prefix('=') {
+                let v = v.trim_start();
+                if let Some(quoted) = v.strip_prefix('"') {
+                    let mut chars = quoted.char_indices();
+                    let mut end = quoted.len();
+                    while let Some((i, c)) = chars.next() {
+                        match c {
+                            '\\' => if let Some((_, c)) = chars.next() { value.push(c) },
+                            '"' => { end = i + 1; break }
+                            c => value.push(c),
+                        }
+                    }
+                    value_rest = &quoted[end..];
+                } else {
+                    let end = v.find([';', ',']).unwrap_or(v.len());
+                    value = v[..end].trim().to_owned();
+                    value_rest = &v[end..];
+                }
+            }
+            // Only the first occurrence of `rel` counts
+            let duplicate_rel = name == "rel" && link.get("rel").is_some();
+            if !name.is_empty() && !duplicate_rel {
+                link.params.push((name, value));
+            }
+            after = value_rest;
+        }
+        links.push(link);
+        rest = after;
+    }
+}
+
+// After the next `,` which isn't in a quoted string
+fn skip_past_comma(s: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => return &s[i + 1..],
+            _ => {}
+        }
+    }
+    ""
+}
+
+/// All links in the `Link` headers of a request (or response)
+pub fn links(headers: &http::HeaderMap) -> Vec<Link> {
+    headers.get_all(http::header::LINK).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(Link::new("/a").rel("next").rel("prefetch").param("title", "Next \"page\"").to_string(),
+            "</a>; rel=\"next prefetch\"; title=\"Next \\\"page\\\"\"");
+        assert_eq!(Link::preload("/f.woff2", "font").to_string(), "</f.woff2>; rel=preload; as=font; crossorigin=anonymous");
+        let mut response = crate::empty_response(200);
+        add_links(&mut response, &[]).unwrap();
+        assert!(!response.headers().contains_key("link"));
+        assert_eq!(add_links(&mut response, &[Link::new("/a").param("a b", "c")]), Err(InvalidLink("a b".into())));
+        assert_eq!(add_links(&mut response, &[Link::new("/a").param("title", "a\r\nb")]), Err(InvalidLink("title".into())));
+        assert_eq!(add_links(&mut response, &[Link::new("/a\nb")]), Err(InvalidLink("/a\nb".into())));
+        assert!(!response.headers().contains_key("link"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = parse("<https://example.com/a,b>; rel=\"next last\"; title=\"a, \\\"b\\\"\", </c> ;REL=prev;rel=ignored;hreflang=de, garbage; x=\",\", </d>");
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].target, "https://example.com/a,b");
+        assert!(parsed[0].has_rel("LAST"));
+        assert_eq!(parsed[0].get("title"), Some("a, \"b\""));
+        assert_eq!(parsed[1].params, vec![("rel".to_owned(), "prev".to_owned()), ("hreflang".to_owned(), "de".to_owned())]);
+        assert_eq!(parsed[2], Link::new("/d"));
+
+        let links = [Link::preload("/x.js", "script"), Link::new("/y").param("title", "Why; not?")];
+        assert_eq!(parse(&link_header(&links)), links);
+    }
+}
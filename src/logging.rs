@@ -0,0 +1,260 @@
+//! A stderr logger for the [`log`](https://docs.rs/log) crate (`logging` feature).
+//!
+//! A CGI programme's stderr ends up in the web server's error log, mixed with everything else
+//! the server logs. With this feature, [`handle`](crate::handle) (and FastCGI, SCGI & the dev
+//! server) installs a logger before the handler runs, unless the programme has set another
+//! one, and every line says when, which script and which request:
+//!
+//! ```text
+//! 2026-10-16T11:21:52Z WARN /cgi-bin/app.cgi [4f2a9c] my_app::db: slow query (812 ms)
+//! ```
+//!
+//! The request ID is the [`RequestId`](crate::meta::RequestId). Which messages are logged is set
+//! with `RUST_LOG` like for `env_logger`: a level (`info`), `target=level` directives
+//! (`my_app=debug`), or both separated by commas (`warn,my_app::db=trace`). By default, only
+//! errors are logged.
+//!
+//! For log collectors like Loki or Elasticsearch, set `CGI_LOG_FORMAT=json` (or use
+//! [`CgiLogger::format`] with [`init_with`]) to write one JSON object per line, with the
+//! request's method, path and client address too:
+//!
+//! ```text
+//! {"time":"2026-10-16T11:21:52Z","level":"WARN","target":"my_app::db","message":"slow query (812 ms)","script":"/cgi-bin/app.cgi","request_id":"4f2a9c","method":"GET","path":"/cgi-bin/app.cgi/users","remote_addr":"192.0.2.1"}
+//! ```
+//!
+//! ```rust,no_run
+//! cgi::handle(|request: cgi::Request| {
+//!     log::info!("{} {}", request.method(), request.uri());
+//!     "Hello World"
+//! })
+//! ```
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Once;
+use std::time::SystemTime;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::meta::{CgiMeta, RemoteAddr, RequestId};
+use crate::reporting::json_string;
+
+thread_local! {
+    // The request being handled
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Context {
+    script: String,
+    request_id: String,
+    method: String,
+    path: String,
+    remote_addr: Option<String>,
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `time LEVEL script [request ID] target: message`
+    #[default]
+    Text,
+    /// A JSON object with `time`, `level`, `target`, `message` and, while a request is
+    /// handled, `script`, `request_id`, `method`, `path` & `remote_addr`
+    Json,
+}
+
+/// The logger installed by [`init`], configured from `RUST_LOG` & `CGI_LOG_FORMAT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CgiLogger {
+    default: LevelFilter,
+    // Target prefix and its level, longest prefix first
+    directives: Vec<(String, LevelFilter)>,
+    format: LogFormat,
+}
+
+impl CgiLogger {
+    /// A logger for the directives in `RUST_LOG`, writing JSON if `CGI_LOG_FORMAT` is `json`
+    pub fn from_env() -> Self {
+        let format = match std::env::var("CGI_LOG_FORMAT") {
+            Ok(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        };
+        CgiLogger::parse(&std::env::var("RUST_LOG").unwrap_or_default()).format(format)
+    }
+
+    /// A logger for directives like those in `RUST_LOG`; invalid ones are ignored
+    pub fn parse(spec: &str) -> Self {
+        let mut logger = CgiLogger { default: LevelFilter::Error, directives: Vec::new(), format: LogFormat::Text };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => if let Ok(level) = LevelFilter::from_str(level.trim()) {
+                    logger.directives.push((target.trim().to_owned(), level));
+                },
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => logger.default = level,
+                    Err(_) => logger.directives.push((directive.to_owned(), LevelFilter::Trace)),
+                },
+            }
+        }
+        logger.directives.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        logger
+    }
+
+    /// Write lines in this format
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// The most verbose level of any target
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.directives.iter()
+            .find(|(prefix, _)| target == prefix || target.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with("::")))
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+impl Log for CgiLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // One write per line, so lines of several processes don't interleave
+            let line = CONTEXT.with(|context| format_line(self.format, SystemTime::now(), context.borrow().as_ref(), record));
+            let _ = std::io::stderr().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+fn format_line(format: LogFormat, time: SystemTime, context: Option<&Context>, record: &Record) -> String {
+    let time = crate::date::timestamp(time);
+    match (format, context) {
+        (LogFormat::Text, Some(context)) => format!("{} {} {} [{}] {}: {}\n",
+            time, record.level(), context.script, context.request_id, record.target(), record.args()),
+        (LogFormat::Text, None) => format!("{} {} {}: {}\n", time, record.level(), record.target(), record.args()),
+        (LogFormat::Json, context) => {
+            let mut line = format!("{{\"time\":\"{}\",\"level\":\"{}\",\"target\":{},\"message\":{}",
+                time, record.level(), json_string(record.target()), json_string(&record.args().to_string()));
+            if let Some(context) = context {
+                line.push_str(&format!(",\"script\":{},\"request_id\":{},\"method\":{},\"path\":{}",
+                    json_string(&context.script), json_string(&context.request_id), json_string(&context.method), json_string(&context.path)));
+                if let Some(addr) = &context.remote_addr {
+                    line.push_str(&format!(",\"remote_addr\":{}", json_string(addr)));
+                }
+            }
+            line.push_str("}\n");
+            line
+        }
+    }
+}
+
+/// Install a [`CgiLogger`] from `RUST_LOG` & `CGI_LOG_FORMAT`, unless another logger is set.
+/// `handle` & co do this before the handler runs, so it's only needed to log before that.
+pub fn init() {
+    init_with(CgiLogger::from_env());
+}
+
+/// Install `logger`, unless another logger is set. Call it before `handle` to configure the
+/// logger in code.
+///
+/// ```rust,no_run
+/// use cgi::logging::{CgiLogger, LogFormat};
+///
+/// cgi::logging::init_with(CgiLogger::parse("info").format(LogFormat::Json));
+/// ```
+pub fn init_with(logger: CgiLogger) {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let max_level = logger.max_level();
+        if log::set_boxed_logger(Box::new(logger)).is_ok() {
+            log::set_max_level(max_level);
+        }
+    });
+}
+
+// Clears the context of a request when it's handled
+pub(crate) struct RequestContext(());
+
+impl Drop for RequestContext {
+    fn drop(&mut self) {
+        CONTEXT.with(|context| context.borrow_mut().take());
+    }
+}
+
+pub(crate) fn start_request<B>(request: &http::Request<B>) -> RequestContext {
+    init();
+    let context = Context {
+        script: request.extensions().get::<CgiMeta>().map_or_else(|| request.uri().path().to_owned(), |meta| meta.script_name.clone()),
+        request_id: request.extensions().get::<RequestId>().map_or_else(|| "-".to_owned(), |id| id.0.clone()),
+        method: request.method().to_string(),
+        path: request.uri().path().to_owned(),
+        remote_addr: request.extensions().get::<RemoteAddr>().map(|addr| addr.0.to_string()),
+    };
+    CONTEXT.with(|c| *c.borrow_mut() = Some(context));
+    RequestContext(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_levels() {
+        let logger = CgiLogger::parse("warn, my_app=debug,my_app::db=trace,nonsense=loud");
+        assert_eq!(logger.level("other"), LevelFilter::Warn);
+        assert_eq!(logger.level("my_app"), LevelFilter::Debug);
+        assert_eq!(logger.level("my_app::http"), LevelFilter::Debug);
+        assert_eq!(logger.level("my_app::db::pool"), LevelFilter::Trace);
+        assert_eq!(logger.level("my_apple"), LevelFilter::Warn);
+        assert_eq!(logger.max_level(), LevelFilter::Trace);
+        assert_eq!(CgiLogger::parse("").max_level(), LevelFilter::Error);
+        assert_eq!(CgiLogger::parse("my_app").level("my_app"), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_format_line() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        let line = |format, context| format_line(format, time, context, &Record::builder()
+            .level(log::Level::Warn)
+            .target("my_app")
+            .args(format_args!("slow \"query\""))
+            .build());
+        assert_eq!(line(LogFormat::Text, None), "1994-11-06T08:49:37Z WARN my_app: slow \"query\"\n");
+        let context = Context {
+            script: "/cgi-bin/app.cgi".into(),
+            request_id: "4f2a9c".into(),
+            method: "GET".into(),
+            path: "/cgi-bin/app.cgi/users".into(),
+            remote_addr: Some("192.0.2.1".into()),
+        };
+        assert_eq!(line(LogFormat::Text, Some(&context)), "1994-11-06T08:49:37Z WARN /cgi-bin/app.cgi [4f2a9c] my_app: slow \"query\"\n");
+        assert_eq!(line(LogFormat::Json, None),
+            "{\"time\":\"1994-11-06T08:49:37Z\",\"level\":\"WARN\",\"target\":\"my_app\",\"message\":\"slow \\\"query\\\"\"}\n");
+        assert!(line(LogFormat::Json, Some(&context)).ends_with(
+            ",\"script\":\"/cgi-bin/app.cgi\",\"request_id\":\"4f2a9c\",\"method\":\"GET\",\"path\":\"/cgi-bin/app.cgi/users\",\"remote_addr\":\"192.0.2.1\"}\n"));
+    }
+
+    #[test]
+    fn test_start_request() {
+        let request = crate::testing::CgiRequestBuilder::new().header("X-Request-Id", "abc").build();
+        let context = start_request(&request);
+        let current = CONTEXT.with(|c| c.borrow().clone()).unwrap();
+        assert_eq!((current.script.as_str(), current.request_id.as_str()), ("/cgi-bin/test", "abc"));
+        assert_eq!((current.method.as_str(), current.remote_addr.as_deref()), ("GET", Some("127.0.0.1")));
+        drop(context);
+        assert_eq!(CONTEXT.with(|c| c.borrow().clone()), None);
+    }
+}
@@ -0,0 +1,448 @@
+//! Send email from a CGI programme (e.g. a contact form), via `sendmail` or SMTP.
+//!
+//! Requires the `mail` feature. Form input often ends up in email headers, so everything is
+//! checked before it's sent: addresses must be plain `local@domain` (optionally with a display
+//! name), and no header may contain a line break, so a visitor can't add their own `Bcc:`.
+//! Non-ASCII subjects & names are encoded (RFC 2047).
+//!
+//! ```rust,no_run
+//! use cgi::mail::{Message, Sendmail, Transport};
+//!
+//! # fn handle(name: &str, email: &str, text: &str) -> Result<(), cgi::mail::MailError> {
+//! let message = Message::new()
+//!     .from("Website <www@example.com>")
+//!     .to("contact@example.com")
+//!     .reply_to(email)
+//!     .subject(format!("Contact form: {}", name))
+//!     .body(text);
+//! Sendmail::new().send(&message)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Smtp`] is a minimal SMTP client, without TLS or authentication, meant for talking to a
+//! relay on the same machine or network.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use crate::{base64, date, random};
+
+/// Why an email couldn't be built or sent.
+#[derive(Debug)]
+pub enum MailError {
+    /// An address isn't a valid, plain email address
+    InvalidAddress(String),
+    /// A header name or value isn't allowed (e.g. contains a line break)
+    InvalidHeader(String),
+    /// There's no `From` or no recipient
+    MissingField(&'static str),
+    Io(io::Error),
+    /// `sendmail` exited unsuccessfully
+    Sendmail(std::process::ExitStatus),
+    /// The SMTP server replied with an error code
+    Smtp(u16, String),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MailError::InvalidAddress(a) => write!(f, "invalid email address {:?}", a),
+            MailError::InvalidHeader(h) => write!(f, "invalid email header {:?}", h),
+            MailError::MissingField(field) => write!(f, "email has no {}", field),
+            MailError::Io(e) => write!(f, "could not send email: {}", e),
+            MailError::Sendmail(status) => write!(f, "sendmail failed: {}", status),
+            MailError::Smtp(code, msg) => write!(f, "SMTP error {}: {}", code, msg),
+        }
+    }
+}
+
+impl std::error::Error for MailError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MailError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MailError {
+    fn from(e: io::Error) -> Self {
+        MailError::Io(e)
+    }
+}
+
+/// A plain text email.
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    from: Option<String>,
+    to: Vec<String>,
+    cc: Vec<String>,
+    bcc: Vec<String>,
+    reply_to: Option<String>,
+    subject: String,
+    body: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Message {
+    pub fn new() -> Self {
+        Message::default()
+    }
+
+    /// `addr@example.com` or `Name <addr@example.com>`
+    pub fn from(mut self, mailbox: impl Into<String>) -> Self {
+        self.from = Some(mailbox.into());
+        self
+    }
+
+    /// Add a recipient
+    pub fn to(mut self, mailbox: impl Into<String>) -> Self {
+        self.to.push(mailbox.into());
+        self
+    }
+
+    pub fn cc(mut self, mailbox: impl Into<String>) -> Self {
+        self.cc.push(mailbox.into());
+        self
+    }
+
+    /// Add a recipient which isn't shown in the headers
+    pub fn bcc(mut self, mailbox: impl Into<String>) -> Self {
+        self.bcc.push(mailbox.into());
+        self
+    }
+
+    pub fn reply_to(mut self, mailbox: impl Into<String>) -> Self {
+        self.reply_to = Some(mailbox.into());
+        self
+    }
+
+    pub fn subject(mut self, subject: impl Into<String>) -> Self {
+        self.subject = subject.into();
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Add another header, e.g. `X-Mailer`
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The envelope sender address
+    fn sender(&self) -> Result<String, MailError> {
+        Ok(Mailbox::parse(self.from.as_deref().ok_or(MailError::MissingField("From"))?)?.address)
+    }
+
+    /// All the envelope recipient addresses (To, Cc & Bcc)
+    fn recipients(&self) -> Result<Vec<String>, MailError> {
+        let recipients: Vec<String> = self.to.iter().chain(&self.cc).chain(&self.bcc)
+            .map(|m| Mailbox::parse(m).map(|m| m.address))
+            .collect::<Result<_, _>>()?;
+        if recipients.is_empty() {
+            return Err(MailError::MissingField("recipient"));
+        }
+        Ok(recipients)
+    }
+
+    /// The whole message (RFC 5322), with CRLF line endings
+    pub fn format(&self) -> Result<String, MailError> {
+        let from = Mailbox::parse(self.from.as_deref().ok_or(MailError::MissingField("From"))?)?;
+        self.recipients()?;
+
+        let mut out = String::new();
+        out.push_str(&format!("Date: {}\r\n", date::http_date(SystemTime::now()).replace("GMT", "+0000")));
+        out.push_str(&format!("From: {}\r\n", from.format()));
+        for (name, list) in [("To", &self.to), ("Cc", &self.cc)] {
+            if !list.is_empty() {
+                let mailboxes: Vec<String> = list.iter().map(|m| Mailbox::parse(m).map(|m| m.format())).collect::<Result<_, _>>()?;
+                out.push_str(&format!("{}: {}\r\n", name, mailboxes.join(",\r\n ")));
+            }
+        }
+        if let Some(reply_to) = &self.reply_to {
+            out.push_str(&format!("Reply-To: {}\r\n", Mailbox::parse(reply_to)?.format()));
+        }
+        check_header_value(&self.subject)?;
+        out.push_str(&format!("Subject: {}\r\n", encode_word(&self.subject)));
+        let domain = from.address.rsplit('@').next().unwrap_or("localhost");
+        out.push_str(&format!("Message-ID: <{}@{}>\r\n", random::hex(16), domain));
+        for (name, value) in &self.headers {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':') {
+                return Err(MailError::InvalidHeader(name.clone()));
+            }
+            check_header_value(value)?;
+            out.push_str(&format!("{}: {}\r\n", name, encode_word(value)));
+        }
+        out.push_str("MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n");
+
+        let body = self.body.replace("\r\n", "\n").replace('\r', "\n");
+        if body.is_ascii() {
+            out.push_str("Content-Transfer-Encoding: 7bit\r\n\r\n");
+            out.push_str(&body.replace('\n', "\r\n"));
+        } else {
+            out.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+            let encoded = base64::encode(body.replace('\n', "\r\n").as_bytes());
+            for line in encoded.as_bytes().chunks(76) {
+                out.push_str(std::str::from_utf8(line).unwrap());
+                out.push_str("\r\n");
+            }
+        }
+        if !out.ends_with("\r\n") {
+            out.push_str("\r\n");
+        }
+        Ok(out)
+    }
+}
+
+/// A way to send email.
+pub trait Transport {
+    fn send(&self, message: &Message) -> Result<(), MailError>;
+}
+
+/// Pipe the message to the local `sendmail` programme.
+#[derive(Debug, Clone)]
+pub struct Sendmail {
+    path: PathBuf,
+}
+
+impl Sendmail {
+    /// Use `/usr/sbin/sendmail`
+    pub fn new() -> Self {
+        Sendmail { path: "/usr/sbin/sendmail".into() }
+    }
+
+    pub fn with_path(path: impl Into<PathBuf>) -> Self {
+        Sendmail { path: path.into() }
+    }
+}
+
+impl Default for Sendmail {
+    fn default() -> Self {
+        Sendmail::new()
+    }
+}
+
+impl Transport for Sendmail {
+    fn send(&self, message: &Message) -> Result<(), MailError> {
+        let text = message.format()?;
+        // The addresses have been validated, so can't start with `-`, but the `--` makes sure
+        let mut child = Command::new(&self.path)
+            .arg("-i")
+            .arg("-f").arg(message.sender()?)
+            .arg("--")
+            .args(message.recipients()?)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().expect("stdin is piped").write_all(text.as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(MailError::Sendmail(status));
+        }
+        Ok(())
+    }
+}
+
+/// A minimal SMTP client, without TLS or authentication.
+#[derive(Debug, Clone)]
+pub struct Smtp {
+    addr: String,
+    helo: String,
+    timeout: Duration,
+}
+
+impl Smtp {
+    /// Connect to `addr`, e.g. `localhost:25`
+    pub fn new(addr: impl Into<String>) -> Self {
+        Smtp { addr: addr.into(), helo: "localhost".into(), timeout: Duration::from_secs(30) }
+    }
+
+    /// The name to send in `EHLO`, default `localhost`
+    pub fn helo(mut self, helo: impl Into<String>) -> Self {
+        self.helo = helo.into();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Transport for Smtp {
+    fn send(&self, message: &Message) -> Result<(), MailError> {
+        let text = message.format()?;
+        let stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut session = SmtpSession { reader: BufReader::new(stream.try_clone()?), writer: stream };
+
+        session.expect(220)?;
+        session.command(&format!("EHLO {}", self.helo), 250)?;
+        session.command(&format!("MAIL FROM:<{}>", message.sender()?), 250)?;
+        for recipient in message.recipients()? {
+            session.command(&format!("RCPT TO:<{}>", recipient), 250)?;
+        }
+        session.command("DATA", 354)?;
+        let mut data = String::with_capacity(text.len() + 5);
+        for line in text.split_inclusive("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+        }
+        data.push_str(".\r\n");
+        session.writer.write_all(data.as_bytes())?;
+        session.expect(250)?;
+        session.command("QUIT", 221)?;
+        Ok(())
+    }
+}
+
+struct SmtpSession {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl SmtpSession {
+    fn command(&mut self, command: &str, expected: u16) -> Result<(), MailError> {
+        self.writer.write_all(command.as_bytes())?;
+        self.writer.write_all(b"\r\n")?;
+        self.expect(expected)
+    }
+
+    // Read a (possibly multi-line) reply
+    fn expect(&mut self, expected: u16) -> Result<(), MailError> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let code: u16 = line.get(..3).and_then(|c| c.parse().ok())
+                .ok_or_else(|| MailError::Smtp(0, line.trim().to_owned()))?;
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            if code != expected {
+                return Err(MailError::Smtp(code, line.get(4..).unwrap_or("").trim().to_owned()));
+            }
+            return Ok(());
+        }
+    }
+}
+
+struct Mailbox {
+    name: Option<String>,
+    address: String,
+}
+
+impl Mailbox {
+    fn parse(s: &str) -> Result<Mailbox, MailError> {
+        let invalid = || MailError::InvalidAddress(s.to_owned());
+        let s = s.trim();
+        let (name, address) = match s.strip_suffix('>').and_then(|s| s.rsplit_once('<')) {
+            Some((name, address)) => {
+                let name = name.trim().trim_matches('"').trim();
+                (if name.is_empty() { None } else { Some(name.to_owned()) }, address.trim())
+            }
+            None => (None, s),
+        };
+        if let Some(name) = &name {
+            if name.chars().any(|c| c.is_control()) {
+                return Err(invalid());
+            }
+        }
+        let (local, domain) = address.rsplit_once('@').ok_or_else(invalid)?;
+        let valid_part = |part: &str| !part.is_empty()
+            && part.bytes().all(|b| b.is_ascii_graphic() && !b"<>()[]\\,;:\"@".contains(&b));
+        if !valid_part(local) || !valid_part(domain) || address.starts_with('-') {
+            return Err(invalid());
+        }
+        Ok(Mailbox { name, address: address.to_owned() })
+    }
+
+    fn format(&self) -> String {
+        match &self.name {
+            None => self.address.clone(),
+            Some(name) if name.is_ascii() => {
+                format!("\"{}\" <{}>", name.replace('\\', "\\\\").replace('"', "\\\""), self.address)
+            }
+            Some(name) => format!("{} <{}>", encode_word(name), self.address),
+        }
+    }
+}
+
+fn check_header_value(value: &str) -> Result<(), MailError> {
+    if value.chars().any(|c| c == '\r' || c == '\n' || c == '\0') {
+        return Err(MailError::InvalidHeader(value.to_owned()));
+    }
+    Ok(())
+}
+
+// RFC 2047 encoded words for non-ASCII header text, each at most 75 characters
+fn encode_word(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_owned();
+    }
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in text.chars() {
+        if chunk.len() + c.len_utf8() > 45 {
+            words.push(format!("=?utf-8?B?{}?=", base64::encode(chunk.as_bytes())));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    words.push(format!("=?utf-8?B?{}?=", base64::encode(chunk.as_bytes())));
+    words.join("\r\n ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let text = Message::new()
+            .from("Web Site <www@example.com>")
+            .to("a@example.com")
+            .to("b@example.com")
+            .bcc("hidden@example.com")
+            .reply_to("visitor@example.org")
+            .subject("Héllo")
+            .header("X-Form", "contact")
+            .body("Line 1\n.Line 2")
+            .format()
+            .unwrap();
+        assert!(text.contains("\r\nFrom: \"Web Site\" <www@example.com>\r\n"));
+        assert!(text.contains("\r\nTo: a@example.com,\r\n b@example.com\r\n"));
+        assert!(!text.contains("hidden"));
+        assert!(text.contains("\r\nReply-To: visitor@example.org\r\n"));
+        assert!(text.contains("\r\nSubject: =?utf-8?B?SMOpbGxv?=\r\n"));
+        assert!(text.contains("\r\nX-Form: contact\r\n"));
+        assert!(text.ends_with("\r\n\r\nLine 1\r\n.Line 2\r\n"));
+    }
+
+    #[test]
+    fn test_injection() {
+        let base = || Message::new().from("www@example.com").to("a@example.com");
+        assert!(matches!(base().subject("Hi\r\nBcc: victim@example.com").format(), Err(MailError::InvalidHeader(_))));
+        assert!(matches!(base().reply_to("x@example.com\nBcc: victim@example.com").format(), Err(MailError::InvalidAddress(_))));
+        assert!(matches!(base().reply_to("a@example.com, b@example.com").format(), Err(MailError::InvalidAddress(_))));
+        assert!(matches!(base().to("-oQ/tmp/@example.com").format(), Err(MailError::InvalidAddress(_))));
+        assert!(matches!(base().header("X-A: b", "c").format(), Err(MailError::InvalidHeader(_))));
+        assert!(matches!(Message::new().to("a@example.com").format(), Err(MailError::MissingField("From"))));
+        let text = base().body("a\r.\r\nb").format().unwrap();
+        assert!(text.ends_with("\r\n\r\na\r\n.\r\nb\r\n"));
+        assert!(!text.replace("\r\n", "").contains('\r'));
+        assert_eq!(base().bcc("c@example.com").recipients().unwrap(), ["a@example.com", "c@example.com"]);
+    }
+}
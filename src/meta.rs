@@ -0,0 +1,232 @@
+//! Typed CGI meta-variables.
+//!
+//! [`handle`](crate::handle) parses some meta-variables into types, which are in the
+//! request's extensions:
+//!
+//! ```rust
+//! use cgi::meta::RemoteAddr;
+//!
+//! let request = cgi::testing::CgiRequestBuilder::new().env("REMOTE_ADDR", "2001:db8::1").env("REMOTE_PORT", "51234").build();
+//! let remote = request.extensions().get::<RemoteAddr>().unwrap();
+//! assert_eq!(remote.to_string(), "[2001:db8::1]:51234");
+//! assert!(remote.ip().is_ipv6());
+//! ```
+//!
+//! The request also gets a [`RequestId`]. All meta-variables are in [`CgiMeta`]. They're also still passed as `X-CGI-` headers (e.g.
+//! `X-CGI-Server-Name`), but these are only strings.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+/// The meta-variables of RFC 3875, typed. A variable which isn't set (or can't be parsed) is
+/// `None`, an empty string, or `0` for `server_port`.
+///
+/// ```rust
+/// use cgi::meta::{AuthType, CgiMeta};
+///
+/// let request = cgi::testing::CgiRequestBuilder::new().env("AUTH_TYPE", "Basic").env("REMOTE_USER", "jo").build();
+/// let meta = request.extensions().get::<CgiMeta>().unwrap();
+/// assert_eq!(meta.auth_type, Some(AuthType::Basic));
+/// assert_eq!(meta.remote_user.as_deref(), Some("jo"));
+/// assert_eq!(meta.server_port, 80);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgiMeta {
+    pub auth_type: Option<AuthType>,
+    pub content_length: Option<u64>,
+    pub content_type: Option<String>,
+    pub gateway_interface: Option<String>,
+    /// `true` if `HTTPS` is `on` (or `1`)
+    pub https: bool,
+    pub path_info: String,
+    pub path_translated: Option<String>,
+    pub query_string: String,
+    pub remote_addr: Option<RemoteAddr>,
+    pub remote_host: Option<String>,
+    pub remote_ident: Option<String>,
+    pub remote_user: Option<String>,
+    pub request_method: http::Method,
+    pub request_scheme: Option<String>,
+    pub script_name: String,
+    pub server_name: String,
+    pub server_port: u16,
+    pub server_protocol: Option<http::Version>,
+    pub server_software: Option<String>,
+}
+
+impl CgiMeta {
+    // From the meta-variables; non-UTF-8 values are converted lossily
+    pub(crate) fn from_vars<V: AsRef<[u8]>>(env_vars: &HashMap<String, V>) -> Self {
+        let var = |name: &str| env_vars.get(name).map(|v| String::from_utf8_lossy(v.as_ref()).into_owned());
+        let non_empty = |name: &str| var(name).filter(|v| !v.is_empty());
+        CgiMeta {
+            auth_type: non_empty("AUTH_TYPE").map(|t| AuthType::parse(&t)),
+            content_length: var("CONTENT_LENGTH").and_then(|cl| cl.trim().parse().ok()),
+            content_type: non_empty("CONTENT_TYPE"),
+            gateway_interface: non_empty("GATEWAY_INTERFACE"),
+            https: var("HTTPS").is_some_and(|v| v.eq_ignore_ascii_case("on") || v == "1"),
+            path_info: var("PATH_INFO").unwrap_or_default(),
+            path_translated: non_empty("PATH_TRANSLATED"),
+            query_string: var("QUERY_STRING").unwrap_or_default(),
+            remote_addr: var("REMOTE_ADDR").and_then(|addr| RemoteAddr::parse(&addr, var("REMOTE_PORT").as_deref())),
+            remote_host: non_empty("REMOTE_HOST"),
+            remote_ident: non_empty("REMOTE_IDENT"),
+            remote_user: non_empty("REMOTE_USER"),
+            request_method: env_vars.get("REQUEST_METHOD").and_then(|m| http::Method::from_bytes(m.as_ref()).ok()).unwrap_or_default(),
+            request_scheme: non_empty("REQUEST_SCHEME"),
+            script_name: var("SCRIPT_NAME").unwrap_or_default(),
+            server_name: var("SERVER_NAME").unwrap_or_default(),
+            server_port: var("SERVER_PORT").and_then(|p| p.trim().parse().ok()).unwrap_or(0),
+            server_protocol: var("SERVER_PROTOCOL").and_then(|p| match p.as_str() {
+                "HTTP/0.9" => Some(http::Version::HTTP_09),
+                "HTTP/1.0" => Some(http::Version::HTTP_10),
+                "HTTP/1.1" => Some(http::Version::HTTP_11),
+                "HTTP/2.0" => Some(http::Version::HTTP_2),
+                _ => None,
+            }),
+            server_software: non_empty("SERVER_SOFTWARE"),
+        }
+    }
+}
+
+/// How the web server authenticated the user, from `AUTH_TYPE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthType {
+    Basic,
+    Digest,
+    /// Another scheme, as the server named it
+    Other(String),
+}
+
+impl AuthType {
+    fn parse(auth_type: &str) -> Self {
+        if auth_type.eq_ignore_ascii_case("basic") {
+            AuthType::Basic
+        } else if auth_type.eq_ignore_ascii_case("digest") {
+            AuthType::Digest
+        } else {
+            AuthType::Other(auth_type.to_owned())
+        }
+    }
+}
+
+/// The client's address, from `REMOTE_ADDR` & `REMOTE_PORT` (which not every server sets).
+///
+/// Brackets around an IPv6 address and a zone index (`fe80::1%eth0`) are removed, and an
+/// IPv4-mapped IPv6 address (`::ffff:192.0.2.1`) becomes the IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RemoteAddr(pub IpAddr, pub Option<u16>);
+
+impl RemoteAddr {
+    /// Parse the values of `REMOTE_ADDR` & `REMOTE_PORT`. `None` if the address isn't an IP
+    /// address; an invalid port is left out.
+    pub fn parse(addr: &str, port: Option<&str>) -> Option<Self> {
+        let addr = addr.trim();
+        let addr = addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')).unwrap_or(addr);
+        let addr = addr.split_once('%').map_or(addr, |(a, _zone)| a);
+        let ip = addr.parse::<IpAddr>().ok()?.to_canonical();
+        Some(RemoteAddr(ip, port.and_then(|p| p.trim().parse().ok())))
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.0
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.1
+    }
+
+    /// The address with the port, if it's known
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.1.map(|port| SocketAddr::new(self.0, port))
+    }
+}
+
+/// The IP address, with the port if it's known (`192.0.2.1:80`, `[2001:db8::1]:80`)
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.socket_addr() {
+            Some(addr) => addr.fmt(f),
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+/// An ID of the request, to find what was logged about it. It's the `X-Request-Id` header of
+/// the request (e.g. set by a proxy), otherwise Apache's `UNIQUE_ID` (from `mod_unique_id`,
+/// which the access log can show as `%{UNIQUE_ID}e`), otherwise 16 random hex digits. An
+/// `X-Request-Id` which is empty, longer than 200 characters or not visible ASCII is
+/// replaced.
+///
+/// ```rust
+/// use cgi::meta::RequestId;
+///
+/// let request = cgi::testing::CgiRequestBuilder::new().header("X-Request-Id", "f81d4fae").build();
+/// assert_eq!(request.extensions().get::<RequestId>().unwrap().as_str(), "f81d4fae");
+/// ```
+///
+/// With [`set_echo_request_id`](crate::set_echo_request_id), responses have the ID in their
+/// `X-Request-Id` header too.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub(crate) fn from_vars<V: AsRef<[u8]>>(env_vars: &HashMap<String, V>) -> Self {
+        let valid = |id: &&[u8]| !id.is_empty() && id.len() <= 200 && id.iter().all(u8::is_ascii_graphic);
+        let id = ["HTTP_X_REQUEST_ID", "UNIQUE_ID"].iter()
+            .find_map(|name| env_vars.get(*name).map(|v| v.as_ref()).filter(valid))
+            .map_or_else(|| crate::random::hex(8), |id| String::from_utf8_lossy(id).into_owned());
+        RequestId(id)
+    }
+
+    /// The ID
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cgi_meta() {
+        let env_vars: HashMap<String, &str> = [
+            ("AUTH_TYPE", "NTLM"), ("CONTENT_LENGTH", "12"), ("HTTPS", "on"), ("REQUEST_METHOD", "PUT"),
+            ("SERVER_PORT", "8443"), ("SERVER_PROTOCOL", "HTTP/1.0"), ("REMOTE_ADDR", "192.0.2.1"),
+        ].into_iter().map(|(k, v)| (k.to_owned(), v)).collect();
+        let meta = CgiMeta::from_vars(&env_vars);
+        assert_eq!(meta.auth_type, Some(AuthType::Other("NTLM".into())));
+        assert_eq!((meta.content_length, meta.https, meta.server_port), (Some(12), true, 8443));
+        assert_eq!((meta.request_method, meta.server_protocol), (http::Method::PUT, Some(http::Version::HTTP_10)));
+        assert_eq!(meta.remote_addr.map(|r| r.ip()), Some(IpAddr::from([192, 0, 2, 1])));
+        assert_eq!((meta.path_info.as_str(), meta.content_type), ("", None));
+    }
+
+    #[test]
+    fn test_request_id() {
+        let vars = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+        assert_eq!(RequestId::from_vars(&vars(&[("HTTP_X_REQUEST_ID", "abc"), ("UNIQUE_ID", "Zx1")])).as_str(), "abc");
+        assert_eq!(RequestId::from_vars(&vars(&[("HTTP_X_REQUEST_ID", "a b"), ("UNIQUE_ID", "Zx1")])).as_str(), "Zx1");
+        let generated = RequestId::from_vars(&vars(&[]));
+        assert_eq!(generated.as_str().len(), 16);
+        assert_ne!(generated, RequestId::from_vars(&vars(&[])));
+    }
+
+    #[test]
+    fn test_remote_addr() {
+        let remote = RemoteAddr::parse("192.0.2.1", Some("8080")).unwrap();
+        assert_eq!(remote.socket_addr(), Some("192.0.2.1:8080".parse().unwrap()));
+        assert_eq!(RemoteAddr::parse("[::1]", None), Some(RemoteAddr("::1".parse().unwrap(), None)));
+        assert_eq!(RemoteAddr::parse("fe80::1%eth0", Some("x")).unwrap().to_string(), "fe80::1");
+        assert_eq!(RemoteAddr::parse("::ffff:192.0.2.1", Some("80")).unwrap().to_string(), "192.0.2.1:80");
+        assert_eq!(RemoteAddr::parse("localhost", None), None);
+    }
+}
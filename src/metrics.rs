@@ -0,0 +1,133 @@
+//! Where the time of a request goes: reading & parsing it, the handler, and writing the
+//! response.
+//!
+//! [`handle`](crate::handle) (and [`Options::handle`](crate::Options::handle)) measures every
+//! request. The handler finds the [`Metrics`] so far (parsing & the request body) in the
+//! request's extensions, and once the response is written, the complete metrics go to the
+//! exporter set with [`set_exporter`], e.g. [`stderr`] for a line in the error log:
+//!
+//! ```rust,no_run
+//! cgi::metrics::set_exporter(cgi::metrics::stderr);
+//! cgi::handle(|request: cgi::Request| "Hello World");
+//! ```
+//!
+//! ```text
+//! metrics: parse=0.412ms handler=12.870ms serialize=0.051ms request_body=0 response_body=11
+//! ```
+//!
+//! With the `statsd` feature, [`Statsd`] sends them to a StatsD server instead.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// The measurements of one request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Reading the request body and parsing the request
+    pub parse: Duration,
+    /// Running the handler (with its middleware)
+    pub handler: Duration,
+    /// Serializing & writing the response, with a body written by a
+    /// [`BodyWriter`](crate::stream::BodyWriter)
+    pub serialize: Duration,
+    /// The size of the request body
+    pub request_body: u64,
+    /// The size of the response body, `None` if a `BodyWriter` without a `Content-Length`
+    /// writes it
+    pub response_body: Option<u64>,
+}
+
+type Exporter = Box<dyn Fn(&Metrics) + Send + Sync>;
+
+static EXPORTER: RwLock<Option<Exporter>> = RwLock::new(None);
+
+/// Call `exporter` with the metrics of every request, after its response is written. This
+/// applies to the whole programme.
+pub fn set_exporter(exporter: impl Fn(&Metrics) + Send + Sync + 'static) {
+    *EXPORTER.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(exporter));
+}
+
+pub(crate) fn export(metrics: &Metrics) {
+    if let Some(exporter) = &*EXPORTER.read().unwrap_or_else(|e| e.into_inner()) {
+        exporter(metrics);
+    }
+}
+
+// `0.412ms`
+fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// An exporter writing the metrics as one line to stderr
+pub fn stderr(metrics: &Metrics) {
+    eprintln!("metrics: parse={} handler={} serialize={} request_body={} response_body={}",
+        millis(metrics.parse), millis(metrics.handler), millis(metrics.serialize), metrics.request_body,
+        metrics.response_body.map_or_else(|| "-".to_owned(), |size| size.to_string()));
+}
+
+/// An exporter sending the metrics to a StatsD server over UDP (`statsd` feature): the
+/// durations as timers (`prefix.parse`, `prefix.handler` & `prefix.serialize`, in
+/// milliseconds), the sizes as histograms (`prefix.request_body` & `prefix.response_body`).
+///
+/// ```rust,no_run
+/// let statsd = cgi::metrics::Statsd::new("127.0.0.1:8125", "my_app").unwrap();
+/// cgi::metrics::set_exporter(move |metrics| statsd.send(metrics));
+/// ```
+#[cfg(feature = "statsd")]
+#[derive(Debug)]
+pub struct Statsd {
+    socket: std::net::UdpSocket,
+    prefix: String,
+}
+
+#[cfg(feature = "statsd")]
+impl Statsd {
+    /// Send to the server at `addr`, with names starting with `prefix.`
+    pub fn new(addr: impl std::net::ToSocketAddrs, prefix: &str) -> std::io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(addr)?;
+        Ok(Statsd { socket, prefix: prefix.to_owned() })
+    }
+
+    /// Send `metrics` in one packet. Errors are ignored, like StatsD clients do.
+    pub fn send(&self, metrics: &Metrics) {
+        let _ = self.socket.send(self.packet(metrics).as_bytes());
+    }
+
+    fn packet(&self, metrics: &Metrics) -> String {
+        let mut lines = vec![
+            format!("{}.parse:{:.3}|ms", self.prefix, metrics.parse.as_secs_f64() * 1000.0),
+            format!("{}.handler:{:.3}|ms", self.prefix, metrics.handler.as_secs_f64() * 1000.0),
+            format!("{}.serialize:{:.3}|ms", self.prefix, metrics.serialize.as_secs_f64() * 1000.0),
+            format!("{}.request_body:{}|h", self.prefix, metrics.request_body),
+        ];
+        if let Some(size) = metrics.response_body {
+            lines.push(format!("{}.response_body:{}|h", self.prefix, size));
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_millis() {
+        assert_eq!(millis(Duration::from_micros(412)), "0.412ms");
+        assert_eq!(millis(Duration::from_millis(12)), "12.000ms");
+    }
+
+    #[cfg(feature = "statsd")]
+    #[test]
+    fn test_statsd() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let statsd = Statsd::new(server.local_addr().unwrap(), "app").unwrap();
+        let metrics = Metrics { handler: Duration::from_millis(5), request_body: 3, ..Metrics::default() };
+        statsd.send(&metrics);
+        let mut buf = [0; 512];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(std::str::from_utf8(&buf[..len]).unwrap(),
+            "app.parse:0.000|ms\napp.handler:5.000|ms\napp.serialize:0.000|ms\napp.request_body:3|h");
+    }
+}
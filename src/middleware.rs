@@ -0,0 +1,135 @@
+//! Wrap a handler in layers for cross-cutting concerns: logging, authentication, caching...
+//!
+//! A [`Middleware`] gets the request and a [`Next`], which calls the rest of the pipeline. It
+//! can change the request, answer it without calling `next`, or change the response. Closures
+//! with the same signature are middlewares too, and so are the types in this crate with a
+//! `handle(request, next)` method, like [`ConcurrencyLimit`](crate::limit::ConcurrencyLimit).
+//!
+//! ```rust,no_run
+//! use cgi::middleware::Next;
+//! use cgi::limit::ConcurrencyLimit;
+//!
+//! fn log(request: cgi::Request, next: Next) -> cgi::Response {
+//!     let uri = request.uri().clone();
+//!     let response = next.run(request);
+//!     eprintln!("{} {}", uri, response.status());
+//!     response
+//! }
+//!
+//! fn main() {
+//!     let limit = ConcurrencyLimit::new("/tmp/my-cgi-slots", 8);
+//!     cgi::handle_with(&[&log, &limit], |request: cgi::Request| {
+//!         cgi::text_response(200, "Hello World")
+//!     })
+//! }
+//! ```
+//!
+//! The first middleware is the outermost: it sees the request first and the response last.
+
+use crate::{Request, Response};
+
+/// A layer around the handler. See the [module docs](self).
+pub trait Middleware {
+    /// Handle the request, usually by calling `next.run(request)`
+    fn handle(&self, request: Request, next: Next) -> Response;
+}
+
+impl<F> Middleware for F
+    where F: Fn(Request, Next) -> Response
+{
+    fn handle(&self, request: Request, next: Next) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the pipeline: the following middlewares, then the handler.
+pub struct Next<'a> {
+    middlewares: &'a [&'a dyn Middleware],
+    handler: Box<dyn FnOnce(Request) -> Response + 'a>,
+}
+
+impl Next<'_> {
+    /// Pass the request on
+    pub fn run(self, request: Request) -> Response {
+        match self.middlewares.split_first() {
+            Some((first, rest)) => first.handle(request, Next { middlewares: rest, handler: self.handler }),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+/// Call `handler` through `middlewares`, the first one outermost
+pub fn apply<F>(middlewares: &[&dyn Middleware], request: Request, handler: F) -> Response
+    where F: FnOnce(Request) -> Response
+{
+    Next { middlewares, handler: Box::new(handler) }.run(request)
+}
+
+// The middlewares of this crate
+macro_rules! impl_middleware {
+    ($($(#[$attr:meta])* $type:ty $(where $param:ident: $bound:path)?;)*) => {
+        $(
+            $(#[$attr])*
+            impl$(<$param: $bound>)? Middleware for $type {
+                fn handle(&self, request: Request, next: Next) -> Response {
+                    <$type>::handle(self, request, |request| next.run(request))
+                }
+            }
+        )*
+    };
+}
+
+impl_middleware! {
+    crate::access::AccessControl;
+    crate::cache::MicroCache;
+    #[cfg(any(feature = "brotli", feature = "gzip", feature = "zstd"))]
+    crate::compress::Compression;
+    #[cfg(feature = "digest-auth")]
+    crate::digest_auth::DigestAuth;
+    #[cfg(feature = "idempotency")]
+    crate::idempotency::Idempotency;
+    crate::limit::ConcurrencyLimit;
+    crate::mime::SniffPolicy;
+    #[cfg(feature = "normalize")]
+    crate::normalize::Normalize;
+    crate::proxy::TrustedProxies;
+    crate::scan::VirusScan<S> where S: crate::scan::Scanner;
+    crate::server_timing::ResponseTime;
+    crate::server_timing::ServerTiming;
+    crate::shadow::Shadow;
+    crate::single_flight::SingleFlight;
+    #[cfg(feature = "spam")]
+    crate::spam::SpamGuard;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        let tag = |name: &'static str| move |mut request: Request, next: Next| {
+            request.body_mut().extend_from_slice(name.as_bytes());
+            let mut response = next.run(request);
+            response.body_mut().extend_from_slice(name.as_bytes());
+            response
+        };
+        let (a, b) = (tag("a"), tag("b"));
+        let deny = |_: Request, _: Next| crate::empty_response(403);
+
+        let response = apply(&[&a, &b], crate::Request::new(b">".to_vec()), |request| {
+            let mut body = request.into_body();
+            body.push(b'|');
+            crate::binary_response(200, None, body)
+        });
+        assert_eq!(response.body(), b">ab|ba");
+
+        let response = apply(&[&a, &deny, &b], crate::Request::new(vec![]), |_| unreachable!());
+        assert_eq!(response.status(), 403);
+        assert_eq!(response.body(), b"a");
+
+        let timing = crate::server_timing::ServerTiming::new();
+        let response = apply(&[&timing], crate::Request::new(vec![]), |_| crate::empty_response(204));
+        assert!(response.headers().contains_key("server-timing"));
+    }
+}
@@ -0,0 +1,361 @@
+//! Detect the type of content from its first bytes, or a file's extension.
+//!
+//! Browsers (and attackers) declare whatever `Content-Type` they like for an upload.
+//! [`sniff`] recognises common formats from their magic bytes, and [`SniffPolicy`] rejects
+//! uploads which claim to be one type but are actually another, e.g. an executable sent as
+//! `image/png`. The files of a `multipart/form-data` upload are checked one by one.
+//!
+//! ```rust
+//! assert_eq!(cgi::mime::sniff(b"%PDF-1.7\n..."), Some("application/pdf"));
+//! assert_eq!(cgi::mime::from_path("static/style.css"), Some("text/css; charset=utf-8"));
+//! ```
+
+use std::path::Path;
+
+use crate::multipart::{Multipart, MultipartError};
+use crate::{text_response, Request, Response};
+
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"II*\x00", "image/tiff"),
+    (b"MM\x00*", "image/tiff"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b\x08", "application/gzip"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07", "application/vnd.rar"),
+    (b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1", "application/x-ole-storage"),
+    (b"{\\rtf", "application/rtf"),
+    (b"\x00asm", "application/wasm"),
+    (b"OggS\x00", "application/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"\x7fELF", "application/x-executable"),
+];
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// The type of a file from its extension (ignoring case), for common web formats. Text types
+/// come with `charset=utf-8`.
+pub fn from_path(path: impl AsRef<Path>) -> Option<&'static str> {
+    let extension = path.as_ref().extension()?.to_str()?;
+    EXTENSIONS.iter().find(|(e, _)| e.eq_ignore_ascii_case(extension)).map(|(_, mime)| *mime)
+}
+
+/// The type of `bytes`, if it's a recognised format. Only the first few kilobytes are needed.
+///
+/// Recognises common images, PDF, archives (zip, gzip, 7z, rar), Office documents (both the
+/// zip based formats, like `.docx`, and OpenDocument), audio & video containers, and
+/// executables.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"PK\x03\x04") {
+        return Some(sniff_zip(bytes));
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" {
+        return match &bytes[8..12] {
+            b"WEBP" => Some("image/webp"),
+            b"WAVE" => Some("audio/wav"),
+            b"AVI " => Some("video/x-msvideo"),
+            _ => None,
+        };
+    }
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return match &bytes[8..12] {
+            b"avif" => Some("image/avif"),
+            b"heic" | b"heix" => Some("image/heic"),
+            b"qt  " => Some("video/quicktime"),
+            _ => Some("video/mp4"),
+        };
+    }
+    // Short signatures which plain text can start with need more of the header to match
+    if is_bmp(bytes) {
+        return Some("image/bmp");
+    }
+    if bytes.len() >= 10 && bytes.starts_with(b"ID3") && matches!(bytes[3], 2..=4) && bytes[4] == 0 {
+        return Some("audio/mpeg");
+    }
+    if is_windows_executable(bytes) {
+        return Some("application/x-msdownload");
+    }
+    SIGNATURES.iter().find(|(magic, _)| bytes.starts_with(magic)).map(|(_, mime)| *mime)
+}
+
+// `BM`, the file size, 4 reserved zero bytes, the pixel offset and a known DIB header size
+fn is_bmp(bytes: &[u8]) -> bool {
+    let u32_at = |i: usize| bytes.get(i..i + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    bytes.starts_with(b"BM") && u32_at(6) == Some(0)
+        && matches!(u32_at(14), Some(12 | 40 | 52 | 56 | 64 | 108 | 124))
+}
+
+// `MZ` with a PE header where the DOS header says it is (`e_lfanew` at 0x3c), or the DOS stub
+// if the PE header is beyond the bytes we have
+fn is_windows_executable(bytes: &[u8]) -> bool {
+    const DOS_STUB: &[u8] = b"This program cannot be run in DOS mode";
+    if !bytes.starts_with(b"MZ") || bytes.len() < 64 {
+        return false;
+    }
+    let pe_offset = u32::from_le_bytes([bytes[60], bytes[61], bytes[62], bytes[63]]) as usize;
+    match bytes.get(pe_offset..pe_offset.saturating_add(4)) {
+        Some(header) => header == b"PE\0\0",
+        None => pe_offset >= 64 && bytes.windows(DOS_STUB.len()).any(|w| w == DOS_STUB),
+    }
+}
+
+// Office documents are zip files; tell them apart by the names of the first entries
+fn sniff_zip(bytes: &[u8]) -> &'static str {
+    const OPEN_DOCUMENT: &[u8] = b"mimetypeapplication/vnd.oasis.opendocument.";
+    if bytes.get(30..30 + OPEN_DOCUMENT.len()) == Some(OPEN_DOCUMENT) {
+        let rest = &bytes[30 + OPEN_DOCUMENT.len()..];
+        if rest.starts_with(b"text") {
+            return "application/vnd.oasis.opendocument.text";
+        } else if rest.starts_with(b"spreadsheet") {
+            return "application/vnd.oasis.opendocument.spreadsheet";
+        } else if rest.starts_with(b"presentation") {
+            return "application/vnd.oasis.opendocument.presentation";
+        }
+    }
+
+    let head = &bytes[..bytes.len().min(8192)];
+    let contains = |needle: &[u8]| head.windows(needle.len()).any(|w| w == needle);
+    if contains(b"word/") {
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+    } else if contains(b"xl/") {
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+    } else if contains(b"ppt/") {
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+    } else {
+        "application/zip"
+    }
+}
+
+/// Whether content sniffed as `sniffed` may be declared as `declared` (a `Content-Type`,
+/// parameters are ignored). Common aliases are accepted, e.g. `image/jpg` for `image/jpeg`,
+/// and Office documents may be declared as zip files.
+pub fn is_compatible(declared: &str, sniffed: &str) -> bool {
+    let declared = declared.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let declared = match declared.as_str() {
+        "image/jpg" | "image/pjpeg" => "image/jpeg",
+        "image/x-png" => "image/png",
+        "image/vnd.microsoft.icon" => "image/x-icon",
+        "application/x-zip-compressed" | "application/x-zip" => "application/zip",
+        "application/x-gzip" => "application/gzip",
+        "application/x-rar-compressed" => "application/vnd.rar",
+        "audio/wave" | "audio/x-wav" => "audio/wav",
+        "audio/mp3" => "audio/mpeg",
+        "text/rtf" => "application/rtf",
+        other => other,
+    };
+    if declared == sniffed {
+        return true;
+    }
+    let is_zip_office = sniffed.starts_with("application/vnd.openxmlformats-officedocument.")
+        || sniffed.starts_with("application/vnd.oasis.opendocument.");
+    let is_ole_office = declared == "application/msword" || declared.starts_with("application/vnd.ms-");
+    (declared == "application/zip" && is_zip_office) || (sniffed == "application/x-ole-storage" && is_ole_office)
+}
+
+/// Rejects request bodies whose sniffed type disagrees with their `Content-Type`.
+#[derive(Debug, Clone, Default)]
+pub struct SniffPolicy {
+    allowed: Option<Vec<String>>,
+    reject_unknown: bool,
+}
+
+impl SniffPolicy {
+    /// By default, a body is accepted if its type isn't recognised, or is compatible with the
+    /// declared type (see [`is_compatible`]), or nothing is declared at all.
+    pub fn new() -> Self {
+        SniffPolicy::default()
+    }
+
+    /// Only accept bodies sniffed as one of these types
+    pub fn allow(mut self, types: &[&str]) -> Self {
+        self.allowed = Some(types.iter().map(|t| t.to_string()).collect());
+        self
+    }
+
+    /// Also reject bodies whose type isn't recognised
+    pub fn reject_unknown(mut self, reject: bool) -> Self {
+        self.reject_unknown = reject;
+        self
+    }
+
+    /// Check `bytes` declared as `declared`. `Err` has the reason.
+    pub fn check(&self, declared: Option<&str>, bytes: &[u8]) -> Result<(), String> {
+        let sniffed = match sniff(bytes) {
+            Some(sniffed) => sniffed,
+            None if self.reject_unknown || self.allowed.is_some() => return Err("content type not recognised".into()),
+            None => return Ok(()),
+        };
+        if let Some(allowed) = &self.allowed {
+            if !allowed.iter().any(|a| a == sniffed) {
+                return Err(format!("{} is not allowed", sniffed));
+            }
+        }
+        match declared {
+            Some(declared) if !is_compatible(declared, sniffed) => Err(format!("declared as {} but is {}", declared, sniffed)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the request body (if there is one) against its `Content-Type`, and call `next`
+    /// if it passes. Otherwise answer `415 Unsupported Media Type`.
+    ///
+    /// For a `multipart/form-data` body, each uploaded file is checked against the
+    /// `Content-Type` of its part instead; text fields aren't checked. A malformed multipart
+    /// body gets a `400 Bad Request`.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        if request.body().is_empty() {
+            return next(request);
+        }
+        let result = match Multipart::from_request(&request) {
+            Ok(form) => form.files().filter(|file| !file.data.is_empty())
+                .try_for_each(|file| self.check(file.content_type.as_deref(), &file.data)
+                    .map_err(|reason| format!("{:?} {}", file.filename.as_deref().unwrap_or_default(), reason))),
+            Err(MultipartError::NotMultipart) => {
+                let declared = request.headers().get(http::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+                self.check(declared, request.body())
+            }
+            Err(err) => return err.into(),
+        };
+        match result {
+            Ok(()) => next(request),
+            Err(reason) => text_response(415, format!("Unsupported Media Type: {}", reason)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff(b"PK\x03\x04\x14\0\0\0\x08\0....[Content_Types].xml....word/document.xml"),
+            Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document"));
+        let mut odt = b"PK\x03\x04".to_vec();
+        odt.resize(30, 0);
+        odt.extend_from_slice(b"mimetypeapplication/vnd.oasis.opendocument.spreadsheetPK");
+        assert_eq!(sniff(&odt), Some("application/vnd.oasis.opendocument.spreadsheet"));
+        assert_eq!(sniff(b"PK\x03\x04 readme.txt"), Some("application/zip"));
+        assert_eq!(sniff(b"hello"), None);
+        // Text starting like a short signature
+        assert_eq!(sniff(b"BMW owners club"), None);
+        assert_eq!(sniff(b"MZ: notes on the Mazda MX-5 Miata, the best roadster ever built, for sure"), None);
+        assert_eq!(sniff(b"ID3 tags are metadata"), None);
+        let mut bmp = b"BM\x46\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0".to_vec();
+        bmp.resize(70, 0);
+        assert_eq!(sniff(&bmp), Some("image/bmp"));
+        assert_eq!(sniff(&exe()), Some("application/x-msdownload"));
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(from_path("index.HTML"), Some("text/html; charset=utf-8"));
+        assert_eq!(from_path("/srv/a.b/logo.svg"), Some("image/svg+xml"));
+        assert_eq!(from_path("README"), None);
+        assert_eq!(from_path("archive.unknown"), None);
+    }
+
+    #[test]
+    fn test_policy() {
+        assert!(is_compatible("image/JPG; name=x", "image/jpeg"));
+        assert!(is_compatible("application/zip", "application/vnd.oasis.opendocument.text"));
+        assert!(is_compatible("application/vnd.ms-excel", "application/x-ole-storage"));
+        assert!(!is_compatible("image/png", "application/x-msdownload"));
+
+        let policy = SniffPolicy::new();
+        assert!(policy.check(Some("image/png"), b"\x89PNG\r\n\x1a\n").is_ok());
+        assert!(policy.check(Some("text/plain"), b"just text").is_ok());
+        assert!(policy.check(None, &exe()).is_ok());
+        assert_eq!(policy.check(Some("image/png"), &exe()).unwrap_err(), "declared as image/png but is application/x-msdownload");
+
+        let policy = SniffPolicy::new().allow(&["image/png", "image/jpeg"]);
+        assert!(policy.check(None, b"%PDF-1.4").is_err());
+        assert!(policy.check(None, b"text").is_err());
+
+        let request = |ct: &str, body: &[u8]| http::Request::builder().method("POST").header("content-type", ct).body(body.to_vec()).unwrap();
+        let ok = |_| crate::empty_response(200);
+        assert_eq!(SniffPolicy::new().handle(request("image/gif", b"GIF89a"), ok).status(), 200);
+        assert_eq!(SniffPolicy::new().handle(request("image/gif", b"%PDF-"), ok).status(), 415);
+    }
+
+    // A DOS header pointing to a PE header
+    fn exe() -> Vec<u8> {
+        let mut exe = b"MZ\x90\0".to_vec();
+        exe.resize(0x80, 0);
+        exe[0x3c] = 0x80;
+        exe.extend_from_slice(b"PE\0\0\x4c\x01");
+        exe
+    }
+
+    #[test]
+    fn test_policy_multipart() {
+        let form = |ct: &str, data: &[u8]| {
+            let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nMy holiday\r\n".to_vec();
+            body.extend_from_slice(format!("--XyZ\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"a.png\"\r\nContent-Type: {}\r\n\r\n", ct).as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n--XyZ--\r\n");
+            http::Request::builder().method("POST").header("content-type", "multipart/form-data; boundary=XyZ").body(body).unwrap()
+        };
+        let ok = |_| crate::empty_response(200);
+        let policy = SniffPolicy::new().allow(&["image/png"]).reject_unknown(true);
+        assert_eq!(policy.handle(form("image/png", b"\x89PNG\r\n\x1a\n"), ok).status(), 200);
+        let response = policy.handle(form("image/png", &exe()), ok);
+        assert_eq!(response.status(), 415);
+        assert_eq!(response.body(), b"Unsupported Media Type: \"a.png\" application/x-msdownload is not allowed");
+        assert_eq!(SniffPolicy::new().handle(form("image/png", &exe()), ok).status(), 415);
+        // No file chosen
+        assert_eq!(policy.handle(form("application/octet-stream", b""), ok).status(), 200);
+
+        let mut malformed = form("image/png", b"");
+        malformed.body_mut().truncate(20);
+        assert_eq!(policy.handle(malformed, ok).status(), 400);
+    }
+}
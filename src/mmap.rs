@@ -0,0 +1,43 @@
+// Memory-mapped files (`mmap` feature, Unix only)
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+// A read-only mapping of a whole file, unmapped when dropped
+pub(crate) struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this value, like a `Box<[u8]>`
+unsafe impl Send for Mmap {}
+
+impl Mmap {
+    // Map the first `len` bytes of `file`, which mustn't be empty
+    pub(crate) fn map(file: &File, len: usize) -> io::Result<Self> {
+        // SAFETY: a new private mapping, which no Rust value refers to yet
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Only a hint for the read-ahead, failing doesn't matter
+        // SAFETY: the range is the mapping
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mmap { ptr, len })
+    }
+
+    // The mapped bytes. If another process truncates the file meanwhile, reading past its new
+    // end is a `SIGBUS`, like with any mapping.
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        // SAFETY: `len` bytes are mapped readable at `ptr` until `self` is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping made in `map`, which isn't used after this
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
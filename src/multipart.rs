@@ -0,0 +1,303 @@
+//! Parse `multipart/form-data` request bodies: forms with file uploads.
+//!
+//! ```rust,no_run
+//! use cgi::multipart::Multipart;
+//!
+//! cgi::handle(|request: cgi::Request| {
+//!     let form = match Multipart::from_request(&request) {
+//!         Ok(form) => form,
+//!         Err(err) => return err.into(),
+//!     };
+//!     let title = form.text("title").unwrap_or_default();
+//!     match form.file("attachment") {
+//!         Some(file) => {
+//!             let path = file.save_temp().unwrap();
+//!             cgi::text_response(200, format!("{}: {:?} stored at {}", title, file.filename, path.display()))
+//!         }
+//!         None => cgi::text_response(400, "No file uploaded"),
+//!     }
+//! })
+//! ```
+//!
+//! File names are reduced to their last path component, since some browsers send the full
+//! path on the client. They're still chosen by the client: don't use them as paths without
+//! checking.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::{random, text_response, urlencoded, Request, Response};
+
+/// One part of the form: a text field or a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// The form field name
+    pub name: String,
+    /// The file name, for file uploads
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    /// All headers of the part, including `Content-Disposition`
+    pub headers: http::HeaderMap,
+    pub data: Vec<u8>,
+}
+
+impl Part {
+    /// Whether this is a file upload (it has a file name)
+    pub fn is_file(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    /// The data as text. Invalid UTF-8 is replaced.
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.data)
+    }
+
+    /// Write the data to a new file in the temporary directory, returning its path. The file
+    /// isn't removed automatically.
+    pub fn save_temp(&self) -> io::Result<PathBuf> {
+        let path = std::env::temp_dir().join(format!("cgi-upload-{}", random::hex(16)));
+        std::fs::OpenOptions::new().write(true).create_new(true).open(&path)
+            .and_then(|mut file| io::Write::write_all(&mut file, &self.data))?;
+        Ok(path)
+    }
+}
+
+/// A parsed `multipart/form-data` body.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Multipart {
+    pub parts: Vec<Part>,
+}
+
+impl Multipart {
+    /// Parse the body of a request with `Content-Type: multipart/form-data`
+    pub fn from_request(request: &Request) -> Result<Self, MultipartError> {
+        let content_type = request.headers().get(http::header::CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+            .ok_or(MultipartError::NotMultipart)?;
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        if !essence.eq_ignore_ascii_case("multipart/form-data") {
+            return Err(MultipartError::NotMultipart);
+        }
+        let boundary = boundary(content_type).ok_or(MultipartError::NoBoundary)?;
+        Multipart::parse(request.body(), &boundary)
+    }
+
+    /// Parse a multipart body with the `boundary`
+    pub fn parse(body: &[u8], boundary: &str) -> Result<Self, MultipartError> {
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let mut rest = match find(body, &delimiter) {
+            Some(start) => &body[start + delimiter.len()..],
+            None => return Err(MultipartError::Malformed("no boundary in the body".into())),
+        };
+        let delimiter = [b"\r\n".as_slice(), &delimiter].concat();
+
+        let mut parts = Vec::new();
+        loop {
+            if rest.starts_with(b"--") {
+                return Ok(Multipart { parts });
+            }
+            // Transport padding, then the line break after the boundary
+            let line_end = find(rest, b"\n").ok_or_else(|| MultipartError::Malformed("unexpected end".into()))?;
+            rest = &rest[line_end + 1..];
+            let end = find(rest, &delimiter).ok_or_else(|| MultipartError::Malformed("missing closing boundary".into()))?;
+            parts.push(parse_part(&rest[..end])?);
+            rest = &rest[end + delimiter.len()..];
+        }
+    }
+
+    /// The first part named `name`
+    pub fn get(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|p| p.name == name)
+    }
+
+    /// The value of the text field `name`
+    pub fn text(&self, name: &str) -> Option<String> {
+        self.parts.iter().find(|p| p.name == name && !p.is_file()).map(|p| p.text().into_owned())
+    }
+
+    /// The uploaded file `name`
+    pub fn file(&self, name: &str) -> Option<&Part> {
+        self.parts.iter().find(|p| p.name == name && p.is_file())
+    }
+
+    /// All uploaded files
+    pub fn files(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter().filter(|p| p.is_file())
+    }
+}
+
+/// The `boundary` parameter of a `multipart/*` content type
+pub fn boundary(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1)
+        .find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("boundary").then(|| value.trim().trim_matches('"').to_owned())
+        })
+        .filter(|b| !b.is_empty())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    memchr::memmem::find(haystack, needle)
+}
+
+fn parse_part(part: &[u8]) -> Result<Part, MultipartError> {
+    let (head, data) = match find(part, b"\r\n\r\n") {
+        Some(end) => (&part[..end], &part[end + 4..]),
+        // No headers at all
+        None if part.starts_with(b"\r\n") => (&part[..0], &part[2..]),
+        None => return Err(MultipartError::Malformed("part without headers".into())),
+    };
+
+    let mut headers = http::HeaderMap::new();
+    for line in String::from_utf8_lossy(head).split("\r\n") {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        if let (Ok(name), Ok(value)) = (http::HeaderName::from_bytes(name.trim().as_bytes()), value.trim().parse()) {
+            headers.append(name, value);
+        }
+    }
+
+    let disposition = headers.get(http::header::CONTENT_DISPOSITION)
+        .map(|d| String::from_utf8_lossy(d.as_bytes()).into_owned())
+        .ok_or_else(|| MultipartError::Malformed("part without Content-Disposition".into()))?;
+    let params = disposition_params(&disposition);
+    let param = |name: &str| params.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone());
+    let name = param("name").ok_or_else(|| MultipartError::Malformed("part without a name".into()))?;
+    let filename = param("filename*")
+        .and_then(|f| decode_ext_value(&f))
+        .or_else(|| param("filename"))
+        .map(|f| f.rsplit(['/', '\\']).next().unwrap_or("").to_owned());
+    let content_type = headers.get(http::header::CONTENT_TYPE).and_then(|ct| ct.to_str().ok()).map(|ct| ct.to_owned());
+
+    Ok(Part { name, filename, content_type, headers, data: data.to_vec() })
+}
+
+// `form-data; name="a"; filename="b.txt"` → [("name", "a"), ("filename", "b.txt")]
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    // Skip the disposition type
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+    loop {
+        let name: String = chars.by_ref().take_while(|&c| c != '=').collect();
+        let name = name.trim().to_ascii_lowercase();
+        if name.is_empty() {
+            return params;
+        }
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    // Browsers don't escape `\` in file names, so only `\"` & `\\` are escapes
+                    '\\' if matches!(chars.peek(), Some('"') | Some('\\')) => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|&c| c != ';').collect::<String>().trim().to_owned();
+        }
+        params.push((name, value));
+    }
+}
+
+// RFC 8187 `utf-8''%E2%82%AC.txt`
+fn decode_ext_value(value: &str) -> Option<String> {
+    let (charset, rest) = value.split_once('\'')?;
+    let (_language, encoded) = rest.split_once('\'')?;
+    charset.eq_ignore_ascii_case("utf-8").then(|| urlencoded::percent_decode(encoded, false))
+}
+
+/// Why a multipart body couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultipartError {
+    /// The request isn't `multipart/form-data`
+    NotMultipart,
+    /// The `Content-Type` has no `boundary`
+    NoBoundary,
+    Malformed(String),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::NotMultipart => f.write_str("the request isn't multipart/form-data"),
+            MultipartError::NoBoundary => f.write_str("the multipart Content-Type has no boundary"),
+            MultipartError::Malformed(reason) => write!(f, "malformed multipart body: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+/// `415 Unsupported Media Type` if the request isn't multipart, otherwise `400 Bad Request`
+impl From<MultipartError> for Response {
+    fn from(err: MultipartError) -> Self {
+        let status = if err == MultipartError::NotMultipart { 415 } else { 400 };
+        text_response(status, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let body = "preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            Hello\r\nWorld\r\n\
+            --XyZ  \r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"C:\\Users\\me\\a \\\"b\\\".txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            file\r\n--Xy contents\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"euro\"; filename=\"e.txt\"; filename*=UTF-8''%E2%82%AC.txt\r\n\r\n\
+            \r\n\
+            --XyZ--\r\nepilogue";
+        let request = http::Request::builder()
+            .header("content-type", "multipart/form-data; boundary=\"XyZ\"")
+            .body(body.as_bytes().to_vec()).unwrap();
+        let form = Multipart::from_request(&request).unwrap();
+        assert_eq!(form.parts.len(), 3);
+        assert_eq!(form.text("title").as_deref(), Some("Hello\r\nWorld"));
+        let file = form.file("upload").unwrap();
+        assert_eq!(file.filename.as_deref(), Some("a \"b\".txt"));
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(file.data, b"file\r\n--Xy contents");
+        assert_eq!(form.get("euro").unwrap().filename.as_deref(), Some("€.txt"));
+        assert!(form.get("euro").unwrap().data.is_empty());
+        assert_eq!(form.files().count(), 2);
+
+        let path = file.save_temp().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), file.data);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let request = |content_type: &str, body: &str| http::Request::builder()
+            .header("content-type", content_type)
+            .body(body.as_bytes().to_vec()).unwrap();
+        assert_eq!(Multipart::from_request(&request("text/plain", "")), Err(MultipartError::NotMultipart));
+        assert_eq!(Multipart::from_request(&request("multipart/form-data", "")), Err(MultipartError::NoBoundary));
+        let truncated = "--b\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nvalue";
+        let err = Multipart::from_request(&request("multipart/form-data; boundary=b", truncated)).unwrap_err();
+        assert_eq!(Response::from(err).status(), 400);
+        assert_eq!(Multipart::parse(b"--b--", "b"), Ok(Multipart::default()));
+    }
+}
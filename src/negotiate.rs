@@ -0,0 +1,102 @@
+//! Content negotiation with `Accept-*` headers.
+//!
+//! ```rust
+//! use cgi::negotiate::{best_language, preferred_languages};
+//!
+//! let request = cgi::testing::CgiRequestBuilder::new().header("Accept-Language", "de-CH, fr;q=0.8, en;q=0.5").build();
+//! assert_eq!(preferred_languages(&request), [("de-CH".to_owned(), 1.0), ("fr".to_owned(), 0.8), ("en".to_owned(), 0.5)]);
+//! assert_eq!(best_language(&request, &["en", "de"]), Some("de"));
+//! ```
+
+use crate::Request;
+
+// The elements of a header like `a, b;q=0.5, c;q=0` with their q-values (1 if there's none),
+// best first and otherwise in order. Elements with q=0 are kept, as they exclude the element.
+pub(crate) fn parse_qlist<'a>(request: &'a Request, header: &str) -> Vec<(&'a str, f32)> {
+    let mut list: Vec<(&str, f32)> = request.headers().get_all(header).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            let mut params = element.split(';');
+            let value = params.next()?.trim();
+            let q = params.filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+                .next()
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q)))?;
+            (!value.is_empty()).then_some((value, q))
+        })
+        .collect();
+    list.sort_by(|a, b| b.1.total_cmp(&a.1));
+    list
+}
+
+/// The language tags of the `Accept-Language` header with their q-values, best first (and in
+/// the header's order for equal q-values). Tags with q=0 are left out.
+pub fn preferred_languages(request: &Request) -> Vec<(String, f32)> {
+    parse_qlist(request, "accept-language").into_iter()
+        .filter(|(_, q)| *q > 0.0)
+        .map(|(tag, q)| (tag.to_owned(), q))
+        .collect()
+}
+
+/// The best of the `supported` languages for the request, or `None` if the client accepts
+/// none of them.
+///
+/// For each preferred language (RFC 4647 lookup): a supported tag which is the same or more
+/// specific (`en` matches `en-GB`) is used, otherwise the tag is shortened (`de-CH` matches `de`).
+/// `*` matches the first supported language. Without `Accept-Language`, it's also the first.
+pub fn best_language<'a>(request: &Request, supported: &[&'a str]) -> Option<&'a str> {
+    let ranges = parse_qlist(request, "accept-language");
+    if ranges.is_empty() {
+        return supported.first().copied();
+    }
+    let excluded = |tag: &str| ranges.iter().any(|(range, q)| *q == 0.0 && range.eq_ignore_ascii_case(tag));
+    let candidates: Vec<&str> = supported.iter().copied().filter(|tag| !excluded(tag)).collect();
+
+    for (range, _) in ranges.iter().filter(|(_, q)| *q > 0.0) {
+        if *range == "*" {
+            return candidates.first().copied();
+        }
+        let more_specific = |tag: &str| tag.len() > range.len() && tag.as_bytes()[range.len()] == b'-'
+            && tag[..range.len()].eq_ignore_ascii_case(range);
+        if let Some(tag) = candidates.iter().find(|tag| tag.eq_ignore_ascii_case(range) || more_specific(tag)) {
+            return Some(tag);
+        }
+        let mut prefix: &str = range;
+        while let Some((shorter, _)) = prefix.rsplit_once('-') {
+            prefix = shorter;
+            if let Some(tag) = candidates.iter().find(|tag| tag.eq_ignore_ascii_case(prefix)) {
+                return Some(tag);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    fn request(accept_language: &str) -> Request {
+        CgiRequestBuilder::new().header("Accept-Language", accept_language).build()
+    }
+
+    #[test]
+    fn test_preferred_languages() {
+        let languages = preferred_languages(&request("en;q=0.5, fr-CA , de;q=0, es;q=0.5, x;q=2"));
+        assert_eq!(languages, [("fr-CA".to_owned(), 1.0), ("en".to_owned(), 0.5), ("es".to_owned(), 0.5)]);
+        assert!(preferred_languages(&CgiRequestBuilder::new().build()).is_empty());
+    }
+
+    #[test]
+    fn test_best_language() {
+        let supported = ["en-US", "en-GB", "de", "fr"];
+        assert_eq!(best_language(&request("de-AT-x-foo, en;q=0.8"), &supported), Some("de"));
+        assert_eq!(best_language(&request("EN, de;q=0.9"), &supported), Some("en-US"));
+        assert_eq!(best_language(&request("en-gb"), &supported), Some("en-GB"));
+        assert_eq!(best_language(&request("it, *;q=0.1"), &supported), Some("en-US"));
+        assert_eq!(best_language(&request("*, en-US;q=0"), &supported), Some("en-GB"));
+        assert_eq!(best_language(&request("it"), &supported), None);
+        assert_eq!(best_language(&CgiRequestBuilder::new().build(), &supported), Some("en-US"));
+    }
+}
@@ -0,0 +1,219 @@
+//! Unicode normalization of decoded request values. Requires the `normalize` feature.
+//!
+//! The same text can be encoded in different ways: `é` can be one code point, or `e`
+//! followed by a combining accent, depending on the browser & platform which sent it. They
+//! look the same, but don't compare equal, so e.g. a user name registered on one device
+//! can't be found from another. [`Normalize`] rewrites the request so the query string,
+//! urlencoded form body and path are in Normalization Form C (NFC). It can also strip
+//! bidirectional control characters, which can make text display differently from what it is.
+//!
+//! ```rust,no_run
+//! use cgi::normalize::Normalize;
+//!
+//! fn main() {
+//!     let normalize = Normalize::new().strip_bidi(true);
+//!     cgi::handle(|request: cgi::Request| normalize.handle(request, |request| {
+//!         cgi::text_response(200, format!("{:?}", request.uri().query()))
+//!     }))
+//! }
+//! ```
+
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+use crate::{urlencoded, Request, Response};
+
+/// `s` in Normalization Form C
+pub fn nfc(s: &str) -> String {
+    match is_nfc_quick(s.chars()) {
+        IsNormalized::Yes => s.to_owned(),
+        _ => s.nfc().collect(),
+    }
+}
+
+/// Whether `c` is a bidirectional formatting character (e.g. U+202E RIGHT-TO-LEFT OVERRIDE)
+pub fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// `s` without bidirectional formatting characters
+pub fn strip_bidi_controls(s: &str) -> String {
+    s.chars().filter(|&c| !is_bidi_control(c)).collect()
+}
+
+/// Normalizes request values. See the [module docs](self).
+#[derive(Debug, Clone, Default)]
+pub struct Normalize {
+    strip_bidi: bool,
+}
+
+impl Normalize {
+    /// NFC normalization only
+    pub fn new() -> Self {
+        Normalize::default()
+    }
+
+    /// Also remove bidirectional formatting characters
+    pub fn strip_bidi(mut self, strip: bool) -> Self {
+        self.strip_bidi = strip;
+        self
+    }
+
+    /// Normalize one (decoded) value
+    pub fn apply(&self, s: &str) -> String {
+        let s = nfc(s);
+        if self.strip_bidi { strip_bidi_controls(&s) } else { s }
+    }
+
+    // Decode, normalize & encode again, but only if anything changed
+    fn apply_urlencoded(&self, encoded: &str) -> Option<String> {
+        let pairs = urlencoded::parse(encoded);
+        let normalized: Vec<(String, String)> = pairs.iter().map(|(k, v)| (self.apply(k), self.apply(v))).collect();
+        (normalized != pairs).then(|| urlencoded::serialize(&normalized))
+    }
+
+    // Normalize each segment of `path` with `segment`, which returns `None` to keep it. A
+    // segment which would become `.` or `..` (e.g. by stripping a bidi control) is kept too,
+    // so normalizing can't change which directory a path points to.
+    fn segments(&self, path: &str, segment: impl Fn(&str) -> Option<String>) -> Option<String> {
+        let mut changed = false;
+        let segments: Vec<String> = path.split('/').map(|s| match segment(s) {
+            Some(normalized) if normalized != "." && normalized != ".." => {
+                changed = true;
+                normalized
+            }
+            _ => s.to_owned(),
+        }).collect();
+        changed.then(|| segments.join("/"))
+    }
+
+    /// Normalize the path (and `X-CGI-Path-Info`), the query string and an
+    /// `application/x-www-form-urlencoded` body. Values which are already normalized are
+    /// left exactly as they were.
+    ///
+    /// The path is normalized segment by segment, after removing `.` & `..` segments. Encoded
+    /// characters which would change its structure (`%2F`, `%2E%2E`) stay encoded.
+    pub fn request(&self, mut request: Request) -> Request {
+        let uri = request.uri();
+        let path = remove_dot_segments(uri.path());
+        let new_path = self.segments(&path, |segment| {
+            let decoded = urlencoded::percent_decode(segment, false);
+            let normalized = self.apply(&decoded);
+            (normalized != decoded).then(|| urlencoded::percent_encode(&normalized, b"!$&'()*+,;=:@"))
+        }).or_else(|| (path != uri.path()).then_some(path));
+        let new_query = uri.query().and_then(|q| self.apply_urlencoded(q));
+        if new_path.is_some() || new_query.is_some() {
+            let path = new_path.unwrap_or_else(|| uri.path().to_owned());
+            let path_and_query = match new_query.as_deref().or(uri.query()) {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            let mut parts = uri.clone().into_parts();
+            if let Ok(path_and_query) = path_and_query.parse() {
+                parts.path_and_query = Some(path_and_query);
+                if let Ok(new_uri) = http::Uri::from_parts(parts) {
+                    *request.uri_mut() = new_uri;
+                }
+            }
+        }
+
+        if let Some(path_info) = request.headers().get("x-cgi-path-info").and_then(|v| std::str::from_utf8(v.as_bytes()).ok()) {
+            if let Some(normalized) = self.segments(path_info, |segment| Some(self.apply(segment)).filter(|n| n != segment)) {
+                if let Ok(value) = http::HeaderValue::from_bytes(normalized.as_bytes()) {
+                    request.headers_mut().insert("x-cgi-path-info", value);
+                }
+            }
+        }
+
+        let is_form = request.headers().get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+        if is_form {
+            if let Some(body) = std::str::from_utf8(request.body()).ok().and_then(|b| self.apply_urlencoded(b)) {
+                let length = body.len();
+                *request.body_mut() = body.into_bytes();
+                if request.headers().contains_key(http::header::CONTENT_LENGTH) {
+                    request.headers_mut().insert(http::header::CONTENT_LENGTH, length.into());
+                }
+            }
+        }
+        request
+    }
+
+    /// Normalize the request (see [`request`](Self::request)), and call `next` with it
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        next(self.request(request))
+    }
+}
+
+// RFC 3986 section 5.2.4, for the literal `.` & `..` segments of an absolute path
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut parts = path.split('/').peekable();
+    while let Some(segment) = parts.next() {
+        let last = parts.peek().is_none();
+        match segment {
+            "." | ".." => {
+                if segment == ".." && segments.len() > 1 {
+                    segments.pop();
+                }
+                // `/a/..` is the directory `/`, not `/a` without a trailing slash
+                if last {
+                    segments.push("");
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(nfc("e\u{301}"), "\u{e9}");
+        assert_eq!(Normalize::new().strip_bidi(true).apply("ab\u{202E}c"), "abc");
+        assert_eq!(Normalize::new().apply("ab\u{202E}c"), "ab\u{202E}c");
+
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/script/cafe%CC%81?name=Jose%CC%81&x=1")
+            .header("x-cgi-path-info", "/cafe\u{301}".as_bytes())
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(b"q=e%CC%81%E2%80%AE".to_vec())
+            .unwrap();
+        let request = Normalize::new().strip_bidi(true).request(request);
+        assert_eq!(request.uri(), "/script/caf%C3%A9?name=Jos%C3%A9&x=1");
+        assert_eq!(request.headers()["x-cgi-path-info"].as_bytes(), "/caf\u{e9}".as_bytes());
+        assert_eq!(request.body(), b"q=%C3%A9");
+
+        // Already normalized, so untouched
+        let request = http::Request::builder().uri("/a%2Fb?a=b+c&d").body(vec![]).unwrap();
+        assert_eq!(Normalize::new().request(request).uri(), "/a%2Fb?a=b+c&d");
+    }
+
+    #[test]
+    fn test_path_structure() {
+        let normalize = Normalize::new().strip_bidi(true);
+        let uri = |uri: &str| normalize.request(http::Request::builder().uri(uri).body(vec![]).unwrap()).uri().to_string();
+        // Encoded slashes & dots stay encoded when another segment changes
+        assert_eq!(uri("/files/a%2Fb/cafe%CC%81"), "/files/a%2Fb/caf%C3%A9");
+        assert_eq!(uri("/files/%2E%2E%2Fsecret/cafe%CC%81"), "/files/%2E%2E%2Fsecret/caf%C3%A9");
+        assert_eq!(uri("/files/%2E%2E/e%CC%81"), "/files/%2E%2E/%C3%A9");
+        assert_eq!(uri("/files/a%2F..%E2%80%AE/x"), "/files/a%2F../x");
+        // A segment which would become `..` isn't normalized
+        assert_eq!(uri("/files/..%E2%80%AE/secret"), "/files/..%E2%80%AE/secret");
+        // Dot segments are removed first
+        assert_eq!(uri("/a/b/../c/./e%CC%81"), "/a/c/%C3%A9");
+        assert_eq!(uri("/../../x"), "/x");
+        assert_eq!(uri("/a/.."), "/");
+
+        let request = http::Request::builder().header("x-cgi-path-info", "/..\u{202E}/etc/e\u{301}".as_bytes()).body(vec![]).unwrap();
+        let request = normalize.request(request);
+        assert_eq!(request.headers()["x-cgi-path-info"].as_bytes(), "/..\u{202E}/etc/\u{e9}".as_bytes());
+    }
+}
@@ -0,0 +1,205 @@
+//! The `Prefer` request header & `Preference-Applied` response header (RFC 7240).
+//!
+//! ```rust,no_run
+//! use cgi::prefer::{preferences, preference_applied, Return};
+//!
+//! #[cgi::main]
+//! fn main(request: cgi::Request) -> cgi::Response {
+//!     let prefs = preferences(&request);
+//!     if prefs.return_ == Some(Return::Minimal) {
+//!         let mut response = cgi::empty_response(204);
+//!         preference_applied(&mut response, "return=minimal").unwrap();
+//!         return response;
+//!     }
+//!     cgi::text_response(200, "The full representation")
+//! }
+//! ```
+
+use crate::{Request, Response};
+
+/// The `return` preference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Return {
+    /// `return=minimal`
+    Minimal,
+    /// `return=representation`
+    Representation,
+}
+
+/// The `handling` preference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handling {
+    /// `handling=strict`
+    Strict,
+    /// `handling=lenient`
+    Lenient,
+}
+
+/// One preference from the `Prefer` header, e.g. `foo; bar="baz"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preference {
+    /// Lowercased name
+    pub name: String,
+    pub value: Option<String>,
+    /// Parameters, with lowercased names
+    pub params: Vec<(String, Option<String>)>,
+}
+
+/// All the preferences the client sent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Preferences {
+    pub return_: Option<Return>,
+    /// `wait`, in seconds
+    pub wait: Option<u64>,
+    pub respond_async: bool,
+    pub handling: Option<Handling>,
+    /// Every preference, including the ones above, in the order they were sent
+    pub all: Vec<Preference>,
+}
+
+impl Preferences {
+    /// Look up a preference by name (case insensitive)
+    pub fn get(&self, name: &str) -> Option<&Preference> {
+        self.all.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Parse every `Prefer` header on the request. If a preference is sent more than once, the
+/// first one is used. Unparseable preferences are skipped.
+pub fn preferences(request: &Request) -> Preferences {
+    let mut prefs = Preferences::default();
+    for value in request.headers().get_all("prefer") {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        for item in split_quoted(&value, ',') {
+            if let Some(pref) = parse_preference(item) {
+                if prefs.get(&pref.name).is_none() {
+                    prefs.all.push(pref);
+                }
+            }
+        }
+    }
+
+    for pref in &prefs.all {
+        let value = pref.value.as_deref().map(|v| v.to_ascii_lowercase());
+        match (pref.name.as_str(), value.as_deref()) {
+            ("return", Some("minimal")) => prefs.return_ = Some(Return::Minimal),
+            ("return", Some("representation")) => prefs.return_ = Some(Return::Representation),
+            ("wait", Some(v)) => prefs.wait = v.parse().ok(),
+            ("respond-async", None) => prefs.respond_async = true,
+            ("handling", Some("strict")) => prefs.handling = Some(Handling::Strict),
+            ("handling", Some("lenient")) => prefs.handling = Some(Handling::Lenient),
+            _ => {}
+        }
+    }
+    prefs
+}
+
+/// Add `preference` (e.g. `return=minimal`) to the `Preference-Applied` header of the response.
+/// An error if it can't be in a header (e.g. it has a line break); the header is left as it was.
+pub fn preference_applied(response: &mut Response, preference: &str) -> Result<(), http::header::InvalidHeaderValue> {
+    let headers = response.headers_mut();
+    let value = match headers.get("preference-applied").and_then(|v| v.to_str().ok()) {
+        Some(existing) => format!("{}, {}", existing, preference),
+        None => preference.to_owned(),
+    };
+    headers.insert("preference-applied", value.parse()?);
+    Ok(())
+}
+
+fn parse_preference(item: &str) -> Option<Preference> {
+    let mut parts = split_quoted(item, ';').into_iter();
+    let (name, value) = parse_pair(parts.next()?)?;
+    let params = parts.filter_map(parse_pair).collect();
+    Some(Preference { name, value, params })
+}
+
+fn parse_pair(s: &str) -> Option<(String, Option<String>)> {
+    let (name, value) = match s.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(unquote(value.trim()))),
+        None => (s.trim(), None),
+    };
+    if name.is_empty() {
+        return None;
+    }
+    // "foo=" is the same as "foo"
+    let value = value.filter(|v| !v.is_empty());
+    Some((name.to_ascii_lowercase(), value))
+}
+
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    out.extend(chars.next());
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+        None => s.to_owned(),
+    }
+}
+
+// Split on `sep`, but not inside a quoted string
+fn split_quoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if in_quotes && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            parts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts.into_iter().filter(|p| !p.trim().is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prefer: &[&str]) -> Request {
+        let mut req = http::Request::builder();
+        for p in prefer {
+            req = req.header("Prefer", *p);
+        }
+        req.body(vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_preferences() {
+        let prefs = preferences(&request(&["return=minimal, wait=10", "respond-async, Return=representation"]));
+        assert_eq!(prefs.return_, Some(Return::Minimal));
+        assert_eq!(prefs.wait, Some(10));
+        assert!(prefs.respond_async);
+        assert_eq!(prefs.handling, None);
+        assert_eq!(prefs.all.len(), 3);
+
+        let prefs = preferences(&request(&["foo; bar=\"a, b;c\", handling=lenient"]));
+        assert_eq!(prefs.get("FOO").unwrap().params, vec![("bar".to_owned(), Some("a, b;c".to_owned()))]);
+        assert_eq!(prefs.handling, Some(Handling::Lenient));
+
+        assert_eq!(preferences(&request(&[])), Preferences::default());
+    }
+
+    #[test]
+    fn test_preference_applied() {
+        let mut resp = crate::empty_response(204);
+        preference_applied(&mut resp, "return=minimal").unwrap();
+        preference_applied(&mut resp, "respond-async").unwrap();
+        assert!(preference_applied(&mut resp, "wait=1\r\nSet-Cookie: a=b").is_err());
+        assert_eq!(resp.headers()["preference-applied"], "return=minimal, respond-async");
+    }
+}
@@ -0,0 +1,76 @@
+//! Report progress while reading the request body, e.g. for upload progress bars.
+//!
+//! [`handle_with_progress`](crate::handle_with_progress) calls the callback with the number of
+//! bytes read so far, and the total (`CONTENT_LENGTH`), as the body comes in from the web
+//! server. The handler is only called once it has all been read, so the callback has to store
+//! the progress somewhere another request can see it (a file, a database…), for a polling
+//! endpoint to return to the browser.
+//!
+//! ```rust,no_run
+//! let progress_file = "/tmp/upload-progress";
+//! cgi::handle_with_progress(
+//!     |read, total| { let _ = std::fs::write(progress_file, format!("{}/{}", read, total)); },
+//!     |request: cgi::Request| cgi::text_response(200, format!("Got {} bytes", request.body().len())),
+//! )
+//! ```
+
+use std::io::{self, Read};
+
+/// Wraps a reader, calling `callback(bytes_read, total)` after every successful read.
+pub struct ProgressReader<R, F> {
+    inner: R,
+    read: u64,
+    total: u64,
+    callback: F,
+}
+
+impl<R, F> ProgressReader<R, F>
+    where R: Read,
+          F: FnMut(u64, u64)
+{
+    /// `total` is only passed on to the callback, it doesn't limit the reading.
+    pub fn new(inner: R, total: u64, callback: F) -> Self {
+        ProgressReader { inner, read: 0, total, callback }
+    }
+
+    /// How many bytes have been read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R, F> Read for ProgressReader<R, F>
+    where R: Read,
+          F: FnMut(u64, u64)
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.read += n as u64;
+            (self.callback)(self.read, self.total);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let mut calls = Vec::new();
+        let mut reader = ProgressReader::new(io::Cursor::new(vec![0; 10]), 10, |read, total| calls.push((read, total)));
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.bytes_read(), 10);
+        assert_eq!(calls, vec![(4, 10), (8, 10), (10, 10)]);
+    }
+}
@@ -0,0 +1,223 @@
+//! The real client behind trusted reverse proxies.
+//!
+//! Behind a reverse proxy, `REMOTE_ADDR` is the proxy's address, and the client's address,
+//! scheme & host are in `X-Forwarded-For`, `X-Forwarded-Proto` & `X-Forwarded-Host`. Anyone can
+//! send these headers, so they're only believed if they come from a trusted proxy.
+//! [`TrustedProxies::handle`] puts the result into the request as a [`ClientInfo`] extension.
+//!
+//! ```rust,no_run
+//! use cgi::proxy::{ClientInfo, TrustedProxies};
+//!
+//! let proxies = TrustedProxies::parse(&["10.0.0.0/8", "::1"]).unwrap();
+//! cgi::handle(|request: cgi::Request| proxies.handle(request, |request| {
+//!     let client = request.extensions().get::<ClientInfo>().unwrap();
+//!     cgi::text_response(200, format!("Hello {} via {}", client.ip, client.scheme))
+//! }))
+//! ```
+//!
+//! `X-Forwarded-For` is read from right to left: every proxy appends the address it got the
+//! request from, so the first address which isn't a trusted proxy is the client's. Addresses
+//! further left were sent by the client and can't be trusted.
+//!
+//! [`AccessControl`](crate::access::AccessControl) checks the [`ClientInfo`] address instead
+//! of `REMOTE_ADDR` when it runs after [`TrustedProxies`].
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::meta::RemoteAddr;
+use crate::{Request, Response};
+
+/// A network in CIDR notation (`192.0.2.0/24`, `2001:db8::/32`), or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// `None` if the prefix is longer than the address
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        (prefix <= max).then_some(IpNet { addr, prefix })
+    }
+
+    /// Whether `ip` is in this network. IPv4-mapped IPv6 addresses are compared as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(n), IpAddr::V4(i)) => (u128::from(u32::from(n)), u128::from(u32::from(i)), 32),
+            (IpAddr::V6(n), IpAddr::V6(i)) => (u128::from(n), u128::from(i), 128),
+            _ => return false,
+        };
+        let shift = bits - u32::from(self.prefix);
+        shift >= bits || (net >> shift) == (ip >> shift)
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidNet;
+
+    fn from_str(s: &str) -> Result<Self, InvalidNet> {
+        let invalid = || InvalidNet(s.to_owned());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().map_err(|_| invalid())?, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s.parse::<IpAddr>().map_err(|_| invalid())?, None),
+        };
+        let prefix = prefix.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        IpNet::new(addr, prefix).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A network which couldn't be parsed (with the text).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidNet(pub String);
+
+impl fmt::Display for InvalidNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid network {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidNet {}
+
+/// Who sent the request, after taking trusted proxies into account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The client's address
+    pub ip: IpAddr,
+    /// `http` or `https`
+    pub scheme: String,
+    /// The host the client asked for, if it's known
+    pub host: Option<String>,
+}
+
+/// The proxies whose `X-Forwarded-*` headers are believed. See the [module docs](self).
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new(nets: impl IntoIterator<Item = IpNet>) -> Self {
+        TrustedProxies { nets: nets.into_iter().collect() }
+    }
+
+    /// Parse the networks, e.g. `["10.0.0.0/8", "::1"]`
+    pub fn parse(nets: &[&str]) -> Result<Self, InvalidNet> {
+        Ok(TrustedProxies::new(nets.iter().map(|net| net.parse()).collect::<Result<Vec<_>, _>>()?))
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// The client of `request`, or `None` if the request has no (valid) `REMOTE_ADDR`
+    pub fn resolve(&self, request: &Request) -> Option<ClientInfo> {
+        let remote = match request.extensions().get::<RemoteAddr>() {
+            Some(remote) => remote.ip(),
+            None => RemoteAddr::parse(request.headers().get("x-cgi-remote-addr")?.to_str().ok()?, None)?.ip(),
+        };
+        let mut client = ClientInfo {
+            ip: remote,
+            scheme: request.uri().scheme_str().unwrap_or("http").to_owned(),
+            host: request.uri().authority().map(|a| a.to_string())
+                .or_else(|| request.headers().get(http::header::HOST).and_then(|h| h.to_str().ok()).map(|h| h.to_owned())),
+        };
+        if !self.is_trusted(remote) {
+            return Some(client);
+        }
+
+        for hop in header_list(request, "x-forwarded-for").iter().rev() {
+            match RemoteAddr::parse(hop, None) {
+                Some(addr) => {
+                    client.ip = addr.ip();
+                    if !self.is_trusted(addr.ip()) {
+                        break;
+                    }
+                }
+                // Garbage, so the last address is as far as it can be followed
+                None => break,
+            }
+        }
+        // The value added by the nearest proxy
+        if let Some(proto) = header_list(request, "x-forwarded-proto").last() {
+            if proto.eq_ignore_ascii_case("http") || proto.eq_ignore_ascii_case("https") {
+                client.scheme = proto.to_ascii_lowercase();
+            }
+        }
+        if let Some(host) = header_list(request, "x-forwarded-host").last() {
+            if host.parse::<http::uri::Authority>().is_ok() {
+                client.host = Some(host.clone());
+            }
+        }
+        Some(client)
+    }
+
+    /// Add the [`ClientInfo`] to the request and call `next`. A request without a valid
+    /// `REMOTE_ADDR` is passed on without it.
+    pub fn handle<F>(&self, mut request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        if let Some(client) = self.resolve(&request) {
+            request.extensions_mut().insert(client);
+        }
+        next(request)
+    }
+}
+
+// The comma-separated elements of every `name` header, in order
+fn header_list(request: &Request, name: &str) -> Vec<String> {
+    request.headers().get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_owned())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!("::1".parse::<IpNet>().unwrap().contains("::1".parse().unwrap()));
+        assert_eq!("10.0.0.0/33".parse::<IpNet>(), Err(InvalidNet("10.0.0.0/33".into())));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let proxies = TrustedProxies::parse(&["10.0.0.0/8"]).unwrap();
+        let resolve = |remote: &str, headers: &[(&str, &str)]| {
+            let mut builder = CgiRequestBuilder::new().env("REMOTE_ADDR", remote);
+            for (name, value) in headers {
+                builder = builder.header(name, value);
+            }
+            proxies.resolve(&builder.build()).unwrap()
+        };
+
+        let headers = [("X-Forwarded-For", "6.6.6.6, 192.0.2.1, 10.0.0.2"), ("X-Forwarded-Proto", "https"), ("X-Forwarded-Host", "example.com")];
+        let client = resolve("10.0.0.1", &headers);
+        assert_eq!(client, ClientInfo { ip: "192.0.2.1".parse().unwrap(), scheme: "https".into(), host: Some("example.com".into()) });
+
+        // Not from a trusted proxy, so the headers are ignored
+        let client = resolve("192.0.2.9", &headers);
+        assert_eq!(client, ClientInfo { ip: "192.0.2.9".parse().unwrap(), scheme: "http".into(), host: Some("localhost".into()) });
+
+        assert_eq!(resolve("10.0.0.1", &[("X-Forwarded-For", "10.0.0.3")]).ip, "10.0.0.3".parse::<IpAddr>().unwrap());
+        assert_eq!(resolve("10.0.0.1", &[("X-Forwarded-For", "unknown, 10.0.0.3")]).ip, "10.0.0.3".parse::<IpAddr>().unwrap());
+        assert_eq!(resolve("10.0.0.1", &[("X-Forwarded-Proto", "gopher")]).scheme, "http");
+    }
+}
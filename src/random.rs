@@ -0,0 +1,24 @@
+// Unpredictable bytes for IDs, tokens & nonces, from the OS
+
+/// `n` random bytes from the OS's secure random number generator (`getrandom(2)`,
+/// `/dev/urandom` …). Panics if there isn't one: session IDs & nonces mustn't be guessable,
+/// so there's no weaker fallback.
+pub(crate) fn bytes(n: usize) -> Vec<u8> {
+    let mut buf = vec![0; n];
+    getrandom::getrandom(&mut buf).expect("no random number generator available from the OS");
+    buf
+}
+
+/// `n` random bytes, as lowercase hex
+pub(crate) fn hex(n: usize) -> String {
+    bytes(n).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_random() {
+        assert_eq!(super::bytes(5).len(), 5);
+        assert_ne!(super::bytes(16), super::bytes(16));
+    }
+}
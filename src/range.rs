@@ -0,0 +1,224 @@
+//! Range requests (`206 Partial Content`), so downloads can be resumed.
+//!
+//! [`ranged`] turns a complete `200` response into the part the `Range` header asks for, and
+//! [`file_response`] sends a file, reading only the requested part of it from disk.
+//!
+//! ```rust,no_run
+//! cgi::handle(|request: cgi::Request| {
+//!     cgi::range::file_response(&request, "/srv/downloads/image.iso", "application/octet-stream")
+//!         .unwrap_or_else(|_| cgi::empty_response(404))
+//! })
+//! ```
+//!
+//! A single range (`bytes=100-199`, `bytes=100-` or `bytes=-100`) gets a `206` with
+//! `Content-Range`, and a range beyond the end gets `416 Range Not Satisfiable`. Anything else
+//! gets the full `200`: no or an invalid `Range`, requests for several ranges, an `If-Range`
+//! which doesn't match the current `ETag` or `Last-Modified`, and methods other than `GET`.
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::conditional::Validators;
+use crate::stream::BodyWriter;
+use crate::{Request, Response};
+
+/// The part of a body a request asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteRange {
+    /// The whole body
+    Full,
+    /// The bytes `start..end`
+    Partial(Range<u64>),
+    /// A range outside of the body
+    Unsatisfiable,
+}
+
+/// Parse the value of a `Range` header for a body of `len` bytes
+pub fn parse_range(value: &str, len: u64) -> ByteRange {
+    let value = value.trim();
+    let Some(spec) = value.get(..6).filter(|unit| unit.eq_ignore_ascii_case("bytes=")).map(|_| &value[6..]) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else { return ByteRange::Full };
+    let number = |s: &str| (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())).then(|| s.parse::<u64>().ok()).flatten();
+    match (first.trim(), last.trim()) {
+        ("", suffix) => match number(suffix) {
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(suffix) if len > 0 => ByteRange::Partial(len.saturating_sub(suffix)..len),
+            Some(_) => ByteRange::Unsatisfiable,
+            None => ByteRange::Full,
+        },
+        (first, "") => match number(first) {
+            Some(first) if first < len => ByteRange::Partial(first..len),
+            Some(_) => ByteRange::Unsatisfiable,
+            None => ByteRange::Full,
+        },
+        (first, last) => match (number(first), number(last)) {
+            (Some(first), Some(last)) if first > last => ByteRange::Full,
+            (Some(first), Some(last)) if first < len => ByteRange::Partial(first..last.saturating_add(1).min(len)),
+            (Some(_), Some(_)) => ByteRange::Unsatisfiable,
+            _ => ByteRange::Full,
+        },
+    }
+}
+
+/// The range `request` asks for, out of a body of `len` bytes with the validators in
+/// `headers` (`ETag` & `Last-Modified`)
+pub fn request_range(request: &Request, headers: &http::HeaderMap, len: u64) -> ByteRange {
+    if request.method() != http::Method::GET {
+        return ByteRange::Full;
+    }
+    let Some(value) = request.headers().get(http::header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return ByteRange::Full;
+    };
+    if let Some(if_range) = request.headers().get(http::header::IF_RANGE) {
+        // Only a strong ETag or the exact date match, otherwise the parts could be of different
+        // versions
+        let etag = headers.get(http::header::ETAG).filter(|e| !e.as_bytes().starts_with(b"W/"));
+        let matches = if if_range.as_bytes().starts_with(b"\"") {
+            etag == Some(if_range)
+        } else {
+            headers.get(http::header::LAST_MODIFIED) == Some(if_range)
+        };
+        if !matches {
+            return ByteRange::Full;
+        }
+    }
+    parse_range(value, len)
+}
+
+/// The requested part of a complete `200` response. Other responses, and those whose body is
+/// written by a callback, are returned as they are.
+pub fn ranged(request: &Request, mut response: Response) -> Response {
+    if response.status() != http::StatusCode::OK || response.extensions().get::<BodyWriter>().is_some() {
+        return response;
+    }
+    response.headers_mut().insert(http::header::ACCEPT_RANGES, http::HeaderValue::from_static("bytes"));
+    let len = response.body().len() as u64;
+    match request_range(request, response.headers(), len) {
+        ByteRange::Full => response,
+        ByteRange::Partial(range) => {
+            let body = response.body()[range.start as usize..range.end as usize].to_vec();
+            partial(&mut response, range, len);
+            *response.body_mut() = body;
+            response
+        }
+        ByteRange::Unsatisfiable => unsatisfiable(response, len),
+    }
+}
+
+/// A `GET` response for the file, with `Content-Length`, `ETag` & `Last-Modified`, and only
+/// the requested range if there is one. The file is read while the response is written.
+pub fn file_response(request: &Request, path: impl AsRef<Path>, content_type: &str) -> io::Result<Response> {
+    let file = File::open(path.as_ref())?;
+    let len = file.metadata()?.len();
+    let mut response = http::Response::builder()
+        .status(200)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::CONTENT_LENGTH, len)
+        .body(Vec::new())
+        .unwrap();
+    Validators::from_file(path)?.set_headers(&mut response);
+
+    let range = match request_range(request, response.headers(), len) {
+        ByteRange::Full => 0..len,
+        ByteRange::Partial(range) => {
+            partial(&mut response, range.clone(), len);
+            range
+        }
+        ByteRange::Unsatisfiable => return Ok(unsatisfiable(response, len)),
+    };
+    response.extensions_mut().insert(BodyWriter::new(move |out| crate::files::write_file(file, range, out)));
+    Ok(response)
+}
+
+fn partial(response: &mut Response, range: Range<u64>, len: u64) {
+    *response.status_mut() = http::StatusCode::PARTIAL_CONTENT;
+    let headers = response.headers_mut();
+    headers.insert(http::header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, len).parse().unwrap());
+    headers.insert(http::header::CONTENT_LENGTH, (range.end - range.start).into());
+}
+
+fn unsatisfiable(mut response: Response, len: u64) -> Response {
+    *response.status_mut() = http::StatusCode::RANGE_NOT_SATISFIABLE;
+    let headers = response.headers_mut();
+    headers.insert(http::header::CONTENT_RANGE, format!("bytes */{}", len).parse().unwrap());
+    headers.insert(http::header::CONTENT_LENGTH, 0.into());
+    headers.remove(http::header::CONTENT_TYPE);
+    response.extensions_mut().remove::<BodyWriter>();
+    response.body_mut().clear();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0..100));
+        assert_eq!(parse_range("bytes=900-", 1000), ByteRange::Partial(900..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Partial(900..1000));
+        assert_eq!(parse_range("bytes=-2000", 1000), ByteRange::Partial(0..1000));
+        assert_eq!(parse_range("bytes=500-5000", 1000), ByteRange::Partial(500..1000));
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=0-1, 5-6", 1000), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=+1-2", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_ranged() {
+        let body = (0..100u8).collect::<Vec<u8>>();
+        let response = |range: &str, if_range: &str| {
+            let mut builder = CgiRequestBuilder::new().header("Range", range);
+            if !if_range.is_empty() {
+                builder = builder.header("If-Range", if_range);
+            }
+            let mut response = crate::binary_response(200, None, body.clone());
+            response.headers_mut().insert(http::header::ETAG, "\"v1\"".parse().unwrap());
+            ranged(&builder.build(), response)
+        };
+
+        let partial = response("bytes=10-19", "");
+        assert_eq!(partial.status(), 206);
+        assert_eq!(partial.headers()["content-range"], "bytes 10-19/100");
+        assert_eq!(partial.headers()["content-length"], "10");
+        assert_eq!(partial.body(), &body[10..20]);
+
+        let unsatisfiable = response("bytes=100-", "");
+        assert_eq!(unsatisfiable.status(), 416);
+        assert_eq!(unsatisfiable.headers()["content-range"], "bytes */100");
+        assert!(unsatisfiable.body().is_empty());
+
+        assert_eq!(response("bytes=10-19", "\"v1\"").status(), 206);
+        let changed = response("bytes=10-19", "\"v0\"");
+        assert_eq!(changed.status(), 200);
+        assert_eq!(changed.headers()["accept-ranges"], "bytes");
+        assert_eq!(changed.body(), &body);
+    }
+
+    #[test]
+    fn test_file_response() {
+        let path = std::env::temp_dir().join(format!("cgi-range-{}", std::process::id()));
+        std::fs::write(&path, b"Hello World").unwrap();
+        let request = CgiRequestBuilder::new().header("Range", "bytes=-5").build();
+        let mut response = file_response(&request, &path, "text/plain").unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.headers()["content-range"], "bytes 6-10/11");
+        assert!(response.headers().contains_key("etag"));
+        let mut body = Vec::new();
+        crate::stream::take_body_writer(&mut response).unwrap().write_to(&mut body).unwrap();
+        assert_eq!(body, b"World");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,403 @@
+//! Self-hosted error reporting: emit `Reporting-Endpoints`, `Report-To` and `NEL` headers, and
+//! collect the reports browsers send back.
+//!
+//! The headers tell the browser where to send reports (CSP violations, deprecations, network
+//! errors…). [`ReportLog`] is a handler for that endpoint, which appends every report to a
+//! file, one JSON object per line.
+//!
+//! ```rust,no_run
+//! use cgi::reporting::{nel, reporting_endpoints, NelPolicy, ReportLog};
+//!
+//! // cgi-bin/reports
+//! fn main() {
+//!     cgi::handle(|request: cgi::Request| ReportLog::new("/var/log/www/reports.jsonl").handle(request))
+//! }
+//!
+//! // and in the other CGI programmes
+//! fn add_reporting(response: &mut cgi::Response) {
+//!     reporting_endpoints(response, &[("default", "https://example.com/cgi-bin/reports")]).unwrap();
+//!     nel(response, &NelPolicy::new("default", 86400)).unwrap();
+//! }
+//! ```
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::meta::RemoteAddr;
+use crate::structured::{self, BareItem};
+use crate::{append_log_line, empty_response, text_response, Request, Response};
+
+/// Set the `Reporting-Endpoints` header, a list of `(name, url)` pairs. A name which isn't a
+/// structured field key (lowercase, e.g. `csp-endpoint`) or a URL with characters other than
+/// printable ASCII is an error, and the header isn't set.
+pub fn reporting_endpoints(response: &mut Response, endpoints: &[(&str, &str)]) -> Result<(), structured::Error> {
+    let dict = endpoints.iter()
+        .map(|(name, url)| (name.to_string(), BareItem::String(url.to_string()).into()))
+        .collect();
+    let value = structured::serialize_dictionary(&dict)?;
+    // A serialized structured field is always a valid header value
+    if let Ok(value) = http::HeaderValue::from_str(&value) {
+        response.headers_mut().insert("reporting-endpoints", value);
+    }
+    Ok(())
+}
+
+/// Set the (older) `Report-To` header, for browsers which don't support
+/// `Reporting-Endpoints`. `max_age` is in seconds. If the value isn't a valid header value,
+/// that's an error, and the header isn't set.
+pub fn report_to(response: &mut Response, group: &str, max_age: u64, urls: &[&str]) -> Result<(), http::header::InvalidHeaderValue> {
+    let endpoints: Vec<String> = urls.iter().map(|u| format!("{{\"url\":{}}}", json_string(u))).collect();
+    let value = format!("{{\"group\":{},\"max_age\":{},\"endpoints\":[{}]}}", json_string(group), max_age, endpoints.join(","));
+    response.headers_mut().append("report-to", value.parse()?);
+    Ok(())
+}
+
+/// A Network Error Logging policy (the `NEL` header).
+#[derive(Debug, Clone)]
+pub struct NelPolicy {
+    /// The reporting group/endpoint name
+    pub report_to: String,
+    /// In seconds, `0` removes the policy
+    pub max_age: u64,
+    pub include_subdomains: bool,
+    /// Fraction of successful requests to report, between 0 and 1
+    pub success_fraction: Option<f64>,
+    /// Fraction of failed requests to report, between 0 and 1
+    pub failure_fraction: Option<f64>,
+}
+
+impl NelPolicy {
+    pub fn new(report_to: impl Into<String>, max_age: u64) -> Self {
+        NelPolicy {
+            report_to: report_to.into(),
+            max_age,
+            include_subdomains: false,
+            success_fraction: None,
+            failure_fraction: None,
+        }
+    }
+}
+
+/// Set the `NEL` header. Like [`report_to`], an invalid header value is an error.
+pub fn nel(response: &mut Response, policy: &NelPolicy) -> Result<(), http::header::InvalidHeaderValue> {
+    let mut value = format!("{{\"report_to\":{},\"max_age\":{}", json_string(&policy.report_to), policy.max_age);
+    if policy.include_subdomains {
+        value.push_str(",\"include_subdomains\":true");
+    }
+    if let Some(f) = policy.success_fraction {
+        value.push_str(&format!(",\"success_fraction\":{}", f.clamp(0., 1.)));
+    }
+    if let Some(f) = policy.failure_fraction {
+        value.push_str(&format!(",\"failure_fraction\":{}", f.clamp(0., 1.)));
+    }
+    value.push('}');
+    response.headers_mut().insert("nel", value.parse()?);
+    Ok(())
+}
+
+/// A handler for a reporting endpoint, which appends the `application/reports+json` POSTed
+/// to it to a file.
+///
+/// Each line of the file is a JSON object with the time received (UNIX seconds), the
+/// client's address, the `User-Agent`, and the reports as sent by the browser:
+///
+/// ```text
+/// {"received":1700000000,"remote_addr":"192.0.2.1","user_agent":"…","reports":[…]}
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReportLog {
+    path: PathBuf,
+    max_body_size: usize,
+}
+
+impl ReportLog {
+    /// Append reports to the file at `path`, which is created if needed.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        ReportLog { path: path.into(), max_body_size: 64 * 1024 }
+    }
+
+    /// Reject reports larger than this (default 64 KiB) with a `413`
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Handle the request, returning `204 No Content` once the report is stored.
+    pub fn handle(&self, request: Request) -> Response {
+        if request.method() == http::Method::OPTIONS {
+            // CORS preflight, reports may be sent from other origins
+            let mut response = empty_response(204);
+            let headers = response.headers_mut();
+            headers.insert("access-control-allow-origin", "*".parse().unwrap());
+            headers.insert("access-control-allow-methods", "POST".parse().unwrap());
+            headers.insert("access-control-allow-headers", "content-type".parse().unwrap());
+            return response;
+        }
+        if request.method() != http::Method::POST {
+            let mut response = empty_response(405);
+            response.headers_mut().insert(http::header::ALLOW, "POST, OPTIONS".parse().unwrap());
+            return response;
+        }
+
+        let content_type = request.headers().get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase());
+        if !matches!(content_type.as_deref(), Some("application/reports+json" | "application/json")) {
+            return empty_response(415);
+        }
+        if request.body().len() > self.max_body_size {
+            return empty_response(413);
+        }
+
+        // Re-serialized, so the line can't hold anything but the reports
+        let reports = match std::str::from_utf8(request.body()).ok().and_then(compact_json) {
+            Some(reports) => reports,
+            None => return text_response(400, "reports must be a JSON array or object"),
+        };
+
+        let user_agent = request.headers().get(http::header::USER_AGENT)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .unwrap_or_default();
+        let remote_addr = request.extensions().get::<RemoteAddr>().map(|addr| addr.ip().to_string()).unwrap_or_default();
+        let received = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let line = format!("{{\"received\":{},\"remote_addr\":{},\"user_agent\":{},\"reports\":{}}}\n",
+            received, json_string(&remote_addr), json_string(&user_agent), reports);
+
+        match append_log_line(&self.path, &line) {
+            Ok(()) => empty_response(204),
+            Err(err) => {
+                eprintln!("Could not write report to {:?}: {}", self.path, err);
+                empty_response(500)
+            }
+        }
+    }
+}
+
+// The JSON array or object without whitespace between tokens, or `None` if it isn't valid
+fn compact_json(input: &str) -> Option<String> {
+    let mut parser = JsonParser { input: input.as_bytes(), pos: 0, out: String::with_capacity(input.len()) };
+    parser.whitespace();
+    if !matches!(parser.peek(), Some(b'[' | b'{')) {
+        return None;
+    }
+    parser.value(0)?;
+    parser.whitespace();
+    (parser.pos == parser.input.len()).then_some(parser.out)
+}
+
+struct JsonParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    out: String,
+}
+
+impl JsonParser<'_> {
+    // Deeper nesting is rejected, rather than risking the stack
+    const MAX_DEPTH: usize = 64;
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    // Copy `len` bytes of the input, which are ASCII or a whole UTF-8 sequence
+    fn copy(&mut self, len: usize) {
+        self.out.push_str(std::str::from_utf8(&self.input[self.pos..self.pos + len]).unwrap_or_default());
+        self.pos += len;
+    }
+
+    fn value(&mut self, depth: usize) -> Option<()> {
+        if depth > Self::MAX_DEPTH {
+            return None;
+        }
+        match self.peek()? {
+            open @ (b'[' | b'{') => {
+                let close = if open == b'[' { b']' } else { b'}' };
+                self.copy(1);
+                self.whitespace();
+                if self.peek() == Some(close) {
+                    self.copy(1);
+                    return Some(());
+                }
+                loop {
+                    if open == b'{' {
+                        self.string()?;
+                        self.whitespace();
+                        (self.peek()? == b':').then_some(())?;
+                        self.copy(1);
+                        self.whitespace();
+                    }
+                    self.value(depth + 1)?;
+                    self.whitespace();
+                    match self.peek()? {
+                        b',' => self.copy(1),
+                        c if c == close => {
+                            self.copy(1);
+                            return Some(());
+                        }
+                        _ => return None,
+                    }
+                    self.whitespace();
+                }
+            }
+            b'"' => self.string(),
+            b't' => self.literal("true"),
+            b'f' => self.literal("false"),
+            b'n' => self.literal("null"),
+            _ => self.number(),
+        }
+    }
+
+    fn literal(&mut self, literal: &str) -> Option<()> {
+        self.input[self.pos..].starts_with(literal.as_bytes()).then(|| self.copy(literal.len()))
+    }
+
+    fn string(&mut self) -> Option<()> {
+        (self.peek()? == b'"').then_some(())?;
+        self.copy(1);
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.copy(1);
+                    return Some(());
+                }
+                b'\\' => {
+                    match self.input.get(self.pos + 1)? {
+                        b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => self.copy(2),
+                        b'u' if self.input.get(self.pos + 2..self.pos + 6)?.iter().all(u8::is_ascii_hexdigit) => self.copy(6),
+                        _ => return None,
+                    }
+                }
+                // Control characters (like a newline) must be escaped
+                c if c < 0x20 => return None,
+                c => {
+                    let len = match c {
+                        0xf0.. => 4,
+                        0xe0.. => 3,
+                        0xc0.. => 2,
+                        _ => 1,
+                    };
+                    self.copy(len);
+                }
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<()> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let from = parser.pos;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.pos += 1;
+            }
+            parser.pos > from
+        };
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'0') {
+            self.pos += 1;
+        } else if !digits(self) {
+            return None;
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            digits(self).then_some(())?;
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            digits(self).then_some(())?;
+        }
+        let len = self.pos - start;
+        self.pos = start;
+        self.copy(len);
+        Some(())
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\x7f' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let mut resp = empty_response(200);
+        reporting_endpoints(&mut resp, &[("default", "https://example.com/reports"), ("csp", "/csp")]).unwrap();
+        report_to(&mut resp, "default", 3600, &["https://example.com/reports"]).unwrap();
+        nel(&mut resp, &NelPolicy { failure_fraction: Some(0.5), ..NelPolicy::new("default", 86400) }).unwrap();
+        assert_eq!(resp.headers()["reporting-endpoints"], "default=\"https://example.com/reports\", csp=\"/csp\"");
+        assert_eq!(resp.headers()["report-to"], "{\"group\":\"default\",\"max_age\":3600,\"endpoints\":[{\"url\":\"https://example.com/reports\"}]}");
+        assert_eq!(resp.headers()["nel"], "{\"report_to\":\"default\",\"max_age\":86400,\"failure_fraction\":0.5}");
+
+        let mut resp = empty_response(200);
+        assert!(reporting_endpoints(&mut resp, &[("Not A Key", "/reports")]).is_err());
+        assert!(reporting_endpoints(&mut resp, &[("default", "/r\u{e9}ports")]).is_err());
+        assert!(!resp.headers().contains_key("reporting-endpoints"));
+        // Control characters are escaped, so they can't break the header
+        report_to(&mut resp, "group\u{7f}", 3600, &["/reports\r\nSet-Cookie: a=b"]).unwrap();
+        assert_eq!(resp.headers()["report-to"], "{\"group\":\"group\\u007f\",\"max_age\":3600,\"endpoints\":[{\"url\":\"/reports\\r\\nSet-Cookie: a=b\"}]}");
+        assert_eq!(json_string("a\u{7f}\u{1}\"b"), "\"a\\u007f\\u0001\\\"b\"");
+    }
+
+    #[test]
+    fn test_compact_json() {
+        assert_eq!(compact_json(" [ {\"a\" : [1, -2.5e+3, true, null], \"b\\n\": \"x\\u00e9\u{e9} \"}, {} ]\n").as_deref(),
+            Some("[{\"a\":[1,-2.5e+3,true,null],\"b\\n\":\"x\\u00e9\u{e9} \"},{}]"));
+        for invalid in ["", "\"text\"", "1", "[1,]", "{\"a\"}", "[01]", "[\"a\nb\"]", "{\"a\":1} {\"b\":2}", "[tru]", "[\"\\x\"]"] {
+            assert_eq!(compact_json(invalid), None, "{:?}", invalid);
+        }
+        assert_eq!(compact_json(&"[".repeat(100)), None);
+    }
+
+    #[test]
+    fn test_report_log() {
+        let path = std::env::temp_dir().join(format!("cgi-reports-{}.jsonl", std::process::id()));
+        let log = ReportLog::new(&path);
+        let request = |method: &str, content_type: &str, body: &str| http::Request::builder()
+            .method(method)
+            .header("content-type", content_type)
+            .extension(RemoteAddr("192.0.2.1".parse().unwrap(), None))
+            .body(body.as_bytes().to_vec())
+            .unwrap();
+
+        assert_eq!(log.handle(request("GET", "application/reports+json", "")).status(), 405);
+        assert_eq!(log.handle(request("POST", "text/plain", "[]")).status(), 415);
+        assert_eq!(log.handle(request("POST", "application/reports+json", "nope")).status(), 400);
+        assert_eq!(log.handle(request("POST", "application/reports+json", "[{\"type\": \"csp-violation\",\n\"body\": {}}]")).status(), 204);
+        // Not a second record, nor another field of this one
+        assert_eq!(log.handle(request("POST", "application/reports+json", "[]}\n{\"received\":0,\"reports\":[]")).status(), 400);
+        assert_eq!(log.handle(request("POST", "application/reports+json", "[],\"admin\":true")).status(), 400);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.contains("\"remote_addr\":\"192.0.2.1\",\"user_agent\":\"\",\"reports\":[{\"type\":\"csp-violation\",\"body\":{}}]}"));
+    }
+}
@@ -0,0 +1,87 @@
+// A `tracing` span per request (`tracing` feature)
+
+use std::time::Instant;
+
+use tracing::field::Empty;
+use tracing::span::EnteredSpan;
+
+use crate::meta::{RemoteAddr, RequestId};
+use crate::Response;
+
+// The span `request` with the method, path, client address & request ID, entered while the
+// handler runs. `finish` records the status and emits an event with the latency.
+pub(crate) struct RequestSpan {
+    span: EnteredSpan,
+    started: Instant,
+}
+
+impl RequestSpan {
+    pub(crate) fn new<B>(request: &http::Request<B>) -> Self {
+        let remote_addr = request.extensions().get::<RemoteAddr>().map(|addr| addr.0);
+        let request_id = request.extensions().get::<RequestId>().map(RequestId::as_str);
+        let span = tracing::info_span!("request",
+            method = %request.method(),
+            path = request.uri().path(),
+            remote_addr = remote_addr.map(tracing::field::display),
+            request_id,
+            status = Empty,
+        );
+        RequestSpan { span: span.entered(), started: Instant::now() }
+    }
+
+    pub(crate) fn finish(self, response: &Response) {
+        let status = response.status().as_u16();
+        self.span.record("status", status);
+        let latency_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        if response.status().is_server_error() {
+            tracing::warn!(status, latency_ms, "request failed");
+        } else {
+            tracing::info!(status, latency_ms, "request completed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    // Collects `name=value` of every span field & event
+    #[derive(Default, Clone)]
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Collect {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.lock().unwrap().push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Collect {
+        fn enabled(&self, _: &Metadata) -> bool { true }
+        fn new_span(&self, span: &Attributes) -> Id {
+            span.record(&mut self.clone());
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, values: &Record) { values.record(&mut self.clone()) }
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event) { event.record(&mut self.clone()) }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_request_span() {
+        let collect = Collect::default();
+        tracing::subscriber::with_default(collect.clone(), || {
+            let request = crate::testing::CgiRequestBuilder::new().method("POST").path_info("/users").header("X-Request-Id", "r1").build();
+            RequestSpan::new(&request).finish(&crate::empty_response(201));
+        });
+        let fields = collect.0.lock().unwrap();
+        assert_eq!(fields[..5], ["method=POST", "path=\"/cgi-bin/test/users\"", "remote_addr=127.0.0.1", "request_id=\"r1\"", "status=201"]);
+        assert_eq!(fields[5..7], ["message=request completed", "status=201"]);
+        assert!(fields[7].starts_with("latency_ms="));
+    }
+}
@@ -0,0 +1,269 @@
+//! Dispatch requests to handlers by method and `PATH_INFO`.
+//!
+//! Patterns are matched segment by segment: `:name` captures one segment, and `*name` at the
+//! end captures the rest of the path (possibly empty). The captured values are in the
+//! [`PathParams`] extension of the request.
+//!
+//! ```rust,no_run
+//! use cgi::router::{PathParams, Router};
+//!
+//! fn show_item(request: cgi::Request) -> cgi::Response {
+//!     let params = request.extensions().get::<PathParams>().unwrap();
+//!     cgi::text_response(200, format!("Item {}", params.get("id").unwrap()))
+//! }
+//!
+//! let router = Router::new()
+//!     .get("/items/:id", show_item)
+//!     .post("/items", |_| cgi::text_response(201, "Created"))
+//!     .get("/files/*path", |request| {
+//!         let path = request.extensions().get::<PathParams>().unwrap().get("path").unwrap().to_owned();
+//!         cgi::text_response(200, path)
+//!     });
+//! cgi::handle(|request: cgi::Request| router.handle(request))
+//! ```
+//!
+//! A request for a path no route matches is answered with `404 Not Found` (see
+//! [`Router::fallback`]), one for a matching path but another method with `405 Method Not
+//! Allowed` and an `Allow` header. `HEAD` requests are handled by `GET` routes, unless
+//! there's a `HEAD` route. `/items` and `/items/` are different paths.
+
+use http::Method;
+
+use crate::{empty_response, Request, Response};
+
+/// The parameters captured by the matching route's pattern, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathParams(Vec<(String, String)>);
+
+impl PathParams {
+    /// The value captured as `:name` or `*name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+type Handler<'a> = Box<dyn Fn(Request) -> Response + 'a>;
+
+struct Route<'a> {
+    method: Option<Method>,
+    segments: Vec<Segment>,
+    handler: Handler<'a>,
+}
+
+impl Route<'_> {
+    fn matches(&self, path: &str) -> Option<PathParams> {
+        let mut params = Vec::new();
+        let mut rest = path.strip_prefix('/').unwrap_or(path);
+        // `/` and the empty path have no segments
+        let mut parts = (!rest.is_empty()).then(|| rest.split('/')).into_iter().flatten();
+        for segment in &self.segments {
+            if let Segment::Rest(name) = segment {
+                params.push((name.clone(), rest.to_owned()));
+                return Some(PathParams(params));
+            }
+            let part = parts.next()?;
+            rest = rest.get(part.len() + 1..).unwrap_or("");
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name) if !part.is_empty() => params.push((name.clone(), part.to_owned())),
+                _ => return None,
+            }
+        }
+        parts.next().is_none().then_some(PathParams(params))
+    }
+}
+
+/// Routes requests to handlers. See the [module docs](self).
+#[derive(Default)]
+pub struct Router<'a> {
+    routes: Vec<Route<'a>>,
+    fallback: Option<Handler<'a>>,
+}
+
+impl<'a> Router<'a> {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Add a route for `method` (or any method, with `None`) and the `pattern`. Routes are
+    /// tried in the order they were added.
+    pub fn route<F>(mut self, method: Option<Method>, pattern: &str, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        let mut segments: Vec<Segment> = Vec::new();
+        if !pattern.is_empty() {
+            for part in pattern.split('/') {
+                assert!(!matches!(segments.last(), Some(Segment::Rest(_))), "`*` must be the last segment of a route");
+                segments.push(if let Some(name) = part.strip_prefix(':') {
+                    Segment::Param(name.to_owned())
+                } else if let Some(name) = part.strip_prefix('*') {
+                    Segment::Rest(name.to_owned())
+                } else {
+                    Segment::Literal(part.to_owned())
+                });
+            }
+        }
+        self.routes.push(Route { method, segments, handler: Box::new(handler) });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        self.route(Some(Method::GET), pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        self.route(Some(Method::POST), pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        self.route(Some(Method::PUT), pattern, handler)
+    }
+
+    pub fn patch<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        self.route(Some(Method::PATCH), pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        self.route(Some(Method::DELETE), pattern, handler)
+    }
+
+    /// A route for every method
+    pub fn any<F>(self, pattern: &str, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        self.route(None, pattern, handler)
+    }
+
+    /// Handle requests no route matches, instead of answering `404 Not Found`
+    pub fn fallback<F>(mut self, handler: F) -> Self
+        where F: Fn(Request) -> Response + 'a
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Call the handler of the first matching route
+    pub fn handle(&self, mut request: Request) -> Response {
+        let path = path_info(&request);
+        let matching: Vec<(&Route, PathParams)> = self.routes.iter()
+            .filter_map(|route| route.matches(&path).map(|params| (route, params)))
+            .collect();
+
+        let method = request.method().clone();
+        let found = matching.iter().find(|(route, _)| route.method.as_ref().is_none_or(|m| *m == method))
+            .or_else(|| (method == Method::HEAD).then(|| matching.iter().find(|(route, _)| route.method == Some(Method::GET))).flatten());
+        if let Some((route, params)) = found {
+            request.extensions_mut().insert(params.clone());
+            return (route.handler)(request);
+        }
+
+        if matching.is_empty() {
+            return match &self.fallback {
+                Some(fallback) => fallback(request),
+                None => empty_response(404),
+            };
+        }
+        let mut allowed: Vec<&str> = Vec::new();
+        for method in matching.iter().filter_map(|(route, _)| route.method.as_ref()) {
+            if !allowed.contains(&method.as_str()) {
+                allowed.push(method.as_str());
+            }
+            if *method == Method::GET && !allowed.contains(&"HEAD") {
+                allowed.push("HEAD");
+            }
+        }
+        let mut response = empty_response(405);
+        if let Ok(value) = allowed.join(", ").parse() {
+            response.headers_mut().insert(http::header::ALLOW, value);
+        }
+        response
+    }
+}
+
+// `PATH_INFO`, or for requests which didn't come from CGI, the path after `SCRIPT_NAME`
+pub(crate) fn path_info(request: &Request) -> String {
+    if let Some(path_info) = request.headers().get("x-cgi-path-info") {
+        return String::from_utf8_lossy(path_info.as_bytes()).into_owned();
+    }
+    let path = request.uri().path();
+    request.headers().get("x-cgi-script-name")
+        .and_then(|script| path.strip_prefix(script.to_str().ok()?))
+        .unwrap_or(path)
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    #[test]
+    fn test_router() {
+        let echo = |request: Request| {
+            let params = request.extensions().get::<PathParams>().unwrap();
+            let text: Vec<String> = params.iter().map(|(n, v)| format!("{}={}", n, v)).collect();
+            crate::text_response(200, format!("{} {}", request.method(), text.join(" ")))
+        };
+        let router = Router::new()
+            .get("/", echo)
+            .get("/items/:id", echo)
+            .put("/items/:id", echo)
+            .post("/items", echo)
+            .get("/items/:id/tags/:tag", echo)
+            .any("/files/*path", echo);
+        let body = |method: &str, path_info: &str| {
+            let response = CgiRequestBuilder::new().method(method).path_info(path_info).run(|r| router.handle(r));
+            (response.status().as_u16(), String::from_utf8(response.body().clone()).unwrap())
+        };
+
+        assert_eq!(body("GET", "/items/42"), (200, "GET id=42".into()));
+        assert_eq!(body("GET", "/items/42/tags/rust"), (200, "GET id=42 tag=rust".into()));
+        assert_eq!(body("POST", "/items"), (200, "POST ".into()));
+        assert_eq!(body("DELETE", "/files/a/b.txt"), (200, "DELETE path=a/b.txt".into()));
+        assert_eq!(body("GET", "/files/"), (200, "GET path=".into()));
+        assert_eq!(body("GET", ""), (200, "GET ".into()));
+        assert_eq!(body("HEAD", "/items/1").0, 200);
+
+        assert_eq!(body("GET", "/items/").0, 404);
+        assert_eq!(body("GET", "/items/1/2").0, 404);
+        assert_eq!(body("GET", "/other").0, 404);
+        let response = CgiRequestBuilder::new().method("POST").path_info("/items/1").run(|r| router.handle(r));
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.headers()["allow"], "GET, HEAD, PUT");
+
+        let router = Router::new().fallback(|_| crate::text_response(404, "Nothing here"));
+        let response = CgiRequestBuilder::new().run(|r| router.handle(r));
+        assert_eq!(response.body(), b"Nothing here");
+
+        // Not from CGI
+        let router = Router::new().get("/x", echo);
+        let request = http::Request::builder().uri("/x").body(vec![]).unwrap();
+        assert_eq!(router.handle(request).status(), 200);
+    }
+}
@@ -0,0 +1,297 @@
+//! Virus scanning of uploads before the handler sees them.
+//!
+//! Implement [`Scanner`] for your virus scanner, or use the built-in [`Clamd`] client (with
+//! the `clamd` feature). [`VirusScan`] scans the request body (each part of a
+//! `multipart/form-data` body on its own), and answers with an error response instead of
+//! calling the handler if anything is found.
+//!
+//! ```rust,no_run
+//! # #[cfg(all(unix, feature = "clamd"))]
+//! # fn main() {
+//! use cgi::scan::{Clamd, VirusScan};
+//!
+//! let scan = VirusScan::new(Clamd::unix("/run/clamav/clamd.ctl"));
+//! cgi::handle(|request: cgi::Request| scan.handle(request, |request| {
+//!     cgi::text_response(200, "Upload accepted")
+//! }))
+//! # }
+//! # #[cfg(not(all(unix, feature = "clamd")))]
+//! # fn main() {}
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::meta::RemoteAddr;
+use crate::multipart::{Multipart, MultipartError};
+use crate::{text_response, Request, Response};
+
+/// The result of a scan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// Something was found, with the name of what it was
+    Infected(String),
+}
+
+/// A virus scanner.
+pub trait Scanner {
+    /// Scan everything read from `data`
+    fn scan(&self, data: &mut dyn Read) -> io::Result<Verdict>;
+
+    /// Scan a file, e.g. an upload which was spooled to disk
+    fn scan_file(&self, path: &Path) -> io::Result<Verdict> {
+        self.scan(&mut File::open(path)?)
+    }
+}
+
+/// Scans the request body before calling the handler.
+pub struct VirusScan<S> {
+    scanner: S,
+    infected_response: Box<dyn Fn(&str) -> Response>,
+    fail_open: bool,
+}
+
+impl<S: Scanner> VirusScan<S> {
+    /// By default an infected upload gets a `422 Unprocessable Content`, and if the scanner
+    /// fails, a `503 Service Unavailable`.
+    pub fn new(scanner: S) -> Self {
+        VirusScan {
+            scanner,
+            infected_response: Box::new(|_| text_response(422, "The upload was rejected by the virus scanner")),
+            fail_open: false,
+        }
+    }
+
+    /// The response for an infected upload, called with the name of what was found
+    pub fn infected_response<F>(mut self, f: F) -> Self
+        where F: Fn(&str) -> Response + 'static
+    {
+        self.infected_response = Box::new(f);
+        self
+    }
+
+    /// If the scanner fails (e.g. isn't running), call the handler anyway instead of returning
+    /// a `503`. The error is still printed to stderr.
+    pub fn fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    // Scan each part of a multipart body, so the scanner sees the files as they were uploaded
+    fn scan(&self, request: &Request) -> Result<io::Result<Verdict>, MultipartError> {
+        let form = match Multipart::from_request(request) {
+            Ok(form) => form,
+            Err(MultipartError::NotMultipart) => return Ok(self.scanner.scan(&mut request.body().as_slice())),
+            Err(err) => return Err(err),
+        };
+        for part in form.parts.iter().filter(|part| !part.data.is_empty()) {
+            match self.scanner.scan(&mut part.data.as_slice()) {
+                Ok(Verdict::Clean) => {}
+                other => return Ok(other),
+            }
+        }
+        Ok(Ok(Verdict::Clean))
+    }
+
+    /// Scan the request body (if there is one), and call `next` if it's clean. The parts of a
+    /// `multipart/form-data` body are scanned one by one; a malformed one gets a
+    /// `400 Bad Request`.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        if request.body().is_empty() {
+            return next(request);
+        }
+        let verdict = match self.scan(&request) {
+            Ok(verdict) => verdict,
+            Err(err) => return err.into(),
+        };
+        match verdict {
+            Ok(Verdict::Clean) => next(request),
+            Ok(Verdict::Infected(name)) => {
+                let remote = request.extensions().get::<RemoteAddr>().map(|addr| addr.ip().to_string()).unwrap_or_default();
+                eprintln!("Rejected upload from {:?}: {}", remote, name);
+                (self.infected_response)(&name)
+            }
+            Err(err) => {
+                eprintln!("Virus scan failed: {}", err);
+                if self.fail_open { next(request) } else { text_response(503, "The upload could not be scanned") }
+            }
+        }
+    }
+}
+
+/// A client for the ClamAV daemon, using the `INSTREAM` command. Requires the `clamd` feature.
+#[cfg(feature = "clamd")]
+#[derive(Debug, Clone)]
+pub struct Clamd {
+    addr: ClamdAddr,
+    timeout: std::time::Duration,
+}
+
+#[cfg(feature = "clamd")]
+#[derive(Debug, Clone)]
+enum ClamdAddr {
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+    Tcp(String),
+}
+
+#[cfg(feature = "clamd")]
+impl Clamd {
+    /// Connect to clamd's unix socket, e.g. `/run/clamav/clamd.ctl`
+    #[cfg(unix)]
+    pub fn unix(path: impl Into<std::path::PathBuf>) -> Self {
+        Clamd { addr: ClamdAddr::Unix(path.into()), timeout: std::time::Duration::from_secs(60) }
+    }
+
+    /// Connect to clamd over TCP, e.g. `localhost:3310`
+    pub fn tcp(addr: impl Into<String>) -> Self {
+        Clamd { addr: ClamdAddr::Tcp(addr.into()), timeout: std::time::Duration::from_secs(60) }
+    }
+
+    /// How long to wait for clamd (default 60 seconds)
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn instream<C: Read + io::Write>(mut conn: C, data: &mut dyn Read) -> io::Result<Verdict> {
+        conn.write_all(b"zINSTREAM\0")?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = data.read(&mut buf)?;
+            conn.write_all(&(n as u32).to_be_bytes())?;
+            if n == 0 {
+                break;
+            }
+            conn.write_all(&buf[..n])?;
+        }
+        let mut reply = Vec::new();
+        conn.read_to_end(&mut reply)?;
+        parse_reply(&String::from_utf8_lossy(&reply))
+    }
+}
+
+#[cfg(feature = "clamd")]
+impl Scanner for Clamd {
+    fn scan(&self, data: &mut dyn Read) -> io::Result<Verdict> {
+        match &self.addr {
+            #[cfg(unix)]
+            ClamdAddr::Unix(path) => {
+                let conn = std::os::unix::net::UnixStream::connect(path)?;
+                conn.set_read_timeout(Some(self.timeout))?;
+                conn.set_write_timeout(Some(self.timeout))?;
+                Clamd::instream(conn, data)
+            }
+            ClamdAddr::Tcp(addr) => {
+                let conn = std::net::TcpStream::connect(addr)?;
+                conn.set_read_timeout(Some(self.timeout))?;
+                conn.set_write_timeout(Some(self.timeout))?;
+                Clamd::instream(conn, data)
+            }
+        }
+    }
+}
+
+// `stream: OK`, `stream: Eicar-Signature FOUND` or `INSTREAM size limit exceeded. ERROR`
+#[cfg(feature = "clamd")]
+fn parse_reply(reply: &str) -> io::Result<Verdict> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(name) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(name.to_owned()))
+    } else {
+        Err(io::Error::other(format!("clamd: {}", reply)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FindsEicar;
+
+    impl Scanner for FindsEicar {
+        fn scan(&self, data: &mut dyn Read) -> io::Result<Verdict> {
+            let mut contents = String::new();
+            data.read_to_string(&mut contents)?;
+            if contents.contains("EICAR") {
+                Ok(Verdict::Infected("Eicar-Signature".into()))
+            } else if contents.contains("broken") {
+                Err(io::Error::other("scanner broken"))
+            } else {
+                Ok(Verdict::Clean)
+            }
+        }
+    }
+
+    #[test]
+    fn test_virus_scan() {
+        let request = |body: &str| http::Request::builder().method("POST").body(body.as_bytes().to_vec()).unwrap();
+        let ok = |_| crate::empty_response(200);
+
+        let scan = VirusScan::new(FindsEicar);
+        assert_eq!(scan.handle(request("hello"), ok).status(), 200);
+        assert_eq!(scan.handle(request("X5O!P%@AP EICAR"), ok).status(), 422);
+        assert_eq!(scan.handle(request("broken"), ok).status(), 503);
+
+        let scan = VirusScan::new(FindsEicar).fail_open(true).infected_response(|name| crate::text_response(400, name));
+        assert_eq!(scan.handle(request("broken"), ok).status(), 200);
+        let resp = scan.handle(request("EICAR"), ok);
+        assert_eq!(resp.body(), b"Eicar-Signature");
+    }
+
+    // Only finds the signature at the start, like a scanner looking at file headers
+    struct FindsHeader;
+
+    impl Scanner for FindsHeader {
+        fn scan(&self, data: &mut dyn Read) -> io::Result<Verdict> {
+            let mut contents = Vec::new();
+            data.read_to_end(&mut contents)?;
+            Ok(if contents.starts_with(b"EICAR") { Verdict::Infected("Eicar-Signature".into()) } else { Verdict::Clean })
+        }
+    }
+
+    #[test]
+    fn test_virus_scan_multipart() {
+        let form = |file: &str| {
+            let body = format!("--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+                --XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.com\"\r\n\r\n{}\r\n--XyZ--\r\n", file);
+            http::Request::builder().method("POST").header("content-type", "multipart/form-data; boundary=XyZ")
+                .body(body.into_bytes()).unwrap()
+        };
+        let ok = |_| crate::empty_response(200);
+        let scan = VirusScan::new(FindsHeader);
+        assert_eq!(scan.handle(form("harmless"), ok).status(), 200);
+        assert_eq!(scan.handle(form("EICAR test file"), ok).status(), 422);
+        let mut malformed = form("EICAR");
+        malformed.body_mut().truncate(70);
+        assert_eq!(scan.handle(malformed, ok).status(), 400);
+    }
+
+    #[cfg(feature = "clamd")]
+    #[test]
+    fn test_clamd_protocol() {
+        struct Fake { written: Vec<u8>, reply: io::Cursor<Vec<u8>> }
+        impl Read for Fake {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.reply.read(buf) }
+        }
+        impl io::Write for Fake {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.written.write(buf) }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let mut fake = Fake { written: vec![], reply: io::Cursor::new(b"stream: Eicar-Signature FOUND\0".to_vec()) };
+        let verdict = Clamd::instream(&mut fake, &mut &b"abc"[..]).unwrap();
+        assert_eq!(verdict, Verdict::Infected("Eicar-Signature".into()));
+        assert_eq!(fake.written, b"zINSTREAM\0\0\0\0\x03abc\0\0\0\0");
+
+        assert_eq!(parse_reply("stream: OK\0").unwrap(), Verdict::Clean);
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! Run a handler as an SCGI server.
+//!
+//! SCGI is a simpler alternative to FastCGI, supported by nginx (`scgi_pass`), lighttpd and
+//! Apache (`mod_proxy_scgi`). The web server sends the CGI meta-variables and the request body
+//! over a socket, so the programme keeps running between requests; the handler gets the same
+//! [`Request`](crate::Request) as with [`handle`](crate::handle). The handler is any [`Handler`]: a closure, or
+//! a struct set up once.
+//!
+//! ```rust,no_run
+//! let listener = std::net::TcpListener::bind("127.0.0.1:4000").unwrap();
+//! cgi::scgi::serve(listener.incoming(), |request: cgi::Request| cgi::text_response(200, "Hello World"));
+//! ```
+//!
+//! Each connection carries one request. They are handled one after another, and a panic in
+//! the handler is answered with a `500`.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::stream::take_body_writer;
+use crate::{call_handler, empty_response, parse_request, reset_request_state, run_after_response, write_serialized, Handler};
+
+// The header block is rarely more than a few KB
+const MAX_HEADERS: usize = 1 << 20;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// The netstring `<length>:<headers>,`, where headers are `name\0value\0` pairs
+fn read_headers(stream: &mut impl Read) -> io::Result<HashMap<String, Vec<u8>>> {
+    let mut length = 0usize;
+    loop {
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        match byte[0] {
+            b':' => break,
+            digit @ b'0'..=b'9' => {
+                length = length * 10 + (digit - b'0') as usize;
+                if length > MAX_HEADERS {
+                    return Err(invalid("SCGI headers too long"));
+                }
+            }
+            _ => return Err(invalid("invalid SCGI netstring length")),
+        }
+    }
+    let mut block = vec![0; length + 1];
+    stream.read_exact(&mut block)?;
+    if block.pop() != Some(b',') {
+        return Err(invalid("SCGI netstring doesn't end with ','"));
+    }
+
+    // Values are kept as bytes, they needn't be UTF-8
+    let mut fields = block.split(|&b| b == 0);
+    let mut headers = HashMap::new();
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        headers.insert(String::from_utf8_lossy(name).into_owned(), value.to_vec());
+    }
+    if !headers.contains_key("CONTENT_LENGTH") {
+        return Err(invalid("SCGI request without CONTENT_LENGTH"));
+    }
+    Ok(headers)
+}
+
+/// Handle the request on one connection: read it, call `handler` and write the response.
+/// The [`after_response`](crate::after_response) hooks run afterwards, even if writing failed.
+pub fn handle<S, H>(stream: &mut S, handler: &H) -> io::Result<()>
+    where S: Read + Write,
+          H: Handler
+{
+    let headers = read_headers(stream)?;
+    let content_length: u64 = std::str::from_utf8(&headers["CONTENT_LENGTH"]).ok()
+        .and_then(|cl| cl.trim().parse().ok())
+        .ok_or_else(|| invalid("invalid CONTENT_LENGTH"))?;
+    let mut body = Vec::new();
+    stream.take(content_length).read_to_end(&mut body)?;
+    if (body.len() as u64) < content_length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    reset_request_state();
+    let mut response = catch_unwind(AssertUnwindSafe(|| call_handler(parse_request(headers, body), |request| handler.call(request))))
+        .unwrap_or_else(|_| empty_response(500));
+    let body_writer = take_body_writer(&mut response);
+    let mut out = io::BufWriter::new(&mut *stream);
+    let result = write_serialized(response, &mut out)
+        .and_then(|()| body_writer.map_or(Ok(()), |body_writer| body_writer.write_to(&mut out)))
+        .and_then(|()| out.flush());
+    drop(out);
+    run_after_response();
+    result
+}
+
+/// Serve every connection from `incoming`, e.g. `TcpListener::incoming()`. Errors on a
+/// connection are printed to stderr, and the next one is served.
+pub fn serve<I, S, H>(incoming: I, handler: H)
+    where I: IntoIterator<Item = io::Result<S>>,
+          S: Read + Write,
+          H: Handler
+{
+    #[cfg(feature = "dotenv")]
+    crate::dotenv::load_once();
+    for stream in incoming {
+        let result = stream.and_then(|mut stream| handle(&mut stream, &handler));
+        if let Err(err) = result {
+            eprintln!("SCGI connection failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Request;
+
+    struct Conn {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.input.read(buf) }
+    }
+
+    impl Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.output.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn conn(input: &[u8]) -> Conn {
+        Conn { input: io::Cursor::new(input.to_vec()), output: Vec::new() }
+    }
+
+    #[test]
+    fn test_handle() {
+        let headers = b"CONTENT_LENGTH\x005\x00SCGI\x001\x00REQUEST_METHOD\x00POST\x00SCRIPT_NAME\x00/app\x00QUERY_STRING\x00a=1\x00";
+        let mut input = format!("{}:", headers.len()).into_bytes();
+        input.extend_from_slice(headers);
+        input.extend_from_slice(b",hello");
+        let mut stream = conn(&input);
+        handle(&mut stream, &|request: Request| {
+            crate::text_response(200, format!("{} {}", request.uri(), String::from_utf8_lossy(request.body())))
+        }).unwrap();
+        let output = String::from_utf8(stream.output).unwrap();
+        assert!(output.starts_with("Status: 200 OK\n"));
+        assert!(output.ends_with("\n\n/app?a=1 hello"));
+
+        assert!(handle(&mut conn(b"3:a\x00b,"), &|_: Request| empty_response(200)).is_err());
+        assert!(handle(&mut conn(b"x:"), &|_: Request| empty_response(200)).is_err());
+        // The body is shorter than announced
+        assert!(handle(&mut conn(b"25:CONTENT_LENGTH\x0010\x00SCGI\x001\x00,short"), &|_: Request| empty_response(200)).is_err());
+    }
+
+    // A connection the web server has closed before the response is written
+    struct Closed(io::Cursor<Vec<u8>>);
+
+    impl Read for Closed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+    }
+
+    impl Write for Closed {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(io::ErrorKind::BrokenPipe.into()) }
+        fn flush(&mut self) -> io::Result<()> { Err(io::ErrorKind::BrokenPipe.into()) }
+    }
+
+    struct Hello;
+
+    impl Handler for Hello {
+        fn call(&self, _: Request) -> crate::Response {
+            crate::after_response(|| crate::server_timing::record("hook", None, None));
+            crate::text_response(200, "Hello")
+        }
+    }
+
+    #[test]
+    fn test_hooks_after_write_error() {
+        let headers = b"CONTENT_LENGTH\x000\x00SCGI\x001\x00REQUEST_METHOD\x00GET\x00SCRIPT_NAME\x00/app\x00";
+        let mut input = format!("{}:", headers.len()).into_bytes();
+        input.extend_from_slice(headers);
+        input.push(b',');
+
+        crate::server_timing::record("left over", None, None);
+        let mut stream = conn(&input);
+        handle(&mut stream, &Hello).unwrap();
+        assert!(String::from_utf8(stream.output).unwrap().ends_with("Hello"));
+        assert_eq!(crate::server_timing::take_metrics().len(), 1);
+
+        assert!(handle(&mut Closed(io::Cursor::new(input)), &Hello).is_err());
+        assert_eq!(crate::server_timing::take_metrics().len(), 1);
+    }
+}
@@ -0,0 +1,184 @@
+//! Signed and encrypted cookies. Requires the `secure-cookies` feature.
+//!
+//! A CGI programme has no memory between requests, so a cookie is often the only place to keep
+//! state. A [`CookieKey`] makes sure the client can't change it: a *signed* cookie can be read
+//! but not modified (HMAC-SHA256), a *private* cookie can't be read either (AES-256-GCM).
+//! Both are bound to the cookie name, so a value can't be moved to another cookie.
+//!
+//! ```rust,no_run
+//! use cgi::{RequestExt, ResponseExt};
+//! use cgi::cookie::Cookie;
+//! use cgi::secure_cookie::CookieKey;
+//!
+//! // At least 32 bytes, e.g. from `SetEnv` in the Apache config
+//! let key = CookieKey::from_env("COOKIE_SECRET").unwrap();
+//! cgi::handle(|request: cgi::Request| {
+//!     let visits: u64 = key.private_value(&request, "visits").and_then(|v| v.parse().ok()).unwrap_or(0);
+//!     let mut response = cgi::text_response(200, format!("Visit number {}", visits + 1));
+//!     response.add_cookie(&key.encrypt(Cookie::new("visits", (visits + 1).to_string()).http_only()));
+//!     response
+//! })
+//! ```
+//!
+//! Changing the secret invalidates all cookies. Signed & encrypted cookies can't be revoked
+//! before they expire; keep a record on the server for that.
+
+use std::fmt;
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::cookie::Cookie;
+use crate::{base64, random, RequestExt};
+
+/// The shortest secret accepted
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// Keys for signing & encrypting cookies, derived from one secret. See the [module docs](self).
+#[derive(Clone)]
+pub struct CookieKey {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CookieKey")
+    }
+}
+
+impl CookieKey {
+    /// Derive the keys from `secret`, which must have at least [`MIN_SECRET_LEN`] bytes
+    pub fn from_secret(secret: &[u8]) -> Result<Self, KeyError> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(KeyError::TooShort);
+        }
+        let derive = |purpose: &[u8]| -> [u8; 32] {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+            mac.update(purpose);
+            mac.finalize().into_bytes().into()
+        };
+        Ok(CookieKey { signing: derive(b"cgi cookie signing"), encryption: derive(b"cgi cookie encryption") })
+    }
+
+    /// Derive the keys from the secret in the environment variable `var`
+    pub fn from_env(var: &str) -> Result<Self, KeyError> {
+        let secret = std::env::var_os(var).ok_or_else(|| KeyError::Missing(var.to_owned()))?;
+        CookieKey::from_secret(secret.as_encoded_bytes())
+    }
+
+    fn mac(&self, name: &str, value: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.signing).expect("HMAC accepts any key length");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+
+    /// The cookie, with a signature prepended to its value
+    pub fn sign(&self, mut cookie: Cookie) -> Cookie {
+        let signature = base64::encode(&self.mac(&cookie.name, &cookie.value).finalize().into_bytes());
+        cookie.value = format!("{}.{}", signature, cookie.value);
+        cookie
+    }
+
+    /// The original value of a signed cookie, if the signature is valid
+    pub fn verify(&self, name: &str, value: &str) -> Option<String> {
+        let (signature, value) = value.split_once('.')?;
+        let signature = base64::decode(signature)?;
+        self.mac(name, value).verify_slice(&signature).ok()?;
+        Some(value.to_owned())
+    }
+
+    /// The cookie, with its value encrypted
+    pub fn encrypt(&self, mut cookie: Cookie) -> Cookie {
+        let cipher = <Aes256Gcm as aes_gcm::KeyInit>::new_from_slice(&self.encryption).expect("the key is 32 bytes");
+        let nonce = random::bytes(12);
+        let payload = Payload { msg: cookie.value.as_bytes(), aad: cookie.name.as_bytes() };
+        let mut sealed = nonce.clone();
+        sealed.extend(cipher.encrypt(Nonce::from_slice(&nonce), payload).expect("encrypting to a Vec doesn't fail"));
+        cookie.value = base64::encode(&sealed);
+        cookie
+    }
+
+    /// The original value of an encrypted cookie, if it decrypts
+    pub fn decrypt(&self, name: &str, value: &str) -> Option<String> {
+        let sealed = base64::decode(value)?;
+        if sealed.len() < 12 {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let cipher = <Aes256Gcm as aes_gcm::KeyInit>::new_from_slice(&self.encryption).expect("the key is 32 bytes");
+        let plain = cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: name.as_bytes() }).ok()?;
+        String::from_utf8(plain).ok()
+    }
+
+    /// The verified value of the signed cookie `name` in the request
+    pub fn signed_value<B: AsRef<[u8]>>(&self, request: &http::Request<B>, name: &str) -> Option<String> {
+        self.verify(name, &request.cookie(name)?)
+    }
+
+    /// The decrypted value of the private cookie `name` in the request
+    pub fn private_value<B: AsRef<[u8]>>(&self, request: &http::Request<B>, name: &str) -> Option<String> {
+        self.decrypt(name, &request.cookie(name)?)
+    }
+}
+
+/// Why a [`CookieKey`] couldn't be made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The environment variable isn't set
+    Missing(String),
+    /// The secret is shorter than [`MIN_SECRET_LEN`]
+    TooShort,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Missing(var) => write!(f, "the cookie secret {} isn't set", var),
+            KeyError::TooShort => write!(f, "the cookie secret needs at least {} bytes", MIN_SECRET_LEN),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed() {
+        let key = CookieKey::from_secret(&[7; 32]).unwrap();
+        let cookie = key.sign(Cookie::new("user", "alice.admin"));
+        assert_eq!(key.verify("user", &cookie.value).as_deref(), Some("alice.admin"));
+        assert_eq!(key.verify("other", &cookie.value), None);
+        assert_eq!(key.verify("user", &cookie.value.replace("alice", "mallory")), None);
+        assert_eq!(key.verify("user", "alice"), None);
+        let other_key = CookieKey::from_secret(&[8; 32]).unwrap();
+        assert_eq!(other_key.verify("user", &cookie.value), None);
+    }
+
+    #[test]
+    fn test_private() {
+        let key = CookieKey::from_secret(b"a secret which is long enough!!!").unwrap();
+        let cookie = key.encrypt(Cookie::new("cart", "3 apples"));
+        assert!(!cookie.value.contains("apples"));
+        assert_ne!(key.encrypt(Cookie::new("cart", "3 apples")).value, cookie.value);
+        assert_eq!(key.decrypt("cart", &cookie.value).as_deref(), Some("3 apples"));
+        assert_eq!(key.decrypt("other", &cookie.value), None);
+        assert_eq!(key.decrypt("cart", "AAAA"), None);
+
+        // Through the headers, with the value percent-encoded as needed
+        let request = http::Request::builder()
+            .header("cookie", cookie.to_string().split(';').next().unwrap())
+            .body(vec![]).unwrap();
+        assert_eq!(key.private_value(&request, "cart").as_deref(), Some("3 apples"));
+
+        assert_eq!(CookieKey::from_secret(b"short").unwrap_err(), KeyError::TooShort);
+        assert_eq!(CookieKey::from_env("CGI_TEST_NO_SUCH_SECRET").unwrap_err(), KeyError::Missing("CGI_TEST_NO_SUCH_SECRET".into()));
+    }
+}
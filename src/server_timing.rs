@@ -0,0 +1,244 @@
+//! The `Server-Timing` header, to see where the time of a request went in the browser's
+//! developer tools.
+//!
+//! Metrics are recorded for the current request with [`record`], [`time`] or [`start`], from
+//! anywhere in the handler. [`ServerTiming::handle`] times the whole handler as `total`, and
+//! adds every metric to the response.
+//!
+//! ```rust,no_run
+//! use cgi::server_timing::{self, ServerTiming};
+//!
+//! fn main() {
+//!     cgi::handle(|request: cgi::Request| ServerTiming::new().handle(request, |request| {
+//!         let rows = server_timing::time("db", || vec!["row"; 3]);
+//!         let _render = server_timing::start("render").description("Templates");
+//!         cgi::text_response(200, format!("{} rows", rows.len()))
+//!     }))
+//! }
+//! ```
+//!
+//! For just the duration of the handler, [`ResponseTime`] adds an `X-Response-Time: 12.345ms`
+//! header, which is easy to see with `curl -i`.
+//!
+//! The header reveals how long parts of the request take, which can leak information (e.g.
+//! whether a user exists). Only enable it where that doesn't matter, or for trusted clients.
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use crate::{Request, Response};
+
+/// One `Server-Timing` metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metric {
+    pub name: String,
+    pub duration: Option<Duration>,
+    pub description: Option<String>,
+}
+
+impl Metric {
+    // `name;dur=12.3;desc="Description"`
+    fn serialize(&self) -> String {
+        let name: String = self.name.chars().filter(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(*c)).collect();
+        let mut out = if name.is_empty() { "metric".to_owned() } else { name };
+        if let Some(duration) = self.duration {
+            let millis = duration.as_secs_f64() * 1000.0;
+            out.push_str(&format!(";dur={}", (millis * 10.0).round() / 10.0));
+        }
+        if let Some(description) = &self.description {
+            let escaped: String = description.chars()
+                .filter(|c| *c == '\t' || (*c >= ' ' && *c != '\x7f' && c.is_ascii()))
+                .flat_map(|c| if c == '"' || c == '\\' { vec!['\\', c] } else { vec![c] })
+                .collect();
+            out.push_str(&format!(";desc=\"{}\"", escaped));
+        }
+        out
+    }
+}
+
+thread_local! {
+    static METRICS: RefCell<Vec<Metric>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Record a metric for the current request
+pub fn record(name: &str, duration: Option<Duration>, description: Option<&str>) {
+    let metric = Metric { name: name.to_owned(), duration, description: description.map(|d| d.to_owned()) };
+    METRICS.with(|metrics| metrics.borrow_mut().push(metric));
+}
+
+/// Call `f`, and record how long it took as `name`
+pub fn time<T, F: FnOnce() -> T>(name: &str, f: F) -> T {
+    let started = Instant::now();
+    let result = f();
+    record(name, Some(started.elapsed()), None);
+    result
+}
+
+/// Start timing `name`, recorded when the returned timer is dropped (or stopped)
+pub fn start(name: &str) -> Timer {
+    Timer { name: name.to_owned(), description: None, started: Instant::now() }
+}
+
+/// The metrics recorded so far
+pub fn metrics() -> Vec<Metric> {
+    METRICS.with(|metrics| metrics.borrow().clone())
+}
+
+/// Remove all recorded metrics, returning them
+pub fn take_metrics() -> Vec<Metric> {
+    METRICS.with(|metrics| std::mem::take(&mut *metrics.borrow_mut()))
+}
+
+/// A running timer, see [`start`].
+#[derive(Debug)]
+pub struct Timer {
+    name: String,
+    description: Option<String>,
+    started: Instant,
+}
+
+impl Timer {
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_owned());
+        self
+    }
+
+    /// Record the metric now, returning the duration
+    pub fn stop(self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        record(&self.name, Some(self.started.elapsed()), self.description.as_deref());
+    }
+}
+
+/// Adds the recorded metrics to the response. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct ServerTiming {
+    total: Option<String>,
+}
+
+impl Default for ServerTiming {
+    fn default() -> Self {
+        ServerTiming::new()
+    }
+}
+
+impl ServerTiming {
+    /// The whole handler is recorded as `total`
+    pub fn new() -> Self {
+        ServerTiming { total: Some("total".into()) }
+    }
+
+    /// Record the whole handler under another name, or (with `None`) not at all
+    pub fn total(mut self, name: Option<&str>) -> Self {
+        self.total = name.map(|n| n.to_owned());
+        self
+    }
+
+    /// The `Server-Timing` header value for `metrics`
+    pub fn header_value(metrics: &[Metric]) -> String {
+        metrics.iter().map(|m| m.serialize()).collect::<Vec<_>>().join(", ")
+    }
+
+    /// Call `next`, and add a `Server-Timing` header with the metrics recorded meanwhile
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        let started = Instant::now();
+        let mut response = next(request);
+        if let Some(total) = &self.total {
+            record(total, Some(started.elapsed()), None);
+        }
+        let metrics = take_metrics();
+        if !metrics.is_empty() {
+            if let Ok(value) = ServerTiming::header_value(&metrics).parse() {
+                response.headers_mut().append("server-timing", value);
+            }
+        }
+        response
+    }
+}
+
+/// Adds how long the handler took as an `X-Response-Time` header (in milliseconds, e.g.
+/// `12.345ms`). See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct ResponseTime {
+    header: http::HeaderName,
+}
+
+impl Default for ResponseTime {
+    fn default() -> Self {
+        ResponseTime::new()
+    }
+}
+
+impl ResponseTime {
+    pub fn new() -> Self {
+        ResponseTime { header: http::HeaderName::from_static("x-response-time") }
+    }
+
+    /// Use another header name, e.g. `X-Runtime`
+    pub fn header(mut self, header: http::HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Call `next`, and add the header with its duration, unless the response has one
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        let started = Instant::now();
+        let mut response = next(request);
+        let value = format!("{:.3}ms", started.elapsed().as_secs_f64() * 1000.0);
+        if let Ok(value) = value.parse() {
+            response.headers_mut().entry(&self.header).or_insert(value);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_timing() {
+        let recorded = [
+            Metric { name: "db".into(), duration: Some(Duration::from_micros(12345)), description: Some("Query \"users\"".into()) },
+            Metric { name: "cache hit".into(), duration: None, description: None },
+        ];
+        assert_eq!(ServerTiming::header_value(&recorded), "db;dur=12.3;desc=\"Query \\\"users\\\"\", cachehit");
+
+        let request = http::Request::builder().body(vec![]).unwrap();
+        let response = ServerTiming::new().handle(request, |_| {
+            time("work", || ());
+            let _timer = start("render").description("Templates");
+            record("miss", None, None);
+            crate::empty_response(200)
+        });
+        let header = response.headers()["server-timing"].to_str().unwrap();
+        let names: Vec<_> = header.split(", ").map(|m| m.split(';').next().unwrap()).collect();
+        assert_eq!(names, ["work", "miss", "render", "total"]);
+        assert!(header.contains(";desc=\"Templates\", total;dur="), "{}", header);
+        assert!(metrics().is_empty());
+    }
+
+    #[test]
+    fn test_response_time() {
+        let request = || http::Request::builder().body(vec![]).unwrap();
+        let response = ResponseTime::new().handle(request(), |_| {
+            std::thread::sleep(Duration::from_millis(2));
+            crate::empty_response(200)
+        });
+        let value = response.headers()["x-response-time"].to_str().unwrap();
+        let millis: f64 = value.strip_suffix("ms").unwrap().parse().unwrap();
+        assert!(millis >= 2.0, "{}", value);
+
+        let runtime = ResponseTime::new().header(http::HeaderName::from_static("x-runtime"));
+        assert!(runtime.handle(request(), |_| crate::empty_response(200)).headers().contains_key("x-runtime"));
+    }
+}
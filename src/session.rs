@@ -0,0 +1,307 @@
+//! Sessions: data kept on the server between requests, found by a random ID in a cookie.
+//!
+//! [`Sessions`] loads the session of a request from a [`SessionStore`] (by default a
+//! [`FileStore`]), and saves it again, setting the session cookie on the response. The session
+//! is locked from loading to saving, so concurrent requests of the same visitor don't
+//! overwrite each other's changes.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use cgi::session::Sessions;
+//! use cgi::store::FileStore;
+//!
+//! let sessions = Sessions::new(FileStore::new("/tmp/my-cgi-sessions")).ttl(Duration::from_secs(3600));
+//! cgi::handle(|request: cgi::Request| {
+//!     let mut session = match sessions.load(&request) {
+//!         Ok(session) => session,
+//!         Err(_) => return cgi::empty_response(500),
+//!     };
+//!     let visits = session.get("visits").and_then(|v| v.parse().ok()).unwrap_or(0) + 1;
+//!     session.insert("visits", visits.to_string());
+//!     let mut response = cgi::text_response(200, format!("Visit number {}", visits));
+//!     if let Err(err) = sessions.save(session, &mut response) {
+//!         eprintln!("Could not save the session: {}", err);
+//!     }
+//!     response
+//! })
+//! ```
+//!
+//! A session expires when it hasn't been saved for the `ttl`. Expired sessions are ignored,
+//! call [`Sessions::purge_expired`] now and then to delete their files. Call
+//! [`Session::regenerate`] when a user logs in, so an ID known before can't be used to take
+//! over the session.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::time::Duration;
+
+use crate::cookie::{Cookie, SameSite};
+use crate::store::FileStore;
+use crate::{random, urlencoded, RequestExt, ResponseExt, Response};
+
+/// Where sessions are kept. The data is opaque bytes, keyed by the session ID.
+pub trait SessionStore {
+    /// The data of the session `id`, if it exists and hasn't expired
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Store the data of the session `id`, expiring after `ttl`
+    fn save(&self, id: &str, data: &[u8], ttl: Duration) -> io::Result<()>;
+
+    /// Remove the session `id`
+    fn delete(&self, id: &str) -> io::Result<()>;
+
+    /// Lock the session `id` for other processes until the returned file is dropped. Stores
+    /// which don't need locking (or can't lock) return `None`.
+    fn lock(&self, _id: &str, _ttl: Duration) -> io::Result<Option<File>> {
+        Ok(None)
+    }
+
+    /// Remove expired sessions, if the store doesn't do that itself
+    fn purge_expired(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sessions are the entries `session-<id>`, so the store can be shared with other uses
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        self.get(&format!("session-{}", id))
+    }
+
+    fn save(&self, id: &str, data: &[u8], ttl: Duration) -> io::Result<()> {
+        self.set(&format!("session-{}", id), data, Some(ttl))
+    }
+
+    fn delete(&self, id: &str) -> io::Result<()> {
+        FileStore::delete(self, &format!("session-{}", id))
+    }
+
+    fn lock(&self, id: &str, ttl: Duration) -> io::Result<Option<File>> {
+        FileStore::lock(self, &format!("session-{}.lock", id), Some(ttl)).map(Some)
+    }
+
+    fn purge_expired(&self) -> io::Result<()> {
+        FileStore::purge_expired(self)
+    }
+}
+
+/// Loads & saves [`Session`]s. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Sessions<S = FileStore> {
+    store: S,
+    ttl: Duration,
+    cookie_name: String,
+    secure: bool,
+}
+
+impl<S: SessionStore> Sessions<S> {
+    /// Sessions expire after 24 hours by default, and the cookie is called `session`
+    pub fn new(store: S) -> Self {
+        Sessions { store, ttl: Duration::from_secs(24 * 60 * 60), cookie_name: "session".into(), secure: false }
+    }
+
+    /// How long a session is kept after it was last saved
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// The name of the session cookie
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.to_owned();
+        self
+    }
+
+    /// Always mark the cookie `Secure`, not only for HTTPS requests (e.g. behind a proxy which
+    /// terminates TLS)
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    /// The session of the request, or a new, empty one if there's no (valid) session cookie
+    /// or the session expired. The session stays locked until it's saved or dropped.
+    pub fn load<B: AsRef<[u8]>>(&self, request: &http::Request<B>) -> io::Result<Session> {
+        let https = request.headers().get("x-cgi-https").is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"on"));
+        let mut session = Session {
+            id: None,
+            cookie_id: None,
+            data: BTreeMap::new(),
+            destroyed: false,
+            secure: self.secure || https,
+            _lock: None,
+        };
+        let Some(id) = request.cookie(&self.cookie_name).filter(|id| is_valid_id(id)) else {
+            return Ok(session);
+        };
+        session.cookie_id = Some(id.clone());
+        session._lock = self.store.lock(&id, self.ttl)?;
+        if let Some(data) = self.store.load(&id)? {
+            session.data = urlencoded::parse(&String::from_utf8_lossy(&data)).into_iter().collect();
+            session.id = Some(id);
+        }
+        Ok(session)
+    }
+
+    /// Store the session and set the cookie on the response, or remove both if the session
+    /// was destroyed or is empty. Saving also extends the expiry.
+    pub fn save(&self, session: Session, response: &mut Response) -> io::Result<()> {
+        // A regenerated session gets a new ID, and the old one can't be used anymore
+        if let Some(old) = session.cookie_id.as_ref().filter(|old| session.id.as_ref() != Some(*old)) {
+            self.store.delete(old)?;
+        }
+        if session.destroyed || session.data.is_empty() {
+            if let Some(id) = &session.id {
+                self.store.delete(id)?;
+            }
+            if session.cookie_id.is_some() {
+                response.add_cookie(&Cookie::removal(self.cookie_name.clone()));
+            }
+            return Ok(());
+        }
+
+        let id = session.id.clone().unwrap_or_else(new_id);
+        let pairs: Vec<(String, String)> = session.data.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        self.store.save(&id, urlencoded::serialize(&pairs).as_bytes(), self.ttl)?;
+        let mut cookie = Cookie::new(self.cookie_name.clone(), id).max_age(self.ttl).http_only().same_site(SameSite::Lax);
+        cookie.secure = session.secure;
+        response.add_cookie(&cookie);
+        Ok(())
+    }
+
+    /// Delete expired sessions from the store
+    pub fn purge_expired(&self) -> io::Result<()> {
+        self.store.purge_expired()
+    }
+}
+
+/// The data of one visitor's session: string keys & values.
+#[derive(Debug)]
+pub struct Session {
+    // The ID of the stored session, `None` for a new one
+    id: Option<String>,
+    // The ID the client sent, even if there was no such session
+    cookie_id: Option<String>,
+    data: BTreeMap<String, String>,
+    destroyed: bool,
+    secure: bool,
+    _lock: Option<File>,
+}
+
+impl Session {
+    /// The session ID, or `None` for a new session which hasn't been saved yet
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Whether the session was just created
+    pub fn is_new(&self) -> bool {
+        self.id.is_none()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(|v| v.as_str())
+    }
+
+    /// Set `key`, returning the previous value
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        self.data.insert(key.into(), value.into())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.data.remove(key)
+    }
+
+    /// All keys & values, sorted by key
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.data.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Keep the data, but under a new ID. Do this when the user logs in or gets more rights.
+    pub fn regenerate(&mut self) {
+        self.id = Some(new_id());
+    }
+
+    /// Delete the session & its cookie when it's saved
+    pub fn destroy(&mut self) {
+        self.data.clear();
+        self.destroyed = true;
+    }
+}
+
+// 128 bits, as hex
+fn new_id() -> String {
+    random::hex(16)
+}
+
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CgiRequestBuilder;
+
+    #[test]
+    fn test_sessions() {
+        let dir = std::env::temp_dir().join(format!("cgi-session-{}", std::process::id()));
+        let sessions = Sessions::new(FileStore::new(&dir)).cookie_name("sid");
+        let set_cookie = |response: &Response| response.headers()["set-cookie"].to_str().unwrap().to_owned();
+
+        // New session, not stored while it's empty
+        let request = CgiRequestBuilder::new().build();
+        let session = sessions.load(&request).unwrap();
+        assert!(session.is_new());
+        let mut response = crate::empty_response(200);
+        sessions.save(session, &mut response).unwrap();
+        assert!(response.headers().get("set-cookie").is_none());
+
+        let mut session = sessions.load(&request).unwrap();
+        session.insert("user", "alice & bob=1");
+        let mut response = crate::empty_response(200);
+        sessions.save(session, &mut response).unwrap();
+        let cookie = set_cookie(&response);
+        assert!(cookie.ends_with("; Max-Age=86400; Path=/; HttpOnly; SameSite=Lax"), "{}", cookie);
+        let pair = cookie.split(';').next().unwrap().to_owned();
+        let id = pair.strip_prefix("sid=").unwrap().to_owned();
+
+        // Loaded again
+        let request = CgiRequestBuilder::new().header("Cookie", &pair).env("HTTPS", "on").build();
+        let mut session = sessions.load(&request).unwrap();
+        assert_eq!(session.id(), Some(id.as_str()));
+        assert_eq!(session.get("user"), Some("alice & bob=1"));
+        session.regenerate();
+        let mut response = crate::empty_response(200);
+        sessions.save(session, &mut response).unwrap();
+        let cookie = set_cookie(&response);
+        assert!(!cookie.contains(&id) && cookie.contains("; Secure"), "{}", cookie);
+
+        // The old ID is gone
+        let session = sessions.load(&request).unwrap();
+        assert!(session.is_new());
+        drop(session);
+
+        let pair = cookie.split(';').next().unwrap().to_owned();
+        let request = CgiRequestBuilder::new().header("Cookie", &pair).build();
+        let mut session = sessions.load(&request).unwrap();
+        assert_eq!(session.get("user"), Some("alice & bob=1"));
+        session.destroy();
+        let mut response = crate::empty_response(200);
+        sessions.save(session, &mut response).unwrap();
+        assert!(set_cookie(&response).starts_with("sid=; Max-Age=0"));
+        assert!(sessions.load(&request).unwrap().is_new());
+
+        // Invalid IDs are ignored
+        let request = CgiRequestBuilder::new().header("Cookie", "sid=../../etc/passwd").build();
+        assert!(sessions.load(&request).unwrap().is_new());
+
+        sessions.purge_expired().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+//! Mirror requests to a second implementation, and record where the responses differ.
+//!
+//! When rewriting an existing CGI script in Rust, [`Shadow`] lets the new code answer real
+//! traffic while the old script gets a copy of every request (or a sample of them). Once the
+//! response has been sent, the shadow is run and any differences in status, headers or body
+//! are logged, one line per request.
+//!
+//! It also works the other way round: serve the legacy script with
+//! [`client::run_cgi`](crate::client::run_cgi), and shadow it with the new handler. To give
+//! the shadowed script retries and a circuit breaker, use [`Shadow::upstream`].
+//!
+//! ```rust,no_run
+//! use cgi::shadow::Shadow;
+//!
+//! fn main() {
+//!     let shadow = Shadow::cgi("/usr/lib/cgi-bin/legacy.pl")
+//!         .sample_rate(0.1)
+//!         .ignore_header("set-cookie")
+//!         .log("/var/log/www/shadow.log");
+//!     cgi::handle(|request: cgi::Request| shadow.handle(request, |request| {
+//!         cgi::text_response(200, "new implementation")
+//!     }))
+//! }
+//! ```
+//!
+//! Streaming responses are buffered when a request is shadowed, so their bodies can be compared.
+//!
+//! Requests have side effects, so only shadow requests which are safe to run twice (e.g.
+//! against a copy of the data, or only `GET`s — see [`Shadow::methods`]).
+
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::header::HeaderName;
+
+use crate::stream::buffer_body;
+use crate::upstream::Upstream;
+use crate::{after_response, append_log_line, client, clone_request, clone_response, random, Request, Response};
+
+#[derive(Clone)]
+enum Target {
+    Handler(Rc<dyn Fn(Request) -> Response>),
+    Cgi(PathBuf),
+    Upstream(Upstream, PathBuf),
+}
+
+/// Sends a copy of requests to a second handler. See the [module docs](self).
+#[derive(Clone)]
+pub struct Shadow {
+    target: Target,
+    sample_rate: f64,
+    methods: Option<Vec<http::Method>>,
+    ignore_headers: Vec<HeaderName>,
+    log: Option<PathBuf>,
+}
+
+impl Shadow {
+    /// Shadow with another handler function
+    pub fn handler<F>(handler: F) -> Self
+        where F: Fn(Request) -> Response + 'static
+    {
+        Shadow::new(Target::Handler(Rc::new(handler)))
+    }
+
+    /// Shadow with another CGI programme, see [`client::run_cgi`]
+    pub fn cgi(path: impl Into<PathBuf>) -> Self {
+        Shadow::new(Target::Cgi(path.into()))
+    }
+
+    /// Shadow with another CGI programme, run through an [`Upstream`] with its retries and
+    /// circuit breaker, see [`Upstream::run_cgi`]
+    pub fn upstream(upstream: Upstream, path: impl Into<PathBuf>) -> Self {
+        Shadow::new(Target::Upstream(upstream, path.into()))
+    }
+
+    fn new(target: Target) -> Self {
+        Shadow {
+            target,
+            sample_rate: 1.0,
+            methods: None,
+            ignore_headers: vec![http::header::DATE],
+            log: None,
+        }
+    }
+
+    /// Only shadow this fraction of requests (between 0 and 1, default all)
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        self.sample_rate = rate;
+        self
+    }
+
+    /// Only shadow requests with these methods (default all)
+    pub fn methods(mut self, methods: &[http::Method]) -> Self {
+        self.methods = Some(methods.to_vec());
+        self
+    }
+
+    /// Don't compare this header (`Date` is always ignored)
+    pub fn ignore_header(mut self, name: &str) -> Self {
+        self.ignore_headers.push(HeaderName::from_bytes(name.as_bytes()).expect("invalid header name"));
+        self
+    }
+
+    /// Append differences to this file, instead of printing them to stderr
+    pub fn log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log = Some(path.into());
+        self
+    }
+
+    /// Call `next` to make the response, and once it has been sent (see
+    /// [`after_response`]), run the shadow on a copy of the request and record any
+    /// differences.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        let sampled = self.sample_rate >= 1.0 || (random::bytes(1)[0] as f64 / 256.0) < self.sample_rate;
+        let method_ok = self.methods.as_ref().is_none_or(|m| m.contains(request.method()));
+        if !sampled || !method_ok {
+            return next(request);
+        }
+
+        let copy = clone_request(&request);
+        let mut response = next(request);
+        buffer_body(&mut response);
+        let primary = clone_response(&response);
+
+        let shadow = self.clone();
+        after_response(move || shadow.compare_with(copy, &primary));
+        response
+    }
+
+    fn compare_with(&self, request: Request, primary: &Response) {
+        let line = format!("{} {}", request.method(), request.uri());
+        let result = match &self.target {
+            Target::Handler(handler) => Ok(handler(request)),
+            Target::Cgi(path) => client::run_cgi(path, &request),
+            Target::Upstream(upstream, path) => Ok(upstream.run_cgi(path, &request)),
+        };
+        let diffs = match result {
+            Ok(mut shadow) => {
+                buffer_body(&mut shadow);
+                differences(primary, &shadow, &self.ignore_headers)
+            }
+            Err(err) => vec![format!("shadow failed: {}", err)],
+        };
+        if diffs.is_empty() {
+            return;
+        }
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let line = format!("{} {}: {}\n", time, line, diffs.join("; "));
+        match &self.log {
+            Some(path) => if let Err(err) = append_log_line(path, &line) {
+                eprintln!("Could not write shadow log: {}. {}", err, line.trim_end());
+            },
+            None => eprint!("{}", line),
+        }
+    }
+}
+
+/// How `shadow` differs from `primary`, as human readable descriptions, e.g.
+/// `status 200 != 404`. Empty if they're the same, apart from the `ignore`d headers.
+pub fn differences(primary: &Response, shadow: &Response, ignore: &[HeaderName]) -> Vec<String> {
+    let mut diffs = Vec::new();
+    if primary.status() != shadow.status() {
+        diffs.push(format!("status {} != {}", primary.status().as_u16(), shadow.status().as_u16()));
+    }
+
+    let mut names: Vec<&HeaderName> = primary.headers().keys().chain(shadow.headers().keys())
+        .filter(|name| !ignore.contains(name))
+        .collect();
+    names.sort_by_key(|name| name.as_str());
+    names.dedup();
+    for name in names {
+        let a: Vec<_> = primary.headers().get_all(name).iter().collect();
+        let b: Vec<_> = shadow.headers().get_all(name).iter().collect();
+        if a != b {
+            diffs.push(format!("header {} {:?} != {:?}", name, a, b));
+        }
+    }
+
+    if primary.body() != shadow.body() {
+        diffs.push(format!("body differs ({} bytes != {} bytes)", primary.body().len(), shadow.body().len()));
+    }
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_response;
+
+    #[test]
+    fn test_differences() {
+        assert!(differences(&text_response(200, "a"), &text_response(200, "a"), &[]).is_empty());
+        assert_eq!(differences(&text_response(200, "a"), &crate::html_response(404, "bb"), &[]), vec![
+            "status 200 != 404",
+            "header content-length [\"1\"] != [\"2\"]",
+            "header content-type [\"text/plain; charset=utf-8\"] != [\"text/html; charset=utf-8\"]",
+            "body differs (1 bytes != 2 bytes)",
+        ]);
+        let ignore = [http::header::CONTENT_TYPE, http::header::CONTENT_LENGTH];
+        assert_eq!(differences(&text_response(200, "a"), &crate::html_response(200, "b"), &ignore), vec!["body differs (1 bytes != 1 bytes)"]);
+    }
+
+    #[test]
+    fn test_shadow() {
+        let path = std::env::temp_dir().join(format!("cgi-shadow-{}.log", std::process::id()));
+        let shadow = Shadow::handler(|req| text_response(200, format!("old {}", req.uri().path()))).log(&path);
+        let req = http::Request::builder().uri("/page").body(vec![]).unwrap();
+
+        let resp = shadow.handle(req, |req| text_response(200, format!("new {}", req.uri().path())));
+        assert_eq!(resp.body(), b"new /page");
+        crate::run_after_response();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(log.ends_with(" GET /page: body differs (9 bytes != 9 bytes)\n"));
+    }
+
+    #[test]
+    fn test_streaming() {
+        let path = std::env::temp_dir().join(format!("cgi-shadow-stream-{}.log", std::process::id()));
+        let shadow = Shadow::handler(|_| crate::stream::streaming_response(200, "text/plain", |out| out.write_all(b"old")))
+            .log(&path);
+        let req = http::Request::builder().uri("/stream").body(vec![]).unwrap();
+
+        let resp = shadow.handle(req, |_| crate::stream::streaming_response(200, "text/plain", |out| out.write_all(b"new")));
+        assert_eq!(resp.body(), b"new");
+        crate::run_after_response();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(log.ends_with(" GET /stream: body differs (3 bytes != 3 bytes)\n"));
+    }
+}
@@ -0,0 +1,511 @@
+//! HTTP Message Signatures (RFC 9421): verify signed requests, and sign responses.
+//!
+//! Requires the `signatures` feature. HMAC-SHA256 is built in ([`HmacSha256Key`]), other
+//! algorithms (e.g. Ed25519) can be plugged in by implementing [`SignatureKey`].
+//!
+//! Derived components (`@method`, `@target-uri`, `@authority`, `@scheme`, `@request-target`,
+//! `@path`, `@query`) are computed from the request as parsed from the CGI environment, so the
+//! scheme & host are taken from `HTTPS`/`REQUEST_SCHEME` and `Host`/`SERVER_NAME`/`SERVER_PORT`.
+//! Component parameters (`;sf`, `;key`, `;bs`, `;req`, `;name`) aren't supported: a signature
+//! covering a component with parameters doesn't verify.
+//!
+//! A signature without `expires` stays valid forever, and can be replayed. Use
+//! [`verify_request_max_age`] to only accept signatures `created` recently.
+//!
+//! ```rust,no_run
+//! use cgi::signatures::{verify_request, sign_response, HmacSha256Key};
+//!
+//! #[cgi::main]
+//! fn main(request: cgi::Request) -> cgi::Response {
+//!     let key = HmacSha256Key::new("my-key", b"shared secret".to_vec());
+//!     if let Err(e) = verify_request(&request, &key, &["@method", "@target-uri", "content-digest"]) {
+//!         return e.into();
+//!     }
+//!     let mut response = cgi::text_response(200, "OK");
+//!     sign_response(&mut response, "sig1", &key, &["@status", "content-type"]).unwrap();
+//!     response
+//! }
+//! ```
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::structured::{self, BareItem, InnerList, Item, ListEntry};
+use crate::{Request, Response};
+
+/// A key which can create and check signatures.
+pub trait SignatureKey {
+    /// The `alg` parameter, e.g. `hmac-sha256`
+    fn algorithm(&self) -> &str;
+
+    /// The `keyid` parameter. When verifying, only signatures with this `keyid` are checked.
+    fn key_id(&self) -> Option<&str>;
+
+    /// Sign the signature base
+    fn sign(&self, base: &[u8]) -> Vec<u8>;
+
+    /// Check `signature` for the signature base
+    fn verify(&self, base: &[u8], signature: &[u8]) -> bool;
+}
+
+/// A shared secret for `hmac-sha256` signatures.
+#[derive(Clone)]
+pub struct HmacSha256Key {
+    key_id: Option<String>,
+    secret: Vec<u8>,
+}
+
+impl HmacSha256Key {
+    pub fn new(key_id: impl Into<String>, secret: Vec<u8>) -> Self {
+        HmacSha256Key { key_id: Some(key_id.into()), secret }
+    }
+
+    /// A key which will check signatures with any (or no) `keyid`
+    pub fn without_key_id(secret: Vec<u8>) -> Self {
+        HmacSha256Key { key_id: None, secret }
+    }
+
+    fn mac(&self, base: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(base);
+        mac
+    }
+}
+
+impl SignatureKey for HmacSha256Key {
+    fn algorithm(&self) -> &str {
+        "hmac-sha256"
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    fn sign(&self, base: &[u8]) -> Vec<u8> {
+        self.mac(base).finalize().into_bytes().to_vec()
+    }
+
+    fn verify(&self, base: &[u8], signature: &[u8]) -> bool {
+        self.mac(base).verify_slice(signature).is_ok()
+    }
+}
+
+/// Why a signature couldn't be verified or created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// There is no `Signature-Input`/`Signature` (for our key)
+    Missing,
+    /// The `Signature-Input` or `Signature` header couldn't be parsed
+    Malformed,
+    /// A covered component isn't present in the message, or isn't supported
+    MissingComponent(String),
+    /// A component we require isn't covered by the signature
+    NotCovered(String),
+    /// The signature has expired, or is older than the max age
+    Expired,
+    /// The signature was `created` in the future
+    NotYetValid,
+    /// The signature doesn't match
+    Invalid,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "no signature"),
+            SignatureError::Malformed => write!(f, "malformed signature headers"),
+            SignatureError::MissingComponent(c) => write!(f, "signature component {:?} is not available", c),
+            SignatureError::NotCovered(c) => write!(f, "signature does not cover {:?}", c),
+            SignatureError::Expired => write!(f, "signature has expired"),
+            SignatureError::NotYetValid => write!(f, "signature is not valid yet"),
+            SignatureError::Invalid => write!(f, "invalid signature"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// A `401 Unauthorized` with the error as the text body
+impl From<SignatureError> for Response {
+    fn from(err: SignatureError) -> Response {
+        crate::text_response(401, err.to_string())
+    }
+}
+
+/// The parameters of a verified signature.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignatureParams {
+    /// The label in the `Signature` header, e.g. `sig1`
+    pub label: String,
+    /// The covered component identifiers, e.g. `@method`, `content-type`. One with parameters
+    /// is kept as it was serialized, e.g. `"content-type";sf`.
+    pub components: Vec<String>,
+    pub created: Option<u64>,
+    pub expires: Option<u64>,
+    pub key_id: Option<String>,
+    pub alg: Option<String>,
+    pub nonce: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl SignatureParams {
+    // The inner list used in `Signature-Input` and as `@signature-params`
+    fn to_inner_list(&self) -> InnerList {
+        let mut list = InnerList::new(self.components.iter().map(|c| Item::new(BareItem::String(c.clone()))).collect());
+        for (name, value) in [("created", self.created), ("expires", self.expires)] {
+            if let Some(value) = value {
+                list.params.push((name.to_owned(), BareItem::Integer(value as i64)));
+            }
+        }
+        for (name, value) in [("keyid", &self.key_id), ("alg", &self.alg), ("nonce", &self.nonce), ("tag", &self.tag)] {
+            if let Some(value) = value {
+                list.params.push((name.to_owned(), BareItem::String(value.clone())));
+            }
+        }
+        list
+    }
+
+    fn serialize(&self) -> Result<String, SignatureError> {
+        structured::serialize_inner_list(&self.to_inner_list()).map_err(|_| SignatureError::Malformed)
+    }
+
+    fn from_inner_list(label: String, list: &InnerList) -> Option<Self> {
+        let mut params = SignatureParams { label, ..Default::default() };
+        for item in &list.items {
+            let name = item.bare_item.as_str()?;
+            if item.params.is_empty() {
+                params.components.push(name.to_owned());
+            } else {
+                params.components.push(structured::serialize_item(item).ok()?);
+            }
+        }
+        for (name, value) in &list.params {
+            match name.as_str() {
+                "created" => params.created = Some(value.as_integer()?.try_into().ok()?),
+                "expires" => params.expires = Some(value.as_integer()?.try_into().ok()?),
+                "keyid" => params.key_id = Some(value.as_str()?.to_owned()),
+                "alg" => params.alg = Some(value.as_str()?.to_owned()),
+                "nonce" => params.nonce = Some(value.as_str()?.to_owned()),
+                "tag" => params.tag = Some(value.as_str()?.to_owned()),
+                _ => {}
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Verify a signature on `request` made with `key`, which must cover all of
+/// `required_components`.
+///
+/// If several signatures are present, the first one for this key which verifies is returned.
+pub fn verify_request(request: &Request, key: &impl SignatureKey, required_components: &[&str]) -> Result<SignatureParams, SignatureError> {
+    verify(request, key, required_components, None)
+}
+
+/// Like [`verify_request`], but the signature must have been `created` within `max_age`. It
+/// may be `created` up to `leeway` in the future, for clocks which aren't quite in sync.
+pub fn verify_request_max_age(request: &Request, key: &impl SignatureKey, required_components: &[&str], max_age: Duration, leeway: Duration) -> Result<SignatureParams, SignatureError> {
+    verify(request, key, required_components, Some((max_age, leeway)))
+}
+
+fn verify(request: &Request, key: &impl SignatureKey, required_components: &[&str], max_age: Option<(Duration, Duration)>) -> Result<SignatureParams, SignatureError> {
+    let inputs = header_string(request.headers(), "signature-input").ok_or(SignatureError::Missing)?;
+    let signatures = header_string(request.headers(), "signature").ok_or(SignatureError::Missing)?;
+    let inputs = parse_signature_input(&inputs).ok_or(SignatureError::Malformed)?;
+    let signatures = parse_signature(&signatures).ok_or(SignatureError::Malformed)?;
+
+    let mut result = Err(SignatureError::Missing);
+    for (params, serialized) in inputs {
+        if key.key_id().is_some() && params.key_id.is_some() && key.key_id() != params.key_id.as_deref() {
+            continue;
+        }
+        if params.alg.as_deref().is_some_and(|alg| alg != key.algorithm()) {
+            continue;
+        }
+        let signature = match signatures.iter().find(|(label, _)| *label == params.label) {
+            Some((_, sig)) => sig,
+            None => continue,
+        };
+
+        result = check_signature(request, key, required_components, max_age, params, &serialized, signature);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+fn check_signature(request: &Request, key: &impl SignatureKey, required_components: &[&str], max_age: Option<(Duration, Duration)>, params: SignatureParams, serialized: &str, signature: &[u8]) -> Result<SignatureParams, SignatureError> {
+    for required in required_components {
+        if !params.components.iter().any(|c| c == required) {
+            return Err(SignatureError::NotCovered(required.to_string()));
+        }
+    }
+    if params.expires.is_some_and(|expires| expires < now()) {
+        return Err(SignatureError::Expired);
+    }
+    if let Some((max_age, leeway)) = max_age {
+        let created = params.created.ok_or(SignatureError::Expired)?;
+        if created.saturating_add(max_age.as_secs()) < now() {
+            return Err(SignatureError::Expired);
+        }
+        if created > now().saturating_add(leeway.as_secs()) {
+            return Err(SignatureError::NotYetValid);
+        }
+    }
+
+    let base = signature_base(&params.components, serialized, |c| request_component(request, c))?;
+    if key.verify(base.as_bytes(), signature) {
+        Ok(params)
+    } else {
+        Err(SignatureError::Invalid)
+    }
+}
+
+/// Sign `response`, covering `components` (e.g. `@status`, `content-type`), adding the
+/// `Signature-Input` & `Signature` headers.
+pub fn sign_response(response: &mut Response, label: &str, key: &impl SignatureKey, components: &[&str]) -> Result<(), SignatureError> {
+    let params = SignatureParams {
+        label: label.to_owned(),
+        components: components.iter().map(|c| c.to_ascii_lowercase()).collect(),
+        created: Some(now()),
+        key_id: key.key_id().map(|k| k.to_owned()),
+        alg: Some(key.algorithm().to_owned()),
+        ..Default::default()
+    };
+
+    let base = signature_base(&params.components, &params.serialize()?, |c| response_component(response, c))?;
+    let signature = key.sign(base.as_bytes());
+
+    let input = structured::serialize_dictionary(&vec![(label.to_owned(), params.to_inner_list().into())]);
+    let signature = structured::serialize_dictionary(&vec![(label.to_owned(), BareItem::ByteSequence(signature).into())]);
+    let (input, signature) = (input.map_err(|_| SignatureError::Malformed)?, signature.map_err(|_| SignatureError::Malformed)?);
+    let headers = response.headers_mut();
+    headers.append("signature-input", input.parse().map_err(|_| SignatureError::Malformed)?);
+    headers.append("signature", signature.parse().map_err(|_| SignatureError::Malformed)?);
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// `signature_params` is the `Signature-Input` member as it was received (re-serialized, which
+// only normalizes whitespace): the order of its parameters & any unknown ones are signed too
+fn signature_base(components: &[String], signature_params: &str, component: impl Fn(&str) -> Option<String>) -> Result<String, SignatureError> {
+    let mut base = String::new();
+    for c in components {
+        let value = component(c).ok_or_else(|| SignatureError::MissingComponent(c.clone()))?;
+        base.push_str(&format!("\"{}\": {}\n", c, value));
+    }
+    base.push_str(&format!("\"@signature-params\": {}", signature_params));
+    Ok(base)
+}
+
+// Multiple values are combined with ", ", as per RFC 9421 §2.1
+fn header_string(headers: &http::HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers.get_all(name).iter()
+        .map(|v| v.to_str().map(|s| s.trim()))
+        .collect::<Result<_, _>>().ok()?;
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+fn request_component(request: &Request, component: &str) -> Option<String> {
+    let uri = request.uri();
+    let path = if uri.path().is_empty() { "/" } else { uri.path() };
+    match component {
+        "@method" => Some(request.method().as_str().to_owned()),
+        "@scheme" => Some(scheme(request).to_owned()),
+        "@authority" => authority(request),
+        "@target-uri" => Some(format!("{}://{}{}", scheme(request), authority(request)?, request_target(request))),
+        "@request-target" => Some(request_target(request)),
+        "@path" => Some(path.to_owned()),
+        "@query" => Some(format!("?{}", uri.query().unwrap_or(""))),
+        // Derived components we don't know, and any with parameters
+        c if c.starts_with('@') || c.starts_with('"') => None,
+        c => header_string(request.headers(), c),
+    }
+}
+
+fn response_component(response: &Response, component: &str) -> Option<String> {
+    match component {
+        "@status" => Some(response.status().as_str().to_owned()),
+        c if c.starts_with('@') || c.starts_with('"') => None,
+        c => header_string(response.headers(), c),
+    }
+}
+
+fn request_target(request: &Request) -> String {
+    let uri = request.uri();
+    let path = if uri.path().is_empty() { "/" } else { uri.path() };
+    match uri.query() {
+        Some(q) => format!("{}?{}", path, q),
+        None => path.to_owned(),
+    }
+}
+
+fn scheme(request: &Request) -> &'static str {
+    let headers = request.headers();
+    let https = headers.get("x-cgi-https").is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"on") || v == "1");
+    let request_scheme = headers.get("x-cgi-request-scheme").is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"https"));
+    if https || request_scheme || request.uri().scheme_str() == Some("https") {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+fn authority(request: &Request) -> Option<String> {
+    let headers = request.headers();
+    if let Some(host) = headers.get(http::header::HOST).and_then(|h| h.to_str().ok()) {
+        return Some(strip_default_port(&host.to_ascii_lowercase(), scheme(request)));
+    }
+    let name = headers.get("x-cgi-server-name")?.to_str().ok()?.to_ascii_lowercase();
+    match headers.get("x-cgi-server-port").and_then(|p| p.to_str().ok()) {
+        Some(port) => Some(strip_default_port(&format!("{}:{}", name, port), scheme(request))),
+        None => Some(name),
+    }
+}
+
+fn strip_default_port(authority: &str, scheme: &str) -> String {
+    let default = if scheme == "https" { ":443" } else { ":80" };
+    authority.strip_suffix(default).unwrap_or(authority).to_owned()
+}
+
+// `sig1=("@method" "content-type");created=1618884473;keyid="test-key", sig2=(...)`, with the
+// serialized inner list of each
+fn parse_signature_input(value: &str) -> Option<Vec<(SignatureParams, String)>> {
+    structured::parse_dictionary(value).ok()?.into_iter()
+        .map(|(label, entry)| match entry {
+            ListEntry::InnerList(list) => Some((SignatureParams::from_inner_list(label, &list)?, structured::serialize_inner_list(&list).ok()?)),
+            ListEntry::Item(_) => None,
+        })
+        .collect()
+}
+
+// `sig1=:base64:, sig2=:base64:`
+fn parse_signature(value: &str) -> Option<Vec<(String, Vec<u8>)>> {
+    structured::parse_dictionary(value).ok()?.into_iter()
+        .map(|(label, entry)| match entry {
+            ListEntry::Item(item) => Some((label, item.bare_item.as_bytes()?.to_vec())),
+            ListEntry::InnerList(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(signature_input: &str, signature: &str) -> Request {
+        http::Request::builder()
+            .method("POST")
+            .uri("/foo?param=Value&Pet=dog")
+            .header("host", "example.com")
+            .header("x-cgi-https", "on")
+            .header("content-type", "application/json")
+            .header("signature-input", signature_input)
+            .header("signature", signature)
+            .body(vec![])
+            .unwrap()
+    }
+
+    #[test]
+    fn test_signature_base() {
+        let params = parse_signature_input("sig1=(\"@method\" \"@target-uri\" \"@authority\" \"@query\" \"content-type\");created=1618884473;keyid=\"test-key\"").unwrap();
+        assert_eq!(params.len(), 1);
+        let req = request("", "");
+        let base = signature_base(&params[0].0.components, &params[0].1, |c| request_component(&req, c)).unwrap();
+        assert_eq!(base, "\"@method\": POST\n\
+            \"@target-uri\": https://example.com/foo?param=Value&Pet=dog\n\
+            \"@authority\": example.com\n\
+            \"@query\": ?param=Value&Pet=dog\n\
+            \"content-type\": application/json\n\
+            \"@signature-params\": (\"@method\" \"@target-uri\" \"@authority\" \"@query\" \"content-type\");created=1618884473;keyid=\"test-key\"");
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = HmacSha256Key::new("test-key", b"secret".to_vec());
+        let input = "(\"@method\" \"@target-uri\" \"content-type\");created=1618884473;keyid=\"test-key\";alg=\"hmac-sha256\"";
+        let (params, serialized) = &parse_signature_input(&format!("sig1={}", input)).unwrap()[0];
+        let base = signature_base(&params.components, serialized, |c| request_component(&request("", ""), c)).unwrap();
+        let signature = format!("sig1=:{}:", crate::base64::encode(&key.sign(base.as_bytes())));
+
+        let req = request(&format!("sig1={}", input), &signature);
+        assert_eq!(verify_request(&req, &key, &["@method"]).unwrap().label, "sig1");
+        assert_eq!(verify_request(&req, &key, &["@authority"]), Err(SignatureError::NotCovered("@authority".into())));
+
+        let other_key = HmacSha256Key::new("test-key", b"other secret".to_vec());
+        assert_eq!(verify_request(&req, &other_key, &[]), Err(SignatureError::Invalid));
+        let other_id = HmacSha256Key::new("another-key", b"secret".to_vec());
+        assert_eq!(verify_request(&req, &other_id, &[]), Err(SignatureError::Missing));
+    }
+
+    #[test]
+    fn test_verify_params_as_received() {
+        // Parameters in another order than ours, and one we don't know
+        let key = HmacSha256Key::new("test-key", b"secret".to_vec());
+        let input = "(\"@method\" \"content-type\");keyid=\"test-key\";foo=\"bar\";created=1618884473";
+        let base = format!("\"@method\": POST\n\"content-type\": application/json\n\"@signature-params\": {}", input);
+        let signature = format!("sig1=:{}:", crate::base64::encode(&key.sign(base.as_bytes())));
+        let params = verify_request(&request(&format!("sig1={}", input), &signature), &key, &["@method"]).unwrap();
+        assert_eq!((params.created, params.key_id.as_deref()), (Some(1618884473), Some("test-key")));
+    }
+
+    #[test]
+    fn test_component_params() {
+        let key = HmacSha256Key::new("test-key", b"secret".to_vec());
+        let input = "(\"@method\" \"content-type\";sf);keyid=\"test-key\"";
+        let params = parse_signature_input(&format!("sig1={}", input)).unwrap();
+        assert_eq!(params[0].0.components, ["@method", "\"content-type\";sf"]);
+
+        // Signed as if the parameter wasn't there
+        let base = format!("\"@method\": POST\n\"content-type\": application/json\n\"@signature-params\": {}", input);
+        let signature = format!("sig1=:{}:", crate::base64::encode(&key.sign(base.as_bytes())));
+        let req = request(&format!("sig1={}", input), &signature);
+        assert_eq!(verify_request(&req, &key, &[]), Err(SignatureError::MissingComponent("\"content-type\";sf".into())));
+        assert_eq!(verify_request(&req, &key, &["content-type"]), Err(SignatureError::NotCovered("content-type".into())));
+    }
+
+    #[test]
+    fn test_max_age() {
+        let key = HmacSha256Key::new("test-key", b"secret".to_vec());
+        let signed = |created: Option<u64>| {
+            let input = match created {
+                Some(created) => format!("(\"@method\");created={};keyid=\"test-key\"", created),
+                None => "(\"@method\");keyid=\"test-key\"".to_owned(),
+            };
+            let base = format!("\"@method\": POST\n\"@signature-params\": {}", input);
+            request(&format!("sig1={}", input), &format!("sig1=:{}:", crate::base64::encode(&key.sign(base.as_bytes()))))
+        };
+        let check = |req: &Request| verify_request_max_age(req, &key, &[], Duration::from_secs(300), Duration::from_secs(60)).map(|_| ());
+
+        assert_eq!(check(&signed(Some(now() - 10))), Ok(()));
+        assert_eq!(check(&signed(Some(now() + 30))), Ok(()));
+        assert_eq!(check(&signed(Some(now() - 600))), Err(SignatureError::Expired));
+        assert_eq!(check(&signed(Some(now() + 600))), Err(SignatureError::NotYetValid));
+        assert_eq!(check(&signed(None)), Err(SignatureError::Expired));
+        assert!(verify_request(&signed(Some(1618884473)), &key, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_sign_response() {
+        let key = HmacSha256Key::new("test-key", b"secret".to_vec());
+        let mut resp = crate::text_response(200, "Hello");
+        sign_response(&mut resp, "sig1", &key, &["@status", "content-type"]).unwrap();
+        let input = resp.headers()["signature-input"].to_str().unwrap();
+        assert!(input.starts_with("sig1=(\"@status\" \"content-type\");created="));
+        assert!(input.ends_with(";keyid=\"test-key\";alg=\"hmac-sha256\""));
+        assert!(resp.headers()["signature"].to_str().unwrap().starts_with("sig1=:"));
+
+        assert_eq!(sign_response(&mut resp, "sig2", &key, &["@method"]), Err(SignatureError::MissingComponent("@method".into())));
+    }
+}
@@ -0,0 +1,185 @@
+//! Compute an expensive response once, even when many requests for it arrive at once.
+//!
+//! When a popular page expires from a cache, every request for it starts its own CGI process
+//! and they all compute the same response. With [`SingleFlight`], the first process takes a
+//! lock for the URL and computes the response; the others wait for it, and then serve the
+//! stored result. Stored responses are kept in a [`FileStore`] and reused until they expire.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use cgi::single_flight::SingleFlight;
+//! use cgi::store::FileStore;
+//!
+//! fn main() {
+//!     let flight = SingleFlight::new(FileStore::new("/tmp/my-cgi-store")).ttl(Duration::from_secs(30));
+//!     cgi::handle(|request: cgi::Request| flight.handle(request, |request| {
+//!         cgi::text_response(200, "an expensive report")
+//!     }))
+//! }
+//! ```
+//!
+//! Only `GET` and `HEAD` requests without credentials (an `Authorization` or `Cookie` header,
+//! or a `REMOTE_USER` authenticated by the server) are handled this way, since their
+//! responses are shared with everyone asking for the same URL. Only `200 OK` responses which
+//! don't set cookies and aren't `Cache-Control: private` or `no-store` are stored. Streamed
+//! responses (like [`file_response`](crate::file_response)) aren't stored, nor are responses
+//! with a `Vary` header, since they're stored per URL only; use
+//! [`MicroCache`](crate::cache::MicroCache) for those.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::meta::CgiMeta;
+use crate::store::FileStore;
+use crate::stream::BodyWriter;
+use crate::{client, clone_response, serialize_response, Request, Response};
+
+/// Deduplicates concurrent requests for the same URL. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct SingleFlight {
+    store: FileStore,
+    ttl: Duration,
+    max_wait: Duration,
+}
+
+impl SingleFlight {
+    /// By default, responses are stored for 10 seconds, and a process waits at most 30
+    /// seconds for another one before computing the response itself.
+    pub fn new(store: FileStore) -> Self {
+        SingleFlight { store, ttl: Duration::from_secs(10), max_wait: Duration::from_secs(30) }
+    }
+
+    /// How long a computed response is served to later requests
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How long to wait for another process computing the same response
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Serve a stored response for the request's URL, or wait for another process computing
+    /// one, or call `next` to compute it (and store it for the others).
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        if !is_shareable(&request) {
+            return next(request);
+        }
+        let host = request.headers().get(http::header::HOST).map(|h| String::from_utf8_lossy(h.as_bytes())).unwrap_or_default();
+        let key = format!("single-flight {} {}{}", request.method(), host, request.uri());
+        let lock_key = format!("{} lock", key);
+
+        let started = Instant::now();
+        loop {
+            if let Some(response) = self.stored(&key) {
+                return response;
+            }
+            match self.store.try_lock(&lock_key, Some(self.max_wait)) {
+                Ok(Some(_lock)) => {
+                    // The previous holder may have finished between the check & the lock
+                    if let Some(response) = self.stored(&key) {
+                        return response;
+                    }
+                    let response = next(request);
+                    if is_storable(&response) && !response.headers().contains_key(http::header::VARY) {
+                        if let Err(err) = self.store.set(&key, &serialize_response(clone_response(&response)), Some(self.ttl)) {
+                            eprintln!("Could not store response: {}", err);
+                        }
+                    }
+                    return response;
+                }
+                Ok(None) if started.elapsed() < self.max_wait => sleep(Duration::from_millis(50)),
+                Ok(None) => return next(request),
+                Err(err) => {
+                    eprintln!("Could not lock {:?}: {}", key, err);
+                    return next(request);
+                }
+            }
+        }
+    }
+
+    fn stored(&self, key: &str) -> Option<Response> {
+        let stored = self.store.get(key).ok()??;
+        client::parse_output(&stored).ok()
+    }
+}
+
+// Whether the response to a request can be shared with other clients (also used by `cache`):
+// a `GET` or `HEAD` without credentials, so the response isn't personalised
+pub(crate) fn is_shareable(request: &Request) -> bool {
+    (request.method() == http::Method::GET || request.method() == http::Method::HEAD)
+        && !request.headers().contains_key(http::header::AUTHORIZATION)
+        && !request.headers().contains_key(http::header::COOKIE)
+        && request.extensions().get::<CgiMeta>().and_then(|meta| meta.remote_user.as_ref()).is_none()
+}
+
+// Whether a response can be shared with other clients (also used by `cache`). A streamed body
+// isn't in the response, and could be endless (like an event stream).
+pub(crate) fn is_storable(response: &Response) -> bool {
+    let cache_control = response.headers().get_all(http::header::CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    response.status() == http::StatusCode::OK
+        && !response.headers().contains_key(http::header::SET_COOKIE)
+        && !cache_control.iter().any(|d| d == "private" || d == "no-store")
+        && response.extensions().get::<BodyWriter>().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text_response;
+
+    #[test]
+    fn test_single_flight() {
+        let dir = std::env::temp_dir().join(format!("cgi-single-flight-{}", std::process::id()));
+        let flight = SingleFlight::new(FileStore::new(&dir)).max_wait(Duration::from_millis(200));
+        let request = |uri: &str| http::Request::builder().uri(uri).body(vec![]).unwrap();
+
+        let resp = flight.handle(request("/report"), |_| text_response(200, "computed"));
+        assert_eq!(resp.body(), b"computed");
+        let resp = flight.handle(request("/report"), |_| panic!("should be stored"));
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.body(), b"computed");
+        assert_eq!(resp.headers()["content-type"], "text/plain; charset=utf-8");
+
+        // Not stored
+        let mut private = text_response(200, "mine");
+        private.headers_mut().insert(http::header::CACHE_CONTROL, "max-age=5, Private".parse().unwrap());
+        assert!(!is_storable(&private));
+        assert!(!is_storable(&crate::stream::streaming_response(200, "text/plain", |_| Ok(()))));
+        let in_language = |lang: &'static str| move |_| {
+            let mut response = text_response(200, lang);
+            response.headers_mut().insert(http::header::VARY, "accept-language".parse().unwrap());
+            response
+        };
+        flight.handle(request("/varies"), in_language("en"));
+        assert_eq!(flight.handle(request("/varies"), in_language("de")).body(), b"de");
+        flight.handle(request("/error"), |_| text_response(500, "failed"));
+        assert_eq!(flight.handle(request("/error"), |_| text_response(200, "ok")).body(), b"ok");
+
+        // Another process is computing it, and doesn't finish in time
+        let lock = flight.store.try_lock("single-flight GET /slow lock", None).unwrap().unwrap();
+        assert!(flight.store.try_lock("single-flight GET /slow lock", None).unwrap().is_none());
+        let resp = flight.handle(request("/slow"), |_| text_response(200, "gave up waiting"));
+        assert_eq!(resp.body(), b"gave up waiting");
+        drop(lock);
+
+        // Requests with credentials neither get nor store shared responses
+        let with = |name: &str, value: &str| http::Request::builder().uri("/report").header(name, value).body(vec![]).unwrap();
+        assert_eq!(flight.handle(with("cookie", "session=alice"), |_| text_response(200, "alice's report")).body(), b"alice's report");
+        assert_eq!(flight.handle(with("authorization", "Basic Ym9iOg=="), |_| text_response(200, "bob's report")).body(), b"bob's report");
+        let resp = crate::testing::CgiRequestBuilder::new().env("REMOTE_USER", "carol").path_info("/private")
+            .run(|request| flight.handle(request, |_| text_response(200, "carol's report")));
+        assert_eq!(resp.body(), b"carol's report");
+        assert_eq!(flight.handle(request("/private"), |_| text_response(200, "public")).body(), b"public");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! Lightweight spam protection for form endpoints.
+//!
+//! Requires the `spam` feature. [`SpamGuard`] combines three cheap checks:
+//!
+//! * a honeypot: a form field hidden with CSS, which people leave empty but bots fill in
+//! * a minimum submit time: the form includes a signed timestamp token (from
+//!   [`SpamGuard::token`]), and submissions faster than a human could type are rejected
+//! * throttling: at most so many submissions per client IP address in a time window, counted
+//!   in a [`FileStore`]
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use cgi::spam::SpamGuard;
+//! use cgi::store::FileStore;
+//!
+//! fn main() {
+//!     let guard = SpamGuard::new(b"a long random secret".to_vec())
+//!         .honeypot("website")
+//!         .min_submit_time(Duration::from_secs(3))
+//!         .throttle(FileStore::new("/tmp/contact-form"), 5, Duration::from_secs(3600));
+//!
+//!     cgi::handle(|request: cgi::Request| {
+//!         if request.method() == "GET" {
+//!             return cgi::html_response(200, format!(
+//!                 "<form method=post><input name=website style=display:none>\
+//!                  <input type=hidden name=_token value={}>…</form>", guard.token()));
+//!         }
+//!         guard.handle(request, |request| cgi::text_response(200, "Thanks!"))
+//!     })
+//! }
+//! ```
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::meta::RemoteAddr;
+use crate::proxy::ClientInfo;
+use crate::store::FileStore;
+use crate::{text_response, urlencoded, Request, Response};
+
+/// Why a submission was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpamReason {
+    /// The honeypot field wasn't empty
+    Honeypot,
+    /// The timing token is missing, forged or too old
+    InvalidToken,
+    /// The form was submitted too soon after it was shown
+    TooFast,
+    /// This IP address has submitted too often
+    Throttled,
+}
+
+impl fmt::Display for SpamReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpamReason::Honeypot => write!(f, "honeypot field filled in"),
+            SpamReason::InvalidToken => write!(f, "missing or invalid form token"),
+            SpamReason::TooFast => write!(f, "form submitted too quickly"),
+            SpamReason::Throttled => write!(f, "too many submissions"),
+        }
+    }
+}
+
+impl std::error::Error for SpamReason {}
+
+/// Spam checks for a form, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct SpamGuard {
+    secret: Vec<u8>,
+    honeypot: Option<String>,
+    token_field: String,
+    min_submit_time: Option<Duration>,
+    max_token_age: Duration,
+    throttle: Option<(FileStore, u64, Duration)>,
+}
+
+impl SpamGuard {
+    /// `secret` signs the timing tokens, keep it the same across requests.
+    pub fn new(secret: Vec<u8>) -> Self {
+        SpamGuard {
+            secret,
+            honeypot: None,
+            token_field: "_token".into(),
+            min_submit_time: None,
+            max_token_age: Duration::from_secs(24 * 3600),
+            throttle: None,
+        }
+    }
+
+    /// Reject submissions where the field `name` isn't empty
+    pub fn honeypot(mut self, name: impl Into<String>) -> Self {
+        self.honeypot = Some(name.into());
+        self
+    }
+
+    /// Require a token from [`token`](Self::token) at least this old
+    pub fn min_submit_time(mut self, min: Duration) -> Self {
+        self.min_submit_time = Some(min);
+        self
+    }
+
+    /// Tokens older than this are rejected (default 1 day)
+    pub fn max_token_age(mut self, max: Duration) -> Self {
+        self.max_token_age = max;
+        self
+    }
+
+    /// The form field for the token (default `_token`)
+    pub fn token_field(mut self, name: impl Into<String>) -> Self {
+        self.token_field = name.into();
+        self
+    }
+
+    /// Allow at most `max` submissions per IP address in every `window`
+    pub fn throttle(mut self, store: FileStore, max: u64, window: Duration) -> Self {
+        self.throttle = Some((store, max, window));
+        self
+    }
+
+    /// A token to put in a hidden form field, recording when the form was shown
+    pub fn token(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        format!("{}-{}", now, self.sign(now))
+    }
+
+    fn sign(&self, timestamp: u64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.finalize().into_bytes().iter().take(16).map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn check_token(&self, token: Option<&str>) -> Result<(), SpamReason> {
+        let (timestamp, signature) = token.and_then(|t| t.split_once('-')).ok_or(SpamReason::InvalidToken)?;
+        let timestamp: u64 = timestamp.parse().map_err(|_| SpamReason::InvalidToken)?;
+        let expected = self.sign(timestamp);
+        // Compare without leaking where the first difference is
+        if expected.len() != signature.len() || expected.bytes().zip(signature.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+            return Err(SpamReason::InvalidToken);
+        }
+        let shown = UNIX_EPOCH + Duration::from_secs(timestamp);
+        let age = SystemTime::now().duration_since(shown).map_err(|_| SpamReason::InvalidToken)?;
+        if age > self.max_token_age {
+            return Err(SpamReason::InvalidToken);
+        }
+        if self.min_submit_time.is_some_and(|min| age < min) {
+            return Err(SpamReason::TooFast);
+        }
+        Ok(())
+    }
+
+    /// Run all the configured checks on a form submission (an urlencoded body, or the query
+    /// string).
+    ///
+    /// The throttling counter is only incremented when the other checks pass.
+    pub fn check(&self, request: &Request) -> Result<(), SpamReason> {
+        let fields = form_fields(request);
+        let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+
+        if let Some(honeypot) = &self.honeypot {
+            if field(honeypot).is_some_and(|v| !v.trim().is_empty()) {
+                return Err(SpamReason::Honeypot);
+            }
+        }
+        if self.min_submit_time.is_some() {
+            self.check_token(field(&self.token_field))?;
+        }
+        if let Some((store, max, window)) = &self.throttle {
+            let ip = match request.extensions().get::<ClientInfo>() {
+                Some(client) => client.ip.to_string(),
+                None => request.extensions().get::<RemoteAddr>().map_or_else(|| "unknown".to_owned(), |addr| addr.ip().to_string()),
+            };
+            match store.increment(&format!("spam-{}", ip), Some(*window)) {
+                Ok(count) if count > *max => return Err(SpamReason::Throttled),
+                Ok(_) => {}
+                Err(err) => eprintln!("Could not update submission counter: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `next` if the submission passes the checks. Otherwise return a `429 Too Many
+    /// Requests` when throttled, and a `400 Bad Request` for anything else.
+    pub fn handle<F>(&self, request: Request, next: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        match self.check(&request) {
+            Ok(()) => next(request),
+            Err(SpamReason::Throttled) => {
+                let mut response = text_response(429, SpamReason::Throttled.to_string());
+                if let Some((_, _, window)) = &self.throttle {
+                    response.headers_mut().insert(http::header::RETRY_AFTER, window.as_secs().into());
+                }
+                response
+            }
+            Err(reason) => text_response(400, reason.to_string()),
+        }
+    }
+}
+
+fn form_fields(request: &Request) -> Vec<(String, String)> {
+    let urlencoded = request.headers().get(http::header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/x-www-form-urlencoded"));
+    if urlencoded {
+        urlencoded::parse(&String::from_utf8_lossy(request.body()))
+    } else {
+        urlencoded::parse(request.uri().query().unwrap_or(""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(body: &str) -> Request {
+        http::Request::builder()
+            .method("POST")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .extension(RemoteAddr("192.0.2.1".parse().unwrap(), None))
+            .body(body.as_bytes().to_vec())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_honeypot_and_token() {
+        let guard = SpamGuard::new(b"secret".to_vec()).honeypot("website").min_submit_time(Duration::from_secs(0));
+        let token = guard.token();
+        assert_eq!(guard.check(&post(&format!("name=a&website=&_token={}", token))), Ok(()));
+        assert_eq!(guard.check(&post(&format!("name=a&website=spam&_token={}", token))), Err(SpamReason::Honeypot));
+        assert_eq!(guard.check(&post("name=a")), Err(SpamReason::InvalidToken));
+        assert_eq!(guard.check(&post(&format!("_token={}0", token))), Err(SpamReason::InvalidToken));
+
+        let other = SpamGuard::new(b"other".to_vec()).min_submit_time(Duration::from_secs(0));
+        assert_eq!(other.check(&post(&format!("_token={}", token))), Err(SpamReason::InvalidToken));
+
+        let slow = SpamGuard::new(b"secret".to_vec()).min_submit_time(Duration::from_secs(60));
+        assert_eq!(slow.check(&post(&format!("_token={}", token))), Err(SpamReason::TooFast));
+        assert_eq!(slow.handle(post(&format!("_token={}", token)), |_| crate::empty_response(200)).status(), 400);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let dir = std::env::temp_dir().join(format!("cgi-spam-{}", std::process::id()));
+        let guard = SpamGuard::new(b"secret".to_vec()).throttle(FileStore::new(&dir), 2, Duration::from_secs(60));
+        assert_eq!(guard.check(&post("a=1")), Ok(()));
+        assert_eq!(guard.check(&post("a=1")), Ok(()));
+        let resp = guard.handle(post("a=1"), |_| crate::empty_response(200));
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers()[http::header::RETRY_AFTER], "60");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+//! Server-Sent Events: a `text/event-stream` response, e.g. to report the progress of a long
+//! job to the browser, which reads it with `EventSource`.
+//!
+//! [`EventStream`] sends the events of an iterator, or of a callback on another thread, as
+//! they're produced. Each event is flushed right away, and while there's none, a comment is
+//! sent every 15 seconds so proxies don't close the idle connection.
+//!
+//! ```rust,no_run
+//! use cgi::sse::{Event, EventStream};
+//!
+//! cgi::handle(|request: cgi::Request| {
+//!     EventStream::from_fn(|events| {
+//!         for percent in (0..=100).step_by(10) {
+//!             std::thread::sleep(std::time::Duration::from_secs(1));
+//!             if events.send(Event::data(percent.to_string()).event("progress")).is_err() {
+//!                 return; // the client is gone
+//!             }
+//!         }
+//!         let _ = events.send(Event::data("done").event("finished"));
+//!     })
+//! })
+//! ```
+//!
+//! The response has `Cache-Control: no-cache`, and `X-Accel-Buffering: no` for nginx. Other
+//! servers may still buffer the output of CGI programmes: Apache's `mod_deflate`, for
+//! example, has to be switched off for the script.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::{IntoResponse, Response};
+
+/// One event. Its data can have several lines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    /// A `message` event with this data
+    pub fn data(data: impl Into<String>) -> Self {
+        Event { data: Some(data.into()), ..Event::default() }
+    }
+
+    /// A comment, which the browser ignores
+    pub fn comment(comment: impl Into<String>) -> Self {
+        Event { comment: Some(comment.into()), ..Event::default() }
+    }
+
+    /// The type of the event, for `addEventListener` instead of `onmessage`
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// The ID of the event, which the browser sends back as `Last-Event-ID` when it reconnects
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// How long the browser waits before it reconnects
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+// `\r\n`, `\n` and `\r` all end a line in an event stream; `str::lines` doesn't split at `\r`
+fn lines(value: &str) -> impl Iterator<Item = &str> {
+    value.split("\r\n").flat_map(|line| line.split(['\r', '\n']))
+}
+
+// Line breaks would start another field
+fn single_line(value: &str) -> String {
+    lines(value).collect()
+}
+
+/// The event as it's sent: a field per line, and an empty line
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(comment) = &self.comment {
+            for line in lines(comment) {
+                writeln!(f, ": {}", line)?;
+            }
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", single_line(id))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        if let Some(data) = &self.data {
+            for line in lines(data) {
+                writeln!(f, "data: {}", line)?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+type Produce = Box<dyn FnOnce(Sender<Event>) + Send>;
+
+/// A response sending events as they're produced.
+pub struct EventStream {
+    produce: Produce,
+    keep_alive: Option<Duration>,
+    retry: Option<Duration>,
+}
+
+impl fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EventStream").field("keep_alive", &self.keep_alive).field("retry", &self.retry).finish()
+    }
+}
+
+impl EventStream {
+    /// Send the events of `events`, which is iterated on another thread
+    pub fn new<I>(events: I) -> Self
+        where I: IntoIterator<Item = Event> + Send + 'static
+    {
+        EventStream::from_fn(move |sender| {
+            for event in events {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// Send the events which `produce` sends, which runs on another thread. Sending fails
+    /// once the client has gone away.
+    pub fn from_fn<F>(produce: F) -> Self
+        where F: FnOnce(Sender<Event>) + Send + 'static
+    {
+        EventStream { produce: Box::new(produce), keep_alive: Some(Duration::from_secs(15)), retry: None }
+    }
+
+    /// Send a comment when there's been no event for `interval` (15 seconds by default), or
+    /// none with `None`
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// Ask the browser to wait this long before it reconnects
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl IntoResponse for EventStream {
+    fn into_response(self) -> Response {
+        let EventStream { produce, keep_alive, retry } = self;
+        let mut response = crate::stream::streaming_response(200, "text/event-stream", move |out| {
+            let (sender, receiver) = mpsc::channel();
+            std::thread::spawn(move || produce(sender));
+            write_events(out, receiver, keep_alive, retry)
+        });
+        response.headers_mut().insert(http::header::CACHE_CONTROL, http::HeaderValue::from_static("no-cache"));
+        response.headers_mut().insert("x-accel-buffering", http::HeaderValue::from_static("no"));
+        response
+    }
+}
+
+fn write_events(out: &mut dyn Write, events: Receiver<Event>, keep_alive: Option<Duration>, retry: Option<Duration>) -> io::Result<()> {
+    if let Some(retry) = retry {
+        write!(out, "{}", Event { retry: Some(retry), ..Event::default() })?;
+        out.flush()?;
+    }
+    loop {
+        let event = match keep_alive {
+            Some(interval) => match events.recv_timeout(interval) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => Event::comment("keep-alive"),
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            },
+            None => match events.recv() {
+                Ok(event) => event,
+                Err(_) => return Ok(()),
+            },
+        };
+        write!(out, "{}", event)?;
+        out.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event() {
+        assert_eq!(Event::data("hello").to_string(), "data: hello\n\n");
+        assert_eq!(Event::data("one\ntwo\r\nthree\rfour").event("update").id("7\n").to_string(),
+            "event: update\nid: 7\ndata: one\ndata: two\ndata: three\ndata: four\n\n");
+        assert_eq!(Event::data("").retry(Duration::from_secs(3)).to_string(), "retry: 3000\ndata: \n\n");
+        assert_eq!(Event::comment("keep-alive").to_string(), ": keep-alive\n\n");
+    }
+
+    #[test]
+    fn test_write_events() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Event::data("first")).unwrap();
+        sender.send(Event::data("second").event("progress")).unwrap();
+        drop(sender);
+        let mut out = Vec::new();
+        write_events(&mut out, receiver, None, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "retry: 1000\n\ndata: first\n\nevent: progress\ndata: second\n\n");
+
+        let (sender, receiver) = mpsc::channel();
+        let producer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            sender.send(Event::data("late")).unwrap();
+        });
+        let mut out = Vec::new();
+        write_events(&mut out, receiver, Some(Duration::from_millis(20)), None).unwrap();
+        producer.join().unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(": keep-alive\n\n") && out.ends_with("data: late\n\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let mut response = EventStream::new(vec![Event::data("a"), Event::data("b")]).into_response();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        assert_eq!(response.headers()["cache-control"], "no-cache");
+        let mut out = Vec::new();
+        crate::stream::take_body_writer(&mut response).unwrap().write_to(&mut out).unwrap();
+        assert_eq!(out, b"data: a\n\ndata: b\n\n");
+    }
+}
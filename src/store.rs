@@ -0,0 +1,300 @@
+//! A small key-value store kept in a directory, shared by all the CGI processes.
+//!
+//! Every request is a new process, so anything which has to outlive it (counters, cached
+//! responses, progress records…) has to go somewhere else. [`FileStore`] keeps one file per
+//! key, with an optional expiry time, and uses file locks so concurrent processes don't
+//! corrupt each other's updates.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use cgi::store::FileStore;
+//!
+//! let store = FileStore::new("/tmp/my-cgi-store");
+//! let hits = store.increment("hits", None).unwrap();
+//! store.set("last-visitor", b"192.0.2.1", Some(Duration::from_secs(3600))).unwrap();
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A directory of values, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Keep the values in `dir`, which is created when needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileStore { dir: dir.into() }
+    }
+
+    /// The value for `key`, if it's set and hasn't expired
+    pub fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut file = match File::open(self.path(key)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        file.lock_shared()?;
+        read_entry(&mut file)
+    }
+
+    /// Set `key` to `value`, expiring after `ttl` (or never)
+    pub fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        let mut file = self.open_locked(key)?;
+        write_entry(&mut file, value, ttl)
+    }
+
+    /// Remove `key`
+    pub fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Add one to the counter `key`, returning the new value. A new (or expired) counter
+    /// starts at 1, and expires after `ttl`; incrementing doesn't extend the expiry, so this
+    /// counts events in a fixed time window.
+    pub fn increment(&self, key: &str, ttl: Option<Duration>) -> io::Result<u64> {
+        self.update(key, ttl, |value| {
+            let count = value.and_then(|v| std::str::from_utf8(&v).ok()?.parse::<u64>().ok()).unwrap_or(0) + 1;
+            count.to_string().into_bytes()
+        }).map(|v| String::from_utf8_lossy(&v).parse().unwrap_or(0))
+    }
+
+    /// Atomically replace the value of `key` with `f(current value)`. A new value expires
+    /// after `ttl`, an existing one keeps its expiry time. Returns the new value.
+    pub fn update<F>(&self, key: &str, ttl: Option<Duration>, f: F) -> io::Result<Vec<u8>>
+        where F: FnOnce(Option<Vec<u8>>) -> Vec<u8>
+    {
+        let mut file = self.open_locked(key)?;
+        let (current, expires) = match read_raw(&mut file)? {
+            Some((expires, value)) if !is_expired(expires) => (Some(value), Some(expires)),
+            _ => (None, None),
+        };
+        let new = f(current);
+        match expires {
+            Some(expires) => write_raw(&mut file, &new, expires)?,
+            None => write_entry(&mut file, &new, ttl)?,
+        }
+        Ok(new)
+    }
+
+    /// Delete every expired entry (and lock file). Expired entries are ignored anyway, so this
+    /// only frees disk space; call it now and then (e.g. on 1% of requests). Entries which are
+    /// locked are left alone. An error with one entry doesn't stop the others from being
+    /// deleted; the first one is returned at the end.
+    pub fn purge_expired(&self) -> io::Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut first_error = None;
+        for entry in entries {
+            if let Err(err) = entry.and_then(|entry| purge_if_expired(&entry.path())) {
+                first_error.get_or_insert(err);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Take an exclusive lock named `key`, without waiting. `Ok(None)` if another process
+    /// holds it. The lock is released when the returned file is dropped (or the process
+    /// exits), and the lock file expires after `ttl`, so
+    /// [`purge_expired`](Self::purge_expired) removes it once it's no longer used. Use a key
+    /// which isn't also used for a value.
+    pub fn try_lock(&self, key: &str, ttl: Option<Duration>) -> io::Result<Option<File>> {
+        let Some(mut file) = self.open_with_lock(key, false)? else { return Ok(None) };
+        write_entry(&mut file, b"", ttl)?;
+        Ok(Some(file))
+    }
+
+    /// Take an exclusive lock named `key`, waiting until other processes release it. Like
+    /// [`try_lock`](Self::try_lock) otherwise.
+    pub fn lock(&self, key: &str, ttl: Option<Duration>) -> io::Result<File> {
+        let mut file = self.open_locked(key)?;
+        write_entry(&mut file, b"", ttl)?;
+        Ok(file)
+    }
+
+    fn open_locked(&self, key: &str) -> io::Result<File> {
+        Ok(self.open_with_lock(key, true)?.expect("waited for the lock"))
+    }
+
+    // Open the file of `key` and lock it, or `None` if it's locked and `wait` is false.
+    // `purge_expired` may delete the file while we wait for the lock; then the lock is on a
+    // file which is gone, so open the new one & lock it again.
+    fn open_with_lock(&self, key: &str, wait: bool) -> io::Result<Option<File>> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        loop {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+            if wait {
+                file.lock()?;
+            } else {
+                match file.try_lock() {
+                    Ok(()) => {}
+                    Err(fs::TryLockError::WouldBlock) => return Ok(None),
+                    Err(fs::TryLockError::Error(e)) => return Err(e),
+                }
+            }
+            if is_same_file(&file, &path) {
+                return Ok(Some(file));
+            }
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(file_name(key))
+    }
+}
+
+// Delete the file at `path` if it has expired. It's locked first, and only deleted while the
+// lock is held, so nobody is using it; anyone waiting for the lock notices that it's gone.
+fn purge_if_expired(path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    if file.try_lock().is_err() {
+        return Ok(());
+    }
+    // It may have been deleted & recreated since it was listed
+    if !is_same_file(&file, path) {
+        return Ok(());
+    }
+    match read_raw(&mut file)? {
+        Some((expires, _)) if is_expired(expires) => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+// Whether `path` (still) is the open `file`
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(current)) => open.dev() == current.dev() && open.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+// Files which are open can't be deleted on other systems
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+// Simple keys are used as they are, anything else is hashed
+fn file_name(key: &str) -> String {
+    let simple = !key.is_empty() && key.len() <= 128 && !key.starts_with('.')
+        && key.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+    if simple {
+        key.to_owned()
+    } else {
+        format!("~{:016x}{:016x}", fnv1a(key.as_bytes(), 0xcbf29ce484222325), fnv1a(key.as_bytes(), 0x84222325cbf29ce4))
+    }
+}
+
+// Stable across processes & Rust versions, unlike `DefaultHasher`
+pub(crate) fn fnv1a(bytes: &[u8], offset: u64) -> u64 {
+    bytes.iter().fold(offset, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(0x100000001b3))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn is_expired(expires: u64) -> bool {
+    expires != 0 && expires <= now()
+}
+
+// The file is the expiry time (UNIX seconds, 0 for never), a newline, and the value
+fn read_raw(file: &mut File) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut contents = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut contents)?;
+    let newline = match contents.iter().position(|&b| b == b'\n') {
+        Some(idx) => idx,
+        None => return Ok(None),
+    };
+    let expires = String::from_utf8_lossy(&contents[..newline]).parse().unwrap_or(0);
+    Ok(Some((expires, contents.split_off(newline + 1))))
+}
+
+fn read_entry(file: &mut File) -> io::Result<Option<Vec<u8>>> {
+    Ok(match read_raw(file)? {
+        Some((expires, value)) if !is_expired(expires) => Some(value),
+        _ => None,
+    })
+}
+
+fn write_raw(file: &mut File, value: &[u8], expires: u64) -> io::Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(format!("{}\n", expires).as_bytes())?;
+    file.write_all(value)
+}
+
+fn write_entry(file: &mut File, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+    let expires = ttl.map(|ttl| now() + ttl.as_secs().max(1)).unwrap_or(0);
+    write_raw(file, value, expires)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir().join(format!("cgi-store-{}", std::process::id()));
+        let store = FileStore::new(&dir);
+
+        assert_eq!(store.get("missing").unwrap(), None);
+        store.set("key", b"value", None).unwrap();
+        assert_eq!(store.get("key").unwrap(), Some(b"value".to_vec()));
+        store.set("../escape/attempt", b"x", None).unwrap();
+        assert_eq!(store.get("../escape/attempt").unwrap(), Some(b"x".to_vec()));
+        assert!(!dir.parent().unwrap().join("escape").exists());
+
+        assert_eq!(store.increment("count", Some(Duration::from_secs(60))).unwrap(), 1);
+        assert_eq!(store.increment("count", Some(Duration::from_secs(60))).unwrap(), 2);
+
+        // Expired
+        let mut file = store.open_locked("old").unwrap();
+        write_raw(&mut file, b"stale", 1).unwrap();
+        drop(file);
+        assert_eq!(store.get("old").unwrap(), None);
+        assert_eq!(store.increment("old", None).unwrap(), 1);
+
+        store.delete("key").unwrap();
+        store.delete("key").unwrap();
+        assert_eq!(store.get("key").unwrap(), None);
+
+        // Expired lock files are purged, unless they're held
+        let mut held = store.try_lock("held", Some(Duration::from_secs(60))).unwrap().unwrap();
+        assert!(store.try_lock("held", None).unwrap().is_none());
+        write_raw(&mut held, b"", 1).unwrap();
+        let mut released = store.try_lock("released", Some(Duration::from_secs(60))).unwrap().unwrap();
+        write_raw(&mut released, b"", 1).unwrap();
+        drop(released);
+        store.purge_expired().unwrap();
+        assert!(store.path("held").exists());
+        assert!(!store.path("released").exists());
+        drop(held);
+
+        // A file which isn't an entry doesn't stop the sweep
+        fs::create_dir(dir.join("a-directory")).unwrap();
+        store.set("stale", b"", Some(Duration::from_secs(1))).unwrap();
+        let mut file = store.open_locked("stale").unwrap();
+        write_raw(&mut file, b"", 1).unwrap();
+        drop(file);
+        let _ = store.purge_expired();
+        assert!(!store.path("stale").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! Responses whose body is written by a callback, for output too large to build in memory.
+//!
+//! [`streaming_response`] returns an ordinary [`Response`] with an empty body, carrying the
+//! callback as an extension. [`handle`](crate::handle) writes the headers first, and then
+//! calls the callback with stdout, so the body goes out as it's produced. The output is
+//! buffered: the callback can `flush` it to send what it has written so far.
+//!
+//! ```rust,no_run
+//! use std::io::Write;
+//!
+//! cgi::handle(|request: cgi::Request| {
+//!     cgi::stream::streaming_response(200, "text/csv", |out| {
+//!         for id in 0..10_000_000 {
+//!             writeln!(out, "{},item {}", id, id)?;
+//!         }
+//!         Ok(())
+//!     })
+//! })
+//! ```
+//!
+//! There's no `Content-Length`, since it isn't known in advance; the web server takes care of
+//! the framing. An [NPH script](crate#nph-scripts) answering HTTP/1.1 sends the body with
+//! `Transfer-Encoding: chunked` itself, so the client can keep the connection. Middleware
+//! which reads the body only sees the empty `Vec`. If the callback fails, the error is printed
+//! to stderr and the response is cut short.
+//!
+//! [`scgi`](crate::scgi) streams the body too; `fastcgi` and the
+//! [`dev_server`](crate::dev_server) collect it first.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::Response;
+
+type WriteBody = dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send;
+
+/// The callback writing a response body, stored in the response's extensions.
+#[derive(Clone)]
+pub struct BodyWriter(Arc<Mutex<Option<Box<WriteBody>>>>);
+
+impl fmt::Debug for BodyWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BodyWriter")
+    }
+}
+
+impl BodyWriter {
+    pub fn new<F>(write: F) -> Self
+        where F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static
+    {
+        BodyWriter(Arc::new(Mutex::new(Some(Box::new(write)))))
+    }
+
+    /// Call the callback with `out`. It only runs once; later calls do nothing.
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        let write = self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match write {
+            Some(write) => write(out),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A response with the status & `Content-Type`, whose body is written by `write`
+pub fn streaming_response<F>(status_code: u16, content_type: &str, write: F) -> Response
+    where F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static
+{
+    let mut response = http::Response::builder()
+        .status(status_code)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(Vec::new())
+        .unwrap();
+    response.extensions_mut().insert(BodyWriter::new(write));
+    response
+}
+
+/// Remove the body callback from the response, if it has one
+pub fn take_body_writer(response: &mut Response) -> Option<BodyWriter> {
+    response.extensions_mut().remove::<BodyWriter>()
+}
+
+// Writes every write as a chunk of `Transfer-Encoding: chunked`. `finish` writes the last,
+// empty chunk; without it, the client sees the body as cut short.
+pub(crate) struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if !buf.is_empty() {
+            let mut chunk = format!("{:x}\r\n", buf.len()).into_bytes();
+            chunk.extend_from_slice(buf);
+            chunk.extend_from_slice(b"\r\n");
+            self.inner.write_all(&chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Run the callback of a streaming response into its body, for the outputs which need the
+// whole response up front
+pub(crate) fn buffer_body(response: &mut Response) {
+    if let Some(writer) = take_body_writer(response) {
+        let mut body = std::mem::take(response.body_mut());
+        if let Err(err) = writer.write_to(&mut body) {
+            eprintln!("Could not write the response body: {}", err);
+        }
+        *response.body_mut() = body;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streaming_response() {
+        let mut response = streaming_response(200, "text/plain", |out| {
+            out.write_all(b"hello ")?;
+            write!(out, "{}", 42)
+        });
+        assert!(response.body().is_empty());
+        assert!(!response.headers().contains_key("content-length"));
+
+        let writer = take_body_writer(&mut response).unwrap();
+        let mut out = Vec::new();
+        writer.write_to(&mut out).unwrap();
+        assert_eq!(out, b"hello 42");
+        writer.write_to(&mut out).unwrap();
+        assert_eq!(out, b"hello 42");
+        assert!(take_body_writer(&mut response).is_none());
+
+        let mut response = streaming_response(200, "text/plain", |out| out.write_all(b"buffered"));
+        buffer_body(&mut response);
+        assert_eq!(response.body(), b"buffered");
+    }
+
+    #[test]
+    fn test_chunked_writer() {
+        let mut chunks = ChunkedWriter::new(Vec::new());
+        chunks.write_all(b"hello ").unwrap();
+        chunks.write_all(b"").unwrap();
+        write!(chunks, "{}", "x".repeat(20)).unwrap();
+        assert_eq!(chunks.finish().unwrap(), format!("6\r\nhello \r\n14\r\n{}\r\n0\r\n\r\n", "x".repeat(20)).into_bytes());
+    }
+}
@@ -0,0 +1,628 @@
+//! Structured Field Values for HTTP (RFC 8941): parse and serialize the lists, dictionaries
+//! and items used by modern headers like `Priority`, `Cache-Status` and client hints.
+//!
+//! ```rust
+//! use cgi::structured::{parse_dictionary, serialize_dictionary, BareItem, ListEntry};
+//!
+//! let priority = parse_dictionary("u=1, i").unwrap();
+//! assert_eq!(priority[0].0, "u");
+//! assert_eq!(priority[0].1, ListEntry::from(BareItem::Integer(1)));
+//! assert_eq!(serialize_dictionary(&priority).unwrap(), "u=1, i");
+//! ```
+
+use std::fmt;
+
+use crate::base64;
+
+/// A value without any parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BareItem {
+    Integer(i64),
+    Decimal(f64),
+    String(String),
+    Token(String),
+    ByteSequence(Vec<u8>),
+    Boolean(bool),
+}
+
+impl BareItem {
+    pub fn as_integer(&self) -> Option<i64> {
+        if let BareItem::Integer(i) = self { Some(*i) } else { None }
+    }
+
+    pub fn as_decimal(&self) -> Option<f64> {
+        if let BareItem::Decimal(d) = self { Some(*d) } else { None }
+    }
+
+    /// The value of a `String` or `Token`
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            BareItem::String(s) | BareItem::Token(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        if let BareItem::ByteSequence(b) = self { Some(b) } else { None }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if let BareItem::Boolean(b) = self { Some(*b) } else { None }
+    }
+}
+
+/// Parameters, in order. Keys are unique.
+pub type Parameters = Vec<(String, BareItem)>;
+
+fn get_param<'a>(params: &'a Parameters, key: &str) -> Option<&'a BareItem> {
+    params.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// A bare item with parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub bare_item: BareItem,
+    pub params: Parameters,
+}
+
+impl Item {
+    pub fn new(bare_item: BareItem) -> Self {
+        Item { bare_item, params: vec![] }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&BareItem> {
+        get_param(&self.params, key)
+    }
+}
+
+impl From<BareItem> for Item {
+    fn from(bare_item: BareItem) -> Self {
+        Item::new(bare_item)
+    }
+}
+
+/// A parenthesised list of items, with parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InnerList {
+    pub items: Vec<Item>,
+    pub params: Parameters,
+}
+
+impl InnerList {
+    pub fn new(items: Vec<Item>) -> Self {
+        InnerList { items, params: vec![] }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&BareItem> {
+        get_param(&self.params, key)
+    }
+}
+
+/// A member of a list or a dictionary value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListEntry {
+    Item(Item),
+    InnerList(InnerList),
+}
+
+impl From<Item> for ListEntry {
+    fn from(item: Item) -> Self {
+        ListEntry::Item(item)
+    }
+}
+
+impl From<BareItem> for ListEntry {
+    fn from(bare_item: BareItem) -> Self {
+        ListEntry::Item(Item::new(bare_item))
+    }
+}
+
+impl From<InnerList> for ListEntry {
+    fn from(list: InnerList) -> Self {
+        ListEntry::InnerList(list)
+    }
+}
+
+/// A top level list
+pub type List = Vec<ListEntry>;
+
+/// A top level dictionary, in order. Keys are unique.
+pub type Dictionary = Vec<(String, ListEntry)>;
+
+/// A value couldn't be parsed or serialized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    message: &'static str,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid structured field: {}", self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn err<T>(message: &'static str) -> Result<T, Error> {
+    Err(Error { message })
+}
+
+/// Parse a header value as an item, e.g. `"text"; q=0.5`
+pub fn parse_item(input: &str) -> Result<Item, Error> {
+    parse_with(input, |p| p.item())
+}
+
+/// Parse a header value as a list, e.g. `sugar, tea, (rum gin);q=1`
+pub fn parse_list(input: &str) -> Result<List, Error> {
+    parse_with(input, |p| {
+        let mut list = Vec::new();
+        while !p.at_end() {
+            list.push(p.list_entry()?);
+            if p.next_member()? {
+                break;
+            }
+        }
+        Ok(list)
+    })
+}
+
+/// Parse a header value as a dictionary, e.g. `u=1, i`
+pub fn parse_dictionary(input: &str) -> Result<Dictionary, Error> {
+    parse_with(input, |p| {
+        let mut dict: Dictionary = Vec::new();
+        while !p.at_end() {
+            let key = p.key()?;
+            let value = if p.eat(b'=') {
+                p.list_entry()?
+            } else {
+                ListEntry::Item(Item { bare_item: BareItem::Boolean(true), params: p.params()? })
+            };
+            insert(&mut dict, key, value);
+            if p.next_member()? {
+                break;
+            }
+        }
+        Ok(dict)
+    })
+}
+
+/// Parse all the values of header `name` as a list. Multiple header lines are combined.
+/// `None` if the header isn't present.
+pub fn list_header(headers: &http::HeaderMap, name: &str) -> Option<Result<List, Error>> {
+    combined_header(headers, name).map(|v| v.and_then(|v| parse_list(&v)))
+}
+
+/// Parse all the values of header `name` as a dictionary. Multiple header lines are combined.
+/// `None` if the header isn't present.
+pub fn dictionary_header(headers: &http::HeaderMap, name: &str) -> Option<Result<Dictionary, Error>> {
+    combined_header(headers, name).map(|v| v.and_then(|v| parse_dictionary(&v)))
+}
+
+/// Parse header `name` as an item. `None` if the header isn't present.
+pub fn item_header(headers: &http::HeaderMap, name: &str) -> Option<Result<Item, Error>> {
+    combined_header(headers, name).map(|v| v.and_then(|v| parse_item(&v)))
+}
+
+fn combined_header(headers: &http::HeaderMap, name: &str) -> Option<Result<String, Error>> {
+    let mut values = headers.get_all(name).iter().peekable();
+    values.peek()?;
+    Some(values
+        .map(|v| v.to_str().or(err("non-ASCII header")))
+        .collect::<Result<Vec<_>, _>>()
+        .map(|v| v.join(", ")))
+}
+
+fn insert<V>(map: &mut Vec<(String, V)>, key: String, value: V) {
+    match map.iter_mut().find(|(k, _)| *k == key) {
+        Some(existing) => existing.1 = value,
+        None => map.push((key, value)),
+    }
+}
+
+fn parse_with<T>(input: &str, f: impl FnOnce(&mut Parser) -> Result<T, Error>) -> Result<T, Error> {
+    let mut p = Parser { input: input.trim_matches(' ').as_bytes(), pos: 0 };
+    let result = f(&mut p)?;
+    if !p.at_end() {
+        return err("trailing characters");
+    }
+    Ok(result)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_sp(&mut self) {
+        while self.eat(b' ') {}
+    }
+
+    fn skip_ows(&mut self) {
+        while self.eat(b' ') || self.eat(b'\t') {}
+    }
+
+    // After a list/dictionary member. Returns true at the end of the input.
+    fn next_member(&mut self) -> Result<bool, Error> {
+        self.skip_ows();
+        if self.at_end() {
+            return Ok(true);
+        }
+        if !self.eat(b',') {
+            return err("expected a comma");
+        }
+        self.skip_ows();
+        if self.at_end() {
+            return err("trailing comma");
+        }
+        Ok(false)
+    }
+
+    fn list_entry(&mut self) -> Result<ListEntry, Error> {
+        if self.peek() == Some(b'(') {
+            self.inner_list().map(ListEntry::InnerList)
+        } else {
+            self.item().map(ListEntry::Item)
+        }
+    }
+
+    fn inner_list(&mut self) -> Result<InnerList, Error> {
+        self.eat(b'(');
+        let mut items = Vec::new();
+        loop {
+            self.skip_sp();
+            if self.eat(b')') {
+                return Ok(InnerList { items, params: self.params()? });
+            }
+            items.push(self.item()?);
+            if !matches!(self.peek(), Some(b' ' | b')')) {
+                return err("expected a space or ')' in inner list");
+            }
+        }
+    }
+
+    fn item(&mut self) -> Result<Item, Error> {
+        Ok(Item { bare_item: self.bare_item()?, params: self.params()? })
+    }
+
+    fn params(&mut self) -> Result<Parameters, Error> {
+        let mut params = Vec::new();
+        while self.eat(b';') {
+            self.skip_sp();
+            let key = self.key()?;
+            let value = if self.eat(b'=') { self.bare_item()? } else { BareItem::Boolean(true) };
+            insert(&mut params, key, value);
+        }
+        Ok(params)
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        let start = self.pos;
+        if !matches!(self.peek(), Some(b'a'..=b'z' | b'*')) {
+            return err("invalid key");
+        }
+        while matches!(self.peek(), Some(b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-' | b'.' | b'*')) {
+            self.pos += 1;
+        }
+        Ok(self.slice(start))
+    }
+
+    fn slice(&self, start: usize) -> String {
+        String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()
+    }
+
+    fn bare_item(&mut self) -> Result<BareItem, Error> {
+        match self.peek() {
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(b'"') => self.string(),
+            Some(b'*' | b'a'..=b'z' | b'A'..=b'Z') => self.token(),
+            Some(b':') => self.byte_sequence(),
+            Some(b'?') => self.boolean(),
+            _ => err("invalid item"),
+        }
+    }
+
+    fn number(&mut self) -> Result<BareItem, Error> {
+        let start = self.pos;
+        self.eat(b'-');
+        let digits_start = self.pos;
+        let mut dot = None;
+        while let Some(c) = self.peek() {
+            match c {
+                b'0'..=b'9' => {}
+                b'.' if dot.is_none() => {
+                    if self.pos - digits_start > 12 {
+                        return err("decimal too long");
+                    }
+                    dot = Some(self.pos);
+                }
+                _ => break,
+            }
+            self.pos += 1;
+            if dot.is_none() && self.pos - digits_start > 15 {
+                return err("integer too long");
+            }
+        }
+        let text = self.slice(start);
+        match dot {
+            None if self.pos == digits_start => err("expected a digit"),
+            None => text.parse().map(BareItem::Integer).or(err("invalid integer")),
+            Some(dot) => {
+                let fraction = self.pos - dot - 1;
+                if fraction == 0 || fraction > 3 {
+                    return err("invalid decimal");
+                }
+                text.parse().map(BareItem::Decimal).or(err("invalid decimal"))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<BareItem, Error> {
+        self.eat(b'"');
+        let mut out = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return err("unterminated string"),
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(BareItem::String(out)),
+                b'\\' => match self.peek() {
+                    Some(c @ (b'"' | b'\\')) => {
+                        self.pos += 1;
+                        out.push(c as char);
+                    }
+                    _ => return err("invalid escape in string"),
+                },
+                0x20..=0x7E => out.push(c as char),
+                _ => return err("invalid character in string"),
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<BareItem, Error> {
+        let start = self.pos;
+        self.pos += 1;
+        while self.peek().is_some_and(|c| is_tchar(c) || c == b':' || c == b'/') {
+            self.pos += 1;
+        }
+        Ok(BareItem::Token(self.slice(start)))
+    }
+
+    fn byte_sequence(&mut self) -> Result<BareItem, Error> {
+        self.eat(b':');
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == b'+' || c == b'/' || c == b'=') {
+            self.pos += 1;
+        }
+        let encoded = self.slice(start);
+        if !self.eat(b':') {
+            return err("unterminated byte sequence");
+        }
+        base64::decode(&encoded).map(BareItem::ByteSequence).ok_or(Error { message: "invalid base64" })
+    }
+
+    fn boolean(&mut self) -> Result<BareItem, Error> {
+        self.eat(b'?');
+        if self.eat(b'1') {
+            Ok(BareItem::Boolean(true))
+        } else if self.eat(b'0') {
+            Ok(BareItem::Boolean(false))
+        } else {
+            err("invalid boolean")
+        }
+    }
+}
+
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+/// Serialize an item
+pub fn serialize_item(item: &Item) -> Result<String, Error> {
+    let mut out = String::new();
+    write_item(&mut out, item)?;
+    Ok(out)
+}
+
+/// Serialize a list
+pub fn serialize_list(list: &List) -> Result<String, Error> {
+    let mut out = String::new();
+    for (i, entry) in list.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_entry(&mut out, entry)?;
+    }
+    Ok(out)
+}
+
+/// Serialize a dictionary
+pub fn serialize_dictionary(dict: &Dictionary) -> Result<String, Error> {
+    let mut out = String::new();
+    for (i, (key, entry)) in dict.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_key(&mut out, key)?;
+        match entry {
+            ListEntry::Item(Item { bare_item: BareItem::Boolean(true), params }) => write_params(&mut out, params)?,
+            entry => {
+                out.push('=');
+                write_entry(&mut out, entry)?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Serialize an inner list on its own, e.g. for the `@signature-params` of RFC 9421
+pub fn serialize_inner_list(list: &InnerList) -> Result<String, Error> {
+    let mut out = String::new();
+    write_entry(&mut out, &ListEntry::InnerList(list.clone()))?;
+    Ok(out)
+}
+
+fn write_entry(out: &mut String, entry: &ListEntry) -> Result<(), Error> {
+    match entry {
+        ListEntry::Item(item) => write_item(out, item),
+        ListEntry::InnerList(list) => {
+            out.push('(');
+            for (i, item) in list.items.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_item(out, item)?;
+            }
+            out.push(')');
+            write_params(out, &list.params)
+        }
+    }
+}
+
+fn write_item(out: &mut String, item: &Item) -> Result<(), Error> {
+    write_bare_item(out, &item.bare_item)?;
+    write_params(out, &item.params)
+}
+
+fn write_params(out: &mut String, params: &Parameters) -> Result<(), Error> {
+    for (key, value) in params {
+        out.push(';');
+        write_key(out, key)?;
+        if *value != BareItem::Boolean(true) {
+            out.push('=');
+            write_bare_item(out, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_key(out: &mut String, key: &str) -> Result<(), Error> {
+    let valid = key.bytes().next().is_some_and(|c| c.is_ascii_lowercase() || c == b'*')
+        && key.bytes().all(|c| matches!(c, b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-' | b'.' | b'*'));
+    if !valid {
+        return err("invalid key");
+    }
+    out.push_str(key);
+    Ok(())
+}
+
+fn write_bare_item(out: &mut String, item: &BareItem) -> Result<(), Error> {
+    match item {
+        BareItem::Integer(i) => {
+            if i.abs() > 999_999_999_999_999 {
+                return err("integer out of range");
+            }
+            out.push_str(&i.to_string());
+        }
+        BareItem::Decimal(d) => {
+            let rounded = (d * 1000.0).round_ties_even() / 1000.0;
+            if !rounded.is_finite() || rounded.abs() >= 1_000_000_000_000.0 {
+                return err("decimal out of range");
+            }
+            let mut s = format!("{:.3}", rounded);
+            while s.ends_with('0') && !s.ends_with(".0") {
+                s.pop();
+            }
+            out.push_str(&s);
+        }
+        BareItem::String(s) => {
+            out.push('"');
+            for c in s.chars() {
+                if !(' '..='~').contains(&c) {
+                    return err("invalid character in string");
+                }
+                if c == '"' || c == '\\' {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out.push('"');
+        }
+        BareItem::Token(t) => {
+            let valid = t.bytes().next().is_some_and(|c| c.is_ascii_alphabetic() || c == b'*')
+                && t.bytes().all(|c| is_tchar(c) || c == b':' || c == b'/');
+            if !valid {
+                return err("invalid token");
+            }
+            out.push_str(t);
+        }
+        BareItem::ByteSequence(b) => {
+            out.push(':');
+            out.push_str(&base64::encode(b));
+            out.push(':');
+        }
+        BareItem::Boolean(b) => out.push_str(if *b { "?1" } else { "?0" }),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_items() {
+        assert_eq!(parse_item("42").unwrap(), Item::new(BareItem::Integer(42)));
+        assert_eq!(parse_item("-4.5").unwrap(), Item::new(BareItem::Decimal(-4.5)));
+        assert_eq!(parse_item("\"he said \\\"hi\\\"\"").unwrap(), Item::new(BareItem::String("he said \"hi\"".into())));
+        assert_eq!(parse_item("text/html;q=1;x").unwrap(), Item {
+            bare_item: BareItem::Token("text/html".into()),
+            params: vec![("q".into(), BareItem::Integer(1)), ("x".into(), BareItem::Boolean(true))],
+        });
+        assert_eq!(parse_item(":aGVsbG8=:").unwrap(), Item::new(BareItem::ByteSequence(b"hello".to_vec())));
+        assert_eq!(parse_item("?0").unwrap(), Item::new(BareItem::Boolean(false)));
+
+        assert!(parse_item("1.2345").is_err());
+        assert!(parse_item("1234567890123456").is_err());
+        assert!(parse_item("\"unterminated").is_err());
+        assert!(parse_item("a b").is_err());
+    }
+
+    #[test]
+    fn test_list_and_dictionary() {
+        let list = parse_list("sugar, tea, (rum \"gin\");q=1").unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(serialize_list(&list).unwrap(), "sugar, tea, (rum \"gin\");q=1");
+        assert!(parse_list("a,").is_err());
+        assert_eq!(parse_list("").unwrap(), vec![]);
+
+        let dict = parse_dictionary("a=?0, b, c; foo=bar, a=2").unwrap();
+        assert_eq!(dict.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(dict[0].1, ListEntry::from(BareItem::Integer(2)));
+        assert_eq!(serialize_dictionary(&dict).unwrap(), "a=2, b, c;foo=bar");
+    }
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(serialize_item(&Item::new(BareItem::Decimal(1.0))).unwrap(), "1.0");
+        assert_eq!(serialize_item(&Item::new(BareItem::Decimal(0.12345))).unwrap(), "0.123");
+        assert!(serialize_item(&Item::new(BareItem::Token("1abc".into()))).is_err());
+        assert!(serialize_item(&Item::new(BareItem::String("naïve".into()))).is_err());
+
+        let mut headers = http::HeaderMap::new();
+        headers.append("cache-status", "ExampleCache; hit".parse().unwrap());
+        headers.append("cache-status", "OtherCache; fwd=uri-miss".parse().unwrap());
+        let list = list_header(&headers, "cache-status").unwrap().unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list_header(&headers, "priority").is_none());
+    }
+}
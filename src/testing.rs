@@ -0,0 +1,196 @@
+//! Test handlers without a web server.
+//!
+//! [`CgiRequestBuilder`] sets the CGI meta-variables a web server would (`SCRIPT_NAME`,
+//! `PATH_INFO`, `QUERY_STRING`, `CONTENT_TYPE`, `CONTENT_LENGTH`, `HTTP_*`, ...) and turns them
+//! into a [`Request`] the same way [`handle`](crate::handle) does. [`run_handler`] calls a
+//! handler, writes its response in the CGI output format and parses that again, so the test
+//! sees what the web server would.
+//!
+//! ```rust
+//! use cgi::testing::CgiRequestBuilder;
+//!
+//! fn handler(request: cgi::Request) -> cgi::Response {
+//!     let name = request.uri().query().unwrap_or("world").to_owned();
+//!     cgi::text_response(200, format!("Hello {}", name))
+//! }
+//!
+//! let response = CgiRequestBuilder::new().path_info("/greet").query("you").run(handler);
+//! assert_eq!(response.status(), 200);
+//! assert_eq!(response.body(), b"Hello you");
+//! ```
+//!
+//! With [`#[cgi::test]`](crate::test), a test function gets a fresh builder as its argument:
+//!
+//! ```rust,ignore
+//! #[cgi::test]
+//! fn greets(request: cgi::testing::CgiRequestBuilder) {
+//!     assert_eq!(request.query("you").run(handler).body(), b"Hello you");
+//! }
+//! ```
+//!
+//! With the `tokio` or `pollster` feature, the test function can be `async`, and is run with
+//! [`block_on`].
+
+use std::collections::HashMap;
+
+use crate::{call_handler, client, parse_request, run_after_response, serialize_response, Request, Response};
+
+/// Builds a [`Request`] from CGI meta-variables. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct CgiRequestBuilder {
+    env_vars: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Default for CgiRequestBuilder {
+    fn default() -> Self {
+        CgiRequestBuilder::new()
+    }
+}
+
+impl CgiRequestBuilder {
+    /// A `GET` request for the script `/cgi-bin/test` on `localhost`, from `127.0.0.1`
+    pub fn new() -> Self {
+        let env_vars = [
+            ("GATEWAY_INTERFACE", "CGI/1.1"),
+            ("REQUEST_METHOD", "GET"),
+            ("SCRIPT_NAME", "/cgi-bin/test"),
+            ("QUERY_STRING", ""),
+            ("SERVER_PROTOCOL", "HTTP/1.1"),
+            ("SERVER_NAME", "localhost"),
+            ("SERVER_PORT", "80"),
+            ("SERVER_SOFTWARE", "cgi-testing"),
+            ("REMOTE_ADDR", "127.0.0.1"),
+            ("REQUEST_SCHEME", "http"),
+            ("HTTP_HOST", "localhost"),
+        ];
+        CgiRequestBuilder {
+            env_vars: env_vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Set any meta-variable, e.g. `REMOTE_USER` or `HTTPS`
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env_vars.insert(name.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn method(self, method: &str) -> Self {
+        self.env("REQUEST_METHOD", method)
+    }
+
+    /// The path of the script itself
+    pub fn script_name(self, script_name: &str) -> Self {
+        self.env("SCRIPT_NAME", script_name)
+    }
+
+    /// The rest of the path, after the script name
+    pub fn path_info(self, path_info: &str) -> Self {
+        self.env("PATH_INFO", path_info)
+    }
+
+    /// The query string, without the `?`
+    pub fn query(self, query: &str) -> Self {
+        self.env("QUERY_STRING", query)
+    }
+
+    /// A request header, set as the `HTTP_` variable the web server would use
+    pub fn header(self, name: &str, value: &str) -> Self {
+        let var = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        self.env(&var, value)
+    }
+
+    /// The request body, setting `CONTENT_TYPE` & `CONTENT_LENGTH`
+    pub fn body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        let length = self.body.len().to_string();
+        self.env("CONTENT_TYPE", content_type).env("CONTENT_LENGTH", &length)
+    }
+
+    /// The meta-variables set so far
+    pub fn env_vars(&self) -> &HashMap<String, String> {
+        &self.env_vars
+    }
+
+    /// The request, as [`handle`](crate::handle) would pass it to the handler
+    pub fn build(self) -> Request {
+        parse_request(self.env_vars, self.body)
+    }
+
+    /// Build the request and [`run_handler`] with it
+    pub fn run<F>(self, handler: F) -> Response
+        where F: FnOnce(Request) -> Response
+    {
+        run_handler(self.build(), handler)
+    }
+}
+
+/// Call `handler`, and return its response as the web server would read it: serialized to
+/// the CGI output format (with a streaming body written out), and parsed again. Functions
+/// registered with [`after_response`](crate::after_response) are run afterwards.
+pub fn run_handler<F>(request: Request, handler: F) -> Response
+    where F: FnOnce(Request) -> Response
+{
+    let mut response = call_handler(request, handler);
+    crate::stream::buffer_body(&mut response);
+    let output = serialize_response(response);
+    run_after_response();
+    client::parse_output(&output).expect("the response can't be parsed as CGI output")
+}
+
+/// Run a future to completion, the same way [`handle_async`](crate::handle_async) does. An
+/// `async` [`#[cgi::test]`](crate::test) is run with this. Requires the `tokio` or `pollster`
+/// feature.
+#[cfg(any(feature = "tokio", feature = "pollster"))]
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    crate::block_on(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_builder() {
+        let request = CgiRequestBuilder::new()
+            .method("POST")
+            .script_name("/app.cgi")
+            .path_info("/users/1")
+            .query("x=1")
+            .header("Accept-Language", "de")
+            .body("application/json", "{}")
+            .build();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.uri(), "http://localhost/app.cgi/users/1?x=1");
+        assert_eq!(request.headers()["accept-language"], "de");
+        assert_eq!(request.headers()["x-cgi-content-type"], "application/json");
+        assert_eq!(request.headers()["content-type"], "application/json");
+        assert_eq!(request.headers()["x-cgi-content-length"], "2");
+        assert_eq!(request.headers()["x-cgi-remote-addr"], "127.0.0.1");
+        assert_eq!(request.body(), b"{}");
+
+        let response = CgiRequestBuilder::new().run(|_| {
+            let mut response = crate::html_response(404, "<p>Not here</p>");
+            response.headers_mut().append("set-cookie", "a=1".parse().unwrap());
+            response
+        });
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+        assert_eq!(response.body(), b"<p>Not here</p>");
+    }
+
+    #[crate::test]
+    fn test_attribute(request: CgiRequestBuilder) -> Result<(), String> {
+        let response = request.path_info("/x").run(|request| crate::text_response(200, request.uri().to_string()));
+        assert_eq!(response.body(), b"http://localhost/cgi-bin/test/x");
+        Ok(())
+    }
+
+    #[cfg(any(feature = "tokio", feature = "pollster"))]
+    #[crate::test]
+    async fn test_async_attribute(request: CgiRequestBuilder) {
+        let greeting = async { "Hello" }.await;
+        assert_eq!(request.run(|_| crate::text_response(200, greeting)).body(), b"Hello");
+    }
+}
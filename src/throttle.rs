@@ -0,0 +1,110 @@
+//! Limit the bandwidth used to send a response.
+//!
+//! On a shared host, a few large downloads can saturate the uplink for every other site.
+//! Attach a [`Throttle`] to a response, and [`handle`](crate::handle) paces the output to that
+//! rate, allowing a burst at the start. [`ThrottledWriter`] does the same for any writer.
+//!
+//! ```rust,no_run
+//! use cgi::throttle::Throttle;
+//!
+//! cgi::handle(|request: cgi::Request| {
+//!     let mut response = cgi::binary_response(200, "application/zip", std::fs::read("/srv/big.zip").unwrap());
+//!     // 1 MB/s, after the first 4 MB
+//!     response.extensions_mut().insert(Throttle::new(1_000_000).burst(4_000_000));
+//!     response
+//! })
+//! ```
+
+use std::io::{self, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A rate limit, in bytes per second, with a burst allowance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    burst: u64,
+}
+
+impl Throttle {
+    /// The burst defaults to one second's worth
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second.max(1);
+        Throttle { bytes_per_second, burst: bytes_per_second }
+    }
+
+    /// How many bytes can be sent at once, before the rate applies
+    pub fn burst(mut self, bytes: u64) -> Self {
+        self.burst = bytes.max(1);
+        self
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+}
+
+/// Wraps a writer, pacing writes with a token bucket.
+pub struct ThrottledWriter<W> {
+    inner: W,
+    throttle: Throttle,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl<W: Write> ThrottledWriter<W> {
+    /// The bucket starts full, so the first `burst` bytes are written straight away
+    pub fn new(inner: W, throttle: Throttle) -> Self {
+        ThrottledWriter { inner, throttle, tokens: throttle.burst as f64, refilled: Instant::now() }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.throttle.bytes_per_second as f64;
+        self.tokens = (self.tokens + earned).min(self.throttle.burst as f64);
+        self.refilled = now;
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Larger writes are split, so bytes go out steadily instead of after one long sleep
+        let chunk = buf.len().min(self.throttle.burst as usize).min(64 * 1024);
+        self.refill();
+        if self.tokens < chunk as f64 {
+            let missing = chunk as f64 - self.tokens;
+            sleep(Duration::from_secs_f64(missing / self.throttle.bytes_per_second as f64));
+            self.refill();
+        }
+        let written = self.inner.write(&buf[..chunk])?;
+        self.tokens -= written as f64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttled_writer() {
+        let start = Instant::now();
+        let mut writer = ThrottledWriter::new(Vec::new(), Throttle::new(20_000).burst(5_000));
+        writer.write_all(&[1; 5_000]).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+        writer.write_all(&[2; 5_000]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(240), "{:?}", start.elapsed());
+        assert_eq!(writer.into_inner().len(), 10_000);
+    }
+}
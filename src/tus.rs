@@ -0,0 +1,323 @@
+//! Resumable uploads with the [tus 1.0](https://tus.io/protocols/resumable-upload) protocol.
+//!
+//! Requires the `tus` feature. Implements the core protocol, and the `creation` &
+//! `termination` extensions. Uploads are stored in a directory: `<id>` holds the data received
+//! so far, and `<id>.info` the declared length and metadata.
+//!
+//! The CGI programme itself is the creation URL (e.g. `/cgi-bin/upload`), and each upload is
+//! a `PATH_INFO` below it (e.g. `/cgi-bin/upload/3f2a…`).
+//!
+//! ```rust,no_run
+//! use cgi::tus::TusServer;
+//!
+//! fn main() {
+//!     let server = TusServer::new("/var/lib/uploads").max_size(1 << 30);
+//!     cgi::handle(|request: cgi::Request| server.handle(request))
+//! }
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::meta::CgiMeta;
+use crate::{base64, empty_response, random, Request, Response};
+
+const TUS_VERSION: &str = "1.0.0";
+
+/// A tus upload endpoint, storing uploads in a directory.
+#[derive(Debug, Clone)]
+pub struct TusServer {
+    dir: PathBuf,
+    max_size: Option<u64>,
+}
+
+/// An upload, as stored on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    pub id: String,
+    /// How many bytes have been received
+    pub offset: u64,
+    /// The total size of the upload
+    pub length: u64,
+    /// Decoded `Upload-Metadata`, e.g. `("filename", Some(b"cat.jpg"))`
+    pub metadata: Vec<(String, Option<Vec<u8>>)>,
+    /// Where the data is stored
+    pub path: PathBuf,
+}
+
+impl Upload {
+    /// Whether all the data has been received
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length
+    }
+}
+
+impl TusServer {
+    /// Uploads are stored in `dir`, which is created if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TusServer { dir: dir.into(), max_size: None }
+    }
+
+    /// Reject uploads larger than this many bytes
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Look up an upload by ID
+    pub fn upload(&self, id: &str) -> io::Result<Option<Upload>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        let info = match fs::read_to_string(self.info_path(id)) {
+            Ok(info) => info,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut length = None;
+        let mut metadata = Vec::new();
+        for line in info.lines() {
+            match line.split_once('=') {
+                Some(("length", v)) => length = v.parse().ok(),
+                Some(("metadata", v)) => metadata = parse_metadata(v).unwrap_or_default(),
+                _ => {}
+            }
+        }
+        let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt upload info"))?;
+        let path = self.dir.join(id);
+        let offset = fs::metadata(&path)?.len();
+        Ok(Some(Upload { id: id.to_owned(), offset, length, metadata, path }))
+    }
+
+    /// Handle a tus request
+    pub fn handle(&self, request: Request) -> Response {
+        match self.try_handle(request) {
+            Ok(response) => response,
+            Err(err) => {
+                eprintln!("tus upload error in {:?}: {}", self.dir, err);
+                tus_response(500)
+            }
+        }
+    }
+
+    fn try_handle(&self, request: Request) -> io::Result<Response> {
+        let header = |name: &str| request.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.trim());
+
+        if request.method() == http::Method::OPTIONS {
+            let mut response = tus_response(204);
+            let headers = response.headers_mut();
+            headers.insert("tus-version", TUS_VERSION.parse().unwrap());
+            headers.insert("tus-extension", "creation,termination".parse().unwrap());
+            if let Some(max) = self.max_size {
+                headers.insert("tus-max-size", max.into());
+            }
+            return Ok(response);
+        }
+
+        if header("tus-resumable") != Some(TUS_VERSION) {
+            let mut response = tus_response(412);
+            response.headers_mut().insert("tus-version", TUS_VERSION.parse().unwrap());
+            return Ok(response);
+        }
+
+        let meta = request.extensions().get::<CgiMeta>();
+        let id = meta.map_or("", |meta| meta.path_info.as_str()).trim_matches('/').to_owned();
+        let method = request.method().clone();
+        if id.is_empty() {
+            return match method {
+                http::Method::POST => self.create(&request),
+                _ => Ok(method_not_allowed("OPTIONS, POST")),
+            };
+        }
+
+        let upload = match self.upload(&id)? {
+            Some(upload) => upload,
+            None => return Ok(tus_response(404)),
+        };
+        match method {
+            http::Method::HEAD => {
+                let mut response = tus_response(200);
+                let headers = response.headers_mut();
+                headers.insert("upload-offset", upload.offset.into());
+                headers.insert("upload-length", upload.length.into());
+                headers.insert(http::header::CACHE_CONTROL, "no-store".parse().unwrap());
+                if let Ok(info) = fs::read_to_string(self.info_path(&id)) {
+                    // It came from the client's `POST`, which may have been an invalid header value
+                    let metadata = info.lines().find_map(|l| l.strip_prefix("metadata="))
+                        .and_then(|metadata| http::HeaderValue::from_str(metadata).ok());
+                    if let Some(metadata) = metadata {
+                        headers.insert("upload-metadata", metadata);
+                    }
+                }
+                Ok(response)
+            }
+            http::Method::PATCH => self.patch(&request, upload),
+            http::Method::DELETE => {
+                fs::remove_file(&upload.path)?;
+                fs::remove_file(self.info_path(&id))?;
+                Ok(tus_response(204))
+            }
+            _ => Ok(method_not_allowed("HEAD, PATCH, DELETE")),
+        }
+    }
+
+    fn create(&self, request: &Request) -> io::Result<Response> {
+        let headers = request.headers();
+        let length: u64 = match headers.get("upload-length").and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse().ok()) {
+            Some(length) => length,
+            // includes Upload-Defer-Length, which we don't support
+            None => return Ok(tus_response(400)),
+        };
+        if self.max_size.is_some_and(|max| length > max) {
+            return Ok(tus_response(413));
+        }
+        let metadata = headers.get("upload-metadata").and_then(|v| v.to_str().ok()).unwrap_or("").trim();
+        if parse_metadata(metadata).is_none() {
+            return Ok(tus_response(400));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let id = random::hex(16);
+        File::create(self.dir.join(&id))?;
+        fs::write(self.info_path(&id), format!("length={}\nmetadata={}\n", length, metadata))?;
+
+        let script_name = request.extensions().get::<CgiMeta>().map_or("", |meta| meta.script_name.as_str());
+        let location = format!("{}/{}", crate::encode_uri_part(script_name.as_bytes(), b"%?#"), id);
+        let mut response = tus_response(201);
+        response.headers_mut().insert(http::header::LOCATION, location.parse().expect("an encoded URL is a valid header value"));
+        Ok(response)
+    }
+
+    fn patch(&self, request: &Request, upload: Upload) -> io::Result<Response> {
+        let headers = request.headers();
+        if headers.get(http::header::CONTENT_TYPE).is_none_or(|v| v != "application/offset+octet-stream") {
+            return Ok(tus_response(415));
+        }
+        let offset: u64 = match headers.get("upload-offset").and_then(|v| v.to_str().ok()).and_then(|v| v.trim().parse().ok()) {
+            Some(offset) => offset,
+            None => return Ok(tus_response(400)),
+        };
+
+        let mut file = OpenOptions::new().append(true).open(&upload.path)?;
+        // Another request might be appending to the same upload
+        file.lock()?;
+        let current = file.metadata()?.len();
+        if offset != current {
+            return Ok(tus_response(409));
+        }
+        let body = request.body();
+        if current + body.len() as u64 > upload.length {
+            return Ok(tus_response(413));
+        }
+        file.write_all(body)?;
+
+        let mut response = tus_response(204);
+        response.headers_mut().insert("upload-offset", (current + body.len() as u64).into());
+        Ok(response)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.info", id))
+    }
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn tus_response(status: u16) -> Response {
+    let mut response = empty_response(status);
+    response.headers_mut().insert("tus-resumable", TUS_VERSION.parse().unwrap());
+    response
+}
+
+fn method_not_allowed(allow: &'static str) -> Response {
+    let mut response = tus_response(405);
+    response.headers_mut().insert(http::header::ALLOW, allow.parse().unwrap());
+    response
+}
+
+// `filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential`
+fn parse_metadata(value: &str) -> Option<Vec<(String, Option<Vec<u8>>)>> {
+    value.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let mut parts = pair.split_whitespace();
+            let key = parts.next()?.to_owned();
+            let value = match parts.next() {
+                Some(v) => Some(base64::decode(v)?),
+                None => None,
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path_info: &str, headers: &[(&str, &str)], body: &[u8]) -> Request {
+        let mut req = http::Request::builder()
+            .method(method)
+            .header("tus-resumable", "1.0.0")
+            // Not from the client
+            .header("x-cgi-path-info", "/spoofed");
+        for (k, v) in headers {
+            req = req.header(*k, *v);
+        }
+        let meta = CgiMeta { script_name: "/cgi-bin/upload".to_owned(), path_info: path_info.to_owned(), ..CgiMeta::default() };
+        req.extension(meta).body(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_upload() {
+        let dir = std::env::temp_dir().join(format!("cgi-tus-{}", std::process::id()));
+        let server = TusServer::new(&dir).max_size(100);
+
+        let resp = server.handle(request("POST", "", &[("upload-length", "1000")], b""));
+        assert_eq!(resp.status(), 413);
+
+        let resp = server.handle(request("POST", "", &[("upload-length", "11"), ("upload-metadata", "filename aGVsbG8udHh0,secret")], b""));
+        assert_eq!(resp.status(), 201);
+        let location = resp.headers()["location"].to_str().unwrap();
+        let id = location.strip_prefix("/cgi-bin/upload/").unwrap().to_owned();
+        let path = format!("/{}", id);
+
+        let patch = |offset: &str, body: &[u8]| server.handle(request("PATCH", &path, &[
+            ("content-type", "application/offset+octet-stream"), ("upload-offset", offset)], body));
+        let resp = patch("0", b"hello ");
+        assert_eq!(resp.status(), 204);
+        assert_eq!(resp.headers()["upload-offset"], "6");
+        assert_eq!(patch("0", b"hello ").status(), 409);
+
+        let resp = server.handle(request("HEAD", &path, &[], b""));
+        assert_eq!(resp.headers()["upload-offset"], "6");
+        assert_eq!(resp.headers()["upload-length"], "11");
+
+        assert_eq!(patch("6", b"world").status(), 204);
+        let upload = server.upload(&id).unwrap().unwrap();
+        assert!(upload.is_complete());
+        assert_eq!(upload.metadata, vec![("filename".to_owned(), Some(b"hello.txt".to_vec())), ("secret".to_owned(), None)]);
+        assert_eq!(fs::read(&upload.path).unwrap(), b"hello world");
+
+        assert_eq!(server.handle(request("DELETE", &path, &[], b"")).status(), 204);
+        assert_eq!(server.handle(request("HEAD", &path, &[], b"")).status(), 404);
+        assert_eq!(server.handle(request("HEAD", "/../etc", &[], b"")).status(), 404);
+
+        // Metadata which isn't a valid header value any more
+        let resp = server.handle(request("POST", "", &[("upload-length", "1")], b""));
+        let id = resp.headers()["location"].to_str().unwrap().rsplit('/').next().unwrap().to_owned();
+        fs::write(server.info_path(&id), "length=1\nmetadata=bad\x7fvalue\n").unwrap();
+        let resp = server.handle(request("HEAD", &format!("/{}", id), &[], b""));
+        assert_eq!(resp.status(), 200);
+        assert!(!resp.headers().contains_key("upload-metadata"));
+
+        let mut old_client = request("HEAD", &path, &[], b"");
+        old_client.headers_mut().remove("tus-resumable");
+        assert_eq!(server.handle(old_client).status(), 412);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
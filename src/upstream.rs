@@ -0,0 +1,235 @@
+//! Retries and circuit breaking for calls to upstream services.
+//!
+//! [`Upstream`] wraps a call to another service (e.g. a CGI programme with
+//! [`client::run_cgi`](crate::client::run_cgi)), retrying failures with exponential backoff.
+//! When an upstream keeps failing, its circuit opens and further calls fail straight away for
+//! a while, instead of piling up waiting processes. As every request is a new process, the
+//! circuit state is kept in a [`FileStore`], shared by all of them.
+//!
+//! Errors become gateway responses: `504 Gateway Timeout` if the upstream timed out, `502 Bad
+//! Gateway` otherwise, and `503 Service Unavailable` while the circuit is open.
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use cgi::store::FileStore;
+//! use cgi::upstream::Upstream;
+//!
+//! fn main() {
+//!     let legacy = Upstream::new("legacy", FileStore::new("/tmp/my-cgi-store"))
+//!         .retries(2)
+//!         .failure_threshold(5)
+//!         .open_for(Duration::from_secs(30));
+//!     cgi::handle(|request: cgi::Request| legacy.run_cgi("/usr/lib/cgi-bin/legacy.pl", &request))
+//! }
+//! ```
+
+use std::io;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::store::FileStore;
+use crate::{client, empty_response, random, text_response, Request, Response};
+
+/// An upstream service with a retry policy & a circuit breaker. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    name: String,
+    store: FileStore,
+    retries: u32,
+    backoff: Duration,
+    failure_threshold: u32,
+    open_for: Duration,
+}
+
+impl Upstream {
+    /// `name` identifies the upstream's circuit in `store`. By default failures are retried
+    /// once after 100ms, and the circuit opens for 30 seconds after 5 failures in a row.
+    pub fn new(name: &str, store: FileStore) -> Self {
+        Upstream {
+            name: name.to_owned(),
+            store,
+            retries: 1,
+            backoff: Duration::from_millis(100),
+            failure_threshold: 5,
+            open_for: Duration::from_secs(30),
+        }
+    }
+
+    /// How many times to retry a failed call
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// The longest wait before the first retry, doubled for each further one. The actual wait
+    /// is random, between zero and that.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// How many failures in a row open the circuit
+    pub fn failure_threshold(mut self, failures: u32) -> Self {
+        self.failure_threshold = failures.max(1);
+        self
+    }
+
+    /// How long an open circuit fails calls before letting them through again
+    pub fn open_for(mut self, duration: Duration) -> Self {
+        self.open_for = duration;
+        self
+    }
+
+    /// Make a call through the circuit breaker, retrying failures: errors, and `502`, `503` &
+    /// `504` responses. Returns the upstream's response, or a gateway error.
+    ///
+    /// Only use retries for calls which are safe to repeat.
+    pub fn call<F>(&self, mut f: F) -> Response
+        where F: FnMut() -> io::Result<Response>
+    {
+        let mut attempt = 0;
+        loop {
+            if let Some(retry_after) = self.open_remaining() {
+                let mut response = text_response(503, "Service Unavailable");
+                response.headers_mut().insert(http::header::RETRY_AFTER, (retry_after.as_millis() as u64).div_ceil(1000).into());
+                return response;
+            }
+
+            let result = f();
+            let failed = match &result {
+                Ok(response) => is_gateway_error(response.status()),
+                Err(_) => true,
+            };
+            self.record(!failed);
+            if !failed || attempt >= self.retries {
+                return match result {
+                    Ok(response) => response,
+                    Err(err) => {
+                        eprintln!("Upstream {} failed: {}", self.name, err);
+                        let timed_out = matches!(err.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock);
+                        empty_response(if timed_out { 504 } else { 502 })
+                    }
+                };
+            }
+
+            // Full jitter, so retrying processes don't all come back at once
+            let delay = self.backoff.saturating_mul(1 << attempt.min(16));
+            let random = random::bytes(2);
+            let jitter = f64::from(u16::from_le_bytes([random[0], random[1]])) / f64::from(u16::MAX);
+            sleep(delay.mul_f64(jitter));
+            attempt += 1;
+        }
+    }
+
+    /// Run the CGI programme at `path` through [`call`](Self::call). Requests with
+    /// non-idempotent methods (e.g. `POST`) aren't retried.
+    pub fn run_cgi(&self, path: impl AsRef<Path>, request: &Request) -> Response {
+        let upstream = if request.method().is_idempotent() { self.clone() } else { self.clone().retries(0) };
+        upstream.call(|| client::run_cgi(path.as_ref(), request))
+    }
+
+    /// Whether the circuit is open, i.e. calls currently fail without being tried
+    pub fn is_open(&self) -> bool {
+        self.open_remaining().is_some()
+    }
+
+    fn key(&self) -> String {
+        format!("circuit {}", self.name)
+    }
+
+    // The circuit state is `<failures in a row> <open until, in UNIX milliseconds>`
+    fn state(value: Option<&[u8]>) -> (u32, u128) {
+        let value = value.map(String::from_utf8_lossy).unwrap_or_default();
+        let mut parts = value.split(' ').map(|p| p.parse::<u128>().unwrap_or(0));
+        (parts.next().unwrap_or(0) as u32, parts.next().unwrap_or(0))
+    }
+
+    fn open_remaining(&self) -> Option<Duration> {
+        let value = match self.store.get(&self.key()) {
+            Ok(value) => value,
+            Err(err) => {
+                eprintln!("Could not read circuit of {}: {}", self.name, err);
+                return None;
+            }
+        };
+        let (_, open_until) = Upstream::state(value.as_deref());
+        let now = now_millis();
+        (open_until > now).then(|| Duration::from_millis((open_until - now) as u64))
+    }
+
+    fn record(&self, success: bool) {
+        let result = self.store.update(&self.key(), None, |value| {
+            let (failures, open_until) = Upstream::state(value.as_deref());
+            if success {
+                return b"0 0".to_vec();
+            }
+            let failures = failures.saturating_add(1);
+            let open_until = if failures >= self.failure_threshold {
+                now_millis() + self.open_for.as_millis()
+            } else {
+                open_until
+            };
+            format!("{} {}", failures, open_until).into_bytes()
+        });
+        if let Err(err) = result {
+            eprintln!("Could not update circuit of {}: {}", self.name, err);
+        }
+    }
+}
+
+fn is_gateway_error(status: http::StatusCode) -> bool {
+    matches!(status.as_u16(), 502..=504)
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_upstream() {
+        let dir = std::env::temp_dir().join(format!("cgi-upstream-{}", std::process::id()));
+        let upstream = Upstream::new("test", FileStore::new(&dir))
+            .retries(2)
+            .backoff(Duration::from_millis(1))
+            .failure_threshold(4);
+
+        // Fails once, then works
+        let calls = Cell::new(0);
+        let resp = upstream.call(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 { Ok(empty_response(503)) } else { Ok(text_response(200, "ok")) }
+        });
+        assert_eq!(resp.status(), 200);
+        assert_eq!(calls.get(), 2);
+
+        let timeout = upstream.call(|| Err(io::Error::from(io::ErrorKind::TimedOut)));
+        assert_eq!(timeout.status(), 504);
+        assert!(!upstream.is_open());
+
+        // The 4th failure in a row opens the circuit
+        calls.set(0);
+        let resp = upstream.call(|| {
+            calls.set(calls.get() + 1);
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        });
+        assert_eq!(resp.status(), 503);
+        assert_eq!(calls.get(), 1);
+        assert!(upstream.is_open());
+        assert_eq!(upstream.call(|| panic!("circuit is open")).headers()["retry-after"], "30");
+
+        // Closed again once it works
+        let upstream = upstream.open_for(Duration::ZERO).failure_threshold(100);
+        upstream.store.delete(&upstream.key()).unwrap();
+        assert_eq!(upstream.call(|| Err(io::Error::other("broken"))).status(), 502);
+        assert_eq!(upstream.call(|| Ok(empty_response(200))).status(), 200);
+        assert_eq!(Upstream::state(upstream.store.get(&upstream.key()).unwrap().as_deref()), (0, 0));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+// `application/x-www-form-urlencoded` decoding & encoding, for query strings and form bodies
+
+/// Decode `%XX` escapes, and `+` as a space if `plus_as_space`. Invalid escapes are left as
+/// they are, and invalid UTF-8 is replaced.
+pub(crate) fn percent_decode(input: &str, plus_as_space: bool) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        out.push(h << 4 | l);
+                        i += 3;
+                        continue;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Split `a=1&b=2` into decoded pairs. A key without `=` has an empty value.
+pub(crate) fn parse(input: &str) -> Vec<(String, String)> {
+    input.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key, true), percent_decode(value, true))
+        })
+        .collect()
+}
+
+/// `%XX` escape every byte except ASCII letters, digits, `-._~` and those in `keep`
+pub(crate) fn percent_encode(input: &str, keep: &[u8]) -> String {
+    let mut out = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || keep.contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Join pairs into `a=1&b=2`, the reverse of [`parse`]
+pub(crate) fn serialize(pairs: &[(String, String)]) -> String {
+    pairs.iter()
+        .map(|(key, value)| format!("{}={}", percent_encode(key, b""), percent_encode(value, b"")))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("a=1&b=hello+world&c&&d=%C3%BC%2x"), vec![
+            ("a".to_owned(), "1".to_owned()),
+            ("b".to_owned(), "hello world".to_owned()),
+            ("c".to_owned(), "".to_owned()),
+            ("d".to_owned(), "ü%2x".to_owned()),
+        ]);
+        assert_eq!(percent_decode("a+b%20c%", false), "a+b c%");
+    }
+
+    #[cfg(feature = "normalize")]
+    #[test]
+    fn test_serialize() {
+        assert_eq!(percent_encode("/ü a&b", b"/"), "/%C3%BC%20a%26b");
+        let pairs = parse("a=1+2&b=%26");
+        assert_eq!(parse(&serialize(&pairs)), pairs);
+    }
+}